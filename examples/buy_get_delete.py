@@ -51,7 +51,7 @@ response = requests.post(url, **req_kwargs, json={
     "orderType": "buy",
 })
 assert response.ok, 'Failed to execute buy transaction'
-order_id = response.text
+order_id = str(response.json()["orderId"])
 
 ####
 #### Get All User Orders
@@ -0,0 +1,4 @@
+DROP TABLE order_groups;
+
+DROP TYPE OrderGroupStatus;
+DROP TYPE OrderGroupKind;
@@ -0,0 +1,22 @@
+CREATE TYPE OrderGroupKind AS ENUM ('oco', 'bracket');
+CREATE TYPE OrderGroupStatus AS ENUM ('pending', 'active', 'done', 'cancelled');
+
+CREATE TABLE order_groups
+(
+    group_id bigint NOT NULL,
+    exchange ExchangeMarket NOT NULL,
+    user_id bigint NOT NULL,
+    kind OrderGroupKind NOT NULL,
+    status OrderGroupStatus NOT NULL,
+    exit_type OrderType NOT NULL,
+    quantity double precision NOT NULL,
+    take_profit_price bigint NOT NULL,
+    stop_trigger_price bigint NOT NULL,
+    stop_limit_price bigint NOT NULL,
+    entry_order bigint,
+    take_profit_order bigint,
+    stop_loss_order bigint,
+    created_at TIMESTAMPTZ DEFAULT now(),
+
+    PRIMARY KEY ("group_id", "exchange")  -- Composite primary key
+);
@@ -0,0 +1 @@
+ALTER TABLE orders ADD COLUMN notional double precision;  -- Quote-currency size
@@ -0,0 +1,5 @@
+DROP TABLE balances;
+DROP TABLE ledger_entries;
+
+DROP TYPE LedgerAccount;
+DROP TYPE Currency;
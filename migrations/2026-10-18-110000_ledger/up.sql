@@ -0,0 +1,27 @@
+CREATE TYPE Currency AS ENUM ('btc', 'usd', 'gbp', 'eur');
+CREATE TYPE LedgerAccount AS ENUM ('available', 'reserved', 'external');
+
+-- Double-entry ledger. Entries sharing a reference add up to zero per currency
+CREATE TABLE ledger_entries
+(
+    entry_id bigserial PRIMARY KEY,
+    reference text NOT NULL,
+    user_id bigint NOT NULL,
+    currency Currency NOT NULL,
+    account LedgerAccount NOT NULL,
+    amount double precision NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE INDEX ledger_entries_reference ON ledger_entries (reference);
+
+-- Running totals of the available and reserved ledger accounts
+CREATE TABLE balances
+(
+    user_id bigint NOT NULL,
+    currency Currency NOT NULL,
+    available double precision NOT NULL DEFAULT 0,
+    reserved double precision NOT NULL DEFAULT 0,
+
+    PRIMARY KEY ("user_id", "currency")  -- Composite primary key
+);
@@ -0,0 +1,4 @@
+DROP TABLE transfers;
+
+DROP TYPE TransferStatus;
+DROP TYPE TransferKind;
@@ -0,0 +1,18 @@
+CREATE TYPE TransferKind AS ENUM ('deposit', 'withdrawal');
+CREATE TYPE TransferStatus AS ENUM ('requested', 'approved', 'completed', 'failed');
+
+-- Funds entering (deposits) or leaving (withdrawals) the exchange through the custodian
+CREATE TABLE transfers
+(
+    transfer_id bigserial PRIMARY KEY,
+    user_id bigint NOT NULL,
+    kind TransferKind NOT NULL,
+    currency Currency NOT NULL,
+    amount double precision NOT NULL CHECK (amount > 0),
+    status TransferStatus NOT NULL DEFAULT 'requested',
+    external_reference text, -- Custodian reference once the funds moved
+    created_at TIMESTAMPTZ DEFAULT now(),
+    updated_at TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE INDEX transfers_user_id ON transfers (user_id);
@@ -0,0 +1,10 @@
+-- Pre-trade risk limits per user. NULL disables the check
+CREATE TABLE risk_limits
+(
+    user_id bigint PRIMARY KEY,
+    max_order_quantity double precision,
+    max_notional double precision,
+    max_open_orders integer,      -- Per market
+    max_orders_per_second integer,
+    max_price_deviation double precision -- Fraction of the last traded price
+);
@@ -0,0 +1,4 @@
+ALTER TABLE orders DROP COLUMN average_price;
+ALTER TABLE orders DROP COLUMN filled_quantity;
+ALTER TABLE orders DROP COLUMN status;
+DROP TYPE OrderStatus;
@@ -0,0 +1,28 @@
+CREATE TYPE OrderStatus AS ENUM ('new', 'partially_filled', 'filled', 'cancelled', 'rejected', 'expired');
+
+ALTER TABLE orders ADD COLUMN status OrderStatus NOT NULL DEFAULT 'new';
+ALTER TABLE orders ADD COLUMN filled_quantity double precision NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN average_price double precision; -- Volume-weighted price of the fills
+
+-- Backfill from the executions and cancellations already recorded
+UPDATE orders o
+SET filled_quantity = f.quantity,
+    average_price   = f.quote / f.quantity,
+    status          = 'partially_filled'
+FROM (SELECT exchange, order_id, SUM(quantity) AS quantity, SUM(price * quantity) AS quote
+      FROM (SELECT exchange, buy_order AS order_id, price, quantity FROM trades
+            UNION ALL
+            SELECT exchange, sell_order AS order_id, price, quantity FROM trades) t
+      GROUP BY exchange, order_id) f
+WHERE o.order_id = f.order_id AND o.exchange = f.exchange AND f.quantity > 0;
+
+UPDATE orders o
+SET status = 'cancelled'
+FROM cancelled_orders c
+WHERE o.order_id = c.order_id AND o.exchange = c.exchange;
+
+-- No longer pending nor cancelled. Without fills the order never made it to the book
+UPDATE orders o
+SET status = CASE WHEN o.filled_quantity > 0 THEN 'filled'::OrderStatus ELSE 'rejected'::OrderStatus END
+WHERE o.status <> 'cancelled'
+  AND NOT EXISTS (SELECT 1 FROM pending_orders p WHERE p.order_id = o.order_id AND p.exchange = o.exchange);
@@ -0,0 +1,2 @@
+DROP INDEX trades_exchange_trade_id;
+ALTER TABLE trades DROP COLUMN aggressor;
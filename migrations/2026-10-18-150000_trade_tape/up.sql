@@ -0,0 +1,6 @@
+-- Side of the incoming order. Older trades assume the newer order took liquidity
+ALTER TABLE trades ADD COLUMN aggressor OrderType;
+UPDATE trades SET aggressor = CASE WHEN buy_order > sell_order THEN 'buy' ELSE 'sell' END::OrderType;
+ALTER TABLE trades ALTER COLUMN aggressor SET NOT NULL;
+
+CREATE INDEX trades_exchange_trade_id ON trades (exchange, trade_id DESC);
@@ -0,0 +1,3 @@
+DROP INDEX trades_exchange_created_at;
+DROP TABLE candles;
+DROP TYPE CandleInterval;
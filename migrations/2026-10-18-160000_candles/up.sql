@@ -0,0 +1,20 @@
+CREATE TYPE CandleInterval AS ENUM ('one_minute', 'five_minutes', 'one_hour', 'one_day');
+
+-- OHLCV bars built from the trades as they are recorded
+CREATE TABLE candles
+(
+    exchange ExchangeMarket NOT NULL,
+    candle_interval CandleInterval NOT NULL,
+    open_time TIMESTAMPTZ NOT NULL,
+    open bigint NOT NULL,
+    high bigint NOT NULL,
+    low bigint NOT NULL,
+    close bigint NOT NULL,
+    volume double precision NOT NULL,       -- Base quantity
+    quote_volume double precision NOT NULL, -- Quote amount
+    trade_count integer NOT NULL,
+
+    PRIMARY KEY ("exchange", "candle_interval", "open_time")
+);
+
+CREATE INDEX trades_exchange_created_at ON trades (exchange, created_at);
@@ -0,0 +1,13 @@
+-- Next order and trade ids of every market. The match-services reserve them in blocks
+CREATE TABLE id_sequences
+(
+    exchange ExchangeMarket NOT NULL PRIMARY KEY,
+    next_order_id bigint NOT NULL DEFAULT 0,
+    next_trade_id bigint NOT NULL DEFAULT 0
+);
+
+INSERT INTO id_sequences (exchange, next_order_id, next_trade_id)
+SELECT market,
+       COALESCE((SELECT MAX(order_id) + 1 FROM orders WHERE exchange = market), 0),
+       COALESCE((SELECT MAX(trade_id) + 1 FROM trades WHERE exchange = market), 0)
+FROM unnest(enum_range(NULL::ExchangeMarket)) AS market;
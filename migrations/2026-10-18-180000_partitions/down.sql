@@ -0,0 +1,36 @@
+DROP FUNCTION rustex_archive_partitions;
+DROP FUNCTION rustex_create_partitions;
+DROP FUNCTION rustex_default_partition_has_rows;
+
+ALTER TABLE orders RENAME TO partitioned_orders;
+ALTER TABLE trades RENAME TO partitioned_trades;
+ALTER TABLE cancelled_orders RENAME TO partitioned_cancelled_orders;
+ALTER INDEX orders_pkey RENAME TO partitioned_orders_pkey;
+ALTER INDEX trades_pkey RENAME TO partitioned_trades_pkey;
+ALTER INDEX cancelled_orders_pkey RENAME TO partitioned_cancelled_orders_pkey;
+DROP INDEX orders_user_id_created_at;
+DROP INDEX trades_exchange_trade_id;
+DROP INDEX trades_exchange_created_at;
+
+CREATE TABLE orders (LIKE partitioned_orders INCLUDING DEFAULTS);
+ALTER TABLE orders ALTER COLUMN created_at DROP NOT NULL;
+ALTER TABLE orders ADD PRIMARY KEY ("order_id", "exchange");
+CREATE TABLE trades (LIKE partitioned_trades INCLUDING DEFAULTS);
+ALTER TABLE trades ALTER COLUMN created_at DROP NOT NULL;
+ALTER TABLE trades ADD PRIMARY KEY ("trade_id", "exchange");
+CREATE TABLE cancelled_orders (LIKE partitioned_cancelled_orders INCLUDING DEFAULTS);
+ALTER TABLE cancelled_orders ALTER COLUMN created_at DROP NOT NULL;
+ALTER TABLE cancelled_orders ADD PRIMARY KEY ("order_id", "exchange");
+
+INSERT INTO orders SELECT * FROM partitioned_orders;
+INSERT INTO trades SELECT * FROM partitioned_trades;
+INSERT INTO cancelled_orders SELECT * FROM partitioned_cancelled_orders;
+
+-- Drops the archived partitions along with the live ones
+DROP TABLE partitioned_orders;
+DROP TABLE partitioned_trades;
+DROP TABLE partitioned_cancelled_orders;
+DROP SCHEMA archive;
+
+CREATE INDEX trades_exchange_trade_id ON trades (exchange, trade_id DESC);
+CREATE INDEX trades_exchange_created_at ON trades (exchange, created_at);
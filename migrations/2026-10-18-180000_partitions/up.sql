@@ -0,0 +1,163 @@
+-- Orders, trades and cancellations are partitioned by month of creation. Old partitions
+-- are moved to the archive schema but stay attached, so every query still reaches them
+-- while those filtering by time skip them. Rows out of every partition land in the
+-- default one. The primary keys include the partition key, which can no longer be null
+CREATE SCHEMA archive;
+
+UPDATE orders SET created_at = now() WHERE created_at IS NULL;
+UPDATE trades SET created_at = now() WHERE created_at IS NULL;
+UPDATE cancelled_orders SET created_at = now() WHERE created_at IS NULL;
+
+ALTER TABLE orders RENAME TO unpartitioned_orders;
+ALTER TABLE trades RENAME TO unpartitioned_trades;
+ALTER TABLE cancelled_orders RENAME TO unpartitioned_cancelled_orders;
+ALTER INDEX orders_pkey RENAME TO unpartitioned_orders_pkey;
+ALTER INDEX trades_pkey RENAME TO unpartitioned_trades_pkey;
+ALTER INDEX cancelled_orders_pkey RENAME TO unpartitioned_cancelled_orders_pkey;
+DROP INDEX trades_exchange_trade_id;
+DROP INDEX trades_exchange_created_at;
+
+CREATE TABLE orders
+(
+    order_id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    price bigint NOT NULL,
+    quantity double precision NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    order_type OrderType NOT NULL,
+    exchange ExchangeMarket NOT NULL,
+    notional double precision,
+    status OrderStatus NOT NULL DEFAULT 'new',
+    filled_quantity double precision NOT NULL DEFAULT 0,
+    average_price double precision,
+
+    PRIMARY KEY ("order_id", "exchange", "created_at")
+) PARTITION BY RANGE (created_at);
+
+CREATE TABLE trades
+(
+    trade_id bigint NOT NULL,
+    exchange ExchangeMarket NOT NULL,
+    buy_order bigint NOT NULL,
+    sell_order bigint NOT NULL,
+    price bigint NOT NULL,
+    quantity double precision NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    aggressor OrderType NOT NULL,
+
+    PRIMARY KEY ("trade_id", "exchange", "created_at")
+) PARTITION BY RANGE (created_at);
+
+CREATE TABLE cancelled_orders
+(
+    order_id bigint NOT NULL,
+    exchange ExchangeMarket NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY ("order_id", "exchange", "created_at")
+) PARTITION BY RANGE (created_at);
+
+CREATE TABLE orders_default PARTITION OF orders DEFAULT;
+CREATE TABLE trades_default PARTITION OF trades DEFAULT;
+CREATE TABLE cancelled_orders_default PARTITION OF cancelled_orders DEFAULT;
+
+CREATE INDEX orders_user_id_created_at ON orders (user_id, created_at);
+CREATE INDEX trades_exchange_trade_id ON trades (exchange, trade_id DESC);
+CREATE INDEX trades_exchange_created_at ON trades (exchange, created_at);
+
+-- Creates the monthly partitions from the month of `since` until `months_ahead` months
+-- from now. Months whose rows already landed in the default partition are skipped
+CREATE FUNCTION rustex_create_partitions(since TIMESTAMPTZ, months_ahead integer) RETURNS integer AS $$
+DECLARE
+    parent text;
+    month date;
+    partition text;
+    created integer := 0;
+BEGIN
+    FOREACH parent IN ARRAY ARRAY['orders', 'trades', 'cancelled_orders'] LOOP
+        FOR month IN
+            SELECT generate_series(date_trunc('month', since),
+                                   date_trunc('month', now()) + make_interval(months => months_ahead),
+                                   interval '1 month')::date
+        LOOP
+            partition := format('%s_p%s', parent, to_char(month, 'YYYYMM'));
+            CONTINUE WHEN to_regclass('public.' || partition) IS NOT NULL
+                       OR to_regclass('archive.' || partition) IS NOT NULL;
+            CONTINUE WHEN rustex_default_partition_has_rows(parent, month);
+            EXECUTE format('CREATE TABLE %I PARTITION OF %I FOR VALUES FROM (%L) TO (%L)',
+                           partition, parent, month, month + interval '1 month');
+            created := created + 1;
+        END LOOP;
+    END LOOP;
+    RETURN created;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION rustex_default_partition_has_rows(parent text, month date) RETURNS boolean AS $$
+DECLARE
+    found boolean;
+BEGIN
+    EXECUTE format('SELECT EXISTS (SELECT 1 FROM %I WHERE created_at >= %L AND created_at < %L)',
+                   parent || '_default', month, month + interval '1 month')
+        INTO found;
+    RETURN found;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Moves to the archive schema the partitions of the months ended before `before`.
+-- Order partitions holding pending orders stay until every order is completed
+CREATE FUNCTION rustex_archive_partitions(before TIMESTAMPTZ) RETURNS integer AS $$
+DECLARE
+    partition record;
+    month date;
+    pending boolean;
+    archived integer := 0;
+BEGIN
+    FOR partition IN
+        SELECT c.relname, p.relname AS parent
+        FROM pg_inherits i
+                 JOIN pg_class c ON c.oid = i.inhrelid
+                 JOIN pg_class p ON p.oid = i.inhparent
+                 JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE n.nspname = 'public'
+          AND p.relname IN ('orders', 'trades', 'cancelled_orders')
+          AND c.relname ~ '_p\d{6}$'
+    LOOP
+        month := to_date(substring(partition.relname from '(\d{6})$'), 'YYYYMM');
+        CONTINUE WHEN month + interval '1 month' > before;
+        IF partition.parent = 'orders' THEN
+            EXECUTE format('SELECT EXISTS (SELECT 1 FROM %I o JOIN pending_orders p
+                            ON p.order_id = o.order_id AND p.exchange = o.exchange)',
+                           partition.relname)
+                INTO pending;
+            CONTINUE WHEN pending;
+        END IF;
+        EXECUTE format('ALTER TABLE %I SET SCHEMA archive', partition.relname);
+        archived := archived + 1;
+    END LOOP;
+    RETURN archived;
+END;
+$$ LANGUAGE plpgsql;
+
+SELECT rustex_create_partitions(
+    LEAST((SELECT MIN(created_at) FROM unpartitioned_orders),
+          (SELECT MIN(created_at) FROM unpartitioned_trades),
+          (SELECT MIN(created_at) FROM unpartitioned_cancelled_orders),
+          now()),
+    2);
+
+INSERT INTO orders (order_id, user_id, price, quantity, created_at, order_type, exchange, notional, status,
+                    filled_quantity, average_price)
+SELECT order_id, user_id, price, quantity, created_at, order_type, exchange, notional, status,
+       filled_quantity, average_price
+FROM unpartitioned_orders;
+INSERT INTO trades (trade_id, exchange, buy_order, sell_order, price, quantity, created_at, aggressor)
+SELECT trade_id, exchange, buy_order, sell_order, price, quantity, created_at, aggressor
+FROM unpartitioned_trades;
+INSERT INTO cancelled_orders (order_id, exchange, created_at)
+SELECT order_id, exchange, created_at
+FROM unpartitioned_cancelled_orders;
+
+DROP TABLE unpartitioned_orders;
+DROP TABLE unpartitioned_trades;
+DROP TABLE unpartitioned_cancelled_orders;
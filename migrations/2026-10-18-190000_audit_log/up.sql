@@ -0,0 +1,32 @@
+CREATE TYPE AuditAction AS ENUM (
+    'order_accepted', 'order_rejected', 'order_cancelled', 'orders_expired', 'group_placed', 'group_cancelled',
+    'session_closed', 'trade', 'transfer_requested', 'transfer_approved', 'transfer_rejected',
+    'risk_limits_updated', 'risk_limits_deleted', 'candles_backfilled', 'book_reconciled'
+);
+
+-- Every row is hashed along with the hash of the previous one, so editing any row breaks the chain
+CREATE TABLE audit_log
+(
+    seq bigint PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL,
+    actor bigint,     -- Unset for the changes made by the exchange itself
+    source_ip text,
+    action AuditAction NOT NULL,
+    exchange ExchangeMarket,
+    details text NOT NULL, -- JSON
+    prev_hash text NOT NULL,
+    hash text NOT NULL
+);
+
+CREATE FUNCTION rustex_audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'The audit log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_append_only
+    BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION rustex_audit_log_append_only();
+CREATE TRIGGER audit_log_no_truncate
+    BEFORE TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE FUNCTION rustex_audit_log_append_only();
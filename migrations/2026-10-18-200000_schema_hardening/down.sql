@@ -0,0 +1,22 @@
+ALTER TABLE order_groups DROP CONSTRAINT order_groups_price_check;
+ALTER TABLE order_groups DROP CONSTRAINT order_groups_quantity_check;
+ALTER TABLE trades DROP CONSTRAINT trades_quantity_check;
+ALTER TABLE trades DROP CONSTRAINT trades_price_check;
+ALTER TABLE orders DROP CONSTRAINT orders_quantity_check;
+ALTER TABLE orders DROP CONSTRAINT orders_price_check;
+
+ALTER TABLE trades DROP CONSTRAINT trades_sell_order_fkey;
+ALTER TABLE trades DROP CONSTRAINT trades_buy_order_fkey;
+ALTER TABLE cancelled_orders DROP CONSTRAINT cancelled_orders_order_key_fkey;
+ALTER TABLE pending_orders DROP CONSTRAINT pending_orders_order_key_fkey;
+ALTER TABLE orders DROP CONSTRAINT orders_order_key_fkey;
+DROP TRIGGER orders_register_key ON orders;
+DROP FUNCTION rustex_register_order_key;
+DROP TABLE order_keys;
+
+ALTER TABLE orders DROP CONSTRAINT orders_user_id_fkey;
+DROP TABLE users;
+
+CREATE SEQUENCE pending_orders_order_id_seq OWNED BY pending_orders.order_id;
+SELECT setval('pending_orders_order_id_seq', COALESCE(MAX(order_id), 0) + 1, false) FROM pending_orders;
+ALTER TABLE pending_orders ALTER COLUMN order_id SET DEFAULT nextval('pending_orders_order_id_seq');
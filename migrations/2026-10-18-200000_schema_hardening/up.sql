@@ -0,0 +1,61 @@
+-- Order ids are assigned by the exchange, the sequence left by bigserial is never used
+ALTER TABLE pending_orders ALTER COLUMN order_id DROP DEFAULT;
+DROP SEQUENCE pending_orders_order_id_seq;
+
+-- Every user placing orders. They are registered along with their first order
+CREATE TABLE users
+(
+    user_id bigint PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO users (user_id)
+SELECT user_id FROM orders
+UNION SELECT user_id FROM order_groups
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE orders ADD CONSTRAINT orders_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users (user_id);
+
+-- Orders are keyed by their creation time as well, which cannot be referenced
+-- from the tables that only know the order id. Every order registers its key
+-- here on insertion, which also keeps order ids unique across partitions
+CREATE TABLE order_keys
+(
+    order_id bigint NOT NULL,
+    exchange ExchangeMarket NOT NULL,
+
+    PRIMARY KEY ("order_id", "exchange")
+);
+
+INSERT INTO order_keys (order_id, exchange)
+SELECT order_id, exchange FROM orders;
+
+CREATE FUNCTION rustex_register_order_key() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO order_keys (order_id, exchange) VALUES (NEW.order_id, NEW.exchange);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER orders_register_key BEFORE INSERT ON orders
+    FOR EACH ROW EXECUTE FUNCTION rustex_register_order_key();
+
+ALTER TABLE orders ADD CONSTRAINT orders_order_key_fkey
+    FOREIGN KEY (order_id, exchange) REFERENCES order_keys (order_id, exchange);
+ALTER TABLE pending_orders ADD CONSTRAINT pending_orders_order_key_fkey
+    FOREIGN KEY (order_id, exchange) REFERENCES order_keys (order_id, exchange);
+ALTER TABLE cancelled_orders ADD CONSTRAINT cancelled_orders_order_key_fkey
+    FOREIGN KEY (order_id, exchange) REFERENCES order_keys (order_id, exchange);
+ALTER TABLE trades ADD CONSTRAINT trades_buy_order_fkey
+    FOREIGN KEY (buy_order, exchange) REFERENCES order_keys (order_id, exchange);
+ALTER TABLE trades ADD CONSTRAINT trades_sell_order_fkey
+    FOREIGN KEY (sell_order, exchange) REFERENCES order_keys (order_id, exchange);
+
+ALTER TABLE orders ADD CONSTRAINT orders_price_check CHECK (price > 0);
+ALTER TABLE orders ADD CONSTRAINT orders_quantity_check CHECK (quantity > 0);
+ALTER TABLE trades ADD CONSTRAINT trades_price_check CHECK (price > 0);
+ALTER TABLE trades ADD CONSTRAINT trades_quantity_check CHECK (quantity > 0);
+ALTER TABLE order_groups ADD CONSTRAINT order_groups_quantity_check CHECK (quantity > 0);
+ALTER TABLE order_groups ADD CONSTRAINT order_groups_price_check
+    CHECK (take_profit_price > 0 AND stop_trigger_price > 0 AND stop_limit_price > 0);
@@ -0,0 +1,2 @@
+-- The cancelled orders are no longer pending either way
+SELECT 1;
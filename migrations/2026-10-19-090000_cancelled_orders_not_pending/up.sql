@@ -0,0 +1,4 @@
+-- Cancelled orders were kept in the pending orders, reloading them into the book
+DELETE FROM pending_orders p
+USING cancelled_orders c
+WHERE p.order_id = c.order_id AND p.exchange = c.exchange;
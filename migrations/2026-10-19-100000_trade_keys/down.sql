@@ -0,0 +1,4 @@
+ALTER TABLE trades DROP CONSTRAINT trades_trade_key_fkey;
+DROP TRIGGER trades_register_key ON trades;
+DROP FUNCTION rustex_register_trade_key;
+DROP TABLE trade_keys;
@@ -0,0 +1,25 @@
+-- Trades are keyed by their creation time as well, so the primary key no longer keeps
+-- trade ids unique. Every trade registers its key here on insertion instead
+CREATE TABLE trade_keys
+(
+    trade_id bigint NOT NULL,
+    exchange ExchangeMarket NOT NULL,
+
+    PRIMARY KEY ("trade_id", "exchange")
+);
+
+INSERT INTO trade_keys (trade_id, exchange)
+SELECT trade_id, exchange FROM trades;
+
+CREATE FUNCTION rustex_register_trade_key() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO trade_keys (trade_id, exchange) VALUES (NEW.trade_id, NEW.exchange);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER trades_register_key BEFORE INSERT ON trades
+    FOR EACH ROW EXECUTE FUNCTION rustex_register_trade_key();
+
+ALTER TABLE trades ADD CONSTRAINT trades_trade_key_fkey
+    FOREIGN KEY (trade_id, exchange) REFERENCES trade_keys (trade_id, exchange);
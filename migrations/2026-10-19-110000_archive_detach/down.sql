@@ -0,0 +1,68 @@
+DROP FUNCTION rustex_archive_cutoff;
+DROP FUNCTION rustex_archive_partitions;
+
+-- The archived partitions are attached to the live tables again, staying in the archive schema
+DO $$
+DECLARE
+    partition record;
+    month date;
+BEGIN
+    FOR partition IN
+        SELECT c.relname, p.relname AS parent
+        FROM pg_inherits i
+                 JOIN pg_class c ON c.oid = i.inhrelid
+                 JOIN pg_class p ON p.oid = i.inhparent
+                 JOIN pg_namespace pn ON pn.oid = p.relnamespace
+        WHERE pn.nspname = 'archive'
+          AND c.relkind = 'r'
+    LOOP
+        month := to_date(substring(partition.relname from '(\d{6})$'), 'YYYYMM');
+        EXECUTE format('ALTER TABLE archive.%I DETACH PARTITION archive.%I', partition.parent, partition.relname);
+        EXECUTE format('ALTER TABLE public.%I ATTACH PARTITION archive.%I FOR VALUES FROM (%L) TO (%L)',
+                       partition.parent, partition.relname, month, month + interval '1 month');
+    END LOOP;
+END;
+$$;
+
+DROP FUNCTION rustex_detach_partition;
+DROP VIEW all_orders;
+DROP VIEW all_trades;
+DROP VIEW all_cancelled_orders;
+DROP TABLE archive.orders;
+DROP TABLE archive.trades;
+DROP TABLE archive.cancelled_orders;
+
+-- Moves to the archive schema the partitions of the months ended before `before`.
+-- Order partitions holding pending orders stay until every order is completed
+CREATE FUNCTION rustex_archive_partitions(before TIMESTAMPTZ) RETURNS integer AS $$
+DECLARE
+    partition record;
+    month date;
+    pending boolean;
+    archived integer := 0;
+BEGIN
+    FOR partition IN
+        SELECT c.relname, p.relname AS parent
+        FROM pg_inherits i
+                 JOIN pg_class c ON c.oid = i.inhrelid
+                 JOIN pg_class p ON p.oid = i.inhparent
+                 JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE n.nspname = 'public'
+          AND p.relname IN ('orders', 'trades', 'cancelled_orders')
+          AND c.relname ~ '_p\d{6}$'
+    LOOP
+        month := to_date(substring(partition.relname from '(\d{6})$'), 'YYYYMM');
+        CONTINUE WHEN month + interval '1 month' > before;
+        IF partition.parent = 'orders' THEN
+            EXECUTE format('SELECT EXISTS (SELECT 1 FROM %I o JOIN pending_orders p
+                            ON p.order_id = o.order_id AND p.exchange = o.exchange)',
+                           partition.relname)
+                INTO pending;
+            CONTINUE WHEN pending;
+        END IF;
+        EXECUTE format('ALTER TABLE %I SET SCHEMA archive', partition.relname);
+        archived := archived + 1;
+    END LOOP;
+    RETURN archived;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,126 @@
+-- Archived partitions are detached from the live tables, so that queries no longer reach
+-- them, and attached to the tables of the archive schema instead. The all_* views read
+-- both, for the queries whose range starts before the archive cut-off
+CREATE TABLE archive.orders (LIKE public.orders INCLUDING DEFAULTS,
+    PRIMARY KEY ("order_id", "exchange", "created_at")
+) PARTITION BY RANGE (created_at);
+CREATE TABLE archive.trades (LIKE public.trades INCLUDING DEFAULTS,
+    PRIMARY KEY ("trade_id", "exchange", "created_at")
+) PARTITION BY RANGE (created_at);
+CREATE TABLE archive.cancelled_orders (LIKE public.cancelled_orders INCLUDING DEFAULTS,
+    PRIMARY KEY ("order_id", "exchange", "created_at")
+) PARTITION BY RANGE (created_at);
+
+CREATE INDEX archive_orders_user_id_created_at ON archive.orders (user_id, created_at);
+CREATE INDEX archive_trades_exchange_created_at ON archive.trades (exchange, created_at);
+
+CREATE VIEW all_orders AS
+SELECT * FROM public.orders UNION ALL SELECT * FROM archive.orders;
+CREATE VIEW all_trades AS
+SELECT * FROM public.trades UNION ALL SELECT * FROM archive.trades;
+CREATE VIEW all_cancelled_orders AS
+SELECT * FROM public.cancelled_orders UNION ALL SELECT * FROM archive.cancelled_orders;
+
+-- Moves a monthly partition of the live table `parent` to the archive
+CREATE FUNCTION rustex_detach_partition(parent text, month date) RETURNS boolean AS $$
+DECLARE
+    partition text := format('%s_p%s', parent, to_char(month, 'YYYYMM'));
+    archived boolean := to_regclass('archive.' || partition) IS NOT NULL;
+BEGIN
+    IF to_regclass('public.' || partition) IS NULL AND NOT archived THEN
+        RETURN false;
+    END IF;
+    IF archived THEN
+        EXECUTE format('ALTER TABLE public.%I DETACH PARTITION archive.%I', parent, partition);
+    ELSE
+        EXECUTE format('ALTER TABLE public.%I DETACH PARTITION public.%I', parent, partition);
+        EXECUTE format('ALTER TABLE public.%I SET SCHEMA archive', partition);
+    END IF;
+    EXECUTE format('ALTER TABLE archive.%I ATTACH PARTITION archive.%I FOR VALUES FROM (%L) TO (%L)',
+                   parent, partition, month, month + interval '1 month');
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+-- The partitions moved to the archive schema so far stay there, detached
+DO $$
+DECLARE
+    partition record;
+BEGIN
+    FOR partition IN
+        SELECT c.relname, p.relname AS parent
+        FROM pg_inherits i
+                 JOIN pg_class c ON c.oid = i.inhrelid
+                 JOIN pg_class p ON p.oid = i.inhparent
+                 JOIN pg_namespace n ON n.oid = c.relnamespace
+                 JOIN pg_namespace pn ON pn.oid = p.relnamespace
+        WHERE n.nspname = 'archive'
+          AND pn.nspname = 'public'
+          AND c.relkind = 'r'
+    LOOP
+        PERFORM rustex_detach_partition(partition.parent,
+                                        to_date(substring(partition.relname from '(\d{6})$'), 'YYYYMM'));
+    END LOOP;
+END;
+$$;
+
+-- Archives the months ended before `before`, oldest first. A month is archived along with
+-- every earlier one, and only once its orders are completed and none of them has trades
+-- or cancellations in a later month, so the live tables hold every row from the cut-off on
+DROP FUNCTION rustex_archive_partitions;
+CREATE FUNCTION rustex_archive_partitions(before TIMESTAMPTZ) RETURNS integer AS $$
+DECLARE
+    month date;
+    month_end TIMESTAMPTZ;
+    parent text;
+    archived integer := 0;
+BEGIN
+    FOR month IN
+        SELECT DISTINCT to_date(substring(c.relname from '(\d{6})$'), 'YYYYMM')
+        FROM pg_inherits i
+                 JOIN pg_class c ON c.oid = i.inhrelid
+                 JOIN pg_class p ON p.oid = i.inhparent
+                 JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE n.nspname = 'public'
+          AND p.relname IN ('orders', 'trades', 'cancelled_orders')
+          AND c.relname ~ '_p\d{6}$'
+        ORDER BY 1
+    LOOP
+        month_end := month + interval '1 month';
+        EXIT WHEN month_end > before;
+        EXIT WHEN EXISTS (SELECT 1
+                          FROM orders o
+                                   JOIN pending_orders p ON p.order_id = o.order_id AND p.exchange = o.exchange
+                          WHERE o.created_at < month_end);
+        EXIT WHEN EXISTS (SELECT 1
+                          FROM trades t
+                                   JOIN orders o ON o.exchange = t.exchange
+                              AND o.order_id IN (t.buy_order, t.sell_order)
+                          WHERE t.created_at >= month_end
+                            AND o.created_at < month_end);
+        EXIT WHEN EXISTS (SELECT 1
+                          FROM cancelled_orders c
+                                   JOIN orders o ON o.order_id = c.order_id AND o.exchange = c.exchange
+                          WHERE c.created_at >= month_end
+                            AND o.created_at < month_end);
+        FOREACH parent IN ARRAY ARRAY['orders', 'trades', 'cancelled_orders'] LOOP
+            IF to_regclass(format('public.%s_p%s', parent, to_char(month, 'YYYYMM'))) IS NOT NULL THEN
+                PERFORM rustex_detach_partition(parent, month);
+                archived := archived + 1;
+            END IF;
+        END LOOP;
+    END LOOP;
+    RETURN archived;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Start of the live rows: the end of the latest archived month, or null if none was
+CREATE FUNCTION rustex_archive_cutoff() RETURNS TIMESTAMPTZ AS $$
+SELECT (max(to_date(substring(c.relname from '(\d{6})$'), 'YYYYMM')) + interval '1 month')::timestamptz
+FROM pg_inherits i
+         JOIN pg_class c ON c.oid = i.inhrelid
+         JOIN pg_class p ON p.oid = i.inhparent
+         JOIN pg_namespace n ON n.oid = p.relnamespace
+WHERE n.nspname = 'archive'
+  AND p.relname IN ('orders', 'trades', 'cancelled_orders');
+$$ LANGUAGE sql STABLE;
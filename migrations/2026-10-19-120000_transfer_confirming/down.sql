@@ -0,0 +1,11 @@
+-- Enum values cannot be dropped, so the type is recreated without it.
+-- Deposits still confirming go back to waiting for the administrator
+UPDATE transfers SET status = 'requested' WHERE status = 'confirming';
+
+ALTER TYPE TransferStatus RENAME TO TransferStatus_old;
+CREATE TYPE TransferStatus AS ENUM ('requested', 'approved', 'completed', 'failed');
+ALTER TABLE transfers
+    ALTER COLUMN status DROP DEFAULT,
+    ALTER COLUMN status TYPE TransferStatus USING status::text::TransferStatus,
+    ALTER COLUMN status SET DEFAULT 'requested';
+DROP TYPE TransferStatus_old;
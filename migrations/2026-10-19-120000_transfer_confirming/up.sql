@@ -0,0 +1,3 @@
+-- Deposits are marked confirming before the custodian books them, so the db-service
+-- confirms them again on startup if their outcome was lost
+ALTER TYPE TransferStatus ADD VALUE 'confirming' AFTER 'requested';
@@ -0,0 +1,54 @@
+//! Audit of the state changes requested through the REST API
+
+use std::convert::Infallible;
+
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use futures_util::future::{ready, Ready};
+use rustex_core::prelude::{AuditAction, AuditEvent, ExchangeMarket};
+use serde::Serialize;
+use tarpc::context::Context;
+
+use crate::{api_rest::state::AppState, auth::Claims};
+
+/// Address of the peer that sent the request
+pub struct SourceIp(Option<String>);
+
+impl SourceIp {
+    /// Passed on to the services that audit the changes themselves
+    pub fn address(&self) -> Option<String> {
+        self.0.clone()
+    }
+}
+
+impl FromRequest for SourceIp {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(SourceIp(
+            req.peer_addr().map(|addr| addr.ip().to_string()),
+        )))
+    }
+}
+
+/// Appends the change to the audit log. It is already made, so failures are only logged
+pub async fn record(
+    state: &AppState,
+    user: &Claims,
+    source_ip: &SourceIp,
+    action: AuditAction,
+    exchange: Option<ExchangeMarket>,
+    details: impl Serialize,
+) {
+    let event = AuditEvent {
+        actor: Some(user.sub),
+        source_ip: source_ip.0.clone(),
+        action,
+        exchange,
+        details: serde_json::to_string(&details).unwrap_or_default(),
+    };
+    let audit = state.db.append_audit(Context::current(), event);
+    if let Err(e) = async { audit.await? }.await {
+        log::error!("Failed to audit {:?} by {:?}: {:?}", action, user.sub, e);
+    }
+}
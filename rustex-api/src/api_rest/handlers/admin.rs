@@ -0,0 +1,110 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+use rustex_core::prelude::{AuditAction, ExchangeMarket};
+use rustex_errors::RustexError;
+use serde::Deserialize;
+use serde_json::json;
+use tarpc::context::Context;
+
+use crate::{
+    api_rest::{
+        audit::{self, SourceIp},
+        state::AppState,
+    },
+    auth::Claims,
+};
+
+#[derive(Deserialize)]
+pub struct ReconcileQuery {
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Deserialize)]
+pub struct BackfillQuery {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct BookQuery {
+    at: DateTime<Utc>,
+}
+
+/// Trades executed by each market but not yet recorded in the DB
+pub async fn get_outbox_backlog(
+    user: Claims,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let mut backlogs = HashMap::new();
+    for (market, rpc_client) in state.match_orders.iter() {
+        let backlog = rpc_client.get_outbox_backlog(Context::current()).await?;
+        backlogs.insert(market, backlog);
+    }
+    Ok(HttpResponse::Ok().json(backlogs))
+}
+
+/// Compares the book of the market with the DB, optionally repairing the book
+pub async fn reconcile(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    path: web::Path<ExchangeMarket>,
+    query: web::Query<ReconcileQuery>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let market = path.into_inner();
+    if let Some(market_rpc) = state.match_orders.get(&market) {
+        let report = market_rpc
+            .reconcile(Context::current(), query.repair)
+            .await??;
+        if query.repair {
+            // Only repairs change the book
+            let action = AuditAction::BookReconciled;
+            audit::record(&state, &user, &source_ip, action, Some(market), &report).await;
+        }
+        Ok(HttpResponse::Ok().json(report))
+    } else {
+        Err(RustexError::UserFacingError(
+            "Requested market exchange is not available in this server".into(),
+        ))
+    }
+}
+
+/// Rebuilds the candles of the market over the time range from the recorded trades
+pub async fn backfill_candles(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    path: web::Path<ExchangeMarket>,
+    query: web::Query<BackfillQuery>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let market = path.into_inner();
+    let candles = state
+        .db
+        .backfill_candles(Context::current(), market, query.from, query.to)
+        .await??;
+    let details = json!({ "from": query.from, "to": query.to, "candles": candles });
+    let action = AuditAction::CandlesBackfilled;
+    audit::record(&state, &user, &source_ip, action, Some(market), details).await;
+    Ok(HttpResponse::Ok().json(candles))
+}
+
+/// Book of the market as it stood at the given time, price levels and resting orders
+pub async fn get_book_at(
+    user: Claims,
+    state: web::Data<AppState>,
+    path: web::Path<ExchangeMarket>,
+    query: web::Query<BookQuery>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let market = path.into_inner();
+    let book = state
+        .db
+        .get_book_at(Context::current(), market, query.at)
+        .await??;
+    Ok(HttpResponse::Ok().json(book))
+}
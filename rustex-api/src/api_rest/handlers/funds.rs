@@ -0,0 +1,109 @@
+use actix_web::{web, HttpResponse};
+use rustex_core::prelude::{AuditOrigin, ClientTransfer, ExchangeMarket, TransferKind};
+use rustex_errors::RustexError;
+use serde::Deserialize;
+use tarpc::context::Context;
+
+use crate::{
+    api_rest::{audit::SourceIp, state::AppState},
+    auth::Claims,
+};
+
+/// Market whose database books the funds. Only optional while the markets share the default database
+#[derive(Deserialize)]
+pub struct FundsQuery {
+    market: Option<ExchangeMarket>,
+}
+
+pub async fn get_balances(
+    user: Claims,
+    state: web::Data<AppState>,
+    query: web::Query<FundsQuery>,
+) -> Result<HttpResponse, RustexError> {
+    let balances = state
+        .db
+        .get_user_balances(Context::current(), user.sub, query.market)
+        .await??;
+    Ok(HttpResponse::Ok().json(balances))
+}
+
+pub async fn get_transfers(
+    user: Claims,
+    state: web::Data<AppState>,
+    query: web::Query<FundsQuery>,
+) -> Result<HttpResponse, RustexError> {
+    let transfers = state
+        .db
+        .get_user_transfers(Context::current(), user.sub, query.market)
+        .await??;
+    Ok(HttpResponse::Ok().json(transfers))
+}
+
+pub async fn deposit(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    transfer: web::Json<ClientTransfer>,
+) -> Result<HttpResponse, RustexError> {
+    let kind = TransferKind::Deposit;
+    request_transfer(user, source_ip, state, transfer.into_inner(), kind).await
+}
+
+pub async fn withdraw(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    transfer: web::Json<ClientTransfer>,
+) -> Result<HttpResponse, RustexError> {
+    let kind = TransferKind::Withdrawal;
+    request_transfer(user, source_ip, state, transfer.into_inner(), kind).await
+}
+
+async fn request_transfer(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    transfer: ClientTransfer,
+    kind: TransferKind,
+) -> Result<HttpResponse, RustexError> {
+    let market = transfer.market;
+    let transfer = transfer.into_transfer(user.sub, kind)?;
+    let origin = AuditOrigin::user(user.sub, source_ip.address());
+    let transfer = state
+        .db
+        .request_transfer(Context::current(), transfer, market, origin)
+        .await??;
+    Ok(HttpResponse::Ok().json(transfer))
+}
+
+pub async fn approve_transfer(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    path: web::Path<i64>,
+    query: web::Query<FundsQuery>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let origin = AuditOrigin::user(user.sub, source_ip.address());
+    let transfer = state
+        .db
+        .approve_transfer(Context::current(), path.into_inner(), query.market, origin)
+        .await??;
+    Ok(HttpResponse::Ok().json(transfer))
+}
+
+pub async fn reject_transfer(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    path: web::Path<i64>,
+    query: web::Query<FundsQuery>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let origin = AuditOrigin::user(user.sub, source_ip.address());
+    let transfer = state
+        .db
+        .reject_transfer(Context::current(), path.into_inner(), query.market, origin)
+        .await??;
+    Ok(HttpResponse::Ok().json(transfer))
+}
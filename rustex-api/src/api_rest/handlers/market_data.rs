@@ -0,0 +1,38 @@
+use actix_web::{web, HttpResponse};
+use rustex_core::prelude::{CandleQuery, ExchangeMarket, TapeQuery};
+use rustex_errors::RustexError;
+use tarpc::context::Context;
+
+use crate::api_rest::state::AppState;
+
+/// Most recent trades of the market, newest first
+pub async fn get_trade_tape(
+    state: web::Data<AppState>,
+    path: web::Path<ExchangeMarket>,
+    query: web::Query<TapeQuery>,
+) -> Result<HttpResponse, RustexError> {
+    let market = path.into_inner();
+    if let Some(market_rpc) = state.match_orders.get(&market) {
+        let trades = market_rpc
+            .get_trade_tape(Context::current(), query.into_inner())
+            .await??;
+        Ok(HttpResponse::Ok().json(trades))
+    } else {
+        Err(RustexError::UserFacingError(
+            "Requested market exchange is not available in this server".into(),
+        ))
+    }
+}
+
+/// OHLCV candles of the market for the interval, oldest first
+pub async fn get_candles(
+    state: web::Data<AppState>,
+    path: web::Path<ExchangeMarket>,
+    query: web::Query<CandleQuery>,
+) -> Result<HttpResponse, RustexError> {
+    let candles = state
+        .db
+        .get_candles(Context::current(), path.into_inner(), query.into_inner())
+        .await??;
+    Ok(HttpResponse::Ok().json(candles))
+}
@@ -1,3 +1,9 @@
+pub mod admin;
+pub mod funds;
 pub mod health;
+pub mod market_data;
 pub mod orders;
+pub mod positions;
+pub mod risk;
+pub mod sessions;
 pub mod users;
@@ -1,49 +1,54 @@
 use actix_web::{web, HttpResponse};
-use hashbrown::HashMap;
-use rustex_core::prelude::{ClientOrder, ExchangeMarket, OrderId};
+use rustex_core::prelude::{
+    ClientOrder, ClientOrderGroup, ExchangeMarket, GroupId, HistoryQuery, OrderId,
+};
 use rustex_errors::RustexError;
 use tarpc::context::Context;
-use tokio::task::JoinSet;
 
-use crate::{api_rest::state::AppState, auth::Claims};
+use crate::{
+    api_rest::{audit::SourceIp, state::AppState},
+    auth::Claims,
+};
 
+/// Orders of the user, newest first
 pub async fn get_orders(
     user: Claims,
     state: web::Data<AppState>,
+    query: web::Query<HistoryQuery>,
 ) -> Result<HttpResponse, RustexError> {
-    let mut tasks = JoinSet::new();
-
-    for (&market, rpc_client) in state.match_orders.iter() {
-        let rpc_client = rpc_client.clone();
-        tasks.spawn(async move {
-            if let Ok(Ok(orders)) = rpc_client
-                .get_user_orders(Context::current(), user.sub)
-                .await
-            {
-                (market, Some(orders))
-            } else {
-                log::error!("Failed to pull the orders for market: {:?}", market);
-                (market, None)
-            }
-        });
-    }
-
-    let orders: HashMap<ExchangeMarket, Option<Vec<OrderId>>> =
-        tasks.join_all().await.into_iter().collect();
-
+    let orders = state
+        .db
+        .get_order_history(Context::current(), user.sub, query.into_inner())
+        .await??;
     Ok(HttpResponse::Ok().json(orders))
 }
 
+/// Executions of the orders of the user, newest first
+pub async fn get_trades(
+    user: Claims,
+    state: web::Data<AppState>,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, RustexError> {
+    let trades = state
+        .db
+        .get_trade_history(Context::current(), user.sub, query.into_inner())
+        .await??;
+    Ok(HttpResponse::Ok().json(trades))
+}
+
 pub async fn insert_order(
     order_info: web::Json<ClientOrder>,
     user: Claims,
+    source_ip: SourceIp,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse, RustexError> {
-    if let Some(match_service) = state.match_orders.get(&order_info.exchange) {
-        let order_id = match_service
-            .insert_order(Context::current(), user.sub, order_info.into_inner())
+    let order = order_info.into_inner();
+    if let Some(match_service) = state.match_orders.get(&order.exchange) {
+        // Audited by the services along with the order
+        let execution = match_service
+            .insert_order(Context::current(), user.sub, order, source_ip.address())
             .await??;
-        Ok(HttpResponse::Ok().json(order_id))
+        Ok(HttpResponse::Ok().json(execution))
     } else {
         Err(RustexError::MatchServiceError(
             "Exchange market is not available in this server".into(),
@@ -58,10 +63,10 @@ pub async fn get_order_state(
 ) -> Result<HttpResponse, RustexError> {
     let (market, order_id) = (path.0, path.1);
     if let Some(market_rpc) = state.match_orders.get(&market) {
-        let progress = market_rpc
-            .get_order_progress(Context::current(), user.sub, order_id, market)
+        let details = market_rpc
+            .get_order_details(Context::current(), user.sub, order_id, market)
             .await??;
-        Ok(HttpResponse::Ok().json(progress))
+        Ok(HttpResponse::Ok().json(details))
     } else {
         Err(RustexError::UserFacingError(
             "Requested market exchange is not available in this server".into(),
@@ -73,11 +78,87 @@ pub async fn try_delete_order(
     state: web::Data<AppState>,
     path: web::Path<(ExchangeMarket, OrderId)>,
     user: Claims,
+    source_ip: SourceIp,
 ) -> Result<HttpResponse, RustexError> {
     let (market, order_id) = (path.0, path.1);
+    if let Some(market_rpc) = state.match_orders.get(&market) {
+        // Audited by the services along with the cancellation
+        let is_deleted = market_rpc
+            .try_delete_order(
+                Context::current(),
+                user.sub,
+                order_id,
+                market,
+                source_ip.address(),
+            )
+            .await??;
+        Ok(HttpResponse::Ok().json(is_deleted))
+    } else {
+        Err(RustexError::UserFacingError(
+            "Requested market exchange is not available in this server".into(),
+        ))
+    }
+}
+
+pub async fn insert_order_group(
+    group_info: web::Json<ClientOrderGroup>,
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, RustexError> {
+    let client_group = group_info.into_inner();
+    if let Some(match_service) = state.match_orders.get(&client_group.exchange) {
+        // Audited by the match-service along with its orders
+        let group = match_service
+            .insert_order_group(
+                Context::current(),
+                user.sub,
+                client_group,
+                source_ip.address(),
+            )
+            .await??;
+        Ok(HttpResponse::Ok().json(group))
+    } else {
+        Err(RustexError::MatchServiceError(
+            "Exchange market is not available in this server".into(),
+        ))
+    }
+}
+
+pub async fn get_order_group(
+    state: web::Data<AppState>,
+    path: web::Path<(ExchangeMarket, GroupId)>,
+    user: Claims,
+) -> Result<HttpResponse, RustexError> {
+    let (market, group_id) = (path.0, path.1);
+    if let Some(market_rpc) = state.match_orders.get(&market) {
+        let group = market_rpc
+            .get_order_group(Context::current(), user.sub, group_id, market)
+            .await??;
+        Ok(HttpResponse::Ok().json(group))
+    } else {
+        Err(RustexError::UserFacingError(
+            "Requested market exchange is not available in this server".into(),
+        ))
+    }
+}
+
+pub async fn try_delete_order_group(
+    state: web::Data<AppState>,
+    path: web::Path<(ExchangeMarket, GroupId)>,
+    user: Claims,
+    source_ip: SourceIp,
+) -> Result<HttpResponse, RustexError> {
+    let (market, group_id) = (path.0, path.1);
     if let Some(market_rpc) = state.match_orders.get(&market) {
         let is_deleted = market_rpc
-            .try_delete_order(Context::current(), user.sub, order_id, market)
+            .try_delete_order_group(
+                Context::current(),
+                user.sub,
+                group_id,
+                market,
+                source_ip.address(),
+            )
             .await??;
         Ok(HttpResponse::Ok().json(is_deleted))
     } else {
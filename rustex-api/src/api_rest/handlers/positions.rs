@@ -0,0 +1,32 @@
+use actix_web::{web, HttpResponse};
+use futures_util::future::try_join_all;
+use rustex_core::prelude::{Position, PositionsReport};
+use rustex_errors::RustexError;
+use tarpc::context::Context;
+
+use crate::{api_rest::state::AppState, auth::Claims};
+
+/// Positions of the user in every market together with the PnL totals
+pub async fn get_positions(
+    user: Claims,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, RustexError> {
+    let positions = try_join_all(state.match_orders.iter().map(|(&market, rpc_client)| {
+        let state = &state;
+        let user_id = user.sub;
+        async move {
+            let (fills, mark_price) = tokio::join!(
+                state.db.get_user_fills(Context::current(), user_id, market),
+                rpc_client.get_mid_price(Context::current())
+            );
+            Ok::<_, RustexError>(Position::from_fills(market, fills??, mark_price?))
+        }
+    }))
+    .await?;
+    let mut positions = positions
+        .into_iter()
+        .filter(|position| position.net_quantity != 0.0 || position.realised_pnl != 0.0)
+        .collect::<Vec<_>>();
+    positions.sort_by_key(|position| position.exchange);
+    Ok(HttpResponse::Ok().json(PositionsReport::new(positions)))
+}
@@ -0,0 +1,74 @@
+use actix_web::{web, HttpResponse};
+use rustex_core::prelude::{AuditOrigin, RiskLimits, UserId};
+use rustex_errors::RustexError;
+use tarpc::context::Context;
+
+use crate::{
+    api_rest::{audit::SourceIp, state::AppState},
+    auth::Claims,
+};
+
+pub async fn get_risk_limits(
+    user: Claims,
+    state: web::Data<AppState>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let limits = state
+        .db
+        .get_risk_limits(Context::current(), path.into_inner().into())
+        .await??;
+    match limits {
+        Some(limits) => Ok(HttpResponse::Ok().json(limits)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+pub async fn upsert_risk_limits(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    path: web::Path<i64>,
+    limits: web::Json<RiskLimits>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let user_id = UserId::from(path.into_inner());
+    let limits = RiskLimits {
+        user_id,
+        ..limits.into_inner()
+    };
+    let origin = AuditOrigin::user(user.sub, source_ip.address());
+    state
+        .db
+        .upsert_risk_limits(Context::current(), limits, origin)
+        .await??;
+    refresh_risk_limits(&state, user_id).await?;
+    Ok(HttpResponse::Ok().json(limits))
+}
+
+pub async fn delete_risk_limits(
+    user: Claims,
+    source_ip: SourceIp,
+    state: web::Data<AppState>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, RustexError> {
+    user.require_admin()?;
+    let user_id = UserId::from(path.into_inner());
+    let origin = AuditOrigin::user(user.sub, source_ip.address());
+    let deleted = state
+        .db
+        .delete_risk_limits(Context::current(), user_id, origin)
+        .await??;
+    refresh_risk_limits(&state, user_id).await?;
+    Ok(HttpResponse::Ok().json(deleted))
+}
+
+/// Applies the new limits on every market without restarting the match-services
+async fn refresh_risk_limits(state: &AppState, user_id: UserId) -> Result<(), RustexError> {
+    for rpc_client in state.match_orders.values() {
+        rpc_client
+            .refresh_risk_limits(Context::current(), user_id)
+            .await??;
+    }
+    Ok(())
+}
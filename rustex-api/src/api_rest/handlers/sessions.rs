@@ -0,0 +1,84 @@
+use actix_web::{web, HttpResponse};
+use rustex_core::prelude::{AuditAction, ExchangeMarket, SessionId};
+use rustex_errors::RustexError;
+use serde::Deserialize;
+use serde_json::json;
+use tarpc::context::Context;
+
+use crate::{
+    api_rest::{
+        audit::{self, SourceIp},
+        state::AppState,
+    },
+    auth::Claims,
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRequest {
+    timeout_ms: u64,
+}
+
+pub async fn open_session(
+    state: web::Data<AppState>,
+    path: web::Path<ExchangeMarket>,
+    session: web::Json<SessionRequest>,
+    user: Claims,
+) -> Result<HttpResponse, RustexError> {
+    let market = path.into_inner();
+    if let Some(market_rpc) = state.match_orders.get(&market) {
+        let session_id = market_rpc
+            .open_session(Context::current(), user.sub, session.timeout_ms)
+            .await??;
+        Ok(HttpResponse::Ok().json(session_id))
+    } else {
+        Err(RustexError::UserFacingError(
+            "Requested market exchange is not available in this server".into(),
+        ))
+    }
+}
+
+pub async fn heartbeat(
+    state: web::Data<AppState>,
+    path: web::Path<(ExchangeMarket, SessionId)>,
+    user: Claims,
+) -> Result<HttpResponse, RustexError> {
+    let (market, session_id) = (path.0, path.1);
+    if let Some(market_rpc) = state.match_orders.get(&market) {
+        market_rpc
+            .heartbeat(Context::current(), user.sub, session_id)
+            .await??;
+        Ok(HttpResponse::Ok().json(true))
+    } else {
+        Err(RustexError::UserFacingError(
+            "Requested market exchange is not available in this server".into(),
+        ))
+    }
+}
+
+pub async fn close_session(
+    state: web::Data<AppState>,
+    path: web::Path<(ExchangeMarket, SessionId)>,
+    user: Claims,
+    source_ip: SourceIp,
+) -> Result<HttpResponse, RustexError> {
+    let (market, session_id) = (path.0, path.1);
+    if let Some(market_rpc) = state.match_orders.get(&market) {
+        let cancelled_orders = market_rpc
+            .close_session(
+                Context::current(),
+                user.sub,
+                session_id,
+                source_ip.address(),
+            )
+            .await??;
+        let details = json!({ "sessionId": session_id, "orderIds": cancelled_orders });
+        let action = AuditAction::SessionClosed;
+        audit::record(&state, &user, &source_ip, action, Some(market), details).await;
+        Ok(HttpResponse::Ok().json(cancelled_orders))
+    } else {
+        Err(RustexError::UserFacingError(
+            "Requested market exchange is not available in this server".into(),
+        ))
+    }
+}
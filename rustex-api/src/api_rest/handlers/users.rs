@@ -5,16 +5,17 @@ use serde::Deserialize;
 
 use crate::{api_rest::state::AppState, auth};
 
+#[allow(dead_code)] // Unused until login checks the credentials
 #[derive(Deserialize)]
 pub struct Credentials {
-    #[allow(dead_code)]
     username: String,
-    #[allow(dead_code)]
     password: String, // TODO: Salt + Nonce
 }
 
 type JwtToken = String;
 
+/// Issues a token without a role to user 0. Admin tokens
+/// are issued with `rustex-api issue-token <user_id> admin`
 pub async fn login(
     _state: web::Data<AppState>,
     // _credentials: web::Json<Credentials>,
@@ -1,4 +1,4 @@
-use std::{fs::File, io::BufReader, sync::LazyLock};
+use std::sync::LazyLock;
 
 use actix_web::{middleware::Logger, web, App, HttpServer};
 use anyhow::Context;
@@ -7,6 +7,7 @@ use state::AppState;
 
 use crate::auth::JwtMiddleware;
 
+mod audit;
 mod handlers;
 mod routes;
 mod state;
@@ -27,33 +28,14 @@ static SERVER_PORT: LazyLock<Box<str>> = LazyLock::new(|| {
 });
 
 fn get_tls_config() -> anyhow::Result<ServerConfig> {
-    let mut certs_file = BufReader::new(
-        File::open(
-            std::env::var("TLS_CERT_PATH")
-                .context("TLS_CERT_PATH environment variable is not set")?,
-        )
-        .context("Failed to read cert.pem")?,
-    );
-    let mut key_file = BufReader::new(
-        File::open(
-            std::env::var("TLS_KEY_PATH")
-                .context("TLS_KEY_PATH environment variable is not set")?,
-        )
-        .context("Failed to read key.pem")?,
-    );
-
-    let tls_certs = rustls_pemfile::certs(&mut certs_file).collect::<Result<Vec<_>, _>>()?;
-
-    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_file)
-        .map(|key| key.map(rustls::pki_types::PrivateKeyDer::Pkcs8))
-        .collect::<Result<Vec<_>, _>>()?;
-
-    if keys.is_empty() {
-        return Err(anyhow::Error::msg("Failed to parse pkcs8 key"));
-    }
+    let cert_path =
+        std::env::var("TLS_CERT_PATH").context("TLS_CERT_PATH environment variable is not set")?;
+    let key_path =
+        std::env::var("TLS_KEY_PATH").context("TLS_KEY_PATH environment variable is not set")?;
+    let tls_certs = rpc_clients::rpc::tls::load_certs(&cert_path)?;
+    let tls_key = rpc_clients::rpc::tls::load_private_key(&key_path)?;
 
     // set up TLS config options
-    let tls_key = keys.remove(0);
     let tls_config = rustls::ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(tls_certs, tls_key)?;
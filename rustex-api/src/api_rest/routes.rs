@@ -7,19 +7,89 @@ pub fn get_public_api_service() -> Scope {
     web::scope("/v1/public")
         .route("/health", web::get().to(health::service_health))
         .route("/auth/login", web::post().to(users::login)) // TODO
+        // Recent trades of the market. Older ones are backfilled with the cursor
+        .route(
+            "/trades/{exchange_market}",
+            web::get().to(market_data::get_trade_tape),
+        )
+        // OHLCV candles. Takes the interval (1m, 5m, 1h or 1d) and the time range
+        .route(
+            "/candles/{exchange_market}",
+            web::get().to(market_data::get_candles),
+        )
 }
 
 // JWT Middleware-wrapped
 pub fn get_protected_api_service() -> Scope {
     web::scope("/v1")
         .route("/health", web::get().to(health::service_health)) // For testing
+        .route("/balances", web::get().to(funds::get_balances))
+        // Net positions and PnL across every market
+        .route("/positions", web::get().to(positions::get_positions))
+        // Lists the deposits and withdrawals of the user
+        .route("/transfers", web::get().to(funds::get_transfers))
+        .route("/deposits", web::post().to(funds::deposit))
+        .route("/withdrawals", web::post().to(funds::withdraw))
+        .route(
+            "/admin/transfers/{transfer_id}/approve",
+            web::put().to(funds::approve_transfer),
+        )
+        .route(
+            "/admin/transfers/{transfer_id}/reject",
+            web::put().to(funds::reject_transfer),
+        )
+        .route("/admin/outbox", web::get().to(admin::get_outbox_backlog))
+        .route(
+            "/admin/reconcile/{exchange_market}",
+            web::post().to(admin::reconcile),
+        )
+        .route(
+            "/admin/candles/{exchange_market}/backfill",
+            web::post().to(admin::backfill_candles),
+        )
+        .route(
+            "/admin/book/{exchange_market}",
+            web::get().to(admin::get_book_at),
+        )
+        .service(
+            web::resource("/admin/risk-limits/{user_id}")
+                .route(web::get().to(risk::get_risk_limits))
+                // Takes effect right away on every market
+                .route(web::put().to(risk::upsert_risk_limits))
+                .route(web::delete().to(risk::delete_risk_limits)),
+        )
         .service(
             web::resource("/orders")
-                // Lists all orders for a given user
+                // Lists the orders of the user. Filtered and paginated
                 .route(web::get().to(orders::get_orders))
                 // Creates a new order for the given user
                 .route(web::post().to(orders::insert_order)),
         )
+        // Lists the fills of the user with the same filters and pagination as the orders
+        .route("/trades", web::get().to(orders::get_trades))
+        .service(
+            web::resource("/groups")
+                // Creates a new OCO pair or bracket order
+                .route(web::post().to(orders::insert_order_group)),
+        )
+        .service(
+            web::resource("/groups/{exchange_market}/{group_id}")
+                .route(web::get().to(orders::get_order_group))
+                // Cancels the working orders of the group
+                .route(web::delete().to(orders::try_delete_order_group)),
+        )
+        .service(
+            web::resource("/sessions/{exchange_market}")
+                // Opens a cancel-on-disconnect session
+                .route(web::post().to(sessions::open_session)),
+        )
+        .service(
+            web::resource("/sessions/{exchange_market}/{session_id}")
+                // Session heartbeat
+                .route(web::put().to(sessions::heartbeat))
+                // Closes the session cancelling its orders
+                .route(web::delete().to(sessions::close_session)),
+        )
         .service(
             web::resource("/{exchange_market}/{order_id}")
                 .route(web::get().to(orders::get_order_state))
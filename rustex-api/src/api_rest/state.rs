@@ -2,11 +2,12 @@ use std::str::FromStr;
 
 use anyhow::Context;
 use hashbrown::HashMap;
-use rpc_clients::match_service::MatchServiceClient;
+use rpc_clients::{db_service::DbServiceClient, match_service::MatchServiceClient};
 use rustex_core::prelude::ExchangeMarket;
 
 pub struct AppState {
     pub match_orders: HashMap<ExchangeMarket, MatchServiceClient>,
+    pub db: DbServiceClient, // Funds are not bound to any market
 }
 
 impl AppState {
@@ -33,6 +34,13 @@ impl AppState {
                 .context("Failed to connect to Market Match Service Client")?;
             match_orders.insert(market, rpc_client);
         }
-        Ok(Self { match_orders })
+        log::info!(
+            "Connecting to the DB service on {:?}",
+            *rpc_clients::DB_RPC_ADDRESS
+        );
+        let db = rpc_clients::get_db_service_client(rpc_clients::DB_RPC_ADDRESS.as_str())
+            .await
+            .context("Failed to connect to DB Service Client")?;
+        Ok(Self { match_orders, db })
     }
 }
@@ -6,6 +6,8 @@ use serde::{Deserialize, Serialize};
 
 use super::JWT_SECRET_KEY;
 
+const ADMIN_ROLE: &str = "admin";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: UserId, // Subject -> user id
@@ -14,6 +16,18 @@ pub struct Claims {
     pub role: Option<String>,
 }
 
+impl Claims {
+    /// Fails unless the token was issued to an administrator
+    pub fn require_admin(&self) -> Result<(), RustexError> {
+        match self.role.as_deref() {
+            Some(ADMIN_ROLE) => Ok(()),
+            _ => Err(RustexError::AuthorizationError(
+                format!("User {:?} is not an administrator", self.sub).into(),
+            )),
+        }
+    }
+}
+
 pub fn generate_jwt_token(
     curr_time: DateTime<Utc>,
     user_id: UserId,
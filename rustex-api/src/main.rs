@@ -4,6 +4,7 @@ mod api_rest;
 mod api_socket;
 mod auth;
 
+use chrono::Utc;
 use dotenvy::dotenv;
 
 #[tokio::main]
@@ -11,6 +12,22 @@ async fn main() {
     dotenv().unwrap();
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
 
+    // `rustex-api issue-token <user_id> [role]` prints a token signed with JWT_SECRET_KEY.
+    // Login only issues tokens without a role, so this is how admin tokens are issued
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("issue-token") {
+        let user_id: i64 = args
+            .get(2)
+            .and_then(|user_id| user_id.parse().ok())
+            .expect("Usage: rustex-api issue-token <user_id> [role]");
+        let role = args.get(3).cloned();
+        match auth::generate_jwt_token(Utc::now(), user_id.into(), None, role) {
+            Ok(token) => println!("{}", token),
+            Err(e) => log::error!("Failed to issue token: {:?}", e),
+        }
+        return;
+    }
+
     let mut servers = tokio::task::JoinSet::new();
 
     #[cfg(feature = "rest_api")]
@@ -1,49 +1,31 @@
 // Hopefully at some point this can be retrieved dynamically from a database
 // such that adding a new currency does not require a code change
 
-use std::str::FromStr;
-
-use rustex_errors::RustexError;
+use diesel_derive_enum::DbEnum;
 use serde::{Deserialize, Serialize};
 
+use crate::models::orders::ExchangeMarket;
+
 #[allow(non_camel_case_types)]
-#[derive(Debug)]
+#[derive(
+    DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Currency"]
+#[DbValueStyle = "snake_case"]
 pub enum Currencies {
     BTC,
     USD,
     GBP,
+    EUR,
 }
 
-#[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord, Serialize, Deserialize)]
-pub enum ExchangeMarkets {
-    BTC_USD,
-    BTC_GBP,
-    BTC_EUR,
-}
-
-impl FromStr for ExchangeMarkets {
-    type Err = RustexError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.trim() {
-            "BTC_USD" => Ok(ExchangeMarkets::BTC_USD),
-            "BTC_GBP" => Ok(ExchangeMarkets::BTC_GBP),
-            "BTC_EUR" => Ok(ExchangeMarkets::BTC_EUR),
-            _ => Err(RustexError::UserFacingError(format!(
-                "{s} is not a valid Exchange Marker"
-            ))),
+impl ExchangeMarket {
+    /// (Base, Quote) currencies of the market
+    pub fn currencies(&self) -> (Currencies, Currencies) {
+        match self {
+            ExchangeMarket::BTC_USD => (Currencies::BTC, Currencies::USD),
+            ExchangeMarket::BTC_GBP => (Currencies::BTC, Currencies::GBP),
+            ExchangeMarket::BTC_EUR => (Currencies::BTC, Currencies::EUR),
         }
     }
 }
-
-impl ExchangeMarkets {
-    pub fn from_env() -> Result<Self, RustexError> {
-        std::env::var("EXCHANGE_MARKET")
-            .map(|env_var| ExchangeMarkets::from_str(&env_var))
-            .map_err(|_| {
-                RustexError::UserFacingError(
-                    "EXCHANGE_MARKET environment variable is undefined".into(),
-                )
-            })?
-    }
-}
@@ -1 +1,2 @@
 pub mod schema;
+pub mod with_archive;
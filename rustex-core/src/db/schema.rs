@@ -1,39 +1,191 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "auditaction"))]
+    pub struct Auditaction;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "candleinterval"))]
+    pub struct Candleinterval;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "currency"))]
+    pub struct Currency;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "exchangemarket"))]
     pub struct Exchangemarket;
 
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "ledgeraccount"))]
+    pub struct Ledgeraccount;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "ordergroupkind"))]
+    pub struct Ordergroupkind;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "ordergroupstatus"))]
+    pub struct Ordergroupstatus;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "orderstatus"))]
+    pub struct Orderstatus;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "ordertype"))]
     pub struct Ordertype;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "transferkind"))]
+    pub struct Transferkind;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "transferstatus"))]
+    pub struct Transferstatus;
 }
 
 diesel::table! {
     use diesel::sql_types::*;
+    use super::sql_types::Auditaction;
     use super::sql_types::Exchangemarket;
 
-    cancelled_orders (order_id, exchange) {
+    audit_log (seq) {
+        seq -> Int8,
+        created_at -> Timestamptz,
+        actor -> Nullable<Int8>,
+        source_ip -> Nullable<Text>,
+        action -> Auditaction,
+        exchange -> Nullable<Exchangemarket>,
+        details -> Text,
+        prev_hash -> Text,
+        hash -> Text,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Currency;
+
+    balances (user_id, currency) {
+        user_id -> Int8,
+        currency -> Currency,
+        available -> Float8,
+        reserved -> Float8,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Exchangemarket;
+    use super::sql_types::Candleinterval;
+
+    candles (exchange, candle_interval, open_time) {
+        exchange -> Exchangemarket,
+        candle_interval -> Candleinterval,
+        open_time -> Timestamptz,
+        open -> Int8,
+        high -> Int8,
+        low -> Int8,
+        close -> Int8,
+        volume -> Float8,
+        quote_volume -> Float8,
+        trade_count -> Int4,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Exchangemarket;
+
+    cancelled_orders (order_id, exchange, created_at) {
         order_id -> Int8,
         exchange -> Exchangemarket,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Exchangemarket;
+
+    id_sequences (exchange) {
+        exchange -> Exchangemarket,
+        next_order_id -> Int8,
+        next_trade_id -> Int8,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Currency;
+    use super::sql_types::Ledgeraccount;
+
+    ledger_entries (entry_id) {
+        entry_id -> Int8,
+        reference -> Text,
+        user_id -> Int8,
+        currency -> Currency,
+        account -> Ledgeraccount,
+        amount -> Float8,
+        created_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Exchangemarket;
+    use super::sql_types::Ordergroupkind;
+    use super::sql_types::Ordergroupstatus;
+    use super::sql_types::Ordertype;
+
+    order_groups (group_id, exchange) {
+        group_id -> Int8,
+        exchange -> Exchangemarket,
+        user_id -> Int8,
+        kind -> Ordergroupkind,
+        status -> Ordergroupstatus,
+        exit_type -> Ordertype,
+        quantity -> Float8,
+        take_profit_price -> Int8,
+        stop_trigger_price -> Int8,
+        stop_limit_price -> Int8,
+        entry_order -> Nullable<Int8>,
+        take_profit_order -> Nullable<Int8>,
+        stop_loss_order -> Nullable<Int8>,
         created_at -> Nullable<Timestamptz>,
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Exchangemarket;
+
+    order_keys (order_id, exchange) {
+        order_id -> Int8,
+        exchange -> Exchangemarket,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::Ordertype;
     use super::sql_types::Exchangemarket;
+    use super::sql_types::Orderstatus;
 
-    orders (order_id, exchange) {
+    orders (order_id, exchange, created_at) {
         order_id -> Int8,
         user_id -> Int8,
         price -> Int8,
         quantity -> Float8,
-        created_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
         order_type -> Ordertype,
         exchange -> Exchangemarket,
+        notional -> Nullable<Float8>,
+        status -> Orderstatus,
+        filled_quantity -> Float8,
+        average_price -> Nullable<Float8>,
     }
 }
 
@@ -47,19 +199,86 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    risk_limits (user_id) {
+        user_id -> Int8,
+        max_order_quantity -> Nullable<Float8>,
+        max_notional -> Nullable<Float8>,
+        max_open_orders -> Nullable<Int4>,
+        max_orders_per_second -> Nullable<Int4>,
+        max_price_deviation -> Nullable<Float8>,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::Exchangemarket;
+    use super::sql_types::Ordertype;
 
-    trades (trade_id, exchange) {
+    trades (trade_id, exchange, created_at) {
         trade_id -> Int8,
         exchange -> Exchangemarket,
         buy_order -> Int8,
         sell_order -> Int8,
         price -> Int8,
         quantity -> Float8,
+        created_at -> Timestamptz,
+        aggressor -> Ordertype,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Exchangemarket;
+
+    trade_keys (trade_id, exchange) {
+        trade_id -> Int8,
+        exchange -> Exchangemarket,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Transferkind;
+    use super::sql_types::Currency;
+    use super::sql_types::Transferstatus;
+
+    transfers (transfer_id) {
+        transfer_id -> Int8,
+        user_id -> Int8,
+        kind -> Transferkind,
+        currency -> Currency,
+        amount -> Float8,
+        status -> Transferstatus,
+        external_reference -> Nullable<Text>,
         created_at -> Nullable<Timestamptz>,
+        updated_at -> Nullable<Timestamptz>,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(cancelled_orders, orders, pending_orders, trades,);
+diesel::table! {
+    users (user_id) {
+        user_id -> Int8,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::joinable!(orders -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    balances,
+    candles,
+    cancelled_orders,
+    id_sequences,
+    ledger_entries,
+    order_groups,
+    order_keys,
+    orders,
+    pending_orders,
+    risk_limits,
+    trade_keys,
+    trades,
+    transfers,
+    users,
+);
@@ -0,0 +1,56 @@
+//! Views over the live tables along with their archived partitions. They mirror the
+//! tables of the same name, for the queries reaching before the archive cut-off
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::schema::sql_types::Exchangemarket;
+
+    #[sql_name = "all_cancelled_orders"]
+    cancelled_orders (order_id, exchange, created_at) {
+        order_id -> Int8,
+        exchange -> Exchangemarket,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::schema::sql_types::Ordertype;
+    use crate::db::schema::sql_types::Exchangemarket;
+    use crate::db::schema::sql_types::Orderstatus;
+
+    #[sql_name = "all_orders"]
+    orders (order_id, exchange, created_at) {
+        order_id -> Int8,
+        user_id -> Int8,
+        price -> Int8,
+        quantity -> Float8,
+        created_at -> Timestamptz,
+        order_type -> Ordertype,
+        exchange -> Exchangemarket,
+        notional -> Nullable<Float8>,
+        status -> Orderstatus,
+        filled_quantity -> Float8,
+        average_price -> Nullable<Float8>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::schema::sql_types::Exchangemarket;
+    use crate::db::schema::sql_types::Ordertype;
+
+    #[sql_name = "all_trades"]
+    trades (trade_id, exchange, created_at) {
+        trade_id -> Int8,
+        exchange -> Exchangemarket,
+        buy_order -> Int8,
+        sell_order -> Int8,
+        price -> Int8,
+        quantity -> Float8,
+        created_at -> Timestamptz,
+        aggressor -> Ordertype,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(cancelled_orders, orders, trades);
@@ -0,0 +1,74 @@
+//! Builders shared by the tests of the workspace
+
+use chrono::{DateTime, Utc};
+
+use crate::prelude::*;
+
+/// Empty book with the first thousand order and trade ids reserved
+pub fn order_book(exchange: ExchangeMarket) -> OrderBook {
+    let book = OrderBook::new(exchange);
+    for kind in [IdKind::Order, IdKind::Trade] {
+        book.extend_ids(
+            kind,
+            IdBlock {
+                start: 0,
+                end: 1000,
+            },
+        );
+    }
+    book
+}
+
+/// Order of the given id. Unless overridden a new buy of 1.0 BTC_USD at 100 by user 1
+pub fn order(order_id: i64) -> OrderBuilder {
+    OrderBuilder(Order {
+        order_id: order_id.into(),
+        user_id: 1.into(),
+        price: 100,
+        quantity: 1.0,
+        created_at: None,
+        order_type: OrderType::Buy,
+        exchange: ExchangeMarket::BTC_USD,
+        notional: None,
+        status: OrderStatus::New,
+        filled_quantity: 0.0,
+        average_price: None,
+    })
+}
+
+pub struct OrderBuilder(Order);
+
+impl OrderBuilder {
+    pub fn user(mut self, user_id: i64) -> Self {
+        self.0.user_id = user_id.into();
+        self
+    }
+
+    pub fn order_type(mut self, order_type: OrderType) -> Self {
+        self.0.order_type = order_type;
+        self
+    }
+
+    pub fn sell(self) -> Self {
+        self.order_type(OrderType::Sell)
+    }
+
+    pub fn price(mut self, price: i64) -> Self {
+        self.0.price = price;
+        self
+    }
+
+    pub fn quantity(mut self, quantity: f64) -> Self {
+        self.0.quantity = quantity;
+        self
+    }
+
+    pub fn created_at(mut self, created_at: DateTime<Utc>) -> Self {
+        self.0.created_at = Some(created_at);
+        self
+    }
+
+    pub fn build(self) -> Order {
+        self.0
+    }
+}
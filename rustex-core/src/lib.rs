@@ -1,5 +1,7 @@
-// mod currencies;
+mod currencies;
 pub mod db;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
 mod models;
 mod order_matching;
 
@@ -0,0 +1,241 @@
+//! Append-only log of the state changes of the exchange. Every entry is hashed
+//! along with the hash of the previous one, so tampering breaks the chain
+
+use std::fmt;
+
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use diesel::prelude::*;
+use diesel_derive_enum::DbEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{orders::ExchangeMarket, UserId};
+
+/// Previous hash of the first entry
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Auditaction"]
+#[DbValueStyle = "snake_case"]
+#[serde(rename_all = "camelCase")]
+pub enum AuditAction {
+    OrderAccepted,
+    OrderRejected,
+    OrderCancelled,
+    OrdersExpired, // Their session missed its heartbeat
+    GroupPlaced,
+    GroupCancelled,
+    SessionClosed,
+    Trade,
+    TransferRequested,
+    TransferApproved,
+    TransferRejected,
+    RiskLimitsUpdated,
+    RiskLimitsDeleted,
+    CandlesBackfilled,
+    BookReconciled,
+}
+
+/// State change to append to the log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub actor: Option<UserId>, // None for the exchange itself
+    pub source_ip: Option<String>,
+    pub action: AuditAction,
+    pub exchange: Option<ExchangeMarket>,
+    pub details: String, // JSON
+}
+
+impl AuditEvent {
+    /// Change made by the exchange itself
+    pub fn system(action: AuditAction, exchange: ExchangeMarket, details: String) -> Self {
+        AuditOrigin::default().event(action, Some(exchange), details)
+    }
+}
+
+/// Who requested a change. Defaults to the exchange itself
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AuditOrigin {
+    pub actor: Option<UserId>,
+    pub source_ip: Option<String>,
+}
+
+impl AuditOrigin {
+    pub fn user(actor: UserId, source_ip: Option<String>) -> Self {
+        Self {
+            actor: Some(actor),
+            source_ip,
+        }
+    }
+
+    pub fn event(
+        &self,
+        action: AuditAction,
+        exchange: Option<ExchangeMarket>,
+        details: String,
+    ) -> AuditEvent {
+        AuditEvent {
+            actor: self.actor,
+            source_ip: self.source_ip.clone(),
+            action,
+            exchange,
+            details,
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::db::schema::audit_log)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub seq: i64,
+    pub created_at: DateTime<Utc>,
+    pub actor: Option<UserId>,
+    pub source_ip: Option<String>,
+    pub action: AuditAction,
+    pub exchange: Option<ExchangeMarket>,
+    pub details: String,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// Appends the event after the last entry of the log
+    pub fn chain(last: Option<&AuditEntry>, event: AuditEvent, created_at: DateTime<Utc>) -> Self {
+        let mut entry = Self {
+            seq: last.map_or(1, |last| last.seq + 1),
+            created_at: created_at.trunc_subsecs(6), // As stored by the database
+            actor: event.actor,
+            source_ip: event.source_ip,
+            action: event.action,
+            exchange: event.exchange,
+            details: event.details,
+            prev_hash: last.map_or(GENESIS_HASH.into(), |last| last.hash.clone()),
+            hash: String::new(),
+        };
+        entry.hash = entry.digest();
+        entry
+    }
+
+    /// SHA-256 of every field but the hash itself, hex encoded
+    pub fn digest(&self) -> String {
+        let content = format!(
+            "{}\n{}\n{}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+            self.prev_hash,
+            self.seq,
+            self.created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.actor.map(i64::from),
+            self.source_ip,
+            self.action,
+            self.exchange,
+            self.details,
+        );
+        Sha256::digest(content)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// First inconsistency found walking the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainBreak {
+    Missing { expected: i64, found: i64 }, // Entries removed or reordered
+    Unlinked { seq: i64 },                 // The previous hash does not match
+    Tampered { seq: i64 },                 // The entry does not match its hash
+}
+
+impl fmt::Display for ChainBreak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { expected, found } => {
+                write!(f, "expected entry {expected} but found entry {found}")
+            }
+            Self::Unlinked { seq } => write!(f, "entry {seq} does not link to the previous one"),
+            Self::Tampered { seq } => write!(f, "entry {seq} does not match its hash"),
+        }
+    }
+}
+
+/// Checks the entries of the log in order. They can be fed page by page
+#[derive(Debug, Default)]
+pub struct AuditVerifier {
+    last: Option<(i64, String)>,
+}
+
+impl AuditVerifier {
+    pub fn verify(&mut self, entry: &AuditEntry) -> Result<(), ChainBreak> {
+        let (expected, prev_hash) = match &self.last {
+            Some((seq, hash)) => (seq + 1, hash.as_str()),
+            None => (1, GENESIS_HASH),
+        };
+        if entry.seq != expected {
+            return Err(ChainBreak::Missing {
+                expected,
+                found: entry.seq,
+            });
+        }
+        if entry.prev_hash != prev_hash {
+            return Err(ChainBreak::Unlinked { seq: entry.seq });
+        }
+        if entry.digest() != entry.hash {
+            return Err(ChainBreak::Tampered { seq: entry.seq });
+        }
+        self.last = Some((entry.seq, entry.hash.clone()));
+        Ok(())
+    }
+
+    /// Sequence number of the last entry verified
+    pub fn last_seq(&self) -> Option<i64> {
+        self.last.as_ref().map(|(seq, _)| *seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(len: usize) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = vec![];
+        for i in 0..len {
+            let event = AuditEvent {
+                actor: Some(UserId::from(i as i64)),
+                source_ip: Some("127.0.0.1".into()),
+                action: AuditAction::OrderAccepted,
+                exchange: Some(ExchangeMarket::BTC_USD),
+                details: format!("{{\"orderId\":{i}}}"),
+            };
+            entries.push(AuditEntry::chain(entries.last(), event, Utc::now()));
+        }
+        entries
+    }
+
+    fn verify(entries: &[AuditEntry]) -> Result<(), ChainBreak> {
+        let mut verifier = AuditVerifier::default();
+        entries.iter().try_for_each(|entry| verifier.verify(entry))
+    }
+
+    #[test]
+    fn test_tampering_breaks_the_chain() {
+        let entries = log(3);
+        assert_eq!(verify(&entries), Ok(()));
+
+        let mut edited = entries.clone();
+        edited[1].details = "{\"orderId\":7}".into();
+        assert_eq!(verify(&edited), Err(ChainBreak::Tampered { seq: 2 }));
+
+        // Rehashing the edited entry does not hide it
+        edited[1].hash = edited[1].digest();
+        assert_eq!(verify(&edited), Err(ChainBreak::Unlinked { seq: 3 }));
+
+        let removed = [entries[0].clone(), entries[2].clone()];
+        assert_eq!(
+            verify(&removed),
+            Err(ChainBreak::Missing {
+                expected: 2,
+                found: 3
+            })
+        );
+    }
+}
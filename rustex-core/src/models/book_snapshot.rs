@@ -0,0 +1,212 @@
+//! Order book of a market as it stood at a point in time, rebuilt from the
+//! recorded orders, trades and cancellations
+
+use chrono::{DateTime, Utc};
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    cancellations::CancelledOrder,
+    orders::{ExchangeMarket, Order, OrderId, OrderStatus, OrderType},
+    reconciliation::QUANTITY_TOLERANCE,
+    trades::Trade,
+    UserId,
+};
+
+/// Quantity resting at a price (L2)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceLevel {
+    pub price: i64,
+    pub quantity: f64,
+    pub order_count: usize,
+}
+
+/// Order resting in the book with the quantity it had left (L3)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RestingOrder {
+    pub order_id: OrderId,
+    pub user_id: UserId,
+    pub order_type: OrderType,
+    pub price: i64,
+    pub remaining: f64,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BookSnapshot {
+    pub exchange: ExchangeMarket,
+    pub at: DateTime<Utc>,
+    pub bids: Vec<PriceLevel>, // Best price first
+    pub asks: Vec<PriceLevel>,
+    pub orders: Vec<RestingOrder>, // By side, then in matching priority
+}
+
+impl BookSnapshot {
+    /// Replays the orders, trades and cancellations recorded up to `at`, included.
+    /// Orders not cancelled by then rest with the quantity left by their trades,
+    /// as when the book is initialized from the DB
+    pub fn replay<'a>(
+        exchange: ExchangeMarket,
+        at: DateTime<Utc>,
+        orders: impl IntoIterator<Item = &'a Order>,
+        trades: impl IntoIterator<Item = &'a Trade>,
+        cancellations: impl IntoIterator<Item = &'a CancelledOrder>,
+    ) -> Self {
+        let happened = |ts: Option<DateTime<Utc>>| ts.is_some_and(|ts| ts <= at);
+        let cancelled = cancellations
+            .into_iter()
+            .filter(|cancellation| {
+                cancellation.exchange == exchange && happened(cancellation.created_at)
+            })
+            .map(|cancellation| cancellation.order_id)
+            .collect::<HashSet<_>>();
+        let mut order_trades: HashMap<OrderId, Vec<&Trade>> = HashMap::new();
+        let executed = trades
+            .into_iter()
+            .filter(|trade| trade.exchange == exchange && happened(trade.created_at));
+        for trade in executed {
+            order_trades.entry(trade.buy_order).or_default().push(trade);
+            order_trades
+                .entry(trade.sell_order)
+                .or_default()
+                .push(trade);
+        }
+
+        let mut resting = orders
+            .into_iter()
+            .filter(|order| {
+                order.exchange == exchange
+                    && order.status != OrderStatus::Rejected
+                    && happened(order.created_at)
+                    && !cancelled.contains(&order.order_id)
+            })
+            .map(|order| {
+                let trades = order_trades.get(&order.order_id).into_iter().flatten();
+                RestingOrder {
+                    order_id: order.order_id,
+                    user_id: order.user_id,
+                    order_type: order.order_type,
+                    price: order.price,
+                    remaining: order.remaining_quantity(trades.copied()),
+                    created_at: order.created_at,
+                }
+            })
+            .filter(|order| order.remaining > QUANTITY_TOLERANCE)
+            .collect::<Vec<_>>();
+        resting.sort_by_key(|order| {
+            let priority = match order.order_type {
+                OrderType::Buy => -order.price,
+                OrderType::Sell => order.price,
+            };
+            let side = order.order_type == OrderType::Sell; // Bids first
+            (side, priority, order.created_at, order.order_id)
+        });
+
+        let levels = |side| {
+            let mut levels: Vec<PriceLevel> = vec![];
+            for order in resting.iter().filter(|order| order.order_type == side) {
+                match levels.last_mut() {
+                    Some(level) if level.price == order.price => {
+                        level.quantity += order.remaining;
+                        level.order_count += 1;
+                    }
+                    _ => levels.push(PriceLevel {
+                        price: order.price,
+                        quantity: order.remaining,
+                        order_count: 1,
+                    }),
+                }
+            }
+            levels
+        };
+        Self {
+            exchange,
+            at,
+            bids: levels(OrderType::Buy),
+            asks: levels(OrderType::Sell),
+            orders: resting,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::{fixtures, models::trades::TradeId};
+
+    #[test]
+    fn test_replay_at_a_point_in_time() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let at = |seconds| Some(start + TimeDelta::seconds(seconds));
+        let order = |order_id, order_type, price, seconds| {
+            fixtures::order(order_id)
+                .order_type(order_type)
+                .price(price)
+                .quantity(2.0)
+                .created_at(at(seconds).unwrap())
+                .build()
+        };
+        let orders = [
+            order(0, OrderType::Buy, 99, 0),
+            order(1, OrderType::Buy, 100, 1),
+            order(2, OrderType::Buy, 100, 2),
+            order(3, OrderType::Sell, 101, 3),
+            order(4, OrderType::Sell, 100, 4), // Fills order 1
+            order(5, OrderType::Sell, 102, 20),
+        ];
+        let trades = [Trade {
+            trade_id: TradeId::from(0),
+            exchange: ExchangeMarket::BTC_USD,
+            buy_order: 1.into(),
+            sell_order: 4.into(),
+            price: 100,
+            quantity: 2.0,
+            created_at: at(4),
+            aggressor: OrderType::Sell,
+        }];
+        let cancellations = [CancelledOrder {
+            order_id: 0.into(),
+            exchange: ExchangeMarket::BTC_USD,
+            created_at: at(10),
+        }];
+
+        let snapshot = |seconds| {
+            let at = at(seconds).unwrap();
+            BookSnapshot::replay(
+                ExchangeMarket::BTC_USD,
+                at,
+                &orders,
+                &trades,
+                &cancellations,
+            )
+        };
+        let book = snapshot(3);
+        assert_eq!(
+            book.bids,
+            vec![
+                PriceLevel {
+                    price: 100,
+                    quantity: 4.0,
+                    order_count: 2
+                },
+                PriceLevel {
+                    price: 99,
+                    quantity: 2.0,
+                    order_count: 1
+                },
+            ]
+        );
+        assert_eq!(book.asks.len(), 1);
+
+        // Orders 1 and 4 traded away, order 0 was cancelled
+        let book = snapshot(15);
+        let resting = book.orders.iter().map(|o| i64::from(o.order_id));
+        assert_eq!(resting.collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(book.bids[0].quantity, 2.0);
+    }
+}
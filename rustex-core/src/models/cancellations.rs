@@ -10,5 +10,6 @@ use super::orders::{ExchangeMarket, OrderId};
 pub struct CancelledOrder {
     pub order_id: OrderId,
     pub exchange: ExchangeMarket,
+    #[diesel(deserialize_as = DateTime<Utc>)]
     pub created_at: Option<DateTime<Utc>>, // Diesel automatically handles time-zone conversions
 }
@@ -0,0 +1,192 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use diesel::prelude::*;
+use diesel_derive_enum::DbEnum;
+use rustex_errors::RustexError;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    history::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+    orders::ExchangeMarket,
+    trades::Trade,
+};
+
+#[derive(
+    DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Candleinterval"]
+#[DbValueStyle = "snake_case"]
+pub enum CandleInterval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 4] = [
+        CandleInterval::OneMinute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::OneHour,
+        CandleInterval::OneDay,
+    ];
+
+    pub fn duration(&self) -> TimeDelta {
+        match self {
+            CandleInterval::OneMinute => TimeDelta::minutes(1),
+            CandleInterval::FiveMinutes => TimeDelta::minutes(5),
+            CandleInterval::OneHour => TimeDelta::hours(1),
+            CandleInterval::OneDay => TimeDelta::days(1),
+        }
+    }
+
+    /// Opening time of the candle the timestamp falls in
+    pub fn open_time(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        let seconds = self.duration().num_seconds();
+        let open = timestamp.timestamp().div_euclid(seconds) * seconds;
+        DateTime::from_timestamp(open, 0).expect("Candle open time out of range")
+    }
+}
+
+/// OHLCV bar of a market. Prices are in the quote currency
+#[derive(
+    Queryable, Selectable, Insertable, Serialize, Deserialize, Debug, Clone, Copy, PartialEq,
+)]
+#[diesel(table_name = crate::db::schema::candles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[serde(rename_all = "camelCase")]
+pub struct Candle {
+    pub exchange: ExchangeMarket,
+    #[diesel(column_name = candle_interval)]
+    pub interval: CandleInterval,
+    pub open_time: DateTime<Utc>,
+    pub open: i64,
+    pub high: i64,
+    pub low: i64,
+    pub close: i64,
+    pub volume: f64,       // Base quantity traded
+    pub quote_volume: f64, // Quote amount traded
+    pub trade_count: i32,
+}
+
+impl Candle {
+    /// Folds a later candle of the same bar into this one
+    pub fn merge(&mut self, later: &Candle) {
+        self.high = self.high.max(later.high);
+        self.low = self.low.min(later.low);
+        self.close = later.close;
+        self.volume += later.volume;
+        self.quote_volume += later.quote_volume;
+        self.trade_count += later.trade_count;
+    }
+
+    /// Candles of every interval built from the trades, which must
+    /// be timestamped and sorted in execution order
+    pub fn from_trades(exchange: ExchangeMarket, trades: &[Trade]) -> Vec<Candle> {
+        let mut candles: Vec<Candle> = vec![];
+        for interval in CandleInterval::ALL {
+            let first = candles.len();
+            for trade in trades {
+                let Some(executed_at) = trade.created_at else {
+                    continue;
+                };
+                let candle = Candle {
+                    exchange,
+                    interval,
+                    open_time: interval.open_time(executed_at),
+                    open: trade.price,
+                    high: trade.price,
+                    low: trade.price,
+                    close: trade.price,
+                    volume: trade.quantity,
+                    quote_volume: trade.price as f64 * trade.quantity,
+                    trade_count: 1,
+                };
+                match candles[first..]
+                    .iter_mut()
+                    .find(|c| c.open_time == candle.open_time)
+                {
+                    Some(existing) => existing.merge(&candle),
+                    None => candles.push(candle),
+                }
+            }
+        }
+        candles
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CandleQuery {
+    pub interval: CandleInterval,
+    pub from: Option<DateTime<Utc>>, // Inclusive open time
+    pub to: Option<DateTime<Utc>>,   // Exclusive open time
+    pub limit: Option<usize>,        // Most recent candles of the range
+}
+
+impl CandleQuery {
+    pub fn page_size(&self) -> Result<usize, RustexError> {
+        match self.limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+            limit @ 1..=MAX_PAGE_SIZE => Ok(limit),
+            _ => Err(RustexError::UserFacingError(format!(
+                "The candle limit must be between 1 and {MAX_PAGE_SIZE}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::orders::OrderType;
+
+    #[test]
+    fn test_candles_from_trades() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap(); // 22:13:20
+        let trade = |trade_id: i64, seconds, price, quantity| Trade {
+            trade_id: trade_id.into(),
+            exchange: ExchangeMarket::BTC_USD,
+            buy_order: 0.into(),
+            sell_order: 1.into(),
+            price,
+            quantity,
+            created_at: Some(start + TimeDelta::seconds(seconds)),
+            aggressor: OrderType::Buy,
+        };
+        let trades = [
+            trade(0, 0, 100, 1.0),
+            trade(1, 10, 120, 1.0),
+            trade(2, 20, 90, 2.0),
+            trade(3, 60, 110, 1.0), // Next minute
+        ];
+        let candles = Candle::from_trades(ExchangeMarket::BTC_USD, &trades);
+        let of = |interval| {
+            candles
+                .iter()
+                .filter(|c| c.interval == interval)
+                .collect::<Vec<_>>()
+        };
+
+        let minutes = of(CandleInterval::OneMinute);
+        assert_eq!(minutes.len(), 2);
+        assert_eq!(minutes[0].open_time, start - TimeDelta::seconds(20));
+        assert_eq!(
+            (
+                minutes[0].open,
+                minutes[0].high,
+                minutes[0].low,
+                minutes[0].close
+            ),
+            (100, 120, 90, 90)
+        );
+        assert_eq!(minutes[0].volume, 4.0);
+        assert_eq!(minutes[0].quote_volume, 400.0);
+
+        let hours = of(CandleInterval::OneHour);
+        assert_eq!(hours.len(), 1);
+        assert_eq!((hours[0].close, hours[0].trade_count), (110, 4));
+    }
+}
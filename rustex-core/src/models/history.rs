@@ -0,0 +1,219 @@
+use std::{cmp::Reverse, fmt, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use diesel::Queryable;
+use rustex_errors::RustexError;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    orders::{ExchangeMarket, OrderDetails, OrderId, OrderStatus, OrderType},
+    trades::TradeId,
+};
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Filters of the order and trade history of a user. Every filter is optional
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryQuery {
+    pub market: Option<ExchangeMarket>,
+    pub side: Option<OrderType>,
+    pub status: Option<OrderStatus>, // Orders only
+    pub from: Option<DateTime<Utc>>, // Inclusive
+    pub to: Option<DateTime<Utc>>,   // Exclusive
+    pub cursor: Option<String>,      // Returned by the previous page
+    pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    pub fn page_size(&self) -> Result<usize, RustexError> {
+        match self.limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+            limit @ 1..=MAX_PAGE_SIZE => Ok(limit),
+            _ => Err(RustexError::UserFacingError(format!(
+                "The page limit must be between 1 and {MAX_PAGE_SIZE}"
+            ))),
+        }
+    }
+
+    pub fn cursor(&self) -> Result<Option<HistoryCursor>, RustexError> {
+        self.cursor
+            .as_deref()
+            .map(HistoryCursor::from_str)
+            .transpose()
+    }
+}
+
+/// Position in the history, newest first. Records are ordered by
+/// creation time, market, id and, for trades, the order of the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HistoryCursor {
+    pub created_at: DateTime<Utc>,
+    pub exchange: ExchangeMarket,
+    pub id: i64,
+    pub order_id: OrderId,
+}
+
+impl fmt::Display for HistoryCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:?}.{}.{}",
+            self.created_at.timestamp_micros(),
+            self.exchange,
+            self.id,
+            i64::from(self.order_id)
+        )
+    }
+}
+
+impl FromStr for HistoryCursor {
+    type Err = RustexError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || RustexError::UserFacingError(format!("{s} is not a valid cursor"));
+        let parts = s.split('.').collect::<Vec<_>>();
+        let [created_at, exchange, id, order_id] = parts[..] else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            created_at: created_at
+                .parse()
+                .ok()
+                .and_then(DateTime::from_timestamp_micros)
+                .ok_or_else(invalid)?,
+            exchange: exchange.parse()?,
+            id: id.parse().map_err(|_| invalid())?,
+            order_id: order_id.parse::<i64>().map_err(|_| invalid())?.into(),
+        })
+    }
+}
+
+/// Execution of one of the orders of a user
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserTrade {
+    pub trade_id: TradeId,
+    pub exchange: ExchangeMarket,
+    pub order_id: OrderId,
+    pub side: OrderType,
+    pub price: i64,
+    pub quantity: f64,
+    #[diesel(deserialize_as = DateTime<Utc>)]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>, // None on the last page
+}
+
+impl<T> Page<T> {
+    /// Builds the page from up to `page_size + 1` records, the extra one
+    /// only telling whether there is a next page
+    pub fn new<C: fmt::Display>(
+        mut items: Vec<T>,
+        page_size: usize,
+        cursor: impl Fn(&T) -> C,
+    ) -> Self {
+        let next_cursor = if items.len() > page_size {
+            items.truncate(page_size);
+            items.last().map(|item| cursor(item).to_string())
+        } else {
+            None
+        };
+        Self { items, next_cursor }
+    }
+
+    /// Merges the pages returned by several databases for the same
+    /// query. Records are sorted by their cursor, newest first
+    pub fn merge<C: fmt::Display + Ord>(
+        pages: Vec<Page<T>>,
+        page_size: usize,
+        cursor: impl Fn(&T) -> C,
+    ) -> Self {
+        let more = pages.iter().any(|page| page.next_cursor.is_some());
+        let mut items = pages
+            .into_iter()
+            .flat_map(|page| page.items)
+            .collect::<Vec<_>>();
+        items.sort_by_key(|item| Reverse(cursor(item)));
+        let more = more || items.len() > page_size;
+        items.truncate(page_size);
+        let next_cursor = items
+            .last()
+            .filter(|_| more)
+            .map(|item| cursor(item).to_string());
+        Self { items, next_cursor }
+    }
+}
+
+impl OrderDetails {
+    pub fn cursor(&self) -> HistoryCursor {
+        HistoryCursor {
+            created_at: self.created_at.unwrap_or_default(),
+            exchange: self.exchange,
+            id: self.order_id.into(),
+            order_id: self.order_id,
+        }
+    }
+}
+
+impl UserTrade {
+    pub fn cursor(&self) -> HistoryCursor {
+        HistoryCursor {
+            created_at: self.created_at.unwrap_or_default(),
+            exchange: self.exchange,
+            id: self.trade_id.into(),
+            order_id: self.order_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = HistoryCursor {
+            created_at: DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap(),
+            exchange: ExchangeMarket::BTC_GBP,
+            id: 42,
+            order_id: 7.into(),
+        };
+        let query = HistoryQuery {
+            cursor: Some(cursor.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query.cursor().unwrap(), Some(cursor));
+        assert!(HistoryCursor::from_str("42.BTC_GBP").is_err());
+    }
+
+    #[test]
+    fn test_merged_pages_keep_the_newest_records() {
+        let page = |items: Vec<i64>, next_cursor: Option<&str>| Page {
+            items,
+            next_cursor: next_cursor.map(str::to_string),
+        };
+        let merged = Page::merge(
+            vec![page(vec![9, 4], Some("4")), page(vec![7, 6], None)],
+            2,
+            |item| *item,
+        );
+        assert_eq!(merged, page(vec![9, 7], Some("7")));
+
+        // The first database has older records than the last one merged
+        let merged = Page::merge(
+            vec![page(vec![5, 3], Some("3")), page(vec![], None)],
+            2,
+            |item| *item,
+        );
+        assert_eq!(merged, page(vec![5, 3], Some("3")));
+        let merged = Page::merge(vec![page(vec![5], None), page(vec![2], None)], 2, |item| {
+            *item
+        });
+        assert_eq!(merged, page(vec![5, 2], None));
+    }
+}
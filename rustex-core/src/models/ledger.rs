@@ -0,0 +1,254 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel_derive_enum::DbEnum;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    orders::{Order, OrderType},
+    trades::Trade,
+    UserId,
+};
+use crate::currencies::Currencies;
+
+#[derive(DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Ledgeraccount"]
+#[DbValueStyle = "snake_case"]
+#[serde(rename_all = "camelCase")]
+pub enum LedgerAccount {
+    Available, // Funds the user can spend
+    Reserved,  // Funds locked by open orders
+    External,  // Counterpart of funds entering or leaving the exchange
+}
+
+/// Single leg of a ledger movement. The amounts of all the entries
+/// sharing a reference add up to zero for each currency
+#[derive(Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::db::schema::ledger_entries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewLedgerEntry {
+    pub reference: String,
+    pub user_id: UserId,
+    pub currency: Currencies,
+    pub account: LedgerAccount,
+    pub amount: f64, // Credit (positive) or debit (negative)
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::db::schema::ledger_entries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct LedgerEntry {
+    pub entry_id: i64,
+    pub reference: String,
+    pub user_id: UserId,
+    pub currency: Currencies,
+    pub account: LedgerAccount,
+    pub amount: f64,
+    pub created_at: Option<DateTime<Utc>>, // Diesel automatically handles time-zone conversions
+}
+
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::db::schema::balances)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Balance {
+    pub user_id: UserId,
+    pub currency: Currencies,
+    pub available: f64,
+    pub reserved: f64,
+}
+
+impl NewLedgerEntry {
+    fn new(
+        reference: &str,
+        user_id: UserId,
+        currency: Currencies,
+        account: LedgerAccount,
+        amount: f64,
+    ) -> Self {
+        Self {
+            reference: reference.to_string(),
+            user_id,
+            currency,
+            account,
+            amount,
+        }
+    }
+
+    /// Moves funds between two accounts of the same user
+    pub fn transfer(
+        reference: &str,
+        user_id: UserId,
+        currency: Currencies,
+        from: LedgerAccount,
+        to: LedgerAccount,
+        amount: f64,
+    ) -> [Self; 2] {
+        [
+            Self::new(reference, user_id, currency, from, -amount),
+            Self::new(reference, user_id, currency, to, amount),
+        ]
+    }
+
+    /// Locks the funds required by a new order
+    pub fn order_reservation(order: &Order) -> [Self; 2] {
+        let (currency, amount) = order.required_funds();
+        Self::transfer(
+            &order_reference(order),
+            order.user_id,
+            currency,
+            LedgerAccount::Available,
+            LedgerAccount::Reserved,
+            amount,
+        )
+    }
+
+    /// Unlocks the funds reserved for the quantity an order left
+    /// untraded once it is no longer in the book
+    pub fn order_release(order: &Order, unmatched: f64) -> [Self; 2] {
+        let (currency, amount) = order.funds_for(unmatched);
+        Self::transfer(
+            &order_reference(order),
+            order.user_id,
+            currency,
+            LedgerAccount::Reserved,
+            LedgerAccount::Available,
+            amount,
+        )
+    }
+
+    /// The buyer pays the quote amount out of its reservation
+    /// (getting back any price improvement) and the seller
+    /// delivers the base quantity out of its reservation.
+    /// Cancelled orders keep the reservation of their matched quantity until then
+    pub fn trade_settlement(trade: &Trade, buy_order: &Order, sell_order: &Order) -> Vec<Self> {
+        let (base, quote) = trade.exchange.currencies();
+        let reference = format!("trade:{:?}:{}", trade.exchange, i64::from(trade.trade_id));
+        let quote_amount = trade.price as f64 * trade.quantity;
+        let buyer_reserved = match buy_order.notional {
+            Some(_) => quote_amount,
+            None => buy_order.price as f64 * trade.quantity,
+        };
+
+        let mut entries = vec![
+            Self::new(
+                &reference,
+                buy_order.user_id,
+                quote,
+                LedgerAccount::Reserved,
+                -buyer_reserved,
+            ),
+            Self::new(
+                &reference,
+                sell_order.user_id,
+                quote,
+                LedgerAccount::Available,
+                quote_amount,
+            ),
+            Self::new(
+                &reference,
+                sell_order.user_id,
+                base,
+                LedgerAccount::Reserved,
+                -trade.quantity,
+            ),
+            Self::new(
+                &reference,
+                buy_order.user_id,
+                base,
+                LedgerAccount::Available,
+                trade.quantity,
+            ),
+        ];
+        if buyer_reserved > quote_amount {
+            entries.push(Self::new(
+                &reference,
+                buy_order.user_id,
+                quote,
+                LedgerAccount::Available,
+                buyer_reserved - quote_amount,
+            ));
+        }
+        entries
+    }
+}
+
+pub fn order_reference(order: &Order) -> String {
+    format!("order:{:?}:{}", order.exchange, i64::from(order.order_id))
+}
+
+impl Order {
+    /// Funds locked when the order is accepted
+    pub fn required_funds(&self) -> (Currencies, f64) {
+        let (base, quote) = self.exchange.currencies();
+        match self.order_type {
+            OrderType::Buy => (
+                quote,
+                self.notional.unwrap_or(self.price as f64 * self.quantity),
+            ),
+            OrderType::Sell => (base, self.quantity),
+        }
+    }
+
+    /// Funds still locked by the order given its trades
+    pub fn reserved_funds<'a>(
+        &self,
+        trades: impl IntoIterator<Item = &'a Trade>,
+    ) -> (Currencies, f64) {
+        self.funds_for(self.remaining_quantity(trades))
+    }
+
+    /// Funds locked for a quantity of the order left to trade
+    pub fn funds_for(&self, quantity: f64) -> (Currencies, f64) {
+        let (base, quote) = self.exchange.currencies();
+        match self.order_type {
+            // Notional orders keep their unspent quote amount as quantity at the limit price
+            OrderType::Buy => (quote, (self.price as f64 * quantity).max(0.0)),
+            OrderType::Sell => (base, quantity.max(0.0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, models::orders::ExchangeMarket};
+
+    #[test]
+    fn test_trade_settlement_is_balanced() {
+        let order = |order_id, user_id, order_type, price| {
+            fixtures::order(order_id)
+                .user(user_id)
+                .order_type(order_type)
+                .price(price)
+                .quantity(2.0)
+                .build()
+        };
+        let buy_order = order(0, 1, OrderType::Buy, 110);
+        let sell_order = order(1, 2, OrderType::Sell, 100);
+        let trade = Trade {
+            trade_id: 0.into(),
+            exchange: ExchangeMarket::BTC_USD,
+            buy_order: buy_order.order_id,
+            sell_order: sell_order.order_id,
+            price: 100,
+            quantity: 2.0,
+            created_at: None,
+            aggressor: OrderType::Buy,
+        };
+
+        let entries = NewLedgerEntry::trade_settlement(&trade, &buy_order, &sell_order);
+        for currency in [Currencies::BTC, Currencies::USD] {
+            let total: f64 = entries
+                .iter()
+                .filter(|entry| entry.currency == currency)
+                .map(|entry| entry.amount)
+                .sum();
+            assert!(total.abs() < 1e-9);
+        }
+
+        // Buyer reserved 220 USD, paid 200 USD and got 20 USD back
+        let refund = entries.last().unwrap();
+        assert_eq!(refund.account, LedgerAccount::Available);
+        assert!((refund.amount - 20.0).abs() < 1e-9);
+        assert_eq!(buy_order.reserved_funds(&[trade]), (Currencies::USD, 0.0));
+    }
+}
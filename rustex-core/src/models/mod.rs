@@ -1,9 +1,23 @@
 use diesel::{sql_types::BigInt, AsExpression, FromSqlRow};
 use serde::{Deserialize, Serialize};
+pub mod audit;
+pub mod book_snapshot;
 pub mod cancellations;
+pub mod candles;
+pub mod history;
+pub mod ledger;
 pub mod order_book;
+pub mod order_groups;
 pub mod orders;
+pub mod positions;
+pub mod reconciliation;
+pub mod risk;
+pub mod sequences;
+pub mod sessions;
+pub mod trade_tape;
 pub mod trades;
+pub mod transfers;
+pub mod users;
 
 #[derive(
     Debug,
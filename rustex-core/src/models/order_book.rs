@@ -11,12 +11,17 @@ use hashbrown::HashSet;
 use rustex_errors::RustexError;
 
 use super::{
-    orders::{ClientOrder, ExchangeMarket, Order},
+    order_groups::{
+        ClientOrderGroup, GroupId, LinkedOrderUpdates, OrderGroup, OrderGroupKind,
+        OrderGroupStatus, OrderGroups,
+    },
+    orders::{ClientOrder, ExchangeMarket, Order, OrderStatus},
+    sequences::{IdBlock, IdKind, IdSequence},
     trades::TradeId,
     UserId,
 };
 use crate::models::{
-    orders::{BuyOrder, OrderId, SellOrder},
+    orders::{BuyOrder, OrderId, OrderType, SellOrder},
     trades::Trade,
 };
 use crate::{lock, order_matching::MatchOrders};
@@ -33,8 +38,11 @@ pub struct OrderBook {
     pub(crate) buy_orders: Mutex<BinaryHeap<BuyOrder>>, // Max-heap. Highest price at the root
     pub(crate) sell_orders: Mutex<BinaryHeap<SellOrder>>, // Min-heap. Lowest price at the root
     pending_orders: Mutex<HashSet<OrderId>>,            // Orders being processed
-    order_counter: AtomicI64,
-    trade_counter: AtomicI64,
+    groups: Mutex<OrderGroups>,                         // Pending and active OCO/bracket groups
+    order_ids: Mutex<IdSequence>,
+    trade_ids: Mutex<IdSequence>,
+    group_counter: AtomicI64,
+    last_price: AtomicI64, // Price of the last trade. Zero until the first one
     exchange: ExchangeMarket,
 }
 
@@ -44,17 +52,22 @@ impl OrderBook {
             buy_orders: Mutex::new(BinaryHeap::new()),
             sell_orders: Mutex::new(BinaryHeap::new()),
             pending_orders: Mutex::new(HashSet::new()),
-            order_counter: AtomicI64::new(0),
-            trade_counter: AtomicI64::new(0),
+            groups: Mutex::new(OrderGroups::default()),
+            order_ids: Mutex::new(IdSequence::default()),
+            trade_ids: Mutex::new(IdSequence::default()),
+            group_counter: AtomicI64::new(0),
+            last_price: AtomicI64::new(0),
             exchange,
         }
     }
 
     pub fn from_db(
-        last_order: OrderId,
-        last_trade: TradeId,
+        order_ids: IdBlock,
+        trade_ids: IdBlock,
+        last_group: GroupId,
         buy_orders: Vec<BuyOrder>,
         sell_orders: Vec<SellOrder>,
+        order_groups: Vec<OrderGroup>,
         exchange: ExchangeMarket,
     ) -> Self {
         let pending = buy_orders
@@ -66,29 +79,83 @@ impl OrderBook {
             buy_orders: Mutex::new(BinaryHeap::from(buy_orders)),
             sell_orders: Mutex::new(BinaryHeap::from(sell_orders)),
             pending_orders: Mutex::new(pending),
-            order_counter: AtomicI64::new(last_order.into()),
-            trade_counter: AtomicI64::new(last_trade.into()),
+            groups: Mutex::new(OrderGroups::from_groups(order_groups)),
+            order_ids: Mutex::new(IdSequence::new(order_ids)),
+            trade_ids: Mutex::new(IdSequence::new(trade_ids)),
+            group_counter: AtomicI64::new(last_group.into()),
+            last_price: AtomicI64::new(0),
             exchange,
         }
     }
 
-    fn fetch_next_order_id(&self) -> OrderId {
-        self.order_counter.fetch_add(1, Ordering::Relaxed).into()
+    fn fetch_next_order_id(&self) -> Result<OrderId, RustexError> {
+        lock!(self.order_ids)
+            .next_id()
+            .map(OrderId::from)
+            .ok_or_else(|| RustexError::MatchServiceError("No order ids reserved".into()))
+    }
+
+    fn fetch_next_trade_id(&self) -> Option<TradeId> {
+        lock!(self.trade_ids).next_id().map(TradeId::from)
+    }
+
+    fn ids(&self, kind: IdKind) -> &Mutex<IdSequence> {
+        match kind {
+            IdKind::Order => &self.order_ids,
+            IdKind::Trade => &self.trade_ids,
+        }
+    }
+
+    /// Ids of the kind left in the reserved blocks
+    pub fn remaining_ids(&self, kind: IdKind) -> i64 {
+        lock!(self.ids(kind)).remaining()
     }
 
-    fn fetch_next_trade_id(&self) -> TradeId {
-        self.trade_counter.fetch_add(1, Ordering::Relaxed).into()
+    /// Lowest id the next block of the kind can start at
+    pub fn reservation_start(&self, kind: IdKind) -> i64 {
+        lock!(self.ids(kind)).reservation_start()
     }
 
+    pub fn extend_ids(&self, kind: IdKind, block: IdBlock) {
+        lock!(self.ids(kind)).extend(block);
+    }
+
+    /// Most trades matching an order of the type can make: one per order
+    /// resting on the other side
+    pub fn max_trades(&self, order_type: OrderType) -> i64 {
+        let resting = match order_type {
+            OrderType::Buy => lock!(self.sell_orders).len(),
+            OrderType::Sell => lock!(self.buy_orders).len(),
+        };
+        resting as i64
+    }
+
+    /// Most orders a round of matching can activate: the take profit
+    /// and the stop loss of every group
+    pub fn max_linked_orders(&self) -> i64 {
+        2 * lock!(self.groups).len() as i64
+    }
+
+    fn fetch_next_group_id(&self) -> GroupId {
+        self.group_counter.fetch_add(1, Ordering::Relaxed).into()
+    }
+
+    /// Matches the order against the book. Fails without matching unless
+    /// every trade it can make has a reserved id
     pub fn process_order<T: MatchOrders + Deref<Target = Order>>(
         &self,
         order: T,
-    ) -> (Vec<Trade>, Vec<OrderId>) {
+    ) -> Result<(Vec<Trade>, Vec<OrderId>), RustexError> {
         let mut pending_guard = lock!(self.pending_orders);
+        if self.remaining_ids(IdKind::Trade) < self.max_trades(order.order_type) {
+            return Err(RustexError::MatchServiceError(
+                "Not enough trade ids reserved".into(),
+            ));
+        }
         pending_guard.insert(order.order_id);
 
         let (trades, completed_orders) = order.match_order(self, pending_guard);
-        (trades, completed_orders)
+        Ok((trades, completed_orders))
     }
 
     pub fn into_order<T: From<Order>>(
@@ -101,14 +168,39 @@ impl OrderBook {
                 "Exchange markets do not match".into(),
             ));
         }
+        let quantity = match client_order.notional {
+            Some(notional) => {
+                if client_order.order_type != OrderType::Buy {
+                    return Err(RustexError::UserFacingError(
+                        "Only buy orders can be sized by notional".into(),
+                    ));
+                }
+                if notional <= 0.0 || client_order.price <= 0 {
+                    return Err(RustexError::UserFacingError(
+                        "Notional orders require a positive notional and price".into(),
+                    ));
+                }
+                notional / client_order.price as f64
+            }
+            None => client_order.quantity,
+        };
+        if client_order.price <= 0 || quantity.is_nan() || quantity <= 0.0 {
+            return Err(RustexError::UserFacingError(
+                "Orders require a positive price and quantity".into(),
+            ));
+        }
         let order = Order {
-            order_id: self.fetch_next_order_id(),
+            order_id: self.fetch_next_order_id()?,
             user_id,
             price: client_order.price,
-            quantity: client_order.quantity,
+            quantity,
             created_at: None,
             order_type: client_order.order_type,
             exchange: self.exchange,
+            notional: client_order.notional,
+            status: OrderStatus::New,
+            filled_quantity: 0.0,
+            average_price: None,
         };
         Ok(T::from(order))
     }
@@ -119,15 +211,19 @@ impl OrderBook {
         sell_order_id: OrderId,
         price: i64,
         quantity: f64,
+        aggressor: OrderType,
     ) -> Trade {
+        self.last_price.store(price, Ordering::Relaxed);
         Trade {
-            trade_id: self.fetch_next_trade_id(),
+            // Checked by process_order, which holds the pending orders
+            trade_id: self.fetch_next_trade_id().expect("Trade ids are reserved"),
             exchange: self.exchange,
             buy_order: buy_order_id,
             sell_order: sell_order_id,
             price,
             quantity,
             created_at: None,
+            aggressor,
         }
     }
 
@@ -138,4 +234,510 @@ impl OrderBook {
     pub fn try_delete_order(&self, order_id: OrderId) -> bool {
         lock!(self.pending_orders).remove(&order_id)
     }
+
+    /// Same as [`Self::try_delete_order`], returning the quantity the order had left
+    /// to trade. None if it was no longer pending
+    pub fn try_cancel_order(&self, order_id: OrderId) -> Option<f64> {
+        // Same locking order as the matching logic, so the order cannot trade meanwhile
+        let mut pending_guard = lock!(self.pending_orders);
+        if !pending_guard.remove(&order_id) {
+            return None;
+        }
+        let resting = lock!(self.buy_orders)
+            .iter()
+            .find(|order| order.order_id == order_id)
+            .map(|order| order.quantity);
+        let resting = resting.or_else(|| {
+            lock!(self.sell_orders)
+                .iter()
+                .find(|order| order.order_id == order_id)
+                .map(|order| order.quantity)
+        });
+        Some(resting.unwrap_or_default())
+    }
+
+    /// Price of the last trade in the market, if any
+    pub fn last_price(&self) -> Option<i64> {
+        Some(self.last_price.load(Ordering::Relaxed)).filter(|&price| price > 0)
+    }
+
+    pub fn set_last_price(&self, price: i64) {
+        self.last_price.store(price, Ordering::Relaxed);
+    }
+
+    /// Snapshot of the orders resting in the book with their remaining quantity
+    pub fn open_orders(&self) -> Vec<Order> {
+        // Same locking order as the matching logic
+        let pending_guard = lock!(self.pending_orders);
+        let buy_orders = lock!(self.buy_orders);
+        let sell_orders = lock!(self.sell_orders);
+        buy_orders
+            .iter()
+            .map(|order| order.0)
+            .chain(sell_orders.iter().map(|order| order.0))
+            .filter(|order| pending_guard.contains(&order.order_id))
+            .collect()
+    }
+
+    /// Places the order in the book replacing any previous copy of it
+    pub fn restore_order(&self, order: Order) {
+        let mut pending_guard = lock!(self.pending_orders);
+        let mut buy_orders = lock!(self.buy_orders);
+        let mut sell_orders = lock!(self.sell_orders);
+        buy_orders.retain(|o| o.order_id != order.order_id);
+        sell_orders.retain(|o| o.order_id != order.order_id);
+        match order.order_type {
+            OrderType::Buy => buy_orders.push(BuyOrder(order)),
+            OrderType::Sell => sell_orders.push(SellOrder(order)),
+        }
+        pending_guard.insert(order.order_id);
+    }
+
+    /// Midpoint between the best live bid and ask. None if either side is empty
+    pub fn mid_price(&self) -> Option<f64> {
+        // Same locking order as the matching logic
+        let pending_guard = lock!(self.pending_orders);
+        let best_bid = lock!(self.buy_orders)
+            .iter()
+            .filter(|order| pending_guard.contains(&order.order_id))
+            .map(|order| order.price)
+            .max()?;
+        let best_ask = lock!(self.sell_orders)
+            .iter()
+            .filter(|order| pending_guard.contains(&order.order_id))
+            .map(|order| order.price)
+            .min()?;
+        Some((best_bid + best_ask) as f64 / 2.0)
+    }
+
+    /// Number of orders of the user resting in the book
+    pub fn count_open_orders(&self, user_id: UserId) -> usize {
+        // Same locking order as the matching logic
+        let pending_guard = lock!(self.pending_orders);
+        let is_open =
+            |order: &Order| order.user_id == user_id && pending_guard.contains(&order.order_id);
+        let buy_orders = lock!(self.buy_orders).iter().filter(|o| is_open(o)).count();
+        let sell_orders = lock!(self.sell_orders)
+            .iter()
+            .filter(|o| is_open(o))
+            .count();
+        buy_orders + sell_orders
+    }
+
+    /// Registers a new OCO or bracket group. Returns the group together with
+    /// the order to be placed right away (the entry order of a bracket or
+    /// the take profit of an OCO pair). The stop loss is kept off the book
+    /// until a trade reaches its trigger price
+    pub fn into_order_group(
+        &self,
+        client_group: ClientOrderGroup,
+        user_id: UserId,
+    ) -> Result<(OrderGroup, Order), RustexError> {
+        if self.exchange != client_group.exchange {
+            return Err(RustexError::OtherInternal(
+                "Exchange markets do not match".into(),
+            ));
+        }
+        if client_group.quantity <= 0.0 {
+            return Err(RustexError::UserFacingError(
+                "Order group quantity must be positive".into(),
+            ));
+        }
+        let prices = [
+            Some(client_group.take_profit_price),
+            Some(client_group.stop_price),
+            client_group.stop_limit_price,
+            client_group.entry_price,
+        ];
+        if prices.into_iter().flatten().any(|price| price <= 0) {
+            return Err(RustexError::UserFacingError(
+                "Order group prices must be positive".into(),
+            ));
+        }
+        let stop_below_take_profit = client_group.stop_price < client_group.take_profit_price;
+        let valid_prices = match client_group.order_type {
+            OrderType::Sell => stop_below_take_profit,
+            OrderType::Buy => !stop_below_take_profit,
+        };
+        if !valid_prices || client_group.stop_price == client_group.take_profit_price {
+            return Err(RustexError::UserFacingError(
+                "The stop price must be on the losing side of the take profit price".into(),
+            ));
+        }
+
+        let mut group = OrderGroup {
+            group_id: self.fetch_next_group_id(),
+            exchange: self.exchange,
+            user_id,
+            kind: OrderGroupKind::Oco,
+            status: OrderGroupStatus::Active,
+            exit_type: client_group.order_type,
+            quantity: client_group.quantity,
+            take_profit_price: client_group.take_profit_price,
+            stop_trigger_price: client_group.stop_price,
+            stop_limit_price: client_group
+                .stop_limit_price
+                .unwrap_or(client_group.stop_price),
+            entry_order: None,
+            take_profit_order: None,
+            stop_loss_order: None,
+            created_at: None,
+        };
+        let order = match client_group.entry_price {
+            Some(entry_price) => {
+                let entry_type = match client_group.order_type {
+                    OrderType::Buy => OrderType::Sell,
+                    OrderType::Sell => OrderType::Buy,
+                };
+                let entry = Order {
+                    order_type: entry_type,
+                    ..group.exit_order(self.fetch_next_order_id()?, entry_price)
+                };
+                group.kind = OrderGroupKind::Bracket;
+                group.status = OrderGroupStatus::Pending;
+                group.entry_order = Some(entry.order_id);
+                entry
+            }
+            None => {
+                let take_profit =
+                    group.exit_order(self.fetch_next_order_id()?, group.take_profit_price);
+                group.take_profit_order = Some(take_profit.order_id);
+                take_profit
+            }
+        };
+        lock!(self.groups).insert(group.clone());
+        Ok((group, order))
+    }
+
+    /// Pending or active group
+    pub fn get_order_group(&self, group_id: GroupId) -> Option<OrderGroup> {
+        lock!(self.groups).get(group_id).cloned()
+    }
+
+    /// Updates the linked orders after a round of matching:
+    /// - A filled bracket entry activates its take profit and stop loss
+    /// - A filled take profit disarms its stop loss, a partially
+    ///   filled one shrinks it by the filled quantity
+    /// - A trade reaching the stop price cancels the take profit
+    ///   and places the stop loss order in the book
+    ///
+    /// Fails without changes unless every order it can activate has a reserved id
+    pub fn resolve_linked_orders(
+        &self,
+        trades: &[Trade],
+        completed_orders: &[OrderId],
+    ) -> Result<LinkedOrderUpdates, RustexError> {
+        let mut updates = LinkedOrderUpdates::default();
+        let mut groups = lock!(self.groups);
+        let mut order_ids = lock!(self.order_ids);
+        if order_ids.remaining() < 2 * groups.len() as i64 {
+            return Err(RustexError::MatchServiceError(
+                "Not enough order ids reserved for the linked orders".into(),
+            ));
+        }
+        let mut next_order_id =
+            || OrderId::from(order_ids.next_id().expect("Order ids are checked above"));
+
+        for &order_id in completed_orders {
+            let group_id = groups
+                .group_of(order_id)
+                .filter(|group| group.status == OrderGroupStatus::Pending)
+                .filter(|group| group.entry_order == Some(order_id))
+                .map(|group| group.group_id);
+            let Some(group_id) = group_id else {
+                continue;
+            };
+            let mut group = groups.remove(group_id).unwrap();
+            let take_profit = group.exit_order(next_order_id(), group.take_profit_price);
+            group.take_profit_order = Some(take_profit.order_id);
+            group.status = OrderGroupStatus::Active;
+            updates.activated.push(take_profit);
+            updates.groups.push(group.clone());
+            groups.insert(group);
+        }
+
+        for trade in trades {
+            for order_id in [trade.buy_order, trade.sell_order] {
+                let group_id = groups
+                    .group_of(order_id)
+                    .filter(|group| group.status == OrderGroupStatus::Active)
+                    .filter(|group| group.take_profit_order == Some(order_id))
+                    .map(|group| group.group_id);
+                let Some(group_id) = group_id else {
+                    continue;
+                };
+                let mut group = groups.remove(group_id).unwrap();
+                if completed_orders.contains(&order_id) {
+                    group.status = OrderGroupStatus::Done;
+                    updates.groups.push(group);
+                    continue;
+                }
+                // Partially filled. The stop loss only covers what is left
+                group.quantity -= trade.quantity;
+                updates.groups.push(group.clone());
+                groups.insert(group);
+            }
+
+            for group_id in groups.triggered_by(trade.price) {
+                let mut group = groups.remove(group_id).unwrap();
+                if let Some(take_profit) = group.take_profit_order {
+                    if self.try_delete_order(take_profit) {
+                        updates.cancelled.push(take_profit);
+                    }
+                }
+                let stop_loss = group.exit_order(next_order_id(), group.stop_limit_price);
+                group.stop_loss_order = Some(stop_loss.order_id);
+                group.status = OrderGroupStatus::Done;
+                updates.activated.push(stop_loss);
+                updates.groups.push(group);
+            }
+        }
+        Ok(updates)
+    }
+
+    /// Cancels the rest of the group once one of its orders was cancelled
+    pub fn cancel_linked_orders(&self, order_id: OrderId) -> LinkedOrderUpdates {
+        let mut groups = lock!(self.groups);
+        match groups.group_of(order_id).map(|group| group.group_id) {
+            Some(group_id) => self.cancel_group(groups.remove(group_id).unwrap()),
+            None => LinkedOrderUpdates::default(),
+        }
+    }
+
+    /// Cancels a pending or active group. Returns None if the group
+    /// was already done or cancelled
+    pub fn try_delete_group(&self, group_id: GroupId) -> Option<LinkedOrderUpdates> {
+        let group = lock!(self.groups).remove(group_id)?;
+        Some(self.cancel_group(group))
+    }
+
+    /// Cancels the group of a leg that could not be placed in the book.
+    /// `group` is the state the leg was activated in
+    pub fn cancel_unplaced_leg(&self, order_id: OrderId, group: OrderGroup) -> LinkedOrderUpdates {
+        let mut group = lock!(self.groups).remove(group.group_id).unwrap_or(group);
+        for leg in [&mut group.take_profit_order, &mut group.stop_loss_order] {
+            if *leg == Some(order_id) {
+                *leg = None;
+            }
+        }
+        self.cancel_group(group)
+    }
+
+    fn cancel_group(&self, mut group: OrderGroup) -> LinkedOrderUpdates {
+        let mut updates = LinkedOrderUpdates::default();
+        for order_id in [group.entry_order, group.take_profit_order]
+            .into_iter()
+            .flatten()
+        {
+            if self.try_delete_order(order_id) {
+                updates.cancelled.push(order_id);
+            }
+        }
+        group.status = OrderGroupStatus::Cancelled;
+        updates.groups.push(group);
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn client_order(order_type: OrderType, price: i64, quantity: f64) -> ClientOrder {
+        ClientOrder {
+            price,
+            quantity,
+            exchange: ExchangeMarket::BTC_EUR,
+            order_type,
+            session_id: None,
+            notional: None,
+        }
+    }
+
+    fn execute(book: &OrderBook, order: Order) -> LinkedOrderUpdates {
+        let (trades, completed_orders) = match order.order_type {
+            OrderType::Buy => book.process_order(BuyOrder(order)),
+            OrderType::Sell => book.process_order(SellOrder(order)),
+        }
+        .unwrap();
+        book.resolve_linked_orders(&trades, &completed_orders)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_bracket_stop_loss_cancels_take_profit() {
+        let book = fixtures::order_book(ExchangeMarket::BTC_EUR);
+        let seller: UserId = 1.into();
+        let trader: UserId = 2.into();
+
+        let (group, entry) = book
+            .into_order_group(
+                ClientOrderGroup {
+                    exchange: ExchangeMarket::BTC_EUR,
+                    order_type: OrderType::Sell,
+                    quantity: 2.0,
+                    take_profit_price: 120,
+                    stop_price: 90,
+                    stop_limit_price: Some(85),
+                    entry_price: Some(100),
+                },
+                trader,
+            )
+            .unwrap();
+        assert_eq!(group.status, OrderGroupStatus::Pending);
+        assert!(execute(&book, entry).is_empty());
+
+        // The entry fills and activates the take profit
+        let sell = book
+            .into_order(client_order(OrderType::Sell, 100, 2.0), seller)
+            .unwrap();
+        let updates = execute(&book, sell);
+        assert_eq!(updates.activated.len(), 1);
+        let take_profit = updates.activated[0];
+        assert_eq!(take_profit.price, 120);
+        assert_eq!(updates.groups[0].status, OrderGroupStatus::Active);
+        assert!(execute(&book, take_profit).is_empty());
+        assert!(book.is_order_pending(take_profit.order_id));
+
+        // A trade at the stop price cancels the take profit and places the stop loss
+        let bid = book
+            .into_order(client_order(OrderType::Buy, 90, 1.0), seller)
+            .unwrap();
+        assert!(execute(&book, bid).is_empty());
+        let ask = book
+            .into_order(client_order(OrderType::Sell, 90, 1.0), seller)
+            .unwrap();
+        let updates = execute(&book, ask);
+        assert_eq!(updates.cancelled, vec![take_profit.order_id]);
+        assert_eq!(updates.activated.len(), 1);
+        assert_eq!(updates.activated[0].price, 85);
+        assert_eq!(updates.groups[0].status, OrderGroupStatus::Done);
+        assert!(!book.is_order_pending(take_profit.order_id));
+        assert!(book.get_order_group(group.group_id).is_none());
+    }
+
+    #[test]
+    fn test_cancelling_an_oco_leg_cancels_the_group() {
+        let book = fixtures::order_book(ExchangeMarket::BTC_EUR);
+        let (group, take_profit) = book
+            .into_order_group(
+                ClientOrderGroup {
+                    exchange: ExchangeMarket::BTC_EUR,
+                    order_type: OrderType::Buy,
+                    quantity: 1.0,
+                    take_profit_price: 80,
+                    stop_price: 110,
+                    stop_limit_price: None,
+                    entry_price: None,
+                },
+                1.into(),
+            )
+            .unwrap();
+        assert_eq!(group.take_profit_order, Some(take_profit.order_id));
+        execute(&book, take_profit);
+
+        assert!(book.try_delete_order(take_profit.order_id));
+        let updates = book.cancel_linked_orders(take_profit.order_id);
+        assert_eq!(updates.groups[0].status, OrderGroupStatus::Cancelled);
+        assert!(book.try_delete_group(group.group_id).is_none());
+    }
+
+    #[test]
+    fn test_partial_take_profit_shrinks_the_stop_loss() {
+        let book = fixtures::order_book(ExchangeMarket::BTC_EUR);
+        let buyer: UserId = 1.into();
+        let (group, take_profit) = book
+            .into_order_group(
+                ClientOrderGroup {
+                    exchange: ExchangeMarket::BTC_EUR,
+                    order_type: OrderType::Sell,
+                    quantity: 2.0,
+                    take_profit_price: 120,
+                    stop_price: 90,
+                    stop_limit_price: None,
+                    entry_price: None,
+                },
+                2.into(),
+            )
+            .unwrap();
+        execute(&book, take_profit);
+
+        // Half of the take profit fills. The group stays active
+        let bid = book
+            .into_order(client_order(OrderType::Buy, 120, 0.5), buyer)
+            .unwrap();
+        let updates = execute(&book, bid);
+        assert_eq!(updates.groups[0].status, OrderGroupStatus::Active);
+        assert_eq!(updates.groups[0].quantity, 1.5);
+        assert!(book.get_order_group(group.group_id).is_some());
+
+        // The stop loss only sells what the take profit did not
+        let bid = book
+            .into_order(client_order(OrderType::Buy, 90, 1.0), buyer)
+            .unwrap();
+        execute(&book, bid);
+        let ask = book
+            .into_order(client_order(OrderType::Sell, 90, 1.0), buyer)
+            .unwrap();
+        let updates = execute(&book, ask);
+        assert_eq!(updates.cancelled, vec![take_profit.order_id]);
+        assert_eq!(updates.activated[0].quantity, 1.5);
+        assert_eq!(updates.groups[0].status, OrderGroupStatus::Done);
+    }
+
+    #[test]
+    fn test_cancelled_orders_report_their_unmatched_quantity() {
+        let book = fixtures::order_book(ExchangeMarket::BTC_EUR);
+        let buy = book
+            .into_order(client_order(OrderType::Buy, 100, 2.0), 1.into())
+            .unwrap();
+        execute(&book, buy);
+        let sell = book
+            .into_order(client_order(OrderType::Sell, 100, 0.5), 2.into())
+            .unwrap();
+        execute(&book, sell);
+
+        assert_eq!(book.try_cancel_order(buy.order_id), Some(1.5));
+        assert_eq!(book.try_cancel_order(buy.order_id), None);
+        assert_eq!(book.try_cancel_order(sell.order_id), None);
+    }
+
+    #[test]
+    fn test_orders_require_positive_price_and_quantity() {
+        let book = fixtures::order_book(ExchangeMarket::BTC_EUR);
+        for (price, quantity) in [(0, 1.0), (-5, 1.0), (100, 0.0), (100, f64::NAN)] {
+            let order = client_order(OrderType::Buy, price, quantity);
+            let placed = book.into_order::<BuyOrder>(order, 1.into());
+            assert!(matches!(placed, Err(RustexError::UserFacingError(_))));
+        }
+        assert!(book
+            .into_order::<BuyOrder>(client_order(OrderType::Buy, 100, 1.0), 1.into())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_orders_are_not_matched_without_reserved_ids() {
+        let book = OrderBook::new(ExchangeMarket::BTC_EUR);
+        let placed =
+            book.into_order::<SellOrder>(client_order(OrderType::Sell, 100, 1.0), 1.into());
+        assert!(matches!(placed, Err(RustexError::MatchServiceError(_))));
+
+        book.extend_ids(IdKind::Order, IdBlock { start: 0, end: 2 });
+        let ask: SellOrder = book
+            .into_order(client_order(OrderType::Sell, 100, 1.0), 1.into())
+            .unwrap();
+        book.process_order(ask).unwrap();
+
+        // The bid could trade with the ask, which would take a trade id
+        let bid: BuyOrder = book
+            .into_order(client_order(OrderType::Buy, 100, 1.0), 2.into())
+            .unwrap();
+        assert!(book.process_order(bid).is_err());
+        assert_eq!(book.open_orders().len(), 1);
+
+        book.extend_ids(IdKind::Trade, IdBlock { start: 0, end: 1 });
+        let (trades, _) = book.process_order(bid).unwrap();
+        assert_eq!(trades[0].trade_id, 0.into());
+    }
 }
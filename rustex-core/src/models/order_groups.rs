@@ -0,0 +1,233 @@
+use chrono::{DateTime, Utc};
+use diesel::{prelude::*, sql_types::BigInt, AsExpression, FromSqlRow};
+use diesel_derive_enum::DbEnum;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    orders::{ExchangeMarket, Order, OrderId, OrderStatus, OrderType},
+    UserId,
+};
+
+#[derive(
+    Debug,
+    Serialize,
+    Deserialize,
+    Eq,
+    PartialEq,
+    PartialOrd,
+    Ord,
+    Default,
+    Clone,
+    Copy,
+    Hash,
+    FromSqlRow,
+    AsExpression,
+)]
+#[diesel(sql_type = BigInt)]
+pub struct GroupId(i64);
+
+impl From<i64> for GroupId {
+    fn from(value: i64) -> Self {
+        GroupId(value)
+    }
+}
+
+impl From<GroupId> for i64 {
+    fn from(value: GroupId) -> Self {
+        value.0
+    }
+}
+
+impl std::ops::Add<i64> for GroupId {
+    type Output = GroupId;
+    fn add(self, rhs: i64) -> Self::Output {
+        GroupId(self.0 + rhs)
+    }
+}
+
+impl<DB> diesel::serialize::ToSql<BigInt, DB> for GroupId
+where
+    DB: diesel::backend::Backend,
+    i64: diesel::serialize::ToSql<BigInt, DB>,
+{
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, DB>,
+    ) -> diesel::serialize::Result {
+        self.0.to_sql(out)
+    }
+}
+
+impl<DB> diesel::deserialize::FromSql<BigInt, DB> for GroupId
+where
+    DB: diesel::backend::Backend,
+    i64: diesel::deserialize::FromSql<BigInt, DB>,
+{
+    fn from_sql(bytes: DB::RawValue<'_>) -> diesel::deserialize::Result<Self> {
+        Ok(GroupId(i64::from_sql(bytes)?))
+    }
+}
+
+#[derive(DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Ordergroupkind"]
+#[DbValueStyle = "snake_case"]
+#[serde(rename_all = "camelCase")]
+pub enum OrderGroupKind {
+    Oco,     // Take profit + stop loss. Both working immediately
+    Bracket, // Entry order. Take profit + stop loss working once the entry fills
+}
+
+#[derive(DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Ordergroupstatus"]
+#[DbValueStyle = "snake_case"]
+#[serde(rename_all = "camelCase")]
+pub enum OrderGroupStatus {
+    Pending,   // Waiting for the bracket entry to fill
+    Active,    // Take profit resting and stop loss armed
+    Done,      // One of the legs executed and the other one was cancelled
+    Cancelled, // Cancelled before any of the legs executed
+}
+
+/// Linked orders where the execution of one leg cancels the other one
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::db::schema::order_groups)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OrderGroup {
+    pub group_id: GroupId,
+    pub exchange: ExchangeMarket,
+    pub user_id: UserId,
+    pub kind: OrderGroupKind,
+    pub status: OrderGroupStatus,
+    pub exit_type: OrderType, // Side of the take profit and stop loss orders
+    pub quantity: f64,
+    pub take_profit_price: i64,
+    pub stop_trigger_price: i64,
+    pub stop_limit_price: i64,
+    pub entry_order: Option<OrderId>,
+    pub take_profit_order: Option<OrderId>,
+    pub stop_loss_order: Option<OrderId>,
+    pub created_at: Option<DateTime<Utc>>, // Diesel automatically handles time-zone conversions
+}
+
+impl OrderGroup {
+    /// Whether a trade at `price` triggers the stop loss
+    pub fn is_stop_triggered(&self, price: i64) -> bool {
+        if self.status != OrderGroupStatus::Active || self.stop_loss_order.is_some() {
+            return false;
+        }
+        match self.exit_type {
+            OrderType::Sell => price <= self.stop_trigger_price,
+            OrderType::Buy => price >= self.stop_trigger_price,
+        }
+    }
+
+    pub(crate) fn exit_order(&self, order_id: OrderId, price: i64) -> Order {
+        Order {
+            order_id,
+            user_id: self.user_id,
+            price,
+            quantity: self.quantity,
+            created_at: None,
+            order_type: self.exit_type,
+            exchange: self.exchange,
+            notional: None,
+            status: OrderStatus::New,
+            filled_quantity: 0.0,
+            average_price: None,
+        }
+    }
+
+    fn working_orders(&self) -> impl Iterator<Item = OrderId> {
+        [
+            self.entry_order,
+            self.take_profit_order,
+            self.stop_loss_order,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// OCO pair (no entry price) or bracket order (with an entry price)
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientOrderGroup {
+    pub exchange: ExchangeMarket,
+    pub order_type: OrderType, // Side of the take profit and stop loss orders
+    pub quantity: f64,
+    pub take_profit_price: i64,
+    pub stop_price: i64,
+    #[serde(default)]
+    pub stop_limit_price: Option<i64>, // Defaults to the stop price
+    #[serde(default)]
+    pub entry_price: Option<i64>, // Makes it a bracket order
+}
+
+/// Side effects of executing or cancelling a linked order
+#[derive(Debug, Default)]
+pub struct LinkedOrderUpdates {
+    pub activated: Vec<Order>,   // Legs to be placed in the book
+    pub cancelled: Vec<OrderId>, // Sibling legs removed from the book
+    pub groups: Vec<OrderGroup>, // Group states to be persisted
+}
+
+impl LinkedOrderUpdates {
+    pub fn is_empty(&self) -> bool {
+        self.activated.is_empty() && self.cancelled.is_empty() && self.groups.is_empty()
+    }
+}
+
+/// Groups that are still pending or active
+#[derive(Debug, Default)]
+pub(crate) struct OrderGroups {
+    groups: HashMap<GroupId, OrderGroup>,
+    order_groups: HashMap<OrderId, GroupId>,
+}
+
+impl OrderGroups {
+    pub(crate) fn from_groups(groups: Vec<OrderGroup>) -> Self {
+        let mut order_groups = Self::default();
+        groups
+            .into_iter()
+            .for_each(|group| order_groups.insert(group));
+        order_groups
+    }
+
+    pub(crate) fn insert(&mut self, group: OrderGroup) {
+        group.working_orders().for_each(|order_id| {
+            self.order_groups.insert(order_id, group.group_id);
+        });
+        self.groups.insert(group.group_id, group);
+    }
+
+    pub(crate) fn remove(&mut self, group_id: GroupId) -> Option<OrderGroup> {
+        let group = self.groups.remove(&group_id)?;
+        group.working_orders().for_each(|order_id| {
+            self.order_groups.remove(&order_id);
+        });
+        Some(group)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub(crate) fn get(&self, group_id: GroupId) -> Option<&OrderGroup> {
+        self.groups.get(&group_id)
+    }
+
+    pub(crate) fn group_of(&self, order_id: OrderId) -> Option<&OrderGroup> {
+        self.order_groups
+            .get(&order_id)
+            .and_then(|group_id| self.groups.get(group_id))
+    }
+
+    pub(crate) fn triggered_by(&self, price: i64) -> Vec<GroupId> {
+        self.groups
+            .values()
+            .filter(|group| group.is_stop_triggered(price))
+            .map(|group| group.group_id)
+            .collect()
+    }
+}
@@ -9,7 +9,7 @@ use diesel_derive_enum::DbEnum;
 use rustex_errors::RustexError;
 use serde::{Deserialize, Serialize};
 
-use super::UserId;
+use super::{sessions::SessionId, trades::Trade, UserId};
 
 #[derive(
     Debug,
@@ -80,7 +80,30 @@ pub enum OrderType {
     Sell,
 }
 
-#[derive(DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+/// Lifecycle of an order as recorded in the DB
+#[derive(DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Orderstatus"]
+#[DbValueStyle = "snake_case"]
+#[serde(rename_all = "camelCase")]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected, // Could not be funded. It never reached the book
+    Expired,  // Cancelled because its session missed the heartbeat
+}
+
+impl OrderStatus {
+    /// Whether the order can no longer trade
+    pub fn is_final(&self) -> bool {
+        !matches!(self, OrderStatus::New | OrderStatus::PartiallyFilled)
+    }
+}
+
+#[derive(
+    DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
 #[ExistingTypePath = "crate::db::schema::sql_types::Exchangemarket"]
 #[DbValueStyle = "snake_case"]
 #[allow(non_camel_case_types)]
@@ -105,6 +128,12 @@ impl FromStr for ExchangeMarket {
 }
 
 impl ExchangeMarket {
+    pub const ALL: [ExchangeMarket; 3] = [
+        ExchangeMarket::BTC_USD,
+        ExchangeMarket::BTC_GBP,
+        ExchangeMarket::BTC_EUR,
+    ];
+
     pub fn from_env() -> Result<Self, RustexError> {
         std::env::var("EXCHANGE_MARKET")
             .map(|env_var| ExchangeMarket::from_str(&env_var))
@@ -124,9 +153,61 @@ pub struct Order {
     pub user_id: UserId,
     pub price: i64,
     pub quantity: f64,
+    #[diesel(deserialize_as = DateTime<Utc>)]
     pub created_at: Option<DateTime<Utc>>, // Diesel automatically handles time-zone conversions
     pub order_type: OrderType,
     pub exchange: ExchangeMarket,
+    pub notional: Option<f64>, // Quote amount to spend. Quantity is then notional / price
+    pub status: OrderStatus,
+    pub filled_quantity: f64,
+    pub average_price: Option<f64>, // Volume-weighted price of the fills
+}
+
+impl Order {
+    /// Quantity left to be traded given the trades of the order.
+    /// Orders sized by notional convert the unspent quote amount
+    /// into base quantity at the order limit price
+    pub fn remaining_quantity<'a>(&self, trades: impl IntoIterator<Item = &'a Trade>) -> f64 {
+        match self.notional {
+            Some(notional) => {
+                let spent = trades
+                    .into_iter()
+                    .map(|trade| trade.price as f64 * trade.quantity)
+                    .sum::<f64>();
+                ((notional - spent) / self.price as f64).max(0.0)
+            }
+            None => {
+                let filled = trades.into_iter().map(|trade| trade.quantity).sum::<f64>();
+                self.quantity - filled
+            }
+        }
+    }
+
+    /// Records new fills of the order. The status is only
+    /// moved to filled once the order leaves the book
+    pub fn apply_fills(&mut self, quantity: f64, quote_amount: f64) {
+        if quantity <= 0.0 {
+            return;
+        }
+        let previous_quote = self.average_price.unwrap_or(0.0) * self.filled_quantity;
+        self.filled_quantity += quantity;
+        self.average_price = Some((previous_quote + quote_amount) / self.filled_quantity);
+        if self.status == OrderStatus::New {
+            self.status = OrderStatus::PartiallyFilled;
+        }
+    }
+
+    /// Applies the trades of the order, marking it filled if it is completed
+    pub fn record_fills(&mut self, trades: &[Trade], completed_orders: &[OrderId]) {
+        for trade in trades {
+            if trade.buy_order == self.order_id || trade.sell_order == self.order_id {
+                self.apply_fills(trade.quantity, trade.price as f64 * trade.quantity);
+            }
+        }
+        if completed_orders.contains(&self.order_id) {
+            self.status = OrderStatus::Filled;
+        }
+    }
 }
 
 impl Eq for Order {}
@@ -205,7 +286,116 @@ impl Ord for SellOrder {
 #[serde(rename_all = "camelCase")]
 pub struct ClientOrder {
     pub price: i64,
+    #[serde(default)]
     pub quantity: f64,
     pub exchange: ExchangeMarket,
     pub order_type: OrderType,
+    #[serde(default)]
+    pub session_id: Option<SessionId>, // Cancel the order if the session disconnects
+    #[serde(default)]
+    pub notional: Option<f64>, // Buy orders only. Sized in quote currency instead of quantity
+}
+
+/// Order placed by the exchange on behalf of its user, such as a linked leg
+impl From<&Order> for ClientOrder {
+    fn from(order: &Order) -> Self {
+        Self {
+            price: order.price,
+            quantity: order.quantity,
+            exchange: order.exchange,
+            order_type: order.order_type,
+            session_id: None,
+            notional: None,
+        }
+    }
+}
+
+/// State of an order as returned to its owner
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderDetails {
+    pub order_id: OrderId,
+    pub exchange: ExchangeMarket,
+    pub order_type: OrderType,
+    pub price: i64,
+    pub quantity: f64,
+    pub notional: Option<f64>,
+    pub status: OrderStatus,
+    pub filled_quantity: f64,
+    pub remaining_quantity: f64, // Zero once the order can no longer trade
+    pub average_price: Option<f64>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl From<Order> for OrderDetails {
+    fn from(order: Order) -> Self {
+        let remaining_quantity = match (order.status.is_final(), order.notional) {
+            (true, _) => 0.0,
+            (false, Some(notional)) => {
+                let spent = order.average_price.unwrap_or(0.0) * order.filled_quantity;
+                ((notional - spent) / order.price as f64).max(0.0)
+            }
+            (false, None) => (order.quantity - order.filled_quantity).max(0.0),
+        };
+        Self {
+            order_id: order.order_id,
+            exchange: order.exchange,
+            order_type: order.order_type,
+            price: order.price,
+            quantity: order.quantity,
+            notional: order.notional,
+            status: order.status,
+            filled_quantity: order.filled_quantity,
+            remaining_quantity,
+            average_price: order.average_price,
+            created_at: order.created_at,
+        }
+    }
+}
+
+/// Outcome of matching a new order against the book
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderExecution {
+    pub order_id: OrderId,
+    pub filled_quantity: f64, // Base quantity
+    pub quote_amount: f64,    // Quote amount spent (buy) or received (sell)
+}
+
+impl OrderExecution {
+    pub fn from_trades(order_id: OrderId, trades: &[Trade]) -> Self {
+        let (filled_quantity, quote_amount) =
+            trades.iter().fold((0.0, 0.0), |(filled, quote), trade| {
+                (
+                    filled + trade.quantity,
+                    quote + trade.price as f64 * trade.quantity,
+                )
+            });
+        Self {
+            order_id,
+            filled_quantity,
+            quote_amount,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn test_order_details_from_fills() {
+        let mut order = fixtures::order(0).quantity(3.0).build();
+        order.apply_fills(1.0, 90.0);
+        order.apply_fills(1.0, 100.0);
+        let details = OrderDetails::from(order);
+        assert_eq!(details.status, OrderStatus::PartiallyFilled);
+        assert_eq!(details.filled_quantity, 2.0);
+        assert_eq!(details.remaining_quantity, 1.0);
+        assert_eq!(details.average_price, Some(95.0));
+
+        order.status = OrderStatus::Cancelled;
+        assert_eq!(OrderDetails::from(order).remaining_quantity, 0.0);
+    }
 }
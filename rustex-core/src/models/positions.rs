@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    orders::{ExchangeMarket, OrderId, OrderType},
+    trades::{Trade, TradeId},
+};
+use crate::currencies::Currencies;
+
+/// Execution of one of the orders of a user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Fill {
+    pub trade_id: TradeId,
+    pub side: OrderType,
+    pub price: i64,
+    pub quantity: f64,
+}
+
+impl Fill {
+    /// Fills of the user orders in the trades.
+    /// A self-trade yields both a buy and a sell fill
+    pub fn from_trades(trades: &[Trade], user_orders: &[(OrderId, OrderType)]) -> Vec<Fill> {
+        trades
+            .iter()
+            .flat_map(|trade| {
+                user_orders
+                    .iter()
+                    .filter(|(id, _)| *id == trade.buy_order || *id == trade.sell_order)
+                    .map(|(_, side)| Fill {
+                        trade_id: trade.trade_id,
+                        side: *side,
+                        price: trade.price,
+                        quantity: trade.quantity,
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Net base position of a user in a market. PnL is in the quote currency
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Position {
+    pub exchange: ExchangeMarket,
+    pub net_quantity: f64, // Positive when long, negative when short
+    pub average_entry_price: Option<f64>,
+    pub realised_pnl: f64,
+    pub unrealised_pnl: f64,
+    pub mark_price: Option<f64>, // Mid of the book. Unrealised PnL is zero without it
+}
+
+/// PnL of every market quoted in the same currency
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PnlTotal {
+    pub currency: Currencies,
+    pub realised_pnl: f64,
+    pub unrealised_pnl: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionsReport {
+    pub positions: Vec<Position>,
+    pub totals: Vec<PnlTotal>,
+}
+
+impl Position {
+    /// Replays the fills in trade order using the average cost method
+    pub fn from_fills(
+        exchange: ExchangeMarket,
+        mut fills: Vec<Fill>,
+        mark_price: Option<f64>,
+    ) -> Self {
+        fills.sort_by_key(|fill| fill.trade_id);
+        let (mut net_quantity, mut average_price, mut realised_pnl) = (0.0_f64, 0.0_f64, 0.0_f64);
+        for fill in fills {
+            let signed_quantity = match fill.side {
+                OrderType::Buy => fill.quantity,
+                OrderType::Sell => -fill.quantity,
+            };
+            let price = fill.price as f64;
+            if net_quantity == 0.0 || net_quantity.signum() == signed_quantity.signum() {
+                // Opening or increasing the position
+                let quantity = net_quantity.abs() + fill.quantity;
+                average_price =
+                    (average_price * net_quantity.abs() + price * fill.quantity) / quantity;
+                net_quantity += signed_quantity;
+                continue;
+            }
+            // Reducing, closing or flipping the position
+            let closed = net_quantity.abs().min(fill.quantity);
+            realised_pnl += (price - average_price) * closed * net_quantity.signum();
+            net_quantity += signed_quantity;
+            if fill.quantity > closed {
+                average_price = price;
+            } else if net_quantity.abs() < f64::EPSILON {
+                net_quantity = 0.0;
+                average_price = 0.0;
+            }
+        }
+
+        let unrealised_pnl = mark_price
+            .map(|mark| (mark - average_price) * net_quantity)
+            .unwrap_or(0.0);
+        Self {
+            exchange,
+            net_quantity,
+            average_entry_price: Some(average_price).filter(|_| net_quantity != 0.0),
+            realised_pnl,
+            unrealised_pnl,
+            mark_price,
+        }
+    }
+}
+
+impl PositionsReport {
+    /// Aggregates the PnL of the positions by quote currency
+    pub fn new(positions: Vec<Position>) -> Self {
+        let mut totals: Vec<PnlTotal> = vec![];
+        for position in &positions {
+            let (_, currency) = position.exchange.currencies();
+            match totals.iter_mut().find(|total| total.currency == currency) {
+                Some(total) => {
+                    total.realised_pnl += position.realised_pnl;
+                    total.unrealised_pnl += position.unrealised_pnl;
+                }
+                None => totals.push(PnlTotal {
+                    currency,
+                    realised_pnl: position.realised_pnl,
+                    unrealised_pnl: position.unrealised_pnl,
+                }),
+            }
+        }
+        Self { positions, totals }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_average_cost_pnl() {
+        let fill = |trade_id: i64, side, price, quantity| Fill {
+            trade_id: trade_id.into(),
+            side,
+            price,
+            quantity,
+        };
+        let fills = vec![
+            fill(0, OrderType::Buy, 100, 1.0),
+            fill(1, OrderType::Buy, 200, 1.0),
+            fill(2, OrderType::Sell, 250, 1.5), // Realises (250 - 150) * 1.5
+            fill(3, OrderType::Sell, 100, 1.0), // Realises (100 - 150) * 0.5 and opens a 0.5 short at 100
+        ];
+        let position = Position::from_fills(ExchangeMarket::BTC_USD, fills, Some(90.0));
+        assert_eq!(position.net_quantity, -0.5);
+        assert_eq!(position.average_entry_price, Some(100.0));
+        assert_eq!(position.realised_pnl, 150.0 - 25.0);
+        assert_eq!(position.unrealised_pnl, 5.0);
+    }
+}
@@ -0,0 +1,123 @@
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+use super::orders::{ExchangeMarket, Order, OrderId};
+
+pub(crate) const QUANTITY_TOLERANCE: f64 = 1e-9;
+
+/// Difference between the order book and the database
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Discrepancy {
+    /// Resting in the book but not pending in the DB
+    MissingInDb { order_id: OrderId },
+    /// Pending in the DB but not resting in the book
+    MissingInBook { order_id: OrderId, remaining: f64 },
+    /// Resting in both with different remaining quantities
+    QuantityMismatch {
+        order_id: OrderId,
+        book: f64,
+        db: f64,
+    },
+    /// Pending in the DB although its trades fill it completely
+    FilledButPending { order_id: OrderId },
+}
+
+impl Discrepancy {
+    pub fn order_id(&self) -> OrderId {
+        match *self {
+            Discrepancy::MissingInDb { order_id }
+            | Discrepancy::MissingInBook { order_id, .. }
+            | Discrepancy::QuantityMismatch { order_id, .. }
+            | Discrepancy::FilledButPending { order_id } => order_id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconciliationReport {
+    pub exchange: ExchangeMarket,
+    pub book_orders: usize,
+    pub db_orders: usize,
+    pub discrepancies: Vec<Discrepancy>,
+    pub repaired: bool,
+}
+
+/// Compares the orders resting in the book with the pending orders of the DB.
+/// Both must carry their remaining quantity
+pub fn find_discrepancies(book_orders: &[Order], db_orders: &[Order]) -> Vec<Discrepancy> {
+    let book: HashMap<OrderId, f64> = book_orders
+        .iter()
+        .map(|order| (order.order_id, order.quantity))
+        .collect();
+    let db: HashMap<OrderId, f64> = db_orders
+        .iter()
+        .map(|order| (order.order_id, order.quantity))
+        .collect();
+
+    let mut discrepancies = vec![];
+    for order in db_orders {
+        let remaining = order.quantity;
+        match book.get(&order.order_id) {
+            _ if remaining <= QUANTITY_TOLERANCE => {
+                discrepancies.push(Discrepancy::FilledButPending {
+                    order_id: order.order_id,
+                });
+            }
+            None => discrepancies.push(Discrepancy::MissingInBook {
+                order_id: order.order_id,
+                remaining,
+            }),
+            Some(&quantity) if (quantity - remaining).abs() > QUANTITY_TOLERANCE => discrepancies
+                .push(Discrepancy::QuantityMismatch {
+                    order_id: order.order_id,
+                    book: quantity,
+                    db: remaining,
+                }),
+            Some(_) => (),
+        }
+    }
+    for order in book_orders {
+        if !db.contains_key(&order.order_id) {
+            discrepancies.push(Discrepancy::MissingInDb {
+                order_id: order.order_id,
+            });
+        }
+    }
+    discrepancies.sort_by_key(|discrepancy| discrepancy.order_id());
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn test_find_discrepancies() {
+        let order = |order_id, quantity| fixtures::order(order_id).quantity(quantity).build();
+        let book = [order(0, 1.0), order(1, 2.0), order(2, 1.0), order(4, 1.0)];
+        let db = [order(0, 1.0), order(1, 1.5), order(3, 3.0), order(4, 0.0)];
+        assert_eq!(
+            find_discrepancies(&book, &db),
+            vec![
+                Discrepancy::QuantityMismatch {
+                    order_id: 1.into(),
+                    book: 2.0,
+                    db: 1.5
+                },
+                Discrepancy::MissingInDb { order_id: 2.into() },
+                Discrepancy::MissingInBook {
+                    order_id: 3.into(),
+                    remaining: 3.0
+                },
+                Discrepancy::FilledButPending { order_id: 4.into() },
+            ]
+        );
+    }
+}
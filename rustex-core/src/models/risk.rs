@@ -0,0 +1,225 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use diesel::prelude::*;
+use hashbrown::HashMap;
+use rustex_errors::{RiskRejection, RustexError};
+use serde::{Deserialize, Serialize};
+
+use super::{order_book::OrderBook, orders::ClientOrder, UserId};
+use crate::lock;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Pre-trade limits of a user. `None` disables the check
+#[derive(
+    Queryable,
+    Selectable,
+    Insertable,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Default,
+)]
+#[diesel(table_name = crate::db::schema::risk_limits)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[serde(rename_all = "camelCase")]
+pub struct RiskLimits {
+    #[serde(default)]
+    pub user_id: UserId,
+    pub max_order_quantity: Option<f64>,
+    pub max_notional: Option<f64>, // Quote currency
+    pub max_open_orders: Option<i32>,
+    pub max_orders_per_second: Option<i32>,
+    pub max_price_deviation: Option<f64>, // Fraction of the last traded price (fat-finger check)
+}
+
+#[derive(Debug, Default)]
+struct RiskState {
+    limits: HashMap<UserId, RiskLimits>,
+    recent_orders: HashMap<UserId, VecDeque<Instant>>, // Accepted orders within the rate window
+}
+
+/// Checks new orders against the limits of their user before they reach the book
+#[derive(Debug, Default)]
+pub struct RiskEngine {
+    inner: Mutex<RiskState>,
+}
+
+impl RiskEngine {
+    pub fn new(limits: Vec<RiskLimits>) -> Self {
+        let limits = limits
+            .into_iter()
+            .map(|limits| (limits.user_id, limits))
+            .collect();
+        Self {
+            inner: Mutex::new(RiskState {
+                limits,
+                recent_orders: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Replaces the limits of the user. `None` removes every limit
+    pub fn set_limits(&self, user_id: UserId, limits: Option<RiskLimits>) {
+        let mut inner = lock!(self.inner);
+        match limits {
+            Some(limits) => inner.limits.insert(user_id, limits),
+            None => inner.limits.remove(&user_id),
+        };
+    }
+
+    /// Rejects the order if it breaches any of the limits of the user.
+    /// It only counts towards the order rate limit once recorded (see [`Self::record_order`])
+    pub fn check_order(
+        &self,
+        user_id: UserId,
+        order: &ClientOrder,
+        book: &OrderBook,
+        now: Instant,
+    ) -> Result<(), RustexError> {
+        let mut inner = lock!(self.inner);
+        let Some(limits) = inner.limits.get(&user_id).copied() else {
+            return Ok(());
+        };
+        let (quantity, notional) = match order.notional {
+            Some(notional) if order.price > 0 => (notional / order.price as f64, notional),
+            Some(notional) => (0.0, notional), // Rejected later on by the book
+            None => (order.quantity, order.quantity * order.price as f64),
+        };
+
+        if let Some(limit) = limits.max_order_quantity {
+            if quantity > limit {
+                return Err(RustexError::RiskRejection(
+                    RiskRejection::MaxOrderQuantity {
+                        limit,
+                        requested: quantity,
+                    },
+                ));
+            }
+        }
+        if let Some(limit) = limits.max_notional {
+            if notional > limit {
+                return Err(RustexError::RiskRejection(RiskRejection::MaxNotional {
+                    limit,
+                    requested: notional,
+                }));
+            }
+        }
+        if let (Some(limit), Some(reference_price)) =
+            (limits.max_price_deviation, book.last_price())
+        {
+            let deviation = (order.price - reference_price).abs() as f64 / reference_price as f64;
+            if deviation > limit {
+                return Err(RustexError::RiskRejection(RiskRejection::PriceDeviation {
+                    limit,
+                    reference_price,
+                    price: order.price,
+                }));
+            }
+        }
+        if let Some(limit) = limits.max_open_orders {
+            if book.count_open_orders(user_id) >= limit.max(0) as usize {
+                return Err(RustexError::RiskRejection(RiskRejection::MaxOpenOrders {
+                    limit,
+                }));
+            }
+        }
+
+        let recent_orders = inner.recent_orders.entry(user_id).or_default();
+        while recent_orders
+            .front()
+            .is_some_and(|&t| now.duration_since(t) >= RATE_WINDOW)
+        {
+            recent_orders.pop_front();
+        }
+        if let Some(limit) = limits.max_orders_per_second {
+            if recent_orders.len() >= limit.max(0) as usize {
+                return Err(RustexError::RiskRejection(
+                    RiskRejection::MaxOrdersPerSecond { limit },
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts an accepted order towards the order rate limit of the user
+    pub fn record_order(&self, user_id: UserId, now: Instant) {
+        let mut inner = lock!(self.inner);
+        if inner.limits.contains_key(&user_id) {
+            inner
+                .recent_orders
+                .entry(user_id)
+                .or_default()
+                .push_back(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::orders::{ExchangeMarket, OrderType};
+
+    #[test]
+    fn test_risk_limits_reject_with_reason() {
+        let book = OrderBook::new(ExchangeMarket::BTC_USD);
+        let user: UserId = 1.into();
+        let engine = RiskEngine::new(vec![RiskLimits {
+            user_id: user,
+            max_order_quantity: Some(10.0),
+            max_notional: Some(500.0),
+            max_orders_per_second: Some(2),
+            ..Default::default()
+        }]);
+        let order = |price, quantity| ClientOrder {
+            price,
+            quantity,
+            exchange: ExchangeMarket::BTC_USD,
+            order_type: OrderType::Buy,
+            session_id: None,
+            notional: None,
+        };
+        let now = Instant::now();
+
+        assert!(matches!(
+            engine.check_order(user, &order(1, 11.0), &book, now),
+            Err(RustexError::RiskRejection(
+                RiskRejection::MaxOrderQuantity { .. }
+            ))
+        ));
+        assert!(matches!(
+            engine.check_order(user, &order(100, 6.0), &book, now),
+            Err(RustexError::RiskRejection(
+                RiskRejection::MaxNotional { .. }
+            ))
+        ));
+        // Orders only count towards the rate once accepted
+        for _ in 0..3 {
+            assert!(engine
+                .check_order(user, &order(10, 1.0), &book, now)
+                .is_ok());
+        }
+        engine.record_order(user, now);
+        engine.record_order(user, now);
+        assert!(matches!(
+            engine.check_order(user, &order(10, 1.0), &book, now),
+            Err(RustexError::RiskRejection(
+                RiskRejection::MaxOrdersPerSecond { limit: 2 }
+            ))
+        ));
+        assert!(engine
+            .check_order(user, &order(10, 1.0), &book, now + RATE_WINDOW)
+            .is_ok());
+        // Users without limits are not checked
+        assert!(engine
+            .check_order(2.into(), &order(1_000, 1_000.0), &book, now)
+            .is_ok());
+    }
+}
@@ -0,0 +1,90 @@
+//! Order and trade ids are handed out from blocks reserved in the database
+//! beforehand, so they are never reused after a crash
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum IdKind {
+    Order,
+    Trade,
+}
+
+/// Reserved ids, from `start` (inclusive) to `end` (exclusive)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdBlock {
+    pub start: i64,
+    pub end: i64,
+}
+
+/// Hands out the ids of the reserved blocks in order, and none once they run out
+#[derive(Debug, Default)]
+pub struct IdSequence {
+    next: i64,
+    blocks: VecDeque<IdBlock>, // Reserved and not exhausted yet
+}
+
+impl IdSequence {
+    pub fn new(block: IdBlock) -> Self {
+        Self {
+            next: block.start,
+            blocks: VecDeque::from([block]),
+        }
+    }
+
+    /// Next reserved id, or `None` when a new block has to be reserved first
+    pub fn next_id(&mut self) -> Option<i64> {
+        while let Some(block) = self.blocks.front() {
+            if self.next < block.end {
+                self.next = self.next.max(block.start) + 1;
+                return Some(self.next - 1);
+            }
+            self.blocks.pop_front();
+        }
+        None
+    }
+
+    /// Ids left in the reserved blocks
+    pub fn remaining(&self) -> i64 {
+        self.blocks
+            .iter()
+            .map(|block| (block.end - block.start.max(self.next)).max(0))
+            .sum()
+    }
+
+    /// Lowest id the next block can start at. Every id below was handed out or reserved
+    pub fn reservation_start(&self) -> i64 {
+        self.blocks
+            .back()
+            .map_or(self.next, |block| block.end.max(self.next))
+    }
+
+    pub fn extend(&mut self, block: IdBlock) {
+        self.blocks.push_back(block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_skip_the_gaps_between_blocks() {
+        let mut ids = IdSequence::new(IdBlock { start: 10, end: 12 });
+        ids.extend(IdBlock { start: 20, end: 22 });
+        assert_eq!(ids.remaining(), 4);
+        assert_eq!(ids.reservation_start(), 22);
+
+        let handed_out = (0..5).map(|_| ids.next_id()).collect::<Vec<_>>();
+        assert_eq!(
+            handed_out,
+            vec![Some(10), Some(11), Some(20), Some(21), None]
+        );
+        assert_eq!(ids.remaining(), 0);
+        assert_eq!(ids.reservation_start(), 22);
+
+        ids.extend(IdBlock { start: 22, end: 23 });
+        assert_eq!(ids.next_id(), Some(22));
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use hashbrown::{HashMap, HashSet};
+use rustex_errors::RustexError;
+use serde::{Deserialize, Serialize};
+
+use super::{orders::OrderId, UserId};
+use crate::lock;
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Default, Clone, Copy, Hash)]
+pub struct SessionId(i64);
+
+impl From<i64> for SessionId {
+    fn from(value: i64) -> Self {
+        SessionId(value)
+    }
+}
+
+impl From<SessionId> for i64 {
+    fn from(value: SessionId) -> Self {
+        value.0
+    }
+}
+
+#[derive(Debug)]
+struct TradingSession {
+    user_id: UserId,
+    timeout: Duration,
+    last_heartbeat: Instant,
+    orders: HashSet<OrderId>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    sessions: HashMap<SessionId, TradingSession>,
+    order_sessions: HashMap<OrderId, SessionId>, // Reverse index to release completed orders
+}
+
+impl Sessions {
+    fn remove(&mut self, session_id: &SessionId) -> Vec<OrderId> {
+        let Some(session) = self.sessions.remove(session_id) else {
+            return vec![];
+        };
+        session.orders.iter().for_each(|order_id| {
+            self.order_sessions.remove(order_id);
+        });
+        session.orders.into_iter().collect()
+    }
+}
+
+/// Heartbeat-tracked sessions that orders can be bound to
+///
+/// Orders bound to a session are cancelled once the session
+/// misses its heartbeat timeout (cancel-on-disconnect)
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    inner: Mutex<Sessions>,
+    session_counter: AtomicI64,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&self, user_id: UserId, timeout: Duration) -> SessionId {
+        let session_id = self.session_counter.fetch_add(1, Ordering::Relaxed).into();
+        lock!(self.inner).sessions.insert(
+            session_id,
+            TradingSession {
+                user_id,
+                timeout,
+                last_heartbeat: Instant::now(),
+                orders: HashSet::new(),
+            },
+        );
+        session_id
+    }
+
+    /// Refreshes the session timeout
+    pub fn heartbeat(&self, user_id: UserId, session_id: SessionId) -> Result<(), RustexError> {
+        let mut inner = lock!(self.inner);
+        let session = Self::get_user_session(&mut inner, user_id, session_id)?;
+        session.last_heartbeat = Instant::now();
+        Ok(())
+    }
+
+    /// Checks that the session exists and belongs to the user
+    pub fn validate(&self, user_id: UserId, session_id: SessionId) -> Result<(), RustexError> {
+        let mut inner = lock!(self.inner);
+        Self::get_user_session(&mut inner, user_id, session_id).map(|_| ())
+    }
+
+    /// Tags an order with the session so that it is cancelled on disconnect
+    pub fn bind_order(
+        &self,
+        user_id: UserId,
+        session_id: SessionId,
+        order_id: OrderId,
+    ) -> Result<(), RustexError> {
+        let mut inner = lock!(self.inner);
+        let session = Self::get_user_session(&mut inner, user_id, session_id)?;
+        session.orders.insert(order_id);
+        inner.order_sessions.insert(order_id, session_id);
+        Ok(())
+    }
+
+    /// Whether the order is still bound to a live session
+    pub fn is_bound(&self, order_id: OrderId) -> bool {
+        lock!(self.inner).order_sessions.contains_key(&order_id)
+    }
+
+    /// Forgets orders that are no longer resting in the book
+    pub fn release_orders(&self, order_ids: &[OrderId]) {
+        let mut inner = lock!(self.inner);
+        for order_id in order_ids {
+            if let Some(session_id) = inner.order_sessions.remove(order_id) {
+                if let Some(session) = inner.sessions.get_mut(&session_id) {
+                    session.orders.remove(order_id);
+                }
+            }
+        }
+    }
+
+    /// Closes the session returning the orders that were bound to it
+    pub fn close(
+        &self,
+        user_id: UserId,
+        session_id: SessionId,
+    ) -> Result<Vec<OrderId>, RustexError> {
+        let mut inner = lock!(self.inner);
+        Self::get_user_session(&mut inner, user_id, session_id)?;
+        Ok(inner.remove(&session_id))
+    }
+
+    /// Removes every session whose heartbeat timed out before `now`
+    /// returning the orders that were bound to each of them
+    pub fn expire(&self, now: Instant) -> Vec<(SessionId, Vec<OrderId>)> {
+        let mut inner = lock!(self.inner);
+        let expired = inner
+            .sessions
+            .iter()
+            .filter(|(_, session)| now.duration_since(session.last_heartbeat) > session.timeout)
+            .map(|(&session_id, _)| session_id)
+            .collect::<Vec<_>>();
+        expired
+            .into_iter()
+            .map(|session_id| (session_id, inner.remove(&session_id)))
+            .collect()
+    }
+
+    fn get_user_session(
+        inner: &mut Sessions,
+        user_id: UserId,
+        session_id: SessionId,
+    ) -> Result<&mut TradingSession, RustexError> {
+        match inner.sessions.get_mut(&session_id) {
+            Some(session) if session.user_id == user_id => Ok(session),
+            Some(_) => Err(RustexError::AuthorizationError(
+                "You are not authorized to use this session".into(),
+            )),
+            None => Err(RustexError::UserFacingError(
+                "Requested session does not exist or has expired".into(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_sessions_return_bound_orders() {
+        let registry = SessionRegistry::new();
+        let user: UserId = 7.into();
+        let stale = registry.open(user, Duration::from_millis(10));
+        let alive = registry.open(user, Duration::from_secs(3600));
+
+        registry.bind_order(user, stale, 1.into()).unwrap();
+        registry.bind_order(user, stale, 2.into()).unwrap();
+        registry.bind_order(user, alive, 3.into()).unwrap();
+        registry.release_orders(&[2.into()]);
+        assert!(registry.is_bound(1.into()) && !registry.is_bound(2.into()));
+        assert!(registry.bind_order(8.into(), alive, 4.into()).is_err());
+
+        let expired = registry.expire(Instant::now() + Duration::from_secs(1));
+        assert_eq!(expired, vec![(stale, vec![1.into()])]);
+        assert!(!registry.is_bound(1.into()));
+        assert!(registry.heartbeat(user, stale).is_err());
+        assert!(registry.heartbeat(user, alive).is_ok());
+        assert_eq!(registry.close(user, alive).unwrap(), vec![3.into()]);
+    }
+}
@@ -0,0 +1,150 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use diesel::Queryable;
+use rustex_errors::RustexError;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    history::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+    orders::OrderType,
+    trades::{Trade, TradeId},
+};
+use crate::lock;
+
+/// Public view of a trade
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TapeTrade {
+    pub trade_id: TradeId,
+    pub price: i64,
+    pub quantity: f64,
+    pub aggressor: OrderType,
+    #[diesel(deserialize_as = DateTime<Utc>)]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl From<&Trade> for TapeTrade {
+    fn from(trade: &Trade) -> Self {
+        Self {
+            trade_id: trade.trade_id,
+            price: trade.price,
+            quantity: trade.quantity,
+            aggressor: trade.aggressor,
+            created_at: trade.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TapeQuery {
+    pub cursor: Option<i64>, // Only trades older than this trade id
+    pub limit: Option<usize>,
+}
+
+impl TapeQuery {
+    pub fn page_size(&self) -> Result<usize, RustexError> {
+        match self.limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+            limit @ 1..=MAX_PAGE_SIZE => Ok(limit),
+            _ => Err(RustexError::UserFacingError(format!(
+                "The page limit must be between 1 and {MAX_PAGE_SIZE}"
+            ))),
+        }
+    }
+}
+
+/// Ring buffer with the most recent trades of a market
+#[derive(Debug)]
+pub struct TradeTape {
+    inner: Mutex<TapeState>,
+}
+
+#[derive(Debug)]
+struct TapeState {
+    trades: VecDeque<TapeTrade>, // Oldest to newest
+    capacity: usize,
+    complete: bool, // Holds every trade of the market
+}
+
+impl TradeTape {
+    /// `complete` tells whether the history holds every trade of the market
+    pub fn new(capacity: usize, history: Vec<TapeTrade>, complete: bool) -> Self {
+        let tape = Self {
+            inner: Mutex::new(TapeState {
+                trades: VecDeque::with_capacity(capacity),
+                capacity,
+                complete,
+            }),
+        };
+        tape.push(history);
+        tape
+    }
+
+    pub fn push(&self, trades: impl IntoIterator<Item = TapeTrade>) {
+        let mut inner = lock!(self.inner);
+        for trade in trades {
+            // Batches of concurrent orders can be pushed out of order
+            let position = inner
+                .trades
+                .partition_point(|t| t.trade_id < trade.trade_id);
+            if inner
+                .trades
+                .get(position)
+                .is_some_and(|t| t.trade_id == trade.trade_id)
+            {
+                continue;
+            }
+            inner.trades.insert(position, trade);
+            if inner.trades.len() > inner.capacity {
+                inner.trades.pop_front();
+                inner.complete = false;
+            }
+        }
+    }
+
+    /// Up to `limit` trades older than `before`, newest first.
+    /// None if some of them may no longer be in memory
+    pub fn recent(&self, before: Option<TradeId>, limit: usize) -> Option<Vec<TapeTrade>> {
+        let inner = lock!(self.inner);
+        let trades = inner
+            .trades
+            .iter()
+            .rev()
+            .filter(|trade| before.is_none_or(|before| trade.trade_id < before))
+            .take(limit)
+            .copied()
+            .collect::<Vec<_>>();
+        (trades.len() == limit || inner.complete).then_some(trades)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tape_falls_back_once_trades_are_evicted() {
+        let trade = |trade_id: i64| TapeTrade {
+            trade_id: trade_id.into(),
+            price: 100,
+            quantity: 1.0,
+            aggressor: OrderType::Buy,
+            created_at: None,
+        };
+        let tape = TradeTape::new(3, vec![trade(0), trade(1)], true);
+        assert_eq!(tape.recent(None, 5).unwrap().len(), 2);
+
+        tape.push([trade(3), trade(2)]);
+        let ids = |trades: Vec<TapeTrade>| {
+            trades
+                .into_iter()
+                .map(|t| i64::from(t.trade_id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(tape.recent(None, 2).unwrap()), vec![3, 2]);
+        assert_eq!(ids(tape.recent(Some(3.into()), 2).unwrap()), vec![2, 1]);
+        // Trade 0 was evicted
+        assert_eq!(tape.recent(Some(2.into()), 2), None);
+    }
+}
@@ -2,7 +2,7 @@ use chrono::{DateTime, Utc};
 use diesel::{prelude::*, sql_types::BigInt, AsExpression, FromSqlRow};
 use serde::{Deserialize, Serialize};
 
-use super::orders::{ExchangeMarket, OrderId};
+use super::orders::{ExchangeMarket, OrderId, OrderType};
 
 #[derive(
     Debug,
@@ -64,7 +64,7 @@ where
     }
 }
 
-#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(table_name = crate::db::schema::trades)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Trade {
@@ -74,5 +74,7 @@ pub struct Trade {
     pub sell_order: OrderId,
     pub price: i64,
     pub quantity: f64,
+    #[diesel(deserialize_as = DateTime<Utc>)]
     pub created_at: Option<DateTime<Utc>>, // Diesel automatically handles time-zone conversions
+    pub aggressor: OrderType, // Side of the incoming order that took liquidity
 }
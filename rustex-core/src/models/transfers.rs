@@ -0,0 +1,158 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel_derive_enum::DbEnum;
+use rustex_errors::RustexError;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    ledger::{LedgerAccount, NewLedgerEntry},
+    orders::ExchangeMarket,
+    UserId,
+};
+use crate::currencies::Currencies;
+
+#[derive(DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Transferkind"]
+#[DbValueStyle = "snake_case"]
+#[serde(rename_all = "camelCase")]
+pub enum TransferKind {
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(DbEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[ExistingTypePath = "crate::db::schema::sql_types::Transferstatus"]
+#[DbValueStyle = "snake_case"]
+#[serde(rename_all = "camelCase")]
+pub enum TransferStatus {
+    Requested,  // Waiting for the funds to arrive (deposit) or for approval (withdrawal)
+    Confirming, // Deposit being confirmed by the custodian
+    Approved,   // Handed over to the custodian
+    Completed,  // Funds moved and booked in the ledger
+    Failed,     // Rejected or refused by the custodian. Withdrawn funds are released
+}
+
+/// Deposit or withdrawal of funds through the custodian
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::db::schema::transfers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[serde(rename_all = "camelCase")]
+pub struct Transfer {
+    pub transfer_id: i64,
+    pub user_id: UserId,
+    pub kind: TransferKind,
+    pub currency: Currencies,
+    pub amount: f64,
+    pub status: TransferStatus,
+    pub external_reference: Option<String>,
+    pub created_at: Option<DateTime<Utc>>, // Diesel automatically handles time-zone conversions
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[diesel(table_name = crate::db::schema::transfers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewTransfer {
+    pub user_id: UserId,
+    pub kind: TransferKind,
+    pub currency: Currencies,
+    pub amount: f64,
+}
+
+/// Deposit or withdrawal requested by the user
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientTransfer {
+    pub currency: Currencies,
+    pub amount: f64,
+    #[serde(default)]
+    pub market: Option<ExchangeMarket>, // Database holding the funds. The default one if unset
+}
+
+impl ClientTransfer {
+    pub fn into_transfer(
+        self,
+        user_id: UserId,
+        kind: TransferKind,
+    ) -> Result<NewTransfer, RustexError> {
+        if !self.amount.is_finite() || self.amount <= 0.0 {
+            return Err(RustexError::UserFacingError(
+                "The amount to transfer must be positive".into(),
+            ));
+        }
+        Ok(NewTransfer {
+            user_id,
+            kind,
+            currency: self.currency,
+            amount: self.amount,
+        })
+    }
+}
+
+impl Transfer {
+    /// Reference of the ledger entries booked for this transfer
+    pub fn reference(&self) -> String {
+        format!("transfer:{}", self.transfer_id)
+    }
+
+    /// Handed over to the custodian, which has not reported the outcome yet
+    pub fn is_unfinished(&self) -> bool {
+        matches!(
+            self.status,
+            TransferStatus::Confirming | TransferStatus::Approved
+        )
+    }
+
+    /// Withdrawals lock the funds until the custodian sends them
+    pub fn request_entries(&self) -> Vec<NewLedgerEntry> {
+        match self.kind {
+            TransferKind::Deposit => vec![],
+            TransferKind::Withdrawal => {
+                self.entries(LedgerAccount::Available, LedgerAccount::Reserved)
+            }
+        }
+    }
+
+    pub fn completion_entries(&self) -> Vec<NewLedgerEntry> {
+        match self.kind {
+            TransferKind::Deposit => {
+                self.entries(LedgerAccount::External, LedgerAccount::Available)
+            }
+            TransferKind::Withdrawal => {
+                self.entries(LedgerAccount::Reserved, LedgerAccount::External)
+            }
+        }
+    }
+
+    pub fn failure_entries(&self) -> Vec<NewLedgerEntry> {
+        match self.kind {
+            TransferKind::Deposit => vec![],
+            TransferKind::Withdrawal => {
+                self.entries(LedgerAccount::Reserved, LedgerAccount::Available)
+            }
+        }
+    }
+
+    /// Entries booked when the transfer reaches its current status
+    pub fn transition_entries(&self) -> Vec<NewLedgerEntry> {
+        match self.status {
+            TransferStatus::Completed => self.completion_entries(),
+            TransferStatus::Failed => self.failure_entries(),
+            TransferStatus::Requested | TransferStatus::Confirming | TransferStatus::Approved => {
+                vec![]
+            }
+        }
+    }
+
+    fn entries(&self, from: LedgerAccount, to: LedgerAccount) -> Vec<NewLedgerEntry> {
+        NewLedgerEntry::transfer(
+            &self.reference(),
+            self.user_id,
+            self.currency,
+            from,
+            to,
+            self.amount,
+        )
+        .into()
+    }
+}
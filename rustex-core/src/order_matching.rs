@@ -6,7 +6,7 @@ use crate::{
     lock,
     models::{
         order_book::OrderBook,
-        orders::{BuyOrder, Order, SellOrder},
+        orders::{BuyOrder, Order, OrderType, SellOrder},
         trades::Trade,
     },
     prelude::OrderId,
@@ -40,6 +40,9 @@ impl MatchOrders for BuyOrder {
             return (trades, completed_orders);
         }
 
+        // Quote amount left to spend for orders sized by notional
+        let mut remaining_notional = self.notional;
+
         {
             let mut sell_orders = lock!(book.sell_orders);
 
@@ -56,9 +59,19 @@ impl MatchOrders for BuyOrder {
                 }
 
                 // Compute trade amount and update remainders
-                let trade_quantity = sell_order.quantity.min(self.quantity);
+                let trade_quantity = match remaining_notional {
+                    Some(notional) => sell_order.quantity.min(notional / sell_order.price as f64),
+                    None => sell_order.quantity.min(self.quantity),
+                };
                 sell_order.quantity -= trade_quantity;
-                self.quantity -= trade_quantity;
+                match remaining_notional.as_mut() {
+                    Some(notional) => {
+                        // Whatever is left rests in the book at the limit price
+                        *notional -= trade_quantity * sell_order.price as f64;
+                        self.quantity = *notional / self.price as f64;
+                    }
+                    None => self.quantity -= trade_quantity,
+                }
 
                 // Record the trade
                 trades.push(book.make_trade(
@@ -66,6 +79,7 @@ impl MatchOrders for BuyOrder {
                     sell_order.order_id,
                     sell_order.price,
                     trade_quantity,
+                    OrderType::Buy,
                 ));
 
                 // If the sell order still has some quantity
@@ -76,7 +90,7 @@ impl MatchOrders for BuyOrder {
                 }
 
                 if self.quantity.abs() <= f64::EPSILON {
-                    complete_order!(sell_order.order_id, completed_orders, pending_orders);
+                    complete_order!(self.order_id, completed_orders, pending_orders);
                     return (trades, completed_orders);
                 }
             }
@@ -129,6 +143,7 @@ impl MatchOrders for SellOrder {
                     self.order_id,
                     buy_order.price,
                     trade_quantity,
+                    OrderType::Sell,
                 ));
 
                 // If the sell order still has some quantity
@@ -156,48 +171,49 @@ impl MatchOrders for SellOrder {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::orders::{ClientOrder, ExchangeMarket, OrderType};
+    use crate::fixtures;
+    use crate::models::orders::{ClientOrder, ExchangeMarket, OrderExecution, OrderType};
 
     #[test]
     fn test_successful_match() {
-        let book = OrderBook::new(ExchangeMarket::BTC_EUR);
+        let book = fixtures::order_book(ExchangeMarket::BTC_EUR);
         let sell1 = ClientOrder {
             price: 50,
             quantity: 10.0,
             exchange: ExchangeMarket::BTC_EUR,
             order_type: OrderType::Sell,
+            session_id: None,
+            notional: None,
         };
         let sell2 = ClientOrder {
             price: 45,
             quantity: 5.0,
             exchange: ExchangeMarket::BTC_EUR,
             order_type: OrderType::Sell,
+            session_id: None,
+            notional: None,
         };
         let buy1 = ClientOrder {
             price: 50,
             quantity: 8.0,
             exchange: ExchangeMarket::BTC_EUR,
             order_type: OrderType::Buy,
+            session_id: None,
+            notional: None,
         };
         let order: SellOrder = book.into_order(sell1, 123.into()).unwrap();
         assert_eq!(order.order_id, 0.into());
-        let pending = std::sync::Mutex::new(HashSet::from([order.order_id]));
-        let pending_guard = pending.lock().unwrap();
-        let (trades, _completed_orders) = order.match_order(&book, pending_guard);
+        let (trades, _completed_orders) = book.process_order(order).unwrap();
         assert!(trades.is_empty());
 
         let order: SellOrder = book.into_order(sell2, 456.into()).unwrap();
         assert_eq!(order.order_id, 1.into());
-        let pending = std::sync::Mutex::new(HashSet::from([order.order_id]));
-        let pending_guard = pending.lock().unwrap();
-        let (trades, _completed_orders) = order.match_order(&book, pending_guard);
+        let (trades, _completed_orders) = book.process_order(order).unwrap();
         assert!(trades.is_empty());
 
         let order: BuyOrder = book.into_order(buy1, 2.into()).unwrap();
         assert_eq!(order.order_id, 2.into());
-        let pending = std::sync::Mutex::new(HashSet::from([order.order_id]));
-        let pending_guard = pending.lock().unwrap();
-        let (trades, _completed_orders) = order.match_order(&book, pending_guard);
+        let (trades, _completed_orders) = book.process_order(order).unwrap();
 
         assert_eq!(
             trades,
@@ -210,6 +226,7 @@ mod tests {
                     quantity: 5.0,
                     exchange: ExchangeMarket::BTC_EUR,
                     created_at: None,
+                    aggressor: OrderType::Buy,
                 },
                 Trade {
                     trade_id: 1.into(),
@@ -219,6 +236,7 @@ mod tests {
                     quantity: 3.0,
                     exchange: ExchangeMarket::BTC_EUR,
                     created_at: None,
+                    aggressor: OrderType::Buy,
                 },
             ]
         );
@@ -229,4 +247,82 @@ mod tests {
         assert!((computed_sell_order.quantity - 7.0).abs() < f64::EPSILON);
         assert_eq!(computed_sell_order.user_id, 123.into());
     }
+
+    #[test]
+    fn test_filled_buy_order_completes_itself() {
+        let book = fixtures::order_book(ExchangeMarket::BTC_EUR);
+        let sell = ClientOrder {
+            price: 50,
+            quantity: 10.0,
+            exchange: ExchangeMarket::BTC_EUR,
+            order_type: OrderType::Sell,
+            session_id: None,
+            notional: None,
+        };
+        let buy = ClientOrder {
+            price: 50,
+            quantity: 4.0,
+            exchange: ExchangeMarket::BTC_EUR,
+            order_type: OrderType::Buy,
+            session_id: None,
+            notional: None,
+        };
+        let order: SellOrder = book.into_order(sell, 1.into()).unwrap();
+        let sell_id = order.order_id;
+        book.process_order(order).unwrap();
+
+        let order: BuyOrder = book.into_order(buy, 2.into()).unwrap();
+        let buy_id = order.order_id;
+        let (trades, completed_orders) = book.process_order(order).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(completed_orders, vec![buy_id]);
+
+        // The rest of the sell order can still be traded
+        let order: BuyOrder = book.into_order(buy, 2.into()).unwrap();
+        let (trades, _) = book.process_order(order).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].sell_order, sell_id);
+    }
+
+    #[test]
+    fn test_notional_buy_consumes_levels() {
+        let book = fixtures::order_book(ExchangeMarket::BTC_EUR);
+        for (price, quantity) in [(100, 2.0), (110, 10.0)] {
+            let sell = ClientOrder {
+                price,
+                quantity,
+                exchange: ExchangeMarket::BTC_EUR,
+                order_type: OrderType::Sell,
+                session_id: None,
+                notional: None,
+            };
+            let order: SellOrder = book.into_order(sell, 1.into()).unwrap();
+            book.process_order(order).unwrap();
+        }
+
+        let buy = ClientOrder {
+            price: 110,
+            quantity: 0.0,
+            exchange: ExchangeMarket::BTC_EUR,
+            order_type: OrderType::Buy,
+            session_id: None,
+            notional: Some(530.0),
+        };
+        let order: BuyOrder = book.into_order(buy, 2.into()).unwrap();
+        let order_id = order.order_id;
+        let (trades, completed_orders) = book.process_order(order).unwrap();
+
+        // 2.0 @ 100 = 200 spent. The remaining 330 buy 3.0 @ 110
+        assert_eq!(trades.len(), 2);
+        assert!((trades[0].quantity - 2.0).abs() < 1e-9);
+        assert!((trades[1].quantity - 3.0).abs() < 1e-9);
+        assert_eq!(completed_orders, vec![0.into(), order_id]);
+
+        let execution = OrderExecution::from_trades(order_id, &trades);
+        assert!((execution.filled_quantity - 5.0).abs() < 1e-9);
+        assert!((execution.quote_amount - 530.0).abs() < 1e-9);
+
+        let resting_sell = lock!(book.sell_orders).pop().unwrap();
+        assert!((resting_sell.quantity - 7.0).abs() < 1e-9);
+    }
 }
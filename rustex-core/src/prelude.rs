@@ -1,10 +1,27 @@
-// pub use crate::currencies::{Currencies, ExchangeMarkets};
+pub use crate::currencies::Currencies;
 pub use crate::models::{
+    audit::{AuditAction, AuditEntry, AuditEvent, AuditOrigin, AuditVerifier, ChainBreak},
+    book_snapshot::{BookSnapshot, PriceLevel, RestingOrder},
     cancellations::CancelledOrder,
+    candles::{Candle, CandleInterval, CandleQuery},
+    history::{HistoryCursor, HistoryQuery, Page, UserTrade, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+    ledger::{order_reference, Balance, LedgerAccount, LedgerEntry, NewLedgerEntry},
     order_book::OrderBook,
+    order_groups::{
+        ClientOrderGroup, GroupId, LinkedOrderUpdates, OrderGroup, OrderGroupKind, OrderGroupStatus,
+    },
     orders::{
-        BuyOrder, ClientOrder, ExchangeMarket, Order, OrderId, OrderType, PendingOrder, SellOrder,
+        BuyOrder, ClientOrder, ExchangeMarket, Order, OrderDetails, OrderExecution, OrderId,
+        OrderStatus, OrderType, PendingOrder, SellOrder,
     },
+    positions::{Fill, PnlTotal, Position, PositionsReport},
+    reconciliation::{find_discrepancies, Discrepancy, ReconciliationReport},
+    risk::{RiskEngine, RiskLimits},
+    sequences::{IdBlock, IdKind, IdSequence},
+    sessions::{SessionId, SessionRegistry},
+    trade_tape::{TapeQuery, TapeTrade, TradeTape},
     trades::{Trade, TradeId},
+    transfers::{ClientTransfer, NewTransfer, Transfer, TransferKind, TransferStatus},
+    users::User,
     UserId,
 };
@@ -9,12 +9,42 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RustexError {
     UserFacingError(String),
+    RiskRejection(RiskRejection),
     AuthorizationError(RustexInternalError),
     DbServiceError(RustexInternalError),
     MatchServiceError(RustexInternalError),
     OtherInternal(RustexInternalError),
 }
 
+/// Structured reason of an order rejected by the pre-trade risk checks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    tag = "reason",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum RiskRejection {
+    MaxOrderQuantity {
+        limit: f64,
+        requested: f64,
+    },
+    MaxNotional {
+        limit: f64,
+        requested: f64,
+    },
+    MaxOpenOrders {
+        limit: i32,
+    },
+    MaxOrdersPerSecond {
+        limit: i32,
+    },
+    PriceDeviation {
+        limit: f64,
+        reference_price: i64,
+        price: i64,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RustexInternalError {
     msg: Box<str>,
@@ -34,6 +64,9 @@ impl Display for RustexError {
             RustexError::UserFacingError(e) => {
                 write!(f, "User Error: {}", e)
             }
+            RustexError::RiskRejection(e) => {
+                write!(f, "Risk Rejection: {:?}", e)
+            }
             RustexError::AuthorizationError(_) => {
                 write!(f, "AUTH Internal Server Error")
             }
@@ -58,6 +91,7 @@ impl actix_web::ResponseError for RustexError {
             RustexError::UserFacingError(e) => {
                 HttpResponse::build(self.status_code()).body(e.to_owned())
             }
+            RustexError::RiskRejection(e) => HttpResponse::build(self.status_code()).json(e),
             RustexError::AuthorizationError(e) => {
                 log::error!("[AUTH INTERNAL SERVER ERROR]: {:?}", e);
                 HttpResponse::build(self.status_code())
@@ -81,6 +115,7 @@ impl actix_web::ResponseError for RustexError {
     fn status_code(&self) -> StatusCode {
         match self {
             RustexError::UserFacingError(_) => StatusCode::BAD_REQUEST,
+            RustexError::RiskRejection(_) => StatusCode::UNPROCESSABLE_ENTITY,
             RustexError::AuthorizationError(_) => StatusCode::UNAUTHORIZED,
             RustexError::DbServiceError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             RustexError::MatchServiceError(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -118,3 +153,6 @@ impl_from_err!(
     (jsonwebtoken::errors::Error, AuthorizationError),
     (anyhow::Error, OtherInternal)
 );
+
+#[cfg(feature = "sqlite")]
+impl_from_err!((rusqlite::Error, DbServiceError));
@@ -0,0 +1,150 @@
+//! Group commit of the orders inserted by concurrent RPCs. Orders queued while
+//! a batch is being written are recorded together in the next one
+
+use std::{sync::Arc, time::Duration};
+
+use rustex_core::prelude::*;
+use rustex_errors::RustexError;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::storage::Storage;
+
+const DEFAULT_MAX_BATCH_SIZE: usize = 256;
+
+type PendingOrder = (
+    (Order, AuditOrigin),
+    oneshot::Sender<Result<(), RustexError>>,
+);
+
+/// Bounds of a batch: its size and how long its first order waits for others
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    pub max_size: usize,
+    pub window: Duration,
+}
+
+impl Default for BatchConfig {
+    /// No waiting. Batches hold whatever was queued during the previous write
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_BATCH_SIZE,
+            window: Duration::ZERO,
+        }
+    }
+}
+
+impl BatchConfig {
+    /// Overridden by `DB_BATCH_MAX_SIZE` and `DB_BATCH_WINDOW_US`
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|n: &String| !n.is_empty());
+        let default = Self::default();
+        Self {
+            max_size: var("DB_BATCH_MAX_SIZE")
+                .map(|n| n.parse().unwrap())
+                .unwrap_or(default.max_size)
+                .max(1),
+            window: var("DB_BATCH_WINDOW_US")
+                .map(|n| Duration::from_micros(n.parse().unwrap()))
+                .unwrap_or(default.window),
+        }
+    }
+}
+
+/// Queue of the orders to insert in a storage. Clone only increases reference counting
+#[derive(Clone)]
+pub struct OrderBatcher {
+    queue: mpsc::UnboundedSender<PendingOrder>,
+}
+
+impl OrderBatcher {
+    /// Writes the batches in the background, one after another
+    pub fn spawn(storage: Arc<dyn Storage>, config: BatchConfig) -> Self {
+        let (queue, pending) = mpsc::unbounded_channel();
+        tokio::spawn(write_batches(storage, pending, config));
+        Self { queue }
+    }
+
+    /// Returns once the batch holding the order is written
+    pub async fn insert_order(&self, order: Order, origin: AuditOrigin) -> Result<(), RustexError> {
+        let stopped = || RustexError::DbServiceError("The order batcher stopped".into());
+        let (reply, result) = oneshot::channel();
+        self.queue
+            .send(((order, origin), reply))
+            .map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+async fn write_batches(
+    storage: Arc<dyn Storage>,
+    mut pending: mpsc::UnboundedReceiver<PendingOrder>,
+    config: BatchConfig,
+) {
+    while let Some(first) = pending.recv().await {
+        let deadline = tokio::time::Instant::now() + config.window;
+        let mut batch = vec![first];
+        while batch.len() < config.max_size {
+            match pending.try_recv() {
+                Ok(order) => batch.push(order),
+                Err(_) if config.window.is_zero() => break,
+                Err(_) => match tokio::time::timeout_at(deadline, pending.recv()).await {
+                    Ok(Some(order)) => batch.push(order),
+                    _ => break,
+                },
+            }
+        }
+
+        let (orders, replies): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        match storage.insert_orders(orders).await {
+            Ok(results) => {
+                for (reply, result) in replies.into_iter().zip(results) {
+                    let _ = reply.send(result); // The caller may have given up
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to write a batch of {} orders: {:?}",
+                    replies.len(),
+                    e
+                );
+                let e = format!("{e:?}");
+                for reply in replies {
+                    let _ = reply.send(Err(RustexError::DbServiceError(e.clone().into())));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::{fund, order},
+        storage::MemoryStorage,
+    };
+
+    #[tokio::test]
+    async fn test_batched_orders_get_their_own_result() {
+        let storage = Arc::new(MemoryStorage::new());
+        fund(&*storage, 1.into(), Currencies::USD, 150.0).await;
+
+        let config = BatchConfig {
+            max_size: 10,
+            window: Duration::from_millis(50),
+        };
+        let batcher = OrderBatcher::spawn(storage.clone(), config);
+        // The funds only cover one order. Order 0 is repeated
+        let (first, second, repeated) = tokio::join!(
+            batcher.insert_order(order(0).build(), AuditOrigin::default()),
+            batcher.insert_order(order(1).build(), AuditOrigin::default()),
+            batcher.insert_order(order(0).price(10).build(), AuditOrigin::default()),
+        );
+        assert!(first.is_ok());
+        assert!(matches!(second, Err(RustexError::UserFacingError(_))));
+        assert!(matches!(repeated, Err(RustexError::DbServiceError(_))));
+
+        let balances = storage.get_user_balances(1.into()).await.unwrap();
+        assert_eq!((balances[0].available, balances[0].reserved), (50.0, 100.0));
+    }
+}
@@ -0,0 +1,209 @@
+//! Custody adapters move the funds of deposits and withdrawals
+//! in and out of the exchange (wallets, banks, third-party custodians)
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use futures::future::{self, BoxFuture};
+use rustex_core::{lock, prelude::*};
+use rustex_errors::RustexError;
+use serde::{Deserialize, Serialize};
+
+/// Transfers are handed over again if their outcome was not recorded, e.g. after a
+/// restart, so the adapters must move the funds of each transfer reference only once
+pub trait CustodyAdapter: Send + Sync {
+    /// Checks whether the funds of the deposit arrived.
+    /// Returns the custodian reference of the movement if they did
+    fn confirm_deposit<'a>(
+        &'a self,
+        transfer: &'a Transfer,
+    ) -> BoxFuture<'a, Result<Option<String>, RustexError>>;
+
+    /// Sends the funds of an approved withdrawal returning the custodian reference
+    fn send_withdrawal<'a>(
+        &'a self,
+        transfer: &'a Transfer,
+    ) -> BoxFuture<'a, Result<String, RustexError>>;
+}
+
+#[derive(Debug, Default)]
+struct Holdings {
+    funds: HashMap<Currencies, f64>,
+    movements: u64,
+    booked: HashMap<String, String>, // External reference of the movement of each transfer
+}
+
+impl Holdings {
+    fn apply(&mut self, movement: &Movement) {
+        self.booked.insert(
+            movement.reference.clone(),
+            movement.external_reference.clone(),
+        );
+        let funds = self.funds.entry(movement.currency).or_default();
+        match movement.kind {
+            TransferKind::Deposit => *funds += movement.amount,
+            TransferKind::Withdrawal => *funds -= movement.amount,
+        }
+        self.movements += 1;
+    }
+}
+
+/// Journal record of a movement
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Movement {
+    external_reference: String,
+    reference: String,
+    kind: TransferKind,
+    currency: Currencies,
+    amount: f64,
+}
+
+/// Stand-in custodian for development. Deposits are confirmed straight away.
+/// Holdings are kept in memory and every movement is appended to the journal
+/// file if one is configured, which is replayed on startup
+#[derive(Debug, Default)]
+pub struct LocalCustody {
+    holdings: Mutex<Holdings>,
+    journal: Option<Mutex<File>>,
+}
+
+impl LocalCustody {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only available with `CUSTODY_LOCAL_DEV=true`, as no funds really move.
+    /// Journals the movements in `CUSTODY_JOURNAL_PATH` if it is defined
+    pub fn from_env() -> anyhow::Result<Self> {
+        if !std::env::var("CUSTODY_LOCAL_DEV").is_ok_and(|dev| dev == "true") {
+            anyhow::bail!(
+                "No custodian configured. Set CUSTODY_LOCAL_DEV=true to use the local stand-in"
+            );
+        }
+        match std::env::var("CUSTODY_JOURNAL_PATH") {
+            Ok(path) if !path.is_empty() => Self::with_journal(path),
+            _ => Ok(Self::new()),
+        }
+    }
+
+    /// Restores the holdings from the journal and appends the next movements to it
+    pub fn with_journal(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut holdings = Holdings::default();
+        if path.as_ref().exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                match serde_json::from_str(&line?) {
+                    Ok(movement) => holdings.apply(&movement),
+                    // Only the last record can be torn by a crash
+                    Err(e) => log::warn!("Skipping corrupted custody record: {:?}", e),
+                }
+            }
+        }
+        let journal = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            holdings: Mutex::new(holdings),
+            journal: Some(Mutex::new(journal)),
+        })
+    }
+
+    fn book(&self, transfer: &Transfer) -> Result<String, RustexError> {
+        let mut holdings = lock!(self.holdings);
+        if let Some(external_reference) = holdings.booked.get(&transfer.reference()) {
+            return Ok(external_reference.clone());
+        }
+        let funds = holdings.funds.get(&transfer.currency).copied();
+        if transfer.kind == TransferKind::Withdrawal && funds.unwrap_or_default() < transfer.amount
+        {
+            return Err(RustexError::UserFacingError(format!(
+                "The custodian does not hold enough {:?} to send the withdrawal",
+                transfer.currency
+            )));
+        }
+        let movement = Movement {
+            external_reference: format!("local:{}", holdings.movements + 1),
+            reference: transfer.reference(),
+            kind: transfer.kind,
+            currency: transfer.currency,
+            amount: transfer.amount,
+        };
+
+        // Journaled before it is applied, so the holdings never get ahead of the journal
+        if let Some(journal) = &self.journal {
+            let mut line = serde_json::to_string(&movement)
+                .map_err(|e| RustexError::OtherInternal(e.to_string().into()))?;
+            line.push('\n');
+            let mut journal = lock!(journal);
+            journal
+                .write_all(line.as_bytes())
+                .and_then(|_| journal.sync_data())
+                .map_err(|e| RustexError::OtherInternal(e.to_string().into()))?;
+        }
+        holdings.apply(&movement);
+        Ok(movement.external_reference)
+    }
+}
+
+impl CustodyAdapter for LocalCustody {
+    fn confirm_deposit<'a>(
+        &'a self,
+        transfer: &'a Transfer,
+    ) -> BoxFuture<'a, Result<Option<String>, RustexError>> {
+        Box::pin(future::ready(self.book(transfer).map(Some)))
+    }
+
+    fn send_withdrawal<'a>(
+        &'a self,
+        transfer: &'a Transfer,
+    ) -> BoxFuture<'a, Result<String, RustexError>> {
+        Box::pin(future::ready(self.book(transfer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_holdings_are_restored_from_the_journal() {
+        let path =
+            std::env::temp_dir().join(format!("rustex-{}-custody.journal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let transfer = |transfer_id, kind, amount| Transfer {
+            transfer_id,
+            user_id: 1.into(),
+            kind,
+            currency: Currencies::USD,
+            amount,
+            status: TransferStatus::Approved,
+            external_reference: None,
+            created_at: None,
+            updated_at: None,
+        };
+
+        let custody = LocalCustody::with_journal(&path).unwrap();
+        let deposit = transfer(1, TransferKind::Deposit, 100.0);
+        assert!(custody.confirm_deposit(&deposit).await.unwrap().is_some());
+        drop(custody);
+
+        // The deposit survives a restart without being booked twice,
+        // and references keep counting after it
+        let custody = LocalCustody::with_journal(&path).unwrap();
+        let reference = custody.confirm_deposit(&deposit).await.unwrap();
+        assert_eq!(reference.as_deref(), Some("local:1"));
+        let withdrawal = transfer(2, TransferKind::Withdrawal, 60.0);
+        let reference = custody.send_withdrawal(&withdrawal).await.unwrap();
+        assert_eq!(reference, "local:2");
+        assert_eq!(
+            custody.send_withdrawal(&withdrawal).await.unwrap(),
+            "local:2"
+        );
+        let overdraft = transfer(3, TransferKind::Withdrawal, 60.0);
+        assert!(custody.send_withdrawal(&overdraft).await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
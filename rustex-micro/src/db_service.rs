@@ -1,28 +1,26 @@
-use std::{future::Future, sync::LazyLock};
-
-use diesel::{dsl::max, BoolExpressionMethods, ExpressionMethods, QueryDsl, SelectableHelper};
-use diesel_async::{
-    pooled_connection::{deadpool::Pool, AsyncDieselConnectionManager},
-    AsyncPgConnection, RunQueryDsl,
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, LazyLock},
+    time::Duration,
 };
-use futures::StreamExt;
-use rustex_core::{db, prelude::*};
+
+use chrono::{DateTime, Utc};
+use futures::{future::join_all, StreamExt};
+use rustex_core::prelude::*;
 use rustex_errors::RustexError;
 use tarpc::context::Context;
 
-use crate::{create_tarpc_server, DEFAULT_ADDRESS, DEFAULT_MAX_NUMBER_CO_CONNECTIONS};
+use crate::{
+    batcher::{BatchConfig, OrderBatcher},
+    create_tarpc_server,
+    custody::{CustodyAdapter, LocalCustody},
+    storage::{Storage, StorageRouter},
+    DEFAULT_ADDRESS, DEFAULT_MAX_NUMBER_CO_CONNECTIONS,
+};
 
 const DEFAULT_PORT: u16 = 6666;
-
-pub static POSTGRES_ADDRESS: LazyLock<String> = LazyLock::new(|| {
-    let address = std::env::var("POSTGRES_ADDRESS")
-        .expect("POSTGRES_ADDRESS is not defined as an environment variable");
-    let uname =
-        std::env::var("PG_USERNAME").expect("PG_USERNAME not defined as environment variable");
-    let passwd =
-        std::env::var("PG_PASSWORD").expect("PG_PASSWORD not defined as environment variable");
-    format!("postgres://{}:{}@{}", uname, passwd, address)
-});
+const DEFAULT_ARCHIVE_INTERVAL_SECS: u64 = 3600;
 
 pub static ADDRESS: LazyLock<String> = LazyLock::new(|| {
     let addr = std::env::var("DATABASE_RPC_ADDRESS")
@@ -40,22 +38,48 @@ static MAX_NUMBER_CO_CONNECTIONS: LazyLock<usize> = LazyLock::new(|| {
         .unwrap_or(DEFAULT_MAX_NUMBER_CO_CONNECTIONS)
 });
 
+/// Archival is disabled unless the age of the archived rows is configured
+static ARCHIVE_AFTER: LazyLock<Option<chrono::Duration>> = LazyLock::new(|| {
+    std::env::var("DB_ARCHIVE_AFTER_DAYS")
+        .ok()
+        .filter(|n| !n.is_empty())
+        .map(|n| chrono::Duration::days(n.parse().unwrap()))
+});
+
+static ARCHIVE_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    let interval_secs = std::env::var("DB_ARCHIVE_INTERVAL_SECS")
+        .ok()
+        .filter(|n| !n.is_empty())
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(DEFAULT_ARCHIVE_INTERVAL_SECS);
+    Duration::from_secs(interval_secs)
+});
+
 #[tarpc::service]
 pub trait DbService {
-    /// Returns the last order id from the DB. It will be None if the table is emtpy
-    async fn get_last_order_id() -> Result<Option<OrderId>, RustexError>;
-
-    /// Returns the last trade id from the DB. It will be None if the table emtpy
-    async fn get_last_trade_id() -> Result<Option<TradeId>, RustexError>;
+    /// Reserves a block of `count` order or trade ids of the market. The block starts
+    /// at `from` or later, and no id of it is ever reserved again
+    async fn reserve_ids(
+        market: ExchangeMarket,
+        kind: IdKind,
+        from: i64,
+        count: i64,
+    ) -> Result<IdBlock, RustexError>;
 
     /// Returns all pending order ids
     async fn get_pending_orders_ids(market: ExchangeMarket) -> Result<Vec<OrderId>, RustexError>;
 
-    /// Return all the orders of a given user
-    async fn get_user_orders(
+    /// Returns a page of the orders of the user, newest first
+    async fn get_order_history(
         user: UserId,
-        market: ExchangeMarket,
-    ) -> Result<Vec<OrderId>, RustexError>;
+        query: HistoryQuery,
+    ) -> Result<Page<OrderDetails>, RustexError>;
+
+    /// Returns a page of the executions of the orders of the user, newest first
+    async fn get_trade_history(
+        user: UserId,
+        query: HistoryQuery,
+    ) -> Result<Page<UserTrade>, RustexError>;
 
     /// Return the user for a specific order
     async fn get_order_user(
@@ -75,118 +99,338 @@ pub trait DbService {
         market: ExchangeMarket,
     ) -> Result<Vec<Trade>, RustexError>;
 
-    /// Insert in the database a new order
-    async fn insert_order(order: Order) -> Result<(), RustexError>;
+    /// Insert in the database a new order marking it as pending and locking its funds.
+    /// Every write is rolled back if any of them fails (e.g. insufficient funds).
+    /// The order is audited as requested by `origin` in the same write
+    async fn insert_order(order: Order, origin: AuditOrigin) -> Result<(), RustexError>;
 
-    /// Inserts in the database a new list of trades.
-    /// Also, removes from the pending orders table those that are completed
+    /// Inserts in the database a new list of trades settling the funds.
+    /// Also, removes from the pending orders table those that are completed.
+    /// Batches whose trades are already recorded are skipped (idempotent retries)
     async fn insert_trades(
         market: ExchangeMarket,
         trades: Vec<Trade>,
         completed_orders: Vec<OrderId>,
     ) -> Result<(), RustexError>;
 
-    /// Insert a new cancellation
-    async fn insert_cancellation(market: ExchangeMarket, order: OrderId)
+    /// Insert a new cancellation releasing the funds reserved by the order for its `unmatched`
+    /// quantity, or by its recorded trades if unset. The status is either cancelled or expired.
+    /// It is audited as requested by `origin`
+    async fn insert_cancellation(
+        market: ExchangeMarket,
+        order: OrderId,
+        status: OrderStatus,
+        unmatched: Option<f64>,
+        origin: AuditOrigin,
+    ) -> Result<(), RustexError>;
+
+    /// Returns the last order group id of the market. It will be None if there are no groups
+    async fn get_last_group_id(market: ExchangeMarket) -> Result<Option<GroupId>, RustexError>;
+
+    /// Returns the order groups that are still pending or active
+    async fn get_open_order_groups(market: ExchangeMarket) -> Result<Vec<OrderGroup>, RustexError>;
+
+    /// Returns the requested order group
+    async fn get_order_group(
+        group: GroupId,
+        market: ExchangeMarket,
+    ) -> Result<Option<OrderGroup>, RustexError>;
+
+    /// Inserts new order groups or updates the state of existing ones
+    async fn upsert_order_groups(groups: Vec<OrderGroup>) -> Result<(), RustexError>;
+
+    /// Returns the balances of the user for every currency. Funds are booked in the
+    /// database of the market, or the default one if `None`
+    async fn get_user_balances(
+        user: UserId,
+        market: Option<ExchangeMarket>,
+    ) -> Result<Vec<Balance>, RustexError>;
+
+    /// Records a new deposit or withdrawal. Withdrawals lock the funds until they are sent.
+    /// The request is audited as made by `origin` in the same write
+    async fn request_transfer(
+        transfer: NewTransfer,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> Result<Transfer, RustexError>;
+
+    /// Returns the deposits and withdrawals of the user
+    async fn get_user_transfers(
+        user: UserId,
+        market: Option<ExchangeMarket>,
+    ) -> Result<Vec<Transfer>, RustexError>;
+
+    /// Hands a requested transfer over to the custodian. Audited as approved by `origin`
+    async fn approve_transfer(
+        transfer: i64,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> Result<Transfer, RustexError>;
+
+    /// Rejects a requested transfer releasing its funds. Audited as rejected by `origin`
+    async fn reject_transfer(
+        transfer: i64,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> Result<Transfer, RustexError>;
+
+    /// Returns the executions of the orders of the user in the market
+    async fn get_user_fills(user: UserId, market: ExchangeMarket)
+        -> Result<Vec<Fill>, RustexError>;
+
+    /// Returns a page of the trades of the market, newest first
+    async fn get_trade_tape(
+        market: ExchangeMarket,
+        query: TapeQuery,
+    ) -> Result<Page<TapeTrade>, RustexError>;
+
+    /// Returns the most recent candles of the range, oldest first
+    async fn get_candles(
+        market: ExchangeMarket,
+        query: CandleQuery,
+    ) -> Result<Vec<Candle>, RustexError>;
+
+    /// Rebuilds the candles of the time range from the recorded trades.
+    /// Returns the number of candles stored
+    async fn backfill_candles(
+        market: ExchangeMarket,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<usize, RustexError>;
+
+    /// Returns the price of the last trade of the market. None if there are no trades
+    async fn get_last_trade_price(market: ExchangeMarket) -> Result<Option<i64>, RustexError>;
+
+    /// Rebuilds the book of the market as it stood at the given time, for dispute resolution
+    async fn get_book_at(
+        market: ExchangeMarket,
+        at: DateTime<Utc>,
+    ) -> Result<BookSnapshot, RustexError>;
+
+    /// Returns the pre-trade risk limits of every user, kept in the default database
+    async fn get_all_risk_limits() -> Result<Vec<RiskLimits>, RustexError>;
+
+    /// Returns the pre-trade risk limits of the user. None if the user has no limits
+    async fn get_risk_limits(user: UserId) -> Result<Option<RiskLimits>, RustexError>;
+
+    /// Inserts or replaces the pre-trade risk limits of a user. Audited as set by `origin`
+    async fn upsert_risk_limits(limits: RiskLimits, origin: AuditOrigin)
         -> Result<(), RustexError>;
+
+    /// Removes the pre-trade risk limits of the user. Returns false if there were none.
+    /// Otherwise audited as removed by `origin`
+    async fn delete_risk_limits(user: UserId, origin: AuditOrigin) -> Result<bool, RustexError>;
+
+    /// Appends the event to the audit log of the database of its market,
+    /// or the default one if it has none
+    async fn append_audit(event: AuditEvent) -> Result<(), RustexError>;
 }
 
 #[derive(Clone)]
 pub struct DbServer {
-    pool: Pool<AsyncPgConnection>, // Clone only increases reference counting
+    storage: StorageRouter,
+    orders: Arc<HashMap<ExchangeMarket, OrderBatcher>>, // Inserts the orders of each market
+    custody: Arc<dyn CustodyAdapter>,
 }
 
 impl DbServer {
+    /// Storage backends, write batching and custodian configured in the environment
     pub async fn new() -> Result<Self, RustexError> {
-        let config =
-            AsyncDieselConnectionManager::<AsyncPgConnection>::new(POSTGRES_ADDRESS.to_string());
-        let pool = Pool::builder(config).build()?;
-        Ok(Self { pool })
+        let storage = StorageRouter::from_env()?;
+        let custody = Arc::new(LocalCustody::from_env()?);
+        Ok(Self::with_router(storage, custody, BatchConfig::from_env()))
     }
-}
 
-impl DbService for DbServer {
-    async fn get_last_order_id(self, _: Context) -> Result<Option<OrderId>, RustexError> {
-        let conn = &mut *self.pool.get().await?;
-        use db::schema::orders::dsl::*;
-        let max_order_id: Option<i64> = orders.select(max(order_id)).first(conn).await?;
-        Ok(max_order_id.map(|e| e.into()))
+    /// Every market lives in the same storage
+    pub fn with_storage(storage: Arc<dyn Storage>, custody: Arc<dyn CustodyAdapter>) -> Self {
+        Self::with_router(StorageRouter::new(storage), custody, BatchConfig::default())
     }
 
-    async fn get_last_trade_id(self, _: Context) -> Result<Option<TradeId>, RustexError> {
-        let conn = &mut *self.pool.get().await?;
-        use db::schema::trades::dsl::*;
-        let max_trade_id: Option<i64> = trades.select(max(trade_id)).first(conn).await?;
-        Ok(max_trade_id.map(|e| e.into()))
+    /// Spawns a batcher per storage. Must be called within a Tokio runtime
+    pub fn with_router(
+        storage: StorageRouter,
+        custody: Arc<dyn CustodyAdapter>,
+        batching: BatchConfig,
+    ) -> Self {
+        let mut batchers: Vec<(&Arc<dyn Storage>, OrderBatcher)> = vec![];
+        let mut orders = HashMap::new();
+        for market in ExchangeMarket::ALL {
+            let shard = storage.market(market);
+            let batcher = match batchers.iter().find(|(s, _)| Arc::ptr_eq(s, shard)) {
+                Some((_, batcher)) => batcher.clone(),
+                None => {
+                    let batcher = OrderBatcher::spawn(shard.clone(), batching);
+                    batchers.push((shard, batcher.clone()));
+                    batcher
+                }
+            };
+            orders.insert(market, batcher);
+        }
+        Self {
+            orders: Arc::new(orders),
+            storage,
+            custody,
+        }
     }
 
-    async fn get_pending_orders_ids(
+    /// Finishes the transfers handed over to the custodian whose outcome was lost, e.g. by a crash
+    pub async fn resume_transfers(&self) -> Result<(), RustexError> {
+        for storage in self.storage.all() {
+            for transfer in storage.get_unfinished_transfers().await? {
+                log::info!(
+                    "Resuming {:?} transfer {}",
+                    transfer.status,
+                    transfer.transfer_id
+                );
+                self.hand_over(storage, transfer, None).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands a confirming deposit or an approved transfer over to the custodian and
+    /// records the outcome. Safe to repeat, as the custodian moves the funds only once
+    async fn hand_over(
+        &self,
+        storage: &Arc<dyn Storage>,
+        transfer: Transfer,
+        market: Option<ExchangeMarket>,
+    ) -> Result<Transfer, RustexError> {
+        let (to, reference) = match (transfer.status, transfer.kind) {
+            (TransferStatus::Confirming, _) => {
+                match self.custody.confirm_deposit(&transfer).await {
+                    Ok(Some(reference)) => (TransferStatus::Completed, Some(reference)),
+                    // The funds did not arrive yet. Left for the administrator to approve
+                    Ok(None) => (TransferStatus::Requested, None),
+                    Err(e) => {
+                        // The custodian may have booked it, so it stays confirming until the next try
+                        log::error!(
+                            "Custodian failed to confirm deposit {}: {:?}",
+                            transfer.transfer_id,
+                            e
+                        );
+                        return Ok(transfer);
+                    }
+                }
+            }
+            // The administrator confirms the funds arrived
+            (_, TransferKind::Deposit) => (TransferStatus::Completed, None),
+            (_, TransferKind::Withdrawal) => match self.custody.send_withdrawal(&transfer).await {
+                Ok(reference) => (TransferStatus::Completed, Some(reference)),
+                Err(e) => {
+                    log::error!(
+                        "Custodian failed to send withdrawal {}: {:?}",
+                        transfer.transfer_id,
+                        e
+                    );
+                    (TransferStatus::Failed, None)
+                }
+            },
+        };
+        storage
+            .transition_transfer(
+                transfer.transfer_id,
+                transfer.status,
+                to,
+                reference,
+                market,
+                AuditOrigin::default(),
+            )
+            .await
+    }
+}
+
+impl DbService for DbServer {
+    async fn reserve_ids(
         self,
         _: Context,
         market: ExchangeMarket,
-    ) -> Result<Vec<OrderId>, RustexError> {
-        let conn = &mut *self.pool.get().await?;
-        use db::schema::pending_orders::dsl::*;
-        let query = diesel::QueryDsl::select(pending_orders.filter(exchange.eq(market)), order_id);
-        let order_ids: Vec<i64> = query.load(conn).await?;
-        Ok(order_ids.into_iter().map(|e| e.into()).collect())
+        kind: IdKind,
+        from: i64,
+        count: i64,
+    ) -> Result<IdBlock, RustexError> {
+        if count <= 0 {
+            return Err(RustexError::DbServiceError(
+                "Id blocks must hold at least one id".into(),
+            ));
+        }
+        self.storage
+            .market(market)
+            .reserve_ids(market, kind, from, count)
+            .await
     }
 
-    async fn get_user_orders(
+    async fn get_pending_orders_ids(
         self,
         _: Context,
-        user: UserId,
         market: ExchangeMarket,
     ) -> Result<Vec<OrderId>, RustexError> {
-        let conn = &mut *self.pool.get().await?;
+        self.storage
+            .market(market)
+            .get_pending_orders_ids(market)
+            .await
+    }
 
-        use db::schema::orders::dsl::*;
-        let query = orders
-            .filter(user_id.eq(user).and(exchange.eq(market)))
-            .select(order_id);
-        let order_ids: Vec<OrderId> = query.load(conn).await?;
+    async fn get_order_history(
+        self,
+        _: Context,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> Result<Page<OrderDetails>, RustexError> {
+        let page_size = query.page_size()?;
+        let shards = self.storage.shards(query.market);
+        let pages = join_all(
+            shards
+                .iter()
+                .map(|shard| shard.get_order_history(user, query.clone())),
+        )
+        .await;
+        let pages = pages.into_iter().collect::<Result<_, _>>()?;
+        Ok(Page::merge(pages, page_size, OrderDetails::cursor))
+    }
 
-        Ok(order_ids)
+    async fn get_trade_history(
+        self,
+        _: Context,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> Result<Page<UserTrade>, RustexError> {
+        if query.status.is_some() {
+            return Err(RustexError::UserFacingError(
+                "Trades cannot be filtered by order status".into(),
+            ));
+        }
+        let page_size = query.page_size()?;
+        let shards = self.storage.shards(query.market);
+        let pages = join_all(
+            shards
+                .iter()
+                .map(|shard| shard.get_trade_history(user, query.clone())),
+        )
+        .await;
+        let pages = pages.into_iter().collect::<Result<_, _>>()?;
+        Ok(Page::merge(pages, page_size, UserTrade::cursor))
     }
 
     async fn get_order_user(
         self,
         _: Context,
-        order_identifier: OrderId,
+        order: OrderId,
         market: ExchangeMarket,
     ) -> Result<Option<UserId>, RustexError> {
-        let conn = &mut *self.pool.get().await?;
-
-        use db::schema::orders::dsl::*;
-        let query = orders
-            .filter(order_id.eq(order_identifier).and(exchange.eq(market)))
-            .select(user_id);
-        let user_ids = query.load(conn).await?;
-
-        match user_ids.len() {
-            0 => Ok(None),
-            1 => Ok(Some(user_ids[0])),
-            _ => Err(RustexError::DbServiceError(
-                "There are multiple users for a single order id".into(),
-            )),
-        }
+        self.storage
+            .market(market)
+            .get_order_user(order, market)
+            .await
     }
 
     async fn get_orders(
         self,
         _: Context,
-        order_ids: Vec<OrderId>,
+        orders: Vec<OrderId>,
         market: ExchangeMarket,
     ) -> Result<Vec<Order>, RustexError> {
-        let conn = &mut *self.pool.get().await?;
-        let order_ids = order_ids.into_iter().map(i64::from).collect::<Vec<_>>();
-
-        use db::schema::orders::dsl::*;
-        let rows: Vec<Order> = orders
-            .filter(exchange.eq(market).and(order_id.eq_any(order_ids)))
-            .load(conn)
-            .await?;
-        Ok(rows)
+        self.storage.market(market).get_orders(orders, market).await
     }
 
     async fn get_order_trades(
@@ -195,121 +439,315 @@ impl DbService for DbServer {
         order: OrderId,
         market: ExchangeMarket,
     ) -> Result<Vec<Trade>, RustexError> {
-        let conn = &mut *self.pool.get().await?;
-
-        let order: i64 = order.into();
+        self.storage
+            .market(market)
+            .get_order_trades(order, market)
+            .await
+    }
 
-        use db::schema::trades::dsl::*;
-        let query = trades.filter(
-            exchange
-                .eq(market)
-                .and(buy_order.eq(order).or(sell_order.eq(order))),
-        );
-        let rows: Vec<Trade> = query.load(conn).await?;
-        Ok(rows)
+    async fn insert_order(
+        self,
+        _: Context,
+        order: Order,
+        origin: AuditOrigin,
+    ) -> Result<(), RustexError> {
+        self.orders[&order.exchange]
+            .insert_order(order, origin)
+            .await
     }
 
-    async fn insert_order(self, _: Context, new_order: Order) -> Result<(), RustexError> {
-        let conn = &mut *self.pool.get().await?;
+    async fn insert_trades(
+        self,
+        _: Context,
+        market: ExchangeMarket,
+        trades: Vec<Trade>,
+        completed_orders: Vec<OrderId>,
+    ) -> Result<(), RustexError> {
+        let recorded_at = Utc::now();
+        let trades = trades
+            .into_iter()
+            .map(|mut trade| {
+                trade.created_at.get_or_insert(recorded_at);
+                trade
+            })
+            .collect();
+        self.storage
+            .market(market)
+            .insert_trades(market, trades, completed_orders)
+            .await
+    }
 
-        // Insert new order
-        let insert_new_order = {
-            use db::schema::orders::dsl::*;
-            diesel::insert_into(orders).values(&new_order).execute(conn)
-        };
+    async fn insert_cancellation(
+        self,
+        _: Context,
+        market: ExchangeMarket,
+        order: OrderId,
+        status: OrderStatus,
+        unmatched: Option<f64>,
+        origin: AuditOrigin,
+    ) -> Result<(), RustexError> {
+        if !matches!(status, OrderStatus::Cancelled | OrderStatus::Expired) {
+            return Err(RustexError::DbServiceError(
+                format!("{status:?} is not a cancellation status").into(),
+            ));
+        }
+        self.storage
+            .market(market)
+            .insert_cancellation(market, order, status, unmatched, origin)
+            .await
+    }
 
-        // Insert new pending order
-        let pending_order = PendingOrder {
-            order_id: new_order.order_id,
-            exchange: new_order.exchange,
-        };
-        let insert_new_pending_order = {
-            use db::schema::pending_orders::dsl::*;
-            diesel::insert_into(pending_orders)
-                .values(&pending_order)
-                .execute(conn)
-        };
+    async fn get_last_group_id(
+        self,
+        _: Context,
+        market: ExchangeMarket,
+    ) -> Result<Option<GroupId>, RustexError> {
+        self.storage.market(market).get_last_group_id(market).await
+    }
 
-        let (insert_new_order, insert_new_pending_order) =
-            tokio::join!(insert_new_order, insert_new_pending_order);
+    async fn get_open_order_groups(
+        self,
+        _: Context,
+        market: ExchangeMarket,
+    ) -> Result<Vec<OrderGroup>, RustexError> {
+        self.storage
+            .market(market)
+            .get_open_order_groups(market)
+            .await
+    }
 
-        // Raise errors
-        insert_new_order?;
-        insert_new_pending_order?;
+    async fn get_order_group(
+        self,
+        _: Context,
+        group: GroupId,
+        market: ExchangeMarket,
+    ) -> Result<Option<OrderGroup>, RustexError> {
+        self.storage
+            .market(market)
+            .get_order_group(group, market)
+            .await
+    }
 
+    async fn upsert_order_groups(
+        self,
+        _: Context,
+        groups: Vec<OrderGroup>,
+    ) -> Result<(), RustexError> {
+        let mut by_market = BTreeMap::<_, Vec<_>>::new();
+        for group in groups {
+            by_market.entry(group.exchange).or_default().push(group);
+        }
+        for (market, groups) in by_market {
+            self.storage
+                .market(market)
+                .upsert_order_groups(groups)
+                .await?;
+        }
         Ok(())
     }
 
-    async fn insert_trades(
+    async fn get_user_balances(
         self,
         _: Context,
-        market: ExchangeMarket,
-        trades: Vec<Trade>,
-        completed_orders: Vec<OrderId>,
-    ) -> Result<(), RustexError> {
-        let mut conn = self.pool.get().await?;
-
-        // Insertion in Trades Table
-        let inserted_trades = {
-            diesel::insert_into(db::schema::trades::table)
-                .values(&trades)
-                .returning(Trade::as_returning())
-                .execute(&mut *conn)
-        };
+        user: UserId,
+        market: Option<ExchangeMarket>,
+    ) -> Result<Vec<Balance>, RustexError> {
+        self.storage.funds(market)?.get_user_balances(user).await
+    }
 
-        // Removing from Pending Orders Table
-        let marked_completed = {
-            use db::schema::pending_orders::dsl::*;
-            diesel::delete(
-                pending_orders.filter(order_id.eq_any(&completed_orders).and(exchange.eq(market))),
+    async fn request_transfer(
+        self,
+        _: Context,
+        new_transfer: NewTransfer,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> Result<Transfer, RustexError> {
+        let storage = self.storage.funds(market)?;
+        let transfer = storage
+            .insert_transfer(new_transfer, market, origin)
+            .await?;
+        if transfer.kind == TransferKind::Withdrawal {
+            return Ok(transfer);
+        }
+
+        // Recorded before the custodian books the deposit, so it is confirmed again if the outcome is lost
+        let confirming = storage
+            .transition_transfer(
+                transfer.transfer_id,
+                TransferStatus::Requested,
+                TransferStatus::Confirming,
+                None,
+                market,
+                AuditOrigin::default(),
             )
-            .execute(&mut *conn)
-        };
+            .await?;
+        self.hand_over(storage, confirming, market).await
+    }
+
+    async fn get_user_transfers(
+        self,
+        _: Context,
+        user: UserId,
+        market: Option<ExchangeMarket>,
+    ) -> Result<Vec<Transfer>, RustexError> {
+        self.storage.funds(market)?.get_user_transfers(user).await
+    }
 
-        let (inserted_trades, marked_completed) = tokio::join!(inserted_trades, marked_completed);
-        let (inserted_trades, marked_completed) = (inserted_trades?, marked_completed?);
+    async fn approve_transfer(
+        self,
+        _: Context,
+        transfer: i64,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> Result<Transfer, RustexError> {
+        let storage = self.storage.funds(market)?;
+        let approved = storage
+            .transition_transfer(
+                transfer,
+                TransferStatus::Requested,
+                TransferStatus::Approved,
+                None,
+                market,
+                origin,
+            )
+            .await?;
+        self.hand_over(storage, approved, market).await
+    }
 
-        // Final error checking
-        if inserted_trades != trades.len() {
-            return Err(RustexError::DbServiceError(
-                "Failed to insert all orders in the database".into(),
-            ));
-        }
+    async fn reject_transfer(
+        self,
+        _: Context,
+        transfer: i64,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> Result<Transfer, RustexError> {
+        self.storage
+            .funds(market)?
+            .transition_transfer(
+                transfer,
+                TransferStatus::Requested,
+                TransferStatus::Failed,
+                None,
+                market,
+                origin,
+            )
+            .await
+    }
 
-        if marked_completed != completed_orders.len() {
-            return Err(RustexError::DbServiceError(
-                "Failed to delete completed orders from the pending orders table".into(),
+    async fn get_user_fills(
+        self,
+        _: Context,
+        user: UserId,
+        market: ExchangeMarket,
+    ) -> Result<Vec<Fill>, RustexError> {
+        self.storage
+            .market(market)
+            .get_user_fills(user, market)
+            .await
+    }
+
+    async fn get_trade_tape(
+        self,
+        _: Context,
+        market: ExchangeMarket,
+        query: TapeQuery,
+    ) -> Result<Page<TapeTrade>, RustexError> {
+        self.storage
+            .market(market)
+            .get_trade_tape(market, query)
+            .await
+    }
+
+    async fn get_candles(
+        self,
+        _: Context,
+        market: ExchangeMarket,
+        query: CandleQuery,
+    ) -> Result<Vec<Candle>, RustexError> {
+        self.storage.market(market).get_candles(market, query).await
+    }
+
+    async fn backfill_candles(
+        self,
+        _: Context,
+        market: ExchangeMarket,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<usize, RustexError> {
+        if from >= to {
+            return Err(RustexError::UserFacingError(
+                "The backfill range must start before it ends".into(),
             ));
         }
+        self.storage
+            .market(market)
+            .rebuild_candles(market, from, to)
+            .await
+    }
 
-        Ok(())
+    async fn get_last_trade_price(
+        self,
+        _: Context,
+        market: ExchangeMarket,
+    ) -> Result<Option<i64>, RustexError> {
+        self.storage
+            .market(market)
+            .get_last_trade_price(market)
+            .await
     }
 
-    async fn insert_cancellation(
+    async fn get_book_at(
         self,
         _: Context,
         market: ExchangeMarket,
-        order_id: OrderId,
+        at: DateTime<Utc>,
+    ) -> Result<BookSnapshot, RustexError> {
+        self.storage.market(market).get_book_at(market, at).await
+    }
+
+    async fn get_all_risk_limits(self, _: Context) -> Result<Vec<RiskLimits>, RustexError> {
+        self.storage.default().get_all_risk_limits().await
+    }
+
+    async fn get_risk_limits(
+        self,
+        _: Context,
+        user: UserId,
+    ) -> Result<Option<RiskLimits>, RustexError> {
+        self.storage.default().get_risk_limits(user).await
+    }
+
+    async fn upsert_risk_limits(
+        self,
+        _: Context,
+        limits: RiskLimits,
+        origin: AuditOrigin,
     ) -> Result<(), RustexError> {
-        let mut conn = self.pool.get().await?;
+        self.storage
+            .default()
+            .upsert_risk_limits(limits, origin)
+            .await
+    }
 
-        let cancellation = CancelledOrder {
-            order_id,
-            exchange: market,
-            created_at: None,
-        };
-        let insertion = diesel::insert_into(db::schema::cancelled_orders::table)
-            .values(&cancellation)
-            .returning(CancelledOrder::as_returning())
-            .execute(&mut *conn)
-            .await?;
-        if insertion != 1 {
-            return Err(RustexError::DbServiceError(
-                "Failed to record cancelled order".into(),
-            ));
-        }
+    async fn delete_risk_limits(
+        self,
+        _: Context,
+        user: UserId,
+        origin: AuditOrigin,
+    ) -> Result<bool, RustexError> {
+        self.storage
+            .default()
+            .delete_risk_limits(user, origin)
+            .await
+    }
 
-        Ok(())
+    async fn append_audit(self, _: Context, event: AuditEvent) -> Result<(), RustexError> {
+        // Events of no market, e.g. logins, are recorded in the default storage
+        let storage = match event.exchange {
+            Some(market) => self.storage.market(market),
+            None => self.storage.default(),
+        };
+        storage.append_audit(vec![event]).await
     }
 }
 
@@ -318,31 +756,109 @@ pub async fn start_service() {
         .await
         .expect("Failed to create database RPC server state");
 
-    log::info!(
-        "Testing connection with the database: {:?}",
-        *POSTGRES_ADDRESS
-    );
-    let state = test_connection_to_db(state).await;
-    log::info!("Database connection successful");
+    log::info!("Testing connection with the storage");
+    for storage in state.storage.all() {
+        storage.check().await.expect("Failed to reach the storage");
+    }
+    log::info!("Storage connection successful");
+
+    // The partitions of the current month must exist before any write
+    for storage in state.storage.all() {
+        storage
+            .create_partitions()
+            .await
+            .expect("Failed to create the partitions");
+    }
+
+    state
+        .resume_transfers()
+        .await
+        .expect("Failed to resume the transfers");
+
+    tokio::spawn(maintain_partitions(state.storage.clone(), *ARCHIVE_AFTER));
 
     let listener = create_tarpc_server!(ADDRESS.clone(), *MAX_NUMBER_CO_CONNECTIONS, state.clone());
-    log::info!(
-        "DB Service:: RPC listening on: {:?}. Database Address: {:?}",
-        ADDRESS,
-        *POSTGRES_ADDRESS
-    );
+    log::info!("DB Service:: RPC listening on: {:?}", ADDRESS);
     listener.await
 }
 
-async fn test_connection_to_db(db_server: DbServer) -> DbServer {
-    let conn = &mut *db_server
-        .pool
-        .get()
-        .await
-        .expect("Failed to get pool client");
-    let _result = diesel::sql_query("SELECT 1")
-        .execute(conn)
-        .await
-        .expect("Failed to execute test query");
-    db_server
+/// Creates the partitions of the coming months and, given an `age`, moves
+/// the completed orders and trades older than that out of the live tables
+async fn maintain_partitions(storage: StorageRouter, age: Option<chrono::Duration>) {
+    let mut interval = tokio::time::interval(*ARCHIVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        for storage in storage.all() {
+            match storage.create_partitions().await {
+                Ok(0) => {}
+                Ok(created) => log::info!("Created {} partitions", created),
+                Err(e) => log::error!("Failed to create the partitions: {:?}", e),
+            }
+            let Some(age) = age else {
+                continue;
+            };
+            let before = Utc::now() - age;
+            match storage.archive(before).await {
+                Ok(0) => {}
+                Ok(archived) => log::info!("Archived {} partitions before {}", archived, before),
+                Err(e) => log::error!("Failed to archive the rows before {}: {:?}", before, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_deposits_left_confirming_are_finished_once() {
+        let storage = Arc::new(MemoryStorage::new());
+        let server = DbServer::with_storage(storage.clone(), Arc::new(LocalCustody::new()));
+        let deposit = NewTransfer {
+            user_id: 1.into(),
+            kind: TransferKind::Deposit,
+            currency: Currencies::USD,
+            amount: 10.0,
+        };
+        let origin = AuditOrigin::default();
+        let transfer = storage
+            .insert_transfer(deposit, None, origin.clone())
+            .await
+            .unwrap();
+        // As if the db-service stopped before the custodian answered
+        storage
+            .transition_transfer(
+                transfer.transfer_id,
+                TransferStatus::Requested,
+                TransferStatus::Confirming,
+                None,
+                None,
+                origin.clone(),
+            )
+            .await
+            .unwrap();
+
+        server.resume_transfers().await.unwrap();
+        // Retrying the recorded transition books nothing twice
+        let completed = storage
+            .transition_transfer(
+                transfer.transfer_id,
+                TransferStatus::Confirming,
+                TransferStatus::Completed,
+                None,
+                None,
+                origin,
+            )
+            .await
+            .unwrap();
+        server.resume_transfers().await.unwrap();
+
+        assert_eq!(completed.status, TransferStatus::Completed);
+        assert_eq!(completed.external_reference.as_deref(), Some("local:1"));
+        let balances = storage.get_user_balances(1.into()).await.unwrap();
+        assert_eq!(balances[0].available, 10.0);
+        assert!(storage.get_unfinished_transfers().await.unwrap().is_empty());
+    }
 }
@@ -0,0 +1,37 @@
+//! Fixtures shared by the tests and the benches of the services
+
+pub use rustex_core::fixtures::*;
+use rustex_core::prelude::*;
+
+use crate::storage::Storage;
+
+/// Deposits the amount for the user, completing the transfer right away
+pub async fn fund(
+    storage: &(impl Storage + ?Sized),
+    user_id: UserId,
+    currency: Currencies,
+    amount: f64,
+) {
+    let deposit = NewTransfer {
+        user_id,
+        kind: TransferKind::Deposit,
+        currency,
+        amount,
+    };
+    let origin = AuditOrigin::default();
+    let transfer = storage
+        .insert_transfer(deposit, None, origin.clone())
+        .await
+        .unwrap();
+    storage
+        .transition_transfer(
+            transfer.transfer_id,
+            TransferStatus::Requested,
+            TransferStatus::Completed,
+            None,
+            None,
+            origin,
+        )
+        .await
+        .unwrap();
+}
@@ -1,5 +1,12 @@
+pub mod batcher;
+pub mod custody;
 pub mod db_service;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
 pub mod match_service;
+pub mod outbox;
+pub mod rpc;
+pub mod storage;
 
 use db_service::DbServiceClient;
 use match_service::MatchServiceClient;
@@ -14,14 +21,9 @@ macro_rules! generate_client {
     ($($client_type:ty),* $(,)?) => {
         paste::paste! {
             $(
-                pub async fn [< get_ $client_type:snake >]<A: tokio::net::ToSocketAddrs>(addrs: A) -> anyhow::Result<$client_type> {
-                    let mut transport = tarpc::serde_transport::tcp::connect(
-                        addrs,
-                        tarpc::tokio_serde::formats::Json::default
-                    );
-                    transport.config_mut().max_frame_length(u32::MAX as usize);
-
-                    let client = $client_type::new(tarpc::client::Config::default(), transport.await?).spawn();
+                pub async fn [< get_ $client_type:snake >]<A: AsRef<str>>(addr: A) -> anyhow::Result<$client_type> {
+                    let transport = rpc::connect(addr.as_ref(), *rpc::WIRE, rpc::TLS.as_deref()).await?;
+                    let client = $client_type::new(tarpc::client::Config::default(), transport).spawn();
                     Ok(client)
                 }
             )*
@@ -34,21 +36,15 @@ generate_client!(MatchServiceClient, DbServiceClient);
 #[macro_export]
 macro_rules! create_tarpc_server {
     ($address:expr, $max_conns:expr, $server_state:expr) => {{
-        let mut listener = tarpc::serde_transport::tcp::listen(
-            $address,
-            tarpc::tokio_serde::formats::Json::default,
-        )
-        .await
-        .unwrap();
-
-        listener.config_mut().max_frame_length(u32::MAX as usize);
+        let listener = tokio::net::TcpListener::bind($address).await.unwrap();
+        let listener =
+            $crate::rpc::incoming(listener, *$crate::rpc::WIRE, $crate::rpc::TLS.clone());
 
         async fn tokio_spawn(fut: impl Future<Output = ()> + Send + 'static) {
             tokio::spawn(fut);
         }
 
         listener
-            .filter_map(|r| futures::future::ready(r.ok()))
             .map(tarpc::server::BaseChannel::with_defaults)
             .map(|channel| {
                 tarpc::server::Channel::execute(channel, $server_state.clone().serve())
@@ -1,20 +1,38 @@
 use std::{
+    collections::VecDeque,
     future::Future,
     str::FromStr,
     sync::{Arc, LazyLock},
+    time::{Duration, Instant},
 };
 
+use chrono::Utc;
 use futures::StreamExt;
 use rustex_core::prelude::*;
 use rustex_errors::RustexError;
 use tarpc::context::Context;
-use tokio::task::JoinSet;
+use tokio::{
+    sync::{Mutex, MutexGuard, RwLock},
+    task::JoinSet,
+};
 
 use crate::{
-    create_tarpc_server, db_service::DbServiceClient, get_db_service_client, DB_RPC_ADDRESS,
+    create_tarpc_server,
+    db_service::DbServiceClient,
+    get_db_service_client,
+    outbox::{OutboxBacklog, TradeOutbox},
+    DB_RPC_ADDRESS,
 };
 use crate::{DEFAULT_ADDRESS, DEFAULT_MAX_NUMBER_CO_CONNECTIONS};
 const DEFAULT_PORT: u16 = 5555;
+const DEFAULT_SESSION_REAPER_INTERVAL_MS: u64 = 500;
+const MIN_SESSION_TIMEOUT_MS: u64 = 100;
+const MAX_SESSION_TIMEOUT_MS: u64 = 3_600_000;
+const RECONCILE_OUTBOX_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TRADE_TAPE_SIZE: usize = 1000;
+const DEFAULT_ID_BLOCK_SIZE: i64 = 1000;
+const LINKED_ORDER_ATTEMPTS: u32 = 3;
+const LINKED_ORDER_BACKOFF: Duration = Duration::from_millis(100);
 
 pub static ADDRESS: LazyLock<String> = LazyLock::new(|| {
     let addr = std::env::var("MATCH_RPC_ADDRESS")
@@ -32,30 +50,524 @@ static MAX_NUMBER_CO_CONNECTIONS: LazyLock<usize> = LazyLock::new(|| {
         .unwrap_or(DEFAULT_MAX_NUMBER_CO_CONNECTIONS)
 });
 
+static SESSION_REAPER_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    let interval_ms = std::env::var("MATCH_SESSION_REAPER_INTERVAL_MS")
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(DEFAULT_SESSION_REAPER_INTERVAL_MS);
+    Duration::from_millis(interval_ms)
+});
+
+/// Trades kept in memory. At most the maximum page size
+static TRADE_TAPE_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("MATCH_TRADE_TAPE_SIZE")
+        .ok()
+        .filter(|n| !n.is_empty())
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(DEFAULT_TRADE_TAPE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE)
+});
+
+/// Order and trade ids reserved at once. The next block is reserved
+/// when half of the current one is used
+static ID_BLOCK_SIZE: LazyLock<i64> = LazyLock::new(|| {
+    std::env::var("MATCH_ID_BLOCK_SIZE")
+        .ok()
+        .filter(|n| !n.is_empty())
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(DEFAULT_ID_BLOCK_SIZE)
+        .max(2)
+});
+
+/// Reconciliation is disabled unless an interval is configured
+static RECONCILE_INTERVAL: LazyLock<Option<Duration>> = LazyLock::new(|| {
+    std::env::var("MATCH_RECONCILE_INTERVAL_SECS")
+        .ok()
+        .filter(|n| !n.is_empty())
+        .map(|n| Duration::from_secs(n.parse().unwrap()))
+});
+
+static RECONCILE_REPAIR: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("MATCH_RECONCILE_REPAIR")
+        .ok()
+        .filter(|b| !b.is_empty())
+        .is_some_and(|b| b.parse().unwrap())
+});
+
 #[tarpc::service]
 pub trait MatchService {
-    async fn insert_order(user: UserId, client_order: ClientOrder) -> Result<OrderId, RustexError>;
-
-    async fn get_user_orders(user: UserId) -> Result<Vec<OrderId>, RustexError>;
+    /// Orders are audited as requested by the user from `source_ip`
+    async fn insert_order(
+        user: UserId,
+        client_order: ClientOrder,
+        source_ip: Option<String>,
+    ) -> Result<OrderExecution, RustexError>;
 
-    async fn get_order_progress(
+    /// Returns the status and fills of the order as recorded in the DB
+    async fn get_order_details(
         user: UserId,
         order_id: OrderId,
         market: ExchangeMarket,
-    ) -> Result<(bool, f64), RustexError>; // (is_pending, quantity_left)
+    ) -> Result<OrderDetails, RustexError>;
 
     async fn try_delete_order(
         user: UserId,
         order_id: OrderId,
         market: ExchangeMarket,
+        source_ip: Option<String>,
+    ) -> Result<bool, RustexError>;
+
+    /// Opens a session that cancels its bound orders if no heartbeat
+    /// is received within `timeout_ms`
+    async fn open_session(user: UserId, timeout_ms: u64) -> Result<SessionId, RustexError>;
+
+    /// Keeps the session (and its bound orders) alive
+    async fn heartbeat(user: UserId, session: SessionId) -> Result<(), RustexError>;
+
+    /// Closes the session cancelling all of its bound orders
+    async fn close_session(
+        user: UserId,
+        session: SessionId,
+        source_ip: Option<String>,
+    ) -> Result<Vec<OrderId>, RustexError>;
+
+    /// Places an OCO pair or a bracket order, audited as requested by the user from `source_ip`
+    async fn insert_order_group(
+        user: UserId,
+        client_group: ClientOrderGroup,
+        source_ip: Option<String>,
+    ) -> Result<OrderGroup, RustexError>;
+
+    async fn get_order_group(
+        user: UserId,
+        group_id: GroupId,
+        market: ExchangeMarket,
+    ) -> Result<OrderGroup, RustexError>;
+
+    /// Cancels every working order of the group. The group and its legs are audited
+    /// as cancelled by the user from `source_ip`
+    async fn try_delete_order_group(
+        user: UserId,
+        group_id: GroupId,
+        market: ExchangeMarket,
+        source_ip: Option<String>,
     ) -> Result<bool, RustexError>;
+
+    /// Reloads the pre-trade risk limits of the user from the DB
+    async fn refresh_risk_limits(user: UserId) -> Result<(), RustexError>;
+
+    /// Returns the midpoint between the best bid and ask of the book
+    async fn get_mid_price() -> Option<f64>;
+
+    /// Returns the most recent trades of the market, newest first
+    async fn get_trade_tape(query: TapeQuery) -> Result<Page<TapeTrade>, RustexError>;
+
+    /// Returns the trades executed but not yet recorded in the DB
+    async fn get_outbox_backlog() -> OutboxBacklog;
+
+    /// Compares the book with the pending orders of the DB. Repairing
+    /// makes the book match the DB, which is the source of truth
+    async fn reconcile(repair: bool) -> Result<ReconciliationReport, RustexError>;
 }
 
 #[derive(Clone)]
 pub struct MatchingServer {
     pub exchange: ExchangeMarket,
     pub order_book: Arc<OrderBook>,
+    pub sessions: Arc<SessionRegistry>,
+    pub risk: Arc<RiskEngine>,
+    pub outbox: Arc<TradeOutbox>,
+    pub tape: Arc<TradeTape>,
     pub db_rpc_client: Arc<DbServiceClient>,
+    pub book_gate: Arc<RwLock<()>>, // Held exclusively while reconciling
+    pub id_reservation: Arc<Mutex<()>>, // Held while reserving and handing out ids
+}
+
+impl MatchingServer {
+    /// Reserves ids for an order of the type: its own id, one for every trade it can
+    /// make and one for every linked order it can activate. The next block is reserved
+    /// ahead once half of the current one is used. The returned guard keeps other
+    /// orders from taking the ids, and must be held until they are handed out
+    async fn reserve_ids(
+        &self,
+        ctx: Context,
+        order_type: OrderType,
+    ) -> Result<MutexGuard<'_, ()>, RustexError> {
+        let reservation = self.id_reservation.lock().await;
+        let needed = [
+            (IdKind::Order, 1 + self.order_book.max_linked_orders()),
+            (IdKind::Trade, self.order_book.max_trades(order_type)),
+        ];
+        for (kind, needed) in needed {
+            let low = needed + *ID_BLOCK_SIZE / 2;
+            let remaining = self.order_book.remaining_ids(kind);
+            if remaining >= low {
+                continue;
+            }
+            let from = self.order_book.reservation_start(kind);
+            let count = (*ID_BLOCK_SIZE).max(low - remaining);
+            let block = self
+                .db_rpc_client
+                .reserve_ids(ctx, self.exchange, kind, from, count)
+                .await??;
+            self.order_book.extend_ids(kind, block);
+        }
+        Ok(reservation)
+    }
+
+    /// Removes the order from the book and records the cancellation.
+    /// Returns false if the order was no longer pending
+    async fn cancel_order(
+        &self,
+        ctx: Context,
+        order_id: OrderId,
+        status: OrderStatus,
+        origin: &AuditOrigin,
+    ) -> Result<bool, RustexError> {
+        let gate = self.book_gate.read().await;
+        if !self
+            .cancel_resting_order(ctx, order_id, status, origin)
+            .await?
+        {
+            return Ok(false);
+        }
+        drop(gate);
+        let linked_updates = self.order_book.cancel_linked_orders(order_id);
+        self.apply_linked_updates(ctx, linked_updates, origin).await;
+        Ok(true)
+    }
+
+    /// Same as [`Self::cancel_order`] without the linked orders,
+    /// for callers that already hold the book gate
+    async fn cancel_resting_order(
+        &self,
+        ctx: Context,
+        order_id: OrderId,
+        status: OrderStatus,
+        origin: &AuditOrigin,
+    ) -> Result<bool, RustexError> {
+        let Some(unmatched) = self.order_book.try_cancel_order(order_id) else {
+            return Ok(false);
+        };
+        self.sessions.release_orders(&[order_id]);
+        // Trades of the order still in the outbox keep their funds reserved until recorded
+        self.db_rpc_client
+            .insert_cancellation(
+                ctx,
+                self.exchange,
+                order_id,
+                status,
+                Some(unmatched),
+                origin.clone(),
+            )
+            .await??;
+        Ok(true)
+    }
+
+    /// Matches the order while recording it in the DB.
+    /// Trades are recorded in the background. Orders placed in a session
+    /// that expired meanwhile are cancelled once placed
+    async fn execute_order(
+        &self,
+        c: Context,
+        db_order: Order,
+        session_id: Option<SessionId>,
+        origin: &AuditOrigin,
+    ) -> Result<(OrderExecution, LinkedOrderUpdates), RustexError> {
+        // Persist-then-match. The order and its funds are recorded before it can
+        // trade, so a failed write leaves the book untouched and the client gets the
+        // error. Once matched, the trades are journaled in the outbox until recorded
+        let _gate = self.book_gate.read().await;
+        self.outbox.ensure_durable()?;
+        // Tops up the ids before recording the order, so matching rarely waits on the DB
+        drop(self.reserve_ids(c, db_order.order_type).await?);
+        self.db_rpc_client
+            .insert_order(c, db_order, origin.clone())
+            .await??;
+
+        let matched = async {
+            let ids = self.reserve_ids(c, db_order.order_type).await?;
+            let order_book = Arc::clone(&self.order_book);
+            let matched = match db_order.order_type {
+                OrderType::Buy => {
+                    tokio::task::spawn_blocking(move || {
+                        order_book.process_order(BuyOrder(db_order))
+                    })
+                    .await??
+                }
+                OrderType::Sell => {
+                    tokio::task::spawn_blocking(move || {
+                        order_book.process_order(SellOrder(db_order))
+                    })
+                    .await??
+                }
+            };
+            Ok::<_, RustexError>((ids, matched))
+        }
+        .await;
+        let (ids, (mut trades, completed_orders)) = match matched {
+            Ok(matched) => matched,
+            Err(e) => {
+                // Recorded but never matched. The outbox records the
+                // cancellation, retrying it until the funds are released
+                log::error!(
+                    "Failed to match order {:?}. Cancelling it: {:?}",
+                    db_order.order_id,
+                    e
+                );
+                let cancelled = vec![db_order.order_id];
+                drop(
+                    self.outbox
+                        .push_linked(self.exchange, cancelled, vec![], origin.clone()),
+                );
+                return Err(e);
+            }
+        };
+        self.sessions.release_orders(&completed_orders);
+        let executed_at = Utc::now();
+        trades
+            .iter_mut()
+            .for_each(|trade| trade.created_at = Some(executed_at));
+        self.tape.push(trades.iter().map(TapeTrade::from));
+
+        let execution = OrderExecution::from_trades(db_order.order_id, &trades);
+        let linked_updates = self
+            .order_book
+            .resolve_linked_orders(&trades, &completed_orders);
+        drop(ids);
+
+        let recorded = self.outbox.push(self.exchange, trades, completed_orders);
+        // The order executed, so its result is returned whatever happens to its linked orders
+        let linked_updates = linked_updates.unwrap_or_else(|e| {
+            log::error!(
+                "Failed to resolve the linked orders of {:?}: {:?}",
+                db_order.order_id,
+                e
+            );
+            LinkedOrderUpdates::default()
+        });
+        // The session reaper may have cancelled the session orders before this one
+        // rested. Release or cancellation unbinds it, so a resting order that is no
+        // longer bound outlived its session
+        let orphaned = session_id.is_some()
+            && self.order_book.is_order_pending(db_order.order_id)
+            && !self.sessions.is_bound(db_order.order_id);
+        if orphaned || !linked_updates.activated.is_empty() {
+            // The activated legs are funded by the settlement of these trades,
+            // and the cancellation releases what is left of the order after them
+            let _ = recorded.await;
+        }
+        if orphaned {
+            log::warn!(
+                "Session {:?} expired while placing {:?}. Cancelling it",
+                session_id,
+                db_order.order_id
+            );
+            let origin = AuditOrigin::default();
+            if let Err(e) = self
+                .cancel_resting_order(c, db_order.order_id, OrderStatus::Expired, &origin)
+                .await
+            {
+                log::error!(
+                    "Failed to cancel {:?} of expired session {:?}: {:?}",
+                    db_order.order_id,
+                    session_id,
+                    e
+                );
+            }
+        }
+        Ok((execution, linked_updates))
+    }
+
+    /// Records the cancelled legs and group states through the outbox, and places
+    /// the activated legs in the book. The order that caused the updates has already
+    /// been processed, so a leg that cannot be placed cancels its group instead.
+    /// The cancellations are audited as following from the request of `origin`, and
+    /// those caused by the activated legs as made by the exchange
+    async fn apply_linked_updates(
+        &self,
+        ctx: Context,
+        linked_updates: LinkedOrderUpdates,
+        origin: &AuditOrigin,
+    ) {
+        let mut queue = VecDeque::from([(linked_updates, origin.clone())]);
+        while let Some((updates, origin)) = queue.pop_front() {
+            self.sessions.release_orders(&updates.cancelled);
+            let recorded = self.outbox.push_linked(
+                self.exchange,
+                updates.cancelled,
+                updates.groups.clone(),
+                origin,
+            );
+            if updates.activated.is_empty() {
+                continue;
+            }
+            // The groups must be recorded before their legs can execute
+            let _ = recorded.await;
+            for order in updates.activated {
+                match self.place_linked_order(ctx, order).await {
+                    Ok(updates) => queue.push_back((updates, AuditOrigin::default())),
+                    Err(e) => {
+                        log::error!(
+                            "Failed to place linked order {:?}. Cancelling its group: {:?}",
+                            order,
+                            e
+                        );
+                        let group = updates.groups.iter().rev().find(|group| {
+                            [group.take_profit_order, group.stop_loss_order]
+                                .contains(&Some(order.order_id))
+                        });
+                        if let Some(group) = group {
+                            let updates = self
+                                .order_book
+                                .cancel_unplaced_leg(order.order_id, group.clone());
+                            queue.push_back((updates, AuditOrigin::default()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Places an activated leg once it passes the risk checks,
+    /// retrying the failures to record it
+    async fn place_linked_order(
+        &self,
+        ctx: Context,
+        order: Order,
+    ) -> Result<LinkedOrderUpdates, RustexError> {
+        self.risk.check_order(
+            order.user_id,
+            &ClientOrder::from(&order),
+            &self.order_book,
+            Instant::now(),
+        )?;
+        let mut backoff = LINKED_ORDER_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match self
+                .execute_order(ctx, order, None, &AuditOrigin::default())
+                .await
+            {
+                Ok((_, updates)) => {
+                    self.risk.record_order(order.user_id, Instant::now());
+                    return Ok(updates);
+                }
+                Err(e @ (RustexError::UserFacingError(_) | RustexError::RiskRejection(_))) => {
+                    return Err(e)
+                }
+                Err(e) if attempt == LINKED_ORDER_ATTEMPTS => return Err(e),
+                Err(e) => {
+                    log::warn!(
+                        "Failed to place linked order {:?}. Retrying in {:?}: {:?}",
+                        order.order_id,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Appends a change that is not recorded along with any write to the audit log.
+    /// Errors are only logged
+    async fn audit(&self, origin: &AuditOrigin, action: AuditAction, details: serde_json::Value) {
+        let event = origin.event(action, Some(self.exchange), details.to_string());
+        let audit = self.db_rpc_client.append_audit(Context::current(), event);
+        if let Err(e) = async { audit.await? }.await {
+            log::error!("Failed to audit {:?}: {:?}", action, e);
+        }
+    }
+
+    /// Cancels every order bound to a session returning those that were still pending
+    async fn cancel_session_orders(
+        &self,
+        order_ids: Vec<OrderId>,
+        status: OrderStatus,
+        origin: &AuditOrigin,
+    ) -> Vec<OrderId> {
+        let mut cancelled = Vec::with_capacity(order_ids.len());
+        for order_id in order_ids {
+            match self
+                .cancel_order(Context::current(), order_id, status, origin)
+                .await
+            {
+                Ok(true) => cancelled.push(order_id),
+                Ok(false) => (),
+                Err(e) => log::error!("Failed to cancel session order {:?}: {:?}", order_id, e),
+            }
+        }
+        cancelled
+    }
+
+    /// Compares the book with the DB. Order flow is paused and the outbox
+    /// drained so that both sides reflect the same executions
+    async fn reconcile_book(
+        &self,
+        ctx: Context,
+        repair: bool,
+    ) -> Result<ReconciliationReport, RustexError> {
+        let _gate = self.book_gate.write().await;
+        let deadline = Instant::now() + RECONCILE_OUTBOX_TIMEOUT;
+        while self.outbox.backlog().batches > 0 {
+            if Instant::now() > deadline {
+                return Err(RustexError::MatchServiceError(
+                    "Trades are still being recorded. Try to reconcile later".into(),
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let book_orders = self.order_book.open_orders();
+        let db_orders = load_pending_orders(&self.db_rpc_client, self.exchange).await?;
+        let discrepancies = find_discrepancies(&book_orders, &db_orders);
+        if repair {
+            for discrepancy in &discrepancies {
+                self.repair(ctx, discrepancy, &db_orders).await?;
+            }
+        }
+        Ok(ReconciliationReport {
+            exchange: self.exchange,
+            book_orders: book_orders.len(),
+            db_orders: db_orders.len(),
+            discrepancies,
+            repaired: repair,
+        })
+    }
+
+    async fn repair(
+        &self,
+        ctx: Context,
+        discrepancy: &Discrepancy,
+        db_orders: &[Order],
+    ) -> Result<(), RustexError> {
+        let order_id = discrepancy.order_id();
+        match discrepancy {
+            Discrepancy::MissingInDb { .. } => {
+                self.order_book.try_delete_order(order_id);
+                self.sessions.release_orders(&[order_id]);
+            }
+            Discrepancy::MissingInBook { .. } | Discrepancy::QuantityMismatch { .. } => {
+                let order = db_orders
+                    .iter()
+                    .find(|order| order.order_id == order_id)
+                    .expect("Discrepancy of a pending order");
+                self.order_book.restore_order(*order);
+            }
+            Discrepancy::FilledButPending { .. } => {
+                self.order_book.try_delete_order(order_id);
+                self.sessions.release_orders(&[order_id]);
+                // Completes the order releasing any funds left reserved
+                self.db_rpc_client
+                    .insert_trades(ctx, self.exchange, vec![], vec![order_id])
+                    .await??;
+            }
+        }
+        log::warn!("Repaired {:?} in {:?}", discrepancy, self.exchange);
+        Ok(())
+    }
 }
 
 impl MatchService for MatchingServer {
@@ -64,67 +576,65 @@ impl MatchService for MatchingServer {
         c: Context,
         user_id: UserId,
         client_order: ClientOrder,
-    ) -> Result<OrderId, RustexError> {
-        // Optimisitc Strategy. Executing Matching and DB logging in parallel
-        let (trades_fut, db_order) = match client_order.order_type {
-            OrderType::Buy => {
-                let buy_order: BuyOrder = self.order_book.into_order(client_order, user_id)?;
-                (
-                    tokio::task::spawn_blocking(move || self.order_book.process_order(buy_order)),
-                    buy_order.0,
-                )
+        source_ip: Option<String>,
+    ) -> Result<OrderExecution, RustexError> {
+        let origin = AuditOrigin::user(user_id, source_ip);
+        let accepted = async {
+            if let Some(session_id) = client_order.session_id {
+                self.sessions.validate(user_id, session_id)?;
             }
-            OrderType::Sell => {
-                let sell_order: SellOrder = self.order_book.into_order(client_order, user_id)?;
-                (
-                    tokio::task::spawn_blocking(move || self.order_book.process_order(sell_order)),
-                    sell_order.0,
-                )
+            self.risk
+                .check_order(user_id, &client_order, &self.order_book, Instant::now())?;
+            let ids = self.reserve_ids(c, client_order.order_type).await?;
+            let db_order: Order = self.order_book.into_order(client_order, user_id)?;
+            drop(ids);
+            if let Some(session_id) = client_order.session_id {
+                self.sessions
+                    .bind_order(user_id, session_id, db_order.order_id)?;
+            }
+            Ok::<_, RustexError>(db_order)
+        };
+        let db_order = match accepted.await {
+            Ok(db_order) => db_order,
+            Err(e) => {
+                // Rejected before it was recorded
+                let details = serde_json::json!({ "order": client_order, "error": e.to_string() });
+                self.audit(&origin, AuditAction::OrderRejected, details)
+                    .await;
+                return Err(e);
             }
         };
 
-        let db_client = Arc::clone(&self.db_rpc_client);
-        let db_record = tokio::spawn(async move { db_client.insert_order(c, db_order).await });
-
-        // Await concurrently the spawned tasks
-        let (db_record, trades) = tokio::join!(db_record, trades_fut);
-
-        // DANGER DANGER. What if only one of the two fails?
-        // TODO: Handle errors properly
-        let (_db_record, (trades, completed_orders)) = (db_record???, trades?);
-
-        let db_client = Arc::clone(&self.db_rpc_client);
-        tokio::spawn(async move {
-            let r = db_client
-                .insert_trades(c, self.exchange, trades, completed_orders)
-                .await;
-            match &r {
-                Ok(Ok(_)) => (),
-                _ => log::error!(
-                    "An error happened when recording the trades in the DB: {:?}",
-                    r
-                ),
+        let execution = self
+            .execute_order(c, db_order, client_order.session_id, &origin)
+            .await;
+        let (execution, linked_updates) = match execution {
+            Ok(r) => r,
+            Err(e) => {
+                self.sessions.release_orders(&[db_order.order_id]);
+                return Err(e);
             }
-        });
-        Ok(db_order.order_id)
+        };
+        self.risk.record_order(user_id, Instant::now());
+        self.apply_linked_updates(c, linked_updates, &origin).await;
+        Ok(execution)
     }
 
-    async fn get_order_progress(
+    async fn get_order_details(
         self,
         ctx: Context,
         user: UserId,
         order_id: OrderId,
         market: ExchangeMarket,
-    ) -> Result<(/*is pending=*/ bool, /*quantity left=*/ f64), RustexError> {
-        let order = self.db_rpc_client.get_orders(ctx, vec![order_id], market);
-        let trades = self.db_rpc_client.get_order_trades(ctx, order_id, market);
-
-        let (order, trades) = tokio::join!(order, trades);
-        let (orders, trades) = (order??, trades??);
+    ) -> Result<OrderDetails, RustexError> {
+        let orders = self
+            .db_rpc_client
+            .get_orders(ctx, vec![order_id], market)
+            .await??;
 
         if orders.len() != 1 {
-            return Err(RustexError::DbServiceError(
-                "Expecting to receive a single order".into(),
+            return Err(RustexError::UserFacingError(
+                "Requested order does not exist".into(),
             ));
         }
         let order = orders.first().unwrap();
@@ -138,26 +648,7 @@ impl MatchService for MatchingServer {
                 "Order exchange market do not match".into(),
             ));
         }
-
-        let mut remaining = order.quantity;
-        trades.into_iter().for_each(|q| {
-            remaining -= q.quantity;
-        });
-
-        let is_pending = self.order_book.is_order_pending(order_id); // Could have been cancelled
-        Ok((is_pending, remaining))
-    }
-
-    async fn get_user_orders(
-        self,
-        ctx: Context,
-        user: UserId,
-    ) -> Result<Vec<OrderId>, RustexError> {
-        let db_orders = self
-            .db_rpc_client
-            .get_user_orders(ctx, user, self.exchange)
-            .await??;
-        Ok(db_orders)
+        Ok(OrderDetails::from(*order))
     }
 
     async fn try_delete_order(
@@ -166,20 +657,21 @@ impl MatchService for MatchingServer {
         user: UserId,
         order_id: OrderId,
         market: ExchangeMarket,
+        source_ip: Option<String>,
     ) -> Result<bool, RustexError> {
+        if market != self.exchange {
+            return Err(RustexError::UserFacingError(
+                "Order exchange market do not match".into(),
+            ));
+        }
         let registered_user = self
             .db_rpc_client
             .get_order_user(ctx, order_id, market)
             .await??; // O(1) in db
         if registered_user.is_some_and(|reg_user| reg_user == user) {
-            if self.order_book.try_delete_order(order_id) {
-                self.db_rpc_client
-                    .insert_cancellation(ctx, market, order_id)
-                    .await??;
-                Ok(true)
-            } else {
-                Ok(false)
-            }
+            let origin = AuditOrigin::user(user, source_ip);
+            self.cancel_order(ctx, order_id, OrderStatus::Cancelled, &origin)
+                .await
         } else if registered_user.is_some() {
             Err(RustexError::AuthorizationError(
                 "You are not authorized to cancel this order".into(),
@@ -190,6 +682,207 @@ impl MatchService for MatchingServer {
             ))
         }
     }
+
+    async fn open_session(
+        self,
+        _: Context,
+        user: UserId,
+        timeout_ms: u64,
+    ) -> Result<SessionId, RustexError> {
+        if !(MIN_SESSION_TIMEOUT_MS..=MAX_SESSION_TIMEOUT_MS).contains(&timeout_ms) {
+            return Err(RustexError::UserFacingError(format!(
+                "Session timeout must be between {MIN_SESSION_TIMEOUT_MS} and {MAX_SESSION_TIMEOUT_MS} ms"
+            )));
+        }
+        Ok(self.sessions.open(user, Duration::from_millis(timeout_ms)))
+    }
+
+    async fn heartbeat(
+        self,
+        _: Context,
+        user: UserId,
+        session: SessionId,
+    ) -> Result<(), RustexError> {
+        self.sessions.heartbeat(user, session)
+    }
+
+    async fn close_session(
+        self,
+        _: Context,
+        user: UserId,
+        session: SessionId,
+        source_ip: Option<String>,
+    ) -> Result<Vec<OrderId>, RustexError> {
+        let order_ids = self.sessions.close(user, session)?;
+        let origin = AuditOrigin::user(user, source_ip);
+        Ok(self
+            .cancel_session_orders(order_ids, OrderStatus::Cancelled, &origin)
+            .await)
+    }
+
+    async fn insert_order_group(
+        self,
+        ctx: Context,
+        user: UserId,
+        client_group: ClientOrderGroup,
+        source_ip: Option<String>,
+    ) -> Result<OrderGroup, RustexError> {
+        let origin = AuditOrigin::user(user, source_ip);
+        let accepted = async {
+            let ids = self.reserve_ids(ctx, client_group.order_type).await?;
+            let (group, order) = self.order_book.into_order_group(client_group, user)?;
+            drop(ids);
+            let placed_order = ClientOrder::from(&order);
+            if let Err(e) =
+                self.risk
+                    .check_order(user, &placed_order, &self.order_book, Instant::now())
+            {
+                self.order_book.try_delete_group(group.group_id);
+                return Err(e);
+            }
+            Ok::<_, RustexError>((group, order))
+        };
+        let (group, order) = match accepted.await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Rejected before any of its orders was recorded
+                let details = serde_json::json!({ "group": client_group, "error": e.to_string() });
+                self.audit(&origin, AuditAction::OrderRejected, details)
+                    .await;
+                return Err(e);
+            }
+        };
+
+        // The group must be recorded before any of its orders can execute
+        if let Err(e) = self
+            .db_rpc_client
+            .upsert_order_groups(ctx, vec![group.clone()])
+            .await?
+        {
+            self.order_book.try_delete_group(group.group_id);
+            return Err(e);
+        }
+
+        let linked_updates = match self.execute_order(ctx, order, None, &origin).await {
+            Ok((_, linked_updates)) => linked_updates,
+            Err(e) => {
+                if let Some(linked_updates) = self.order_book.try_delete_group(group.group_id) {
+                    self.apply_linked_updates(ctx, linked_updates, &origin)
+                        .await;
+                }
+                return Err(e);
+            }
+        };
+        self.risk.record_order(user, Instant::now());
+        let details = serde_json::json!({ "group": group });
+        self.audit(&origin, AuditAction::GroupPlaced, details).await;
+        self.apply_linked_updates(ctx, linked_updates, &origin)
+            .await;
+        Ok(group)
+    }
+
+    async fn get_order_group(
+        self,
+        ctx: Context,
+        user: UserId,
+        group_id: GroupId,
+        market: ExchangeMarket,
+    ) -> Result<OrderGroup, RustexError> {
+        let group = match self.order_book.get_order_group(group_id) {
+            Some(group) if market == self.exchange => Some(group),
+            _ => {
+                self.db_rpc_client
+                    .get_order_group(ctx, group_id, market)
+                    .await??
+            }
+        };
+        match group {
+            Some(group) if group.user_id == user => Ok(group),
+            Some(_) => Err(RustexError::UserFacingError(
+                "The order group you requested does not match your user_id".into(),
+            )),
+            None => Err(RustexError::UserFacingError(
+                "Requested order group does not exist".into(),
+            )),
+        }
+    }
+
+    async fn try_delete_order_group(
+        self,
+        ctx: Context,
+        user: UserId,
+        group_id: GroupId,
+        market: ExchangeMarket,
+        source_ip: Option<String>,
+    ) -> Result<bool, RustexError> {
+        if market != self.exchange {
+            return Err(RustexError::UserFacingError(
+                "Order group exchange market do not match".into(),
+            ));
+        }
+        match self.order_book.get_order_group(group_id) {
+            Some(group) if group.user_id != user => {
+                return Err(RustexError::AuthorizationError(
+                    "You are not authorized to cancel this order group".into(),
+                ))
+            }
+            Some(_) => (),
+            None => return Ok(false), // Already done or cancelled
+        }
+        match self.order_book.try_delete_group(group_id) {
+            Some(linked_updates) => {
+                let origin = AuditOrigin::user(user, source_ip);
+                let details = serde_json::json!({ "groupId": group_id });
+                self.audit(&origin, AuditAction::GroupCancelled, details)
+                    .await;
+                self.apply_linked_updates(ctx, linked_updates, &origin)
+                    .await;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn refresh_risk_limits(self, ctx: Context, user: UserId) -> Result<(), RustexError> {
+        let limits = self.db_rpc_client.get_risk_limits(ctx, user).await??;
+        self.risk.set_limits(user, limits);
+        Ok(())
+    }
+    async fn get_mid_price(self, _: Context) -> Option<f64> {
+        self.order_book.mid_price()
+    }
+
+    async fn get_trade_tape(
+        self,
+        ctx: Context,
+        query: TapeQuery,
+    ) -> Result<Page<TapeTrade>, RustexError> {
+        let page_size = query.page_size()?;
+        let before = query.cursor.map(TradeId::from);
+        match self.tape.recent(before, page_size + 1) {
+            Some(trades) => Ok(Page::new(trades, page_size, |trade| {
+                i64::from(trade.trade_id)
+            })),
+            // Backfill of trades no longer in memory
+            None => {
+                self.db_rpc_client
+                    .get_trade_tape(ctx, self.exchange, query)
+                    .await?
+            }
+        }
+    }
+
+    async fn get_outbox_backlog(self, _: Context) -> OutboxBacklog {
+        self.outbox.backlog()
+    }
+
+    async fn reconcile(
+        self,
+        ctx: Context,
+        repair: bool,
+    ) -> Result<ReconciliationReport, RustexError> {
+        self.reconcile_book(ctx, repair).await
+    }
 }
 
 pub async fn start_service() {
@@ -205,7 +898,36 @@ pub async fn start_service() {
     let exchange = std::env::var("EXCHANGE_MARKET")
         .map(|env_var| ExchangeMarket::from_str(&env_var).unwrap())
         .expect("EXCHANGE_MARKET environment variable is not defined");
+
+    // The book is rebuilt from the DB. Trades left in the outbox must be recorded first
+    let outbox_path = std::env::var("MATCH_OUTBOX_PATH")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or_else(|| format!("{:?}.outbox", exchange));
+    let outbox = TradeOutbox::open(&outbox_path).unwrap_or_else(|e| {
+        panic!(
+            "Failed to open the outbox {:?}. Error: {:?}",
+            outbox_path, e
+        )
+    });
+    outbox.flush(&db_rpc_client).await;
+    let outbox = Arc::new(outbox);
+
     let book = initialize_order_book(Arc::clone(&db_rpc_client), exchange).await;
+    let tape_query = TapeQuery {
+        cursor: None,
+        limit: Some(*TRADE_TAPE_SIZE),
+    };
+    let recent_trades = db_rpc_client
+        .get_trade_tape(Context::current(), exchange, tape_query)
+        .await
+        .expect("TARPC Error collecting the recent trades")
+        .expect("Error Extracting the recent trades from the database");
+    let risk_limits = db_rpc_client
+        .get_all_risk_limits(Context::current())
+        .await
+        .expect("TARPC Error collecting the risk limits")
+        .expect("Error Extracting the risk limits from the database");
 
     // TODO: Gather order book from database
     // TODO: Specify which order book (by currency, etc...)
@@ -213,43 +935,133 @@ pub async fn start_service() {
         exchange,
         db_rpc_client,
         order_book: Arc::new(book),
+        sessions: Arc::new(SessionRegistry::new()),
+        risk: Arc::new(RiskEngine::new(risk_limits)),
+        outbox: Arc::clone(&outbox),
+        tape: Arc::new(TradeTape::new(
+            *TRADE_TAPE_SIZE,
+            recent_trades.items,
+            recent_trades.next_cursor.is_none(),
+        )),
+        book_gate: Arc::new(RwLock::new(())),
+        id_reservation: Arc::new(Mutex::new(())),
     };
 
+    let db_client = Arc::clone(&state.db_rpc_client);
+    tokio::spawn(async move { outbox.run(&db_client).await });
+
+    tokio::spawn(expire_sessions(state.clone()));
+    if let Some(interval) = *RECONCILE_INTERVAL {
+        tokio::spawn(reconcile_periodically(state.clone(), interval));
+    }
+
     let listener = create_tarpc_server!(ADDRESS.clone(), *MAX_NUMBER_CO_CONNECTIONS, state.clone());
     log::info!("Orders RPC:: listening on: {:?}", ADDRESS);
     listener.await
 }
 
+/// Cancel-on-disconnect: periodically cancels the orders
+/// bound to sessions that missed their heartbeat
+async fn expire_sessions(state: MatchingServer) {
+    let mut interval = tokio::time::interval(*SESSION_REAPER_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (session_id, order_ids) in state.sessions.expire(Instant::now()) {
+            let cancelled = state
+                .cancel_session_orders(order_ids, OrderStatus::Expired, &AuditOrigin::default())
+                .await;
+            log::warn!(
+                "Session {:?} missed its heartbeat. Cancelled orders: {:?}",
+                session_id,
+                cancelled
+            );
+            let details = serde_json::json!({ "sessionId": session_id, "orderIds": cancelled });
+            let origin = AuditOrigin::default();
+            state
+                .audit(&origin, AuditAction::OrdersExpired, details)
+                .await;
+        }
+    }
+}
+
+/// Logs the discrepancies found between the book and the DB
+async fn reconcile_periodically(state: MatchingServer, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await; // The book has just been loaded from the DB
+    loop {
+        interval.tick().await;
+        match state
+            .reconcile_book(Context::current(), *RECONCILE_REPAIR)
+            .await
+        {
+            Ok(report) if report.discrepancies.is_empty() => {
+                log::info!("Book of {:?} reconciled with the DB", report.exchange)
+            }
+            Ok(report) => log::error!("Book and DB diverged: {:?}", report),
+            Err(e) => log::error!("Failed to reconcile the book: {:?}", e),
+        }
+    }
+}
+
+/// Pending orders of the market with the quantity remaining to be traded
+async fn load_pending_orders(
+    db_rpc_client: &Arc<DbServiceClient>,
+    market: ExchangeMarket,
+) -> Result<Vec<Order>, RustexError> {
+    let pending_order_ids = db_rpc_client
+        .get_pending_orders_ids(Context::current(), market)
+        .await??;
+    let pending_orders = db_rpc_client
+        .get_orders(Context::current(), pending_order_ids, market)
+        .await??;
+
+    let mut order_tasks = JoinSet::new();
+    for mut order in pending_orders {
+        let db_client = Arc::clone(db_rpc_client);
+        order_tasks.spawn(async move {
+            let trades = db_client
+                .get_order_trades(Context::current(), order.order_id, market)
+                .await??;
+            // Have to update the quantity remaining to be traded
+            order.quantity = order.remaining_quantity(&trades);
+            Ok::<_, RustexError>(order)
+        });
+    }
+    order_tasks.join_all().await.into_iter().collect()
+}
+
 async fn initialize_order_book(
     db_rpc_client: Arc<DbServiceClient>,
     market: ExchangeMarket,
 ) -> OrderBook {
-    let (last_order, last_trade, pending_orders_ids) = tokio::join!(
-        db_rpc_client.get_last_order_id(Context::current()),
-        db_rpc_client.get_last_trade_id(Context::current()),
-        db_rpc_client.get_pending_orders_ids(Context::current(), market),
+    // Ids start where the sequences of the market left off, skipping any
+    // reserved before a crash
+    let (order_ids, trade_ids, last_price, last_group, pending_orders, order_groups) = tokio::join!(
+        db_rpc_client.reserve_ids(Context::current(), market, IdKind::Order, 0, *ID_BLOCK_SIZE),
+        db_rpc_client.reserve_ids(Context::current(), market, IdKind::Trade, 0, *ID_BLOCK_SIZE),
+        db_rpc_client.get_last_trade_price(Context::current(), market),
+        db_rpc_client.get_last_group_id(Context::current(), market),
+        load_pending_orders(&db_rpc_client, market),
+        db_rpc_client.get_open_order_groups(Context::current(), market),
     );
 
     // Panic on startup if any of these cannot be retrieved
-    let last_order = last_order
+    let order_ids = order_ids
         .unwrap() // Fail startup
+        .unwrap(); // Fail startup
+    let trade_ids = trade_ids
         .unwrap() // Fail startup
-        .map(|e| e + 1)
-        .unwrap_or(OrderId::from(0));
-    let last_trade = last_trade
+        .unwrap(); // Fail startup
+    let last_group = last_group
         .unwrap() // Fail startup
         .unwrap() // Fail startup
         .map(|e| e + 1)
-        .unwrap_or(TradeId::from(0));
+        .unwrap_or(GroupId::from(0));
+    let order_groups = order_groups
+        .expect("TARPC Error collecting open order groups")
+        .expect("Error Extracting open order groups from the database");
 
-    let pending_order_ids = pending_orders_ids
-        .expect("TARPC Error collecting pending order ids")
-        .expect("Error Extracting pending order ids from the database");
-    let pending_orders = db_rpc_client
-        .get_orders(Context::current(), pending_order_ids, market)
-        .await
-        .expect("TARPC Failed to collect pending orders")
-        .expect("DB Failed to collect pending orders");
+    let pending_orders = pending_orders.expect("Failed to sync the pending orders"); // Panic if cannot be synced
 
     let (buy_orders, sell_orders): (Vec<Order>, Vec<Order>) = pending_orders
         .into_iter()
@@ -257,38 +1069,432 @@ async fn initialize_order_book(
     let buy_orders: Vec<BuyOrder> = buy_orders.into_iter().map(BuyOrder::from).collect();
     let sell_orders: Vec<SellOrder> = sell_orders.into_iter().map(SellOrder::from).collect();
 
-    macro_rules! update_book_orders {
-        ($orders:ident, $fname:ident) => {{
-            let mut order_tasks = JoinSet::new();
-            for mut order in $orders {
-                let db_client = Arc::clone(&db_rpc_client);
-                order_tasks.spawn(async move {
-                    let trades = db_client
-                        .$fname(Context::current(), order.order_id, market)
-                        .await??;
-                    trades.into_iter().for_each(|trade| {
-                        // Have to update the quantity remaining to be traded
-                        order.quantity -= trade.quantity;
-                    });
-                    Ok::<_, RustexError>(order)
-                });
+    let book = OrderBook::from_db(
+        order_ids,
+        trade_ids,
+        last_group,
+        buy_orders,
+        sell_orders,
+        order_groups,
+        market,
+    );
+    let last_price = last_price
+        .expect("TARPC Error collecting the last trade price")
+        .expect("Error Extracting the last trade price from the database");
+    if let Some(price) = last_price {
+        book.set_last_price(price);
+    }
+    book
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tarpc::{
+        server::{Channel, Serve},
+        ServerError,
+    };
+
+    use super::*;
+    use crate::{
+        custody::LocalCustody,
+        db_service::{DbServer, DbService, DbServiceRequest, DbServiceResponse},
+        fixtures::fund,
+        storage::{MemoryStorage, Storage},
+    };
+
+    /// In-memory db-service whose writes can be made to fail
+    #[derive(Clone)]
+    struct FlakyDb {
+        storage: Arc<MemoryStorage>,
+        db: DbServer,
+        failing_order_inserts: Arc<AtomicUsize>,
+        failing_trade_inserts: Arc<AtomicUsize>,
+        trade_insert_attempts: Arc<AtomicUsize>,
+        empty_id_reservations: Arc<AtomicUsize>, // Answered with no ids, as if sold out
+        failing_id_reservations: Arc<AtomicUsize>,
+    }
+
+    impl FlakyDb {
+        fn new() -> Self {
+            let storage = Arc::new(MemoryStorage::new());
+            Self {
+                db: DbServer::with_storage(storage.clone(), Arc::new(LocalCustody::new())),
+                storage,
+                failing_order_inserts: Default::default(),
+                failing_trade_inserts: Default::default(),
+                trade_insert_attempts: Default::default(),
+                empty_id_reservations: Default::default(),
+                failing_id_reservations: Default::default(),
             }
-            order_tasks
+        }
+    }
+
+    fn should_fail(failures: &AtomicUsize) -> bool {
+        failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Serves the db-service over the storage, failing the injected writes
+    impl Serve for FlakyDb {
+        type Req = DbServiceRequest;
+        type Resp = DbServiceResponse;
+
+        async fn serve(self, ctx: Context, request: Self::Req) -> Result<Self::Resp, ServerError> {
+            let injected = || Err(RustexError::DbServiceError("Injected failure".into()));
+            match &request {
+                DbServiceRequest::InsertOrder { .. }
+                    if should_fail(&self.failing_order_inserts) =>
+                {
+                    return Ok(DbServiceResponse::InsertOrder(injected()));
+                }
+                DbServiceRequest::InsertTrades { .. } => {
+                    self.trade_insert_attempts.fetch_add(1, Ordering::SeqCst);
+                    if should_fail(&self.failing_trade_inserts) {
+                        return Ok(DbServiceResponse::InsertTrades(injected()));
+                    }
+                }
+                DbServiceRequest::ReserveIds { from, .. }
+                    if should_fail(&self.empty_id_reservations) =>
+                {
+                    let block = IdBlock {
+                        start: *from,
+                        end: *from,
+                    };
+                    return Ok(DbServiceResponse::ReserveIds(Ok(block)));
+                }
+                DbServiceRequest::ReserveIds { .. }
+                    if should_fail(&self.failing_id_reservations) =>
+                {
+                    let e = RustexError::DbServiceError("Injected failure".into());
+                    return Ok(DbServiceResponse::ReserveIds(Err(e)));
+                }
+                _ => (),
+            }
+            self.db.serve().serve(ctx, request).await
+        }
+    }
+
+    /// Serves the db-service in process
+    macro_rules! db_client {
+        ($db:expr) => {{
+            let (client_transport, server_transport) = tarpc::transport::channel::unbounded();
+            let server = tarpc::server::BaseChannel::with_defaults(server_transport);
+            tokio::spawn(server.execute($db).for_each(|response| async move {
+                tokio::spawn(response);
+            }));
+            DbServiceClient::new(tarpc::client::Config::default(), client_transport).spawn()
         }};
     }
 
-    let buy_orders = update_book_orders!(buy_orders, get_order_trades);
-    let sell_orders = update_book_orders!(sell_orders, get_order_trades);
-    let (buy_orders, sell_orders) = tokio::join!(buy_orders.join_all(), sell_orders.join_all());
+    fn matching_server(db_rpc_client: DbServiceClient, name: &str) -> MatchingServer {
+        let outbox_path =
+            std::env::temp_dir().join(format!("rustex-{}-{}.outbox", name, std::process::id()));
+        let _ = std::fs::remove_file(&outbox_path);
+        let _ = std::fs::remove_file(outbox_path.with_extension("outbox.dead"));
+        MatchingServer {
+            exchange: ExchangeMarket::BTC_USD,
+            order_book: Arc::new(OrderBook::new(ExchangeMarket::BTC_USD)),
+            sessions: Arc::new(SessionRegistry::new()),
+            risk: Arc::new(RiskEngine::default()),
+            outbox: Arc::new(TradeOutbox::open(outbox_path).unwrap()),
+            tape: Arc::new(TradeTape::new(10, vec![], true)),
+            db_rpc_client: Arc::new(db_rpc_client),
+            book_gate: Arc::new(RwLock::new(())),
+            id_reservation: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
 
-    let buy_orders = buy_orders
-        .into_iter()
-        .map(|buy_order| buy_order.expect("Failed to sync a specific buy order")) // Panic if cannot be synced
-        .collect::<Vec<_>>();
-    let sell_orders = sell_orders
-        .into_iter()
-        .map(|sell_order| sell_order.expect("Failed to sync a specific sell order")) // Panic if cannot be synced
-        .collect::<Vec<_>>();
+    fn client_order(order_type: OrderType, price: i64, quantity: f64) -> ClientOrder {
+        ClientOrder {
+            price,
+            quantity,
+            exchange: ExchangeMarket::BTC_USD,
+            order_type,
+            session_id: None,
+            notional: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_order_persistence_leaves_book_untouched() {
+        let db = FlakyDb::new();
+        let server = matching_server(db_client!(db.clone()), "failed-persistence");
+        let user = UserId::from(1);
+        fund(&*db.storage, user, Currencies::BTC, 1.0).await;
+        fund(&*db.storage, user, Currencies::USD, 100.0).await;
+
+        db.failing_order_inserts.store(1, Ordering::SeqCst);
+        let sell = client_order(OrderType::Sell, 100, 1.0);
+        let r = server
+            .clone()
+            .insert_order(Context::current(), user, sell, None)
+            .await;
+        assert!(matches!(r, Err(RustexError::DbServiceError(_))));
+
+        // The rejected sell order never reached the book
+        let buy = client_order(OrderType::Buy, 100, 1.0);
+        let execution = server
+            .clone()
+            .insert_order(Context::current(), user, buy, None)
+            .await
+            .unwrap();
+        assert_eq!(execution.filled_quantity, 0.0);
+        let pending = db.storage.get_pending_orders_ids(server.exchange).await;
+        assert_eq!(pending.unwrap(), vec![execution.order_id]);
+        assert_eq!(server.outbox.backlog(), OutboxBacklog::default());
+    }
+
+    #[tokio::test]
+    async fn test_orders_failing_to_match_are_cancelled() {
+        let db = FlakyDb::new();
+        let server = matching_server(db_client!(db.clone()), "failed-match");
+        let user = UserId::from(1);
+        fund(&*db.storage, user, Currencies::USD, 100.0).await;
 
-    OrderBook::from_db(last_order, last_trade, buy_orders, sell_orders, market)
+        // The order is recorded, but no trade ids can be reserved to match it
+        let order_ids = IdBlock {
+            start: 0,
+            end: 1000,
+        };
+        server.order_book.extend_ids(IdKind::Order, order_ids);
+        db.empty_id_reservations.store(2, Ordering::SeqCst);
+        db.failing_id_reservations.store(1, Ordering::SeqCst);
+        let buy = client_order(OrderType::Buy, 100, 1.0);
+        let source_ip = Some("10.0.0.1".to_string());
+        let r = server
+            .clone()
+            .insert_order(Context::current(), user, buy, source_ip.clone())
+            .await;
+        assert!(matches!(r, Err(RustexError::DbServiceError(_))));
+
+        server.outbox.flush(&server.db_rpc_client).await;
+        let orders = db.storage.get_orders(vec![0.into()], server.exchange);
+        assert_eq!(orders.await.unwrap()[0].status, OrderStatus::Cancelled);
+        // Audited as following from the order request
+        let log = db.storage.get_audit_log(None, 10).await.unwrap();
+        let cancellation = log.last().unwrap();
+        assert_eq!(cancellation.action, AuditAction::OrderCancelled);
+        assert_eq!(
+            (cancellation.actor, &cancellation.source_ip),
+            (Some(user), &source_ip)
+        );
+        let balances = db.storage.get_user_balances(user).await.unwrap();
+        assert_eq!((balances[0].available, balances[0].reserved), (100.0, 0.0));
+        assert!(!server.order_book.is_order_pending(0.into()));
+    }
+
+    #[tokio::test]
+    async fn test_failed_trade_persistence_is_retried() {
+        let db = FlakyDb::new();
+        let server = matching_server(db_client!(db.clone()), "failed-trades");
+        let user = UserId::from(1);
+        fund(&*db.storage, user, Currencies::BTC, 1.0).await;
+        fund(&*db.storage, user, Currencies::USD, 100.0).await;
+
+        let sell = client_order(OrderType::Sell, 100, 1.0);
+        let buy = client_order(OrderType::Buy, 100, 1.0);
+        server
+            .clone()
+            .insert_order(Context::current(), user, sell, None)
+            .await
+            .unwrap();
+        db.failing_trade_inserts.store(2, Ordering::SeqCst);
+        let execution = server
+            .clone()
+            .insert_order(Context::current(), user, buy, None)
+            .await
+            .unwrap();
+        assert_eq!(execution.filled_quantity, 1.0);
+
+        // The match is kept in the outbox until the DB records it
+        assert_eq!(
+            server.outbox.backlog(),
+            OutboxBacklog {
+                batches: 1,
+                trades: 1,
+                dead_letters: 0
+            }
+        );
+        server.outbox.flush(&server.db_rpc_client).await;
+        assert_eq!(server.outbox.backlog(), OutboxBacklog::default());
+        let trades = db
+            .storage
+            .get_order_trades(execution.order_id, server.exchange)
+            .await;
+        assert_eq!(trades.unwrap().len(), 1);
+        assert_eq!(db.trade_insert_attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_refused_trades_are_dead_lettered() {
+        let db = FlakyDb::new();
+        let mut server = matching_server(db_client!(db.clone()), "dead-letters");
+        let outbox_path =
+            std::env::temp_dir().join(format!("rustex-dead-letters-{}.outbox", std::process::id()));
+        server.outbox = Arc::new(
+            TradeOutbox::open(&outbox_path)
+                .unwrap()
+                .with_max_rejections(2),
+        );
+        let user = UserId::from(1);
+        fund(&*db.storage, user, Currencies::BTC, 1.0).await;
+        fund(&*db.storage, user, Currencies::USD, 100.0).await;
+
+        let sell = client_order(OrderType::Sell, 100, 1.0);
+        let buy = client_order(OrderType::Buy, 100, 1.0);
+        server
+            .clone()
+            .insert_order(Context::current(), user, sell, None)
+            .await
+            .unwrap();
+        db.failing_trade_inserts.store(2, Ordering::SeqCst);
+        server
+            .clone()
+            .insert_order(Context::current(), user, buy, None)
+            .await
+            .unwrap();
+
+        // Refused twice, so it is set aside and survives restarts
+        server.outbox.flush(&server.db_rpc_client).await;
+        let dead_lettered = OutboxBacklog {
+            batches: 0,
+            trades: 0,
+            dead_letters: 1,
+        };
+        assert_eq!(server.outbox.backlog(), dead_lettered);
+        assert_eq!(db.trade_insert_attempts.load(Ordering::SeqCst), 2);
+        let reopened = TradeOutbox::open(&outbox_path).unwrap();
+        assert_eq!(reopened.backlog(), dead_lettered);
+        let _ = std::fs::remove_file(outbox_path.with_extension("outbox.dead"));
+    }
+
+    #[tokio::test]
+    async fn test_matches_are_settled_in_memory_storage() {
+        let db = FlakyDb::new();
+        let server = matching_server(db_client!(db.clone()), "memory-storage");
+        let (seller, buyer) = (UserId::from(1), UserId::from(2));
+        fund(&*db.storage, seller, Currencies::BTC, 1.0).await;
+        fund(&*db.storage, buyer, Currencies::USD, 150.0).await;
+
+        let sell = client_order(OrderType::Sell, 100, 1.0);
+        let sell = server
+            .clone()
+            .insert_order(Context::current(), seller, sell, None)
+            .await
+            .unwrap();
+        let buy = client_order(OrderType::Buy, 120, 1.0);
+        server
+            .clone()
+            .insert_order(Context::current(), buyer, buy, None)
+            .await
+            .unwrap();
+        server.outbox.flush(&server.db_rpc_client).await;
+
+        // The buyer gets back the price improvement
+        for (user, expected) in [
+            (seller, [(Currencies::BTC, 0.0), (Currencies::USD, 100.0)]),
+            (buyer, [(Currencies::BTC, 1.0), (Currencies::USD, 50.0)]),
+        ] {
+            let balances = db.storage.get_user_balances(user).await.unwrap();
+            let balances = balances
+                .iter()
+                .map(|b| (b.currency, b.available + b.reserved))
+                .collect::<Vec<_>>();
+            assert_eq!(balances, expected);
+        }
+        let orders = db.storage.get_orders(vec![sell.order_id], server.exchange);
+        let orders = orders.await.unwrap();
+        assert_eq!(orders[0].status, OrderStatus::Filled);
+    }
+
+    #[tokio::test]
+    async fn test_orders_outliving_their_session_are_cancelled() {
+        let db = FlakyDb::new();
+        let server = matching_server(db_client!(db.clone()), "expired-session");
+        let user = UserId::from(1);
+        fund(&*db.storage, user, Currencies::USD, 100.0).await;
+
+        // The reaper expires the session while the order is being placed
+        let session_id = server.sessions.open(user, Duration::from_secs(60));
+        let mut buy = client_order(OrderType::Buy, 100, 1.0);
+        buy.session_id = Some(session_id);
+        let ids = server
+            .reserve_ids(Context::current(), OrderType::Buy)
+            .await
+            .unwrap();
+        let order: Order = server.order_book.into_order(buy, user).unwrap();
+        drop(ids);
+        server
+            .sessions
+            .bind_order(user, session_id, order.order_id)
+            .unwrap();
+        assert_eq!(
+            server.sessions.close(user, session_id).unwrap(),
+            vec![order.order_id]
+        );
+
+        server
+            .execute_order(
+                Context::current(),
+                order,
+                Some(session_id),
+                &AuditOrigin::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!server.order_book.is_order_pending(order.order_id));
+        let orders = db.storage.get_orders(vec![order.order_id], server.exchange);
+        assert_eq!(orders.await.unwrap()[0].status, OrderStatus::Expired);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_linked_order_cancels_its_group() {
+        let db = FlakyDb::new();
+        let server = matching_server(db_client!(db.clone()), "rejected-leg");
+        // Activated legs wait for the trades that fund them to be recorded
+        let (outbox, db_rpc_client) = (server.outbox.clone(), server.db_rpc_client.clone());
+        tokio::spawn(async move { outbox.run(&db_rpc_client).await });
+        let (seller, trader) = (UserId::from(1), UserId::from(2));
+        fund(&*db.storage, seller, Currencies::BTC, 1.0).await;
+        fund(&*db.storage, trader, Currencies::USD, 100.0).await;
+
+        let bracket = ClientOrderGroup {
+            exchange: ExchangeMarket::BTC_USD,
+            order_type: OrderType::Sell,
+            quantity: 1.0,
+            take_profit_price: 120,
+            stop_price: 90,
+            stop_limit_price: None,
+            entry_price: Some(100),
+        };
+        let group = server
+            .clone()
+            .insert_order_group(Context::current(), trader, bracket, None)
+            .await
+            .unwrap();
+        // The take profit deviates too much from the entry price
+        let limits = RiskLimits {
+            user_id: trader,
+            max_price_deviation: Some(0.1),
+            ..Default::default()
+        };
+        server.risk.set_limits(trader, Some(limits));
+
+        let sell = client_order(OrderType::Sell, 100, 1.0);
+        server
+            .clone()
+            .insert_order(Context::current(), seller, sell, None)
+            .await
+            .unwrap();
+        server.outbox.flush(&server.db_rpc_client).await;
+
+        assert!(server.order_book.get_order_group(group.group_id).is_none());
+        let recorded = db.storage.get_order_group(group.group_id, server.exchange);
+        let recorded = recorded.await.unwrap().unwrap();
+        assert_eq!(recorded.status, OrderGroupStatus::Cancelled);
+        assert_eq!(recorded.take_profit_order, None);
+    }
 }
@@ -0,0 +1,501 @@
+//! Durable outbox of the trades executed by the match-service and of the
+//! linked order updates they cause. Batches are journaled to disk before being acknowledged to the client
+//! and are retried with backoff until the db-service records them. Batches the db-service keeps
+//! refusing are moved to a dead-letter journal for an operator to resolve
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rustex_core::{lock, prelude::*};
+use rustex_errors::RustexError;
+use serde::{Deserialize, Serialize};
+use tarpc::{client::RpcError, context::Context};
+use tokio::sync::{oneshot, Notify};
+
+use crate::db_service::DbServiceClient;
+
+const MIN_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REJECTIONS: u32 = 10;
+
+/// Times the db-service may refuse a batch before it is dead-lettered.
+/// Transport errors are retried forever, as the batch may never have reached it
+static MAX_REJECTIONS: LazyLock<u32> = LazyLock::new(|| {
+    std::env::var("MATCH_OUTBOX_MAX_REJECTIONS")
+        .ok()
+        .filter(|n| !n.is_empty())
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(DEFAULT_MAX_REJECTIONS)
+        .max(1)
+});
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TradeBatch {
+    batch_id: u64,
+    market: ExchangeMarket,
+    trades: Vec<Trade>,
+    completed_orders: Vec<OrderId>,
+    #[serde(default)]
+    cancelled_orders: Vec<OrderId>, // Linked legs and unmatched orders cancelled by the exchange
+    #[serde(default)]
+    groups: Vec<OrderGroup>,
+    #[serde(default)]
+    origin: AuditOrigin, // Request the cancellations follow from
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum JournalRecord {
+    Pending(TradeBatch),
+    Acked(u64),
+}
+
+/// Batch the db-service kept refusing, along with its last error
+#[derive(Serialize, Deserialize, Debug)]
+struct DeadLetter {
+    batch: TradeBatch,
+    error: String,
+    failed_at: DateTime<Utc>,
+}
+
+/// Why a batch was not recorded
+#[derive(Debug)]
+enum DeliveryError {
+    Transport(RpcError),   // It may not have reached the db-service
+    Rejected(RustexError), // The db-service refused it
+}
+
+/// Trade batches not yet confirmed by the db-service, and
+/// those dead-lettered after it kept refusing them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxBacklog {
+    pub batches: usize,
+    pub trades: usize,
+    #[serde(default)]
+    pub dead_letters: usize,
+}
+
+#[derive(Debug, Default)]
+struct OutboxState {
+    batches: VecDeque<TradeBatch>,
+    trade_keys: HashSet<(TradeId, ExchangeMarket)>, // Trades waiting in the outbox
+    waiters: HashMap<u64, oneshot::Sender<()>>,
+    next_batch_id: u64,
+    dead_letters: usize,
+}
+
+#[derive(Debug)]
+pub struct TradeOutbox {
+    inner: Mutex<OutboxState>,
+    journal: Mutex<File>,
+    journal_failed: AtomicBool, // A batch is only kept in memory
+    dead_letters: Mutex<File>,
+    max_rejections: u32,
+    notify: Notify,
+}
+
+impl TradeOutbox {
+    /// Opens the journal recovering the batches that were never acknowledged.
+    /// Dead letters are kept next to it, in `{path}.dead`
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut state = OutboxState::default();
+        if path.as_ref().exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                match serde_json::from_str(&line?) {
+                    Ok(JournalRecord::Pending(batch)) => {
+                        state.next_batch_id = state.next_batch_id.max(batch.batch_id + 1);
+                        state.push(batch);
+                    }
+                    Ok(JournalRecord::Acked(batch_id)) => state.remove(batch_id),
+                    // Only the last record can be torn by a crash
+                    Err(e) => log::warn!("Skipping corrupted outbox record: {:?}", e),
+                }
+            }
+        }
+        if !state.batches.is_empty() {
+            log::warn!(
+                "Recovered {} unacknowledged trade batches from the outbox",
+                state.batches.len()
+            );
+        }
+        let dead_letters_path = dead_letters_path(path.as_ref());
+        if dead_letters_path.exists() {
+            state.dead_letters = BufReader::new(File::open(&dead_letters_path)?)
+                .lines()
+                .count();
+        }
+        if state.dead_letters > 0 {
+            log::error!(
+                "The outbox holds {} dead-lettered batches in {:?}. They must be resolved by hand",
+                state.dead_letters,
+                dead_letters_path
+            );
+        }
+        let journal = OpenOptions::new().create(true).append(true).open(path)?;
+        let dead_letters = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dead_letters_path)?;
+        Ok(Self {
+            inner: Mutex::new(state),
+            journal: Mutex::new(journal),
+            journal_failed: AtomicBool::new(false),
+            dead_letters: Mutex::new(dead_letters),
+            max_rejections: *MAX_REJECTIONS,
+            notify: Notify::new(),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_max_rejections(mut self, max_rejections: u32) -> Self {
+        self.max_rejections = max_rejections;
+        self
+    }
+
+    /// Fails while a batch could not be journaled, so that no more orders are matched
+    /// until it is delivered and the journal can be written again
+    pub fn ensure_durable(&self) -> Result<(), RustexError> {
+        if !self.journal_failed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let inner = lock!(self.inner);
+        if inner.batches.is_empty() {
+            let journal = lock!(self.journal);
+            if journal.set_len(0).and_then(|_| journal.sync_data()).is_ok() {
+                self.journal_failed.store(false, Ordering::SeqCst);
+                log::warn!("The outbox journal can be written again. Resuming order flow");
+                return Ok(());
+            }
+        }
+        Err(RustexError::MatchServiceError(
+            "Executed trades cannot be journaled. Orders are suspended".into(),
+        ))
+    }
+
+    /// Durably enqueues the trades. Trades already in the outbox are skipped.
+    /// The receiver resolves once the db-service has recorded the batch.
+    /// The trades have already been matched, so if the journal cannot be
+    /// written the batch is still delivered from memory, and orders are
+    /// suspended until then (see [`Self::ensure_durable`])
+    pub fn push(
+        &self,
+        market: ExchangeMarket,
+        trades: Vec<Trade>,
+        completed_orders: Vec<OrderId>,
+    ) -> oneshot::Receiver<()> {
+        let inner = lock!(self.inner);
+        let trades = trades
+            .into_iter()
+            .filter(|trade| !inner.trade_keys.contains(&(trade.trade_id, trade.exchange)))
+            .collect::<Vec<_>>();
+        let batch = TradeBatch {
+            batch_id: inner.next_batch_id,
+            market,
+            trades,
+            completed_orders,
+            cancelled_orders: vec![],
+            groups: vec![],
+            origin: AuditOrigin::default(),
+        };
+        self.enqueue(inner, batch)
+    }
+
+    /// Durably enqueues the orders cancelled by the exchange and the group
+    /// states after the trades pushed so far. They are recorded in the same order.
+    /// The cancellations are audited as following from the request of `origin`
+    pub fn push_linked(
+        &self,
+        market: ExchangeMarket,
+        cancelled_orders: Vec<OrderId>,
+        groups: Vec<OrderGroup>,
+        origin: AuditOrigin,
+    ) -> oneshot::Receiver<()> {
+        let inner = lock!(self.inner);
+        let batch = TradeBatch {
+            batch_id: inner.next_batch_id,
+            market,
+            trades: vec![],
+            completed_orders: vec![],
+            cancelled_orders,
+            groups,
+            origin,
+        };
+        self.enqueue(inner, batch)
+    }
+
+    fn enqueue(
+        &self,
+        mut inner: MutexGuard<'_, OutboxState>,
+        batch: TradeBatch,
+    ) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        if batch.trades.is_empty()
+            && batch.completed_orders.is_empty()
+            && batch.cancelled_orders.is_empty()
+            && batch.groups.is_empty()
+        {
+            let _ = sender.send(());
+            return receiver;
+        }
+
+        if let Err(e) = append(&self.journal, &JournalRecord::Pending(batch.clone())) {
+            self.journal_failed.store(true, Ordering::SeqCst);
+            log::error!(
+                "Failed to journal trade batch {}. Suspending orders until it is recorded: {:?}",
+                batch.batch_id,
+                e
+            );
+        }
+        inner.next_batch_id += 1;
+        inner.waiters.insert(batch.batch_id, sender);
+        inner.push(batch);
+        drop(inner);
+
+        self.notify.notify_one();
+        receiver
+    }
+
+    pub fn backlog(&self) -> OutboxBacklog {
+        let inner = lock!(self.inner);
+        OutboxBacklog {
+            batches: inner.batches.len(),
+            trades: inner.trade_keys.len(),
+            dead_letters: inner.dead_letters,
+        }
+    }
+
+    /// Delivers the pending batches until the outbox is empty
+    pub async fn flush(&self, db_client: &DbServiceClient) {
+        let mut retry = Retry::default();
+        while let Some(batch) = self.front() {
+            self.deliver(db_client, batch, &mut retry).await;
+        }
+    }
+
+    /// Delivers the batches in order as they are pushed
+    pub async fn run(&self, db_client: &DbServiceClient) {
+        let mut retry = Retry::default();
+        loop {
+            match self.front() {
+                Some(batch) => self.deliver(db_client, batch, &mut retry).await,
+                None => self.notify.notified().await,
+            }
+        }
+    }
+
+    async fn deliver(&self, db_client: &DbServiceClient, batch: TradeBatch, retry: &mut Retry) {
+        match record(db_client, batch.clone()).await {
+            Ok(()) => {
+                *retry = Retry::default();
+                if let Err(e) = self.ack(batch.batch_id) {
+                    log::error!("Failed to acknowledge outbox batch: {:?}", e);
+                }
+            }
+            Err(DeliveryError::Rejected(e)) if retry.rejections + 1 >= self.max_rejections => {
+                match self.dead_letter(batch, &e) {
+                    Ok(()) => *retry = Retry::default(),
+                    Err(journal_error) => {
+                        log::error!("Failed to dead-letter outbox batch: {:?}", journal_error);
+                        retry.wait().await;
+                    }
+                }
+            }
+            Err(DeliveryError::Transport(e)) => {
+                log::error!(
+                    "Failed to reach the db-service to record the trades. Retrying in {:?}: {:?}",
+                    retry.backoff,
+                    e
+                );
+                retry.wait().await;
+            }
+            Err(DeliveryError::Rejected(e)) => {
+                retry.rejections += 1;
+                log::error!(
+                    "The DB refused to record the trades ({} of {} attempts). Retrying in {:?}: {:?}",
+                    retry.rejections,
+                    self.max_rejections,
+                    retry.backoff,
+                    e
+                );
+                retry.wait().await;
+            }
+        }
+    }
+
+    /// Moves the batch to the dead-letter journal, so that the batches after it can be recorded
+    fn dead_letter(&self, batch: TradeBatch, e: &RustexError) -> Result<(), RustexError> {
+        let batch_id = batch.batch_id;
+        log::error!(
+            "The DB refused outbox batch {} {} times. Moving it to the dead letters, \
+             its trades and cancellations must be recorded by hand: {:?}",
+            batch_id,
+            self.max_rejections,
+            e
+        );
+        let letter = DeadLetter {
+            batch,
+            error: e.to_string(),
+            failed_at: Utc::now(),
+        };
+        append(&self.dead_letters, &letter)?;
+        lock!(self.inner).dead_letters += 1;
+        self.ack(batch_id)
+    }
+
+    fn front(&self) -> Option<TradeBatch> {
+        lock!(self.inner).batches.front().cloned()
+    }
+
+    fn ack(&self, batch_id: u64) -> Result<(), RustexError> {
+        let mut inner = lock!(self.inner);
+        inner.remove(batch_id);
+        if let Some(waiter) = inner.waiters.remove(&batch_id) {
+            let _ = waiter.send(());
+        }
+        if inner.batches.is_empty() {
+            // Nothing left to recover. Compact the journal
+            lock!(self.journal).set_len(0).map_err(io_error)
+        } else {
+            append(&self.journal, &JournalRecord::Acked(batch_id))
+        }
+    }
+}
+
+/// Backoff before the next attempt, and how many times in a row the batch was refused
+#[derive(Debug)]
+struct Retry {
+    backoff: Duration,
+    rejections: u32,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            backoff: MIN_RETRY_BACKOFF,
+            rejections: 0,
+        }
+    }
+}
+
+impl Retry {
+    async fn wait(&mut self) {
+        tokio::time::sleep(self.backoff).await;
+        self.backoff = (self.backoff * 2).min(MAX_RETRY_BACKOFF);
+    }
+}
+
+/// Records the batch. Recording a cancellation twice is a no-op,
+/// so a partly delivered batch is retried whole
+async fn record(db_client: &DbServiceClient, batch: TradeBatch) -> Result<(), DeliveryError> {
+    let ctx = Context::current();
+    if !batch.trades.is_empty() || !batch.completed_orders.is_empty() {
+        let r = db_client
+            .insert_trades(ctx, batch.market, batch.trades, batch.completed_orders)
+            .await;
+        delivered(r)?;
+    }
+    for order_id in batch.cancelled_orders {
+        let r = db_client
+            .insert_cancellation(
+                ctx,
+                batch.market,
+                order_id,
+                OrderStatus::Cancelled,
+                None, // The trades of the order were recorded before
+                batch.origin.clone(),
+            )
+            .await;
+        delivered(r)?;
+    }
+    if !batch.groups.is_empty() {
+        delivered(db_client.upsert_order_groups(ctx, batch.groups).await)?;
+    }
+    Ok(())
+}
+
+fn delivered<T>(r: Result<Result<T, RustexError>, RpcError>) -> Result<T, DeliveryError> {
+    r.map_err(DeliveryError::Transport)?
+        .map_err(DeliveryError::Rejected)
+}
+
+fn append(file: &Mutex<File>, record: &impl Serialize) -> Result<(), RustexError> {
+    let mut line = serde_json::to_string(record)
+        .map_err(|e| RustexError::OtherInternal(e.to_string().into()))?;
+    line.push('\n');
+    let mut file = lock!(file);
+    file.write_all(line.as_bytes()).map_err(io_error)?;
+    file.sync_data().map_err(io_error)
+}
+
+fn dead_letters_path(path: &Path) -> PathBuf {
+    let mut dead_letters = OsString::from(path.as_os_str());
+    dead_letters.push(".dead");
+    dead_letters.into()
+}
+
+impl OutboxState {
+    fn push(&mut self, batch: TradeBatch) {
+        self.trade_keys.extend(
+            batch
+                .trades
+                .iter()
+                .map(|trade| (trade.trade_id, trade.exchange)),
+        );
+        self.batches.push_back(batch);
+    }
+
+    fn remove(&mut self, batch_id: u64) {
+        if let Some(position) = self
+            .batches
+            .iter()
+            .position(|batch| batch.batch_id == batch_id)
+        {
+            let batch = self.batches.remove(position).unwrap();
+            batch.trades.iter().for_each(|trade| {
+                self.trade_keys.remove(&(trade.trade_id, trade.exchange));
+            });
+        }
+    }
+}
+
+fn io_error(e: std::io::Error) -> RustexError {
+    RustexError::OtherInternal(e.to_string().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orders_are_suspended_while_the_journal_fails() {
+        let path =
+            std::env::temp_dir().join(format!("rustex-{}-journal.outbox", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let outbox = TradeOutbox::open(&path).unwrap();
+        // Every write fails as the disk is full
+        let journal = OpenOptions::new().write(true).open("/dev/full").unwrap();
+        let journal = std::mem::replace(&mut *lock!(outbox.journal), journal);
+
+        let completed_orders = vec![OrderId::from(0)];
+        outbox.push(ExchangeMarket::BTC_USD, vec![], completed_orders);
+        assert!(outbox.ensure_durable().is_err());
+
+        // Resumed once the batch is recorded and the journal can be written
+        *lock!(outbox.journal) = journal;
+        assert!(outbox.ensure_durable().is_err());
+        outbox.ack(0).unwrap();
+        assert!(outbox.ensure_durable().is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+}
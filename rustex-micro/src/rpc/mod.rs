@@ -0,0 +1,394 @@
+//! Wire format of the RPCs between the services. Every connection starts with
+//! a handshake, so peers speaking another protocol version or codec are refused
+//! instead of misparsing each other's frames. Connections can be secured with mutual TLS
+
+pub mod tls;
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream, Stream};
+use serde::{de::DeserializeOwned, Serialize};
+use tarpc::{
+    serde_transport::Transport,
+    tokio_serde::{Deserializer, Serializer},
+    tokio_util::codec::LengthDelimitedCodec,
+};
+use tls::RpcTls;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+/// Bumped on every change to the services or the types they exchange
+/// that peers of the previous version cannot parse
+pub const PROTOCOL_VERSION: u16 = 1;
+
+const MAGIC: &[u8; 4] = b"RTX\0";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Connection carrying the frames, over plain TCP or TLS
+pub trait RpcIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> RpcIo for T {}
+
+pub type RpcTransport<Item, SinkItem> = Transport<Box<dyn RpcIo>, Item, SinkItem, Codec>;
+
+/// Serialization of the RPC frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    MessagePack, // Compact and cheaper to encode. Self-describing, so every serde attribute works
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Json => 0,
+            Codec::MessagePack => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::Json),
+            1 => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Json => write!(f, "json"),
+            Codec::MessagePack => write!(f, "msgpack"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Codec::Json),
+            "msgpack" | "messagepack" => Ok(Codec::MessagePack),
+            other => anyhow::bail!("Unknown RPC codec {other:?}. Expected json or msgpack"),
+        }
+    }
+}
+
+impl<T: Serialize> Serializer<T> for Codec {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &T) -> Result<Bytes, Self::Error> {
+        let bytes = match *self {
+            Codec::Json => serde_json::to_vec(item).map_err(io::Error::other)?,
+            Codec::MessagePack => rmp_serde::to_vec(item).map_err(io::Error::other)?,
+        };
+        Ok(Bytes::from(bytes))
+    }
+}
+
+impl<T: DeserializeOwned> Deserializer<T> for Codec {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<T, Self::Error> {
+        match *self {
+            Codec::Json => serde_json::from_slice(src).map_err(io::Error::other),
+            Codec::MessagePack => rmp_serde::from_slice(src).map_err(io::Error::other),
+        }
+    }
+}
+
+/// Codec and frame limit of the RPC connections of this process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WireConfig {
+    pub codec: Codec,
+    pub max_frame_length: usize, // Larger frames are refused, dropping their connection
+}
+
+impl Default for WireConfig {
+    fn default() -> Self {
+        Self {
+            codec: Codec::MessagePack,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+}
+
+impl WireConfig {
+    /// Overridden by `RPC_CODEC` and `RPC_MAX_FRAME_BYTES`
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|n: &String| !n.is_empty());
+        let default = Self::default();
+        Self {
+            codec: var("RPC_CODEC")
+                .map(|codec| codec.parse().unwrap())
+                .unwrap_or(default.codec),
+            max_frame_length: var("RPC_MAX_FRAME_BYTES")
+                .map(|n| n.parse().unwrap())
+                .unwrap_or(default.max_frame_length),
+        }
+    }
+
+    fn hello(&self) -> [u8; 7] {
+        let [major, minor] = PROTOCOL_VERSION.to_be_bytes();
+        let [m0, m1, m2, m3] = *MAGIC;
+        [m0, m1, m2, m3, major, minor, self.codec.id()]
+    }
+
+    /// Exchanges the protocol version and codec with the peer, failing unless both match
+    async fn handshake(
+        &self,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ) -> io::Result<()> {
+        stream.write_all(&self.hello()).await?;
+        let mut hello = [0; 7];
+        stream.read_exact(&mut hello).await?;
+
+        if hello[..4] != MAGIC[..] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The peer is not a rustex service or predates protocol negotiation",
+            ));
+        }
+        let version = u16::from_be_bytes([hello[4], hello[5]]);
+        let codec = Codec::from_id(hello[6]);
+        if version != PROTOCOL_VERSION || codec != Some(self.codec) {
+            let codec = codec.map_or(format!("unknown codec {}", hello[6]), |c| c.to_string());
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The peer speaks protocol v{version} over {codec}, \
+                     this service v{PROTOCOL_VERSION} over {}",
+                    self.codec
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn transport<Item, SinkItem>(&self, stream: Box<dyn RpcIo>) -> RpcTransport<Item, SinkItem>
+    where
+        Item: DeserializeOwned,
+        SinkItem: Serialize,
+    {
+        let framed = LengthDelimitedCodec::builder()
+            .max_frame_length(self.max_frame_length)
+            .new_framed(stream);
+        tarpc::serde_transport::new(framed, self.codec)
+    }
+}
+
+/// Configuration of this process, shared by its clients and servers
+pub static WIRE: LazyLock<WireConfig> = LazyLock::new(WireConfig::from_env);
+
+/// Mutual TLS of this process. None unless configured
+pub static TLS: LazyLock<Option<Arc<RpcTls>>> =
+    LazyLock::new(|| RpcTls::from_env().unwrap().map(Arc::new));
+
+/// Fails the handshakes that take too long, e.g. with peers that never answer
+async fn within_timeout<T>(handshake: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "No handshake from the peer. It may predate protocol negotiation or TLS",
+            )
+        })?
+}
+
+/// Connects to the service at `host:port` once the handshake succeeds.
+/// The connection is secured first if TLS is configured
+pub async fn connect<Item, SinkItem>(
+    addr: &str,
+    config: WireConfig,
+    tls: Option<&RpcTls>,
+) -> anyhow::Result<RpcTransport<Item, SinkItem>>
+where
+    Item: DeserializeOwned,
+    SinkItem: Serialize,
+{
+    let stream = TcpStream::connect(addr).await?;
+    let peer = stream.peer_addr()?;
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']'); // IPv6
+    let stream = within_timeout(async {
+        let mut stream: Box<dyn RpcIo> = match tls {
+            Some(tls) => Box::new(tls.connect(host, stream).await?),
+            None => Box::new(stream),
+        };
+        config.handshake(&mut stream).await?;
+        Ok(stream)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!("RPC handshake with {peer} failed: {e}"))?;
+    Ok(config.transport(stream))
+}
+
+/// Connections accepted by the listener whose handshake succeeds. Refused
+/// peers are logged. Handshakes run concurrently, so slow peers block no one
+pub fn incoming<Item, SinkItem>(
+    listener: TcpListener,
+    config: WireConfig,
+    tls: Option<Arc<RpcTls>>,
+) -> impl Stream<Item = RpcTransport<Item, SinkItem>>
+where
+    Item: DeserializeOwned + Send + 'static,
+    SinkItem: Serialize + Send + 'static,
+{
+    let (accepted, transports) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while !accepted.is_closed() {
+            let (stream, peer): (TcpStream, SocketAddr) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::warn!("Failed to accept an RPC connection: {:?}", e);
+                    continue;
+                }
+            };
+            let accepted = accepted.clone();
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let stream = within_timeout(async {
+                    let mut stream: Box<dyn RpcIo> = match tls {
+                        Some(tls) => Box::new(tls.accept(stream).await?),
+                        None => Box::new(stream),
+                    };
+                    config.handshake(&mut stream).await?;
+                    Ok(stream)
+                });
+                match stream.await {
+                    Ok(stream) => {
+                        let _ = accepted.send(config.transport(stream));
+                    }
+                    Err(e) => log::warn!("Refused RPC connection from {}: {}", peer, e),
+                }
+            });
+        }
+    });
+    stream::unfold(transports, |mut transports| async move {
+        let transport = transports.recv().await?;
+        Some((transport, transports))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::StreamExt;
+    use rustex_core::prelude::*;
+    use rustex_errors::{RiskRejection, RustexError};
+    use tarpc::{context::Context, server::Channel};
+
+    use rustls::pki_types::PrivateKeyDer;
+
+    use super::*;
+    use crate::{
+        custody::LocalCustody,
+        db_service::{DbServer, DbService, DbServiceClient},
+        storage::MemoryStorage,
+    };
+
+    #[test]
+    fn test_codecs_round_trip_tagged_enums() {
+        let error = RustexError::RiskRejection(RiskRejection::PriceDeviation {
+            limit: 0.1,
+            reference_price: 100,
+            price: 120,
+        });
+        for mut codec in [Codec::Json, Codec::MessagePack] {
+            let bytes = Serializer::serialize(Pin::new(&mut codec), &error).unwrap();
+            let decoded: RustexError =
+                Deserializer::deserialize(Pin::new(&mut codec), &BytesMut::from(&bytes[..]))
+                    .unwrap();
+            assert_eq!(format!("{decoded:?}"), format!("{error:?}"));
+        }
+    }
+
+    async fn serve(config: WireConfig, tls: Option<Arc<RpcTls>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = DbServer::with_storage(
+            Arc::new(MemoryStorage::new()),
+            Arc::new(LocalCustody::new()),
+        );
+        tokio::spawn(incoming(listener, config, tls).for_each(move |transport| {
+            let server = server.clone();
+            tarpc::server::BaseChannel::with_defaults(transport)
+                .execute(server.serve())
+                .for_each(|response| async move {
+                    tokio::spawn(response);
+                })
+        }));
+        address
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_peers_fail_fast() {
+        let msgpack = WireConfig::default();
+        let address = serve(msgpack, None).await.to_string();
+        let transport = connect(&address, msgpack, None).await.unwrap();
+        let client = DbServiceClient::new(Default::default(), transport).spawn();
+        let market = ExchangeMarket::BTC_USD;
+        let price = client.get_last_trade_price(Context::current(), market);
+        assert_eq!(price.await.unwrap().unwrap(), None);
+
+        let json = WireConfig {
+            codec: Codec::Json,
+            ..msgpack
+        };
+        let refused = connect::<(), ()>(&address, json, None).await;
+        let error = refused.err().unwrap().to_string();
+        assert!(error.contains("over msgpack"), "{error}");
+    }
+
+    /// CA and a certificate it signs for localhost, usable by servers and clients
+    fn certify() -> RpcTls {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca = rcgen::CertificateParams::default();
+        ca.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = ca.self_signed(&ca_key).unwrap();
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let params = rcgen::CertificateParams::new(["localhost".to_owned()]).unwrap();
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+        RpcTls::new(vec![ca.der().clone()], vec![cert.der().clone()], key, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tls_requires_client_certificates() {
+        let msgpack = WireConfig::default();
+        let tls = Arc::new(certify());
+        let port = serve(msgpack, Some(Arc::clone(&tls))).await.port();
+        let address = format!("localhost:{port}");
+
+        let transport = connect(&address, msgpack, Some(&tls)).await.unwrap();
+        let client = DbServiceClient::new(Default::default(), transport).spawn();
+        let price = client.get_last_trade_price(Context::current(), ExchangeMarket::BTC_USD);
+        assert_eq!(price.await.unwrap().unwrap(), None);
+
+        // Signed by another CA
+        let stranger = certify();
+        assert!(connect::<(), ()>(&address, msgpack, Some(&stranger))
+            .await
+            .is_err());
+        assert!(connect::<(), ()>(&address, msgpack, None).await.is_err());
+    }
+}
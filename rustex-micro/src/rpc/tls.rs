@@ -0,0 +1,118 @@
+//! Mutual TLS of the RPC connections. Every service presents a certificate
+//! signed by the internal CA and only talks to peers presenting one as well
+
+use std::{fs::File, io, io::BufReader, sync::Arc};
+
+use anyhow::Context;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+/// Certificates of a PEM file
+pub fn load_certs(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut certs_file =
+        BufReader::new(File::open(path).with_context(|| format!("Failed to read {path}"))?);
+    let certs = rustls_pemfile::certs(&mut certs_file).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {path}");
+    }
+    Ok(certs)
+}
+
+/// First PKCS #8 private key of a PEM file
+pub fn load_private_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let mut key_file =
+        BufReader::new(File::open(path).with_context(|| format!("Failed to read {path}"))?);
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_file)
+        .map(|key| key.map(PrivateKeyDer::Pkcs8))
+        .collect::<Result<Vec<_>, _>>()?;
+    if keys.is_empty() {
+        return Err(anyhow::Error::msg("Failed to parse pkcs8 key"));
+    }
+    Ok(keys.remove(0))
+}
+
+/// TLS of the RPC connections, accepted and initiated alike
+pub struct RpcTls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>, // Expected from servers instead of the host they are reached at
+}
+
+impl RpcTls {
+    /// Verifies peers against the CA certificates and presents the certificate
+    /// chain of this service, both as a server and as a client
+    pub fn new(
+        ca_certs: Vec<CertificateDer<'static>>,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        server_name: Option<ServerName<'static>>,
+    ) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for ca_cert in ca_certs {
+            roots.add(ca_cert)?;
+        }
+        let roots = Arc::new(roots);
+
+        let client_verifier = WebPkiClientVerifier::builder(Arc::clone(&roots)).build()?;
+        let server_config = ServerConfig::builder()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(certs.clone(), key.clone_key())?;
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name,
+        })
+    }
+
+    /// Enabled by `RPC_TLS_CA_PATH`, which requires `RPC_TLS_CERT_PATH` and `RPC_TLS_KEY_PATH`.
+    /// `RPC_TLS_SERVER_NAME` overrides the name expected from the servers
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let var = |name| std::env::var(name).ok().filter(|n: &String| !n.is_empty());
+        let Some(ca_path) = var("RPC_TLS_CA_PATH") else {
+            return Ok(None);
+        };
+        let cert_path = var("RPC_TLS_CERT_PATH")
+            .context("RPC_TLS_CERT_PATH environment variable is not set")?;
+        let key_path =
+            var("RPC_TLS_KEY_PATH").context("RPC_TLS_KEY_PATH environment variable is not set")?;
+        let server_name = var("RPC_TLS_SERVER_NAME")
+            .map(ServerName::try_from)
+            .transpose()
+            .context("Invalid RPC_TLS_SERVER_NAME")?;
+        let tls = Self::new(
+            load_certs(&ca_path)?,
+            load_certs(&cert_path)?,
+            load_private_key(&key_path)?,
+            server_name,
+        )?;
+        Ok(Some(tls))
+    }
+
+    pub(super) async fn connect(
+        &self,
+        host: &str,
+        stream: TcpStream,
+    ) -> io::Result<client::TlsStream<TcpStream>> {
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => ServerName::try_from(host.to_owned())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        };
+        self.connector.connect(server_name, stream).await
+    }
+
+    pub(super) async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> io::Result<server::TlsStream<TcpStream>> {
+        self.acceptor.accept(stream).await
+    }
+}
@@ -0,0 +1,43 @@
+//! Walks the audit log of every configured storage checking its hash chain.
+//! Exits with an error at the first broken link
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use dotenvy::dotenv;
+use rpc_clients::storage::StorageRouter;
+use rustex_core::prelude::{AuditVerifier, ExchangeMarket};
+
+const PAGE_SIZE: i64 = 1000;
+
+#[tokio::main]
+pub async fn main() -> anyhow::Result<()> {
+    let _ = dotenv();
+    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    let router = StorageRouter::from_env()?;
+    for storage in router.all() {
+        let markets = ExchangeMarket::ALL
+            .into_iter()
+            .filter(|market| Arc::ptr_eq(router.market(*market), storage))
+            .collect::<Vec<_>>();
+        let mut verifier = AuditVerifier::default();
+        let mut verified = 0;
+        loop {
+            let entries = storage
+                .get_audit_log(verifier.last_seq(), PAGE_SIZE)
+                .await
+                .context("Failed to read the audit log")?;
+            for entry in &entries {
+                verifier
+                    .verify(entry)
+                    .map_err(|e| anyhow::anyhow!("Audit log of {markets:?} is broken: {e}"))?;
+            }
+            verified += entries.len();
+            if entries.len() < PAGE_SIZE as usize {
+                break;
+            }
+        }
+        log::info!("Audit log of {markets:?}: {verified} entries verified");
+    }
+    Ok(())
+}
@@ -0,0 +1,38 @@
+//! Archive awareness of the Postgres queries. Archived partitions are detached from the
+//! live tables, so only the queries reaching before the cut-off read them
+
+use chrono::{DateTime, Utc};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use rustex_errors::RustexError;
+
+diesel::define_sql_function! {
+    /// End of the latest archived month, null if none was archived
+    fn rustex_archive_cutoff() -> Nullable<Timestamptz>;
+}
+
+/// Runs the query importing the listed items of the live tables or, when `$archived`,
+/// of their views along with the archive
+macro_rules! live_or_archived {
+    ($archived:expr, [$($imports:tt)+], $query:block) => {
+        if $archived {
+            use rustex_core::db::with_archive::{$($imports)+};
+            $query
+        } else {
+            use rustex_core::db::schema::{$($imports)+};
+            $query
+        }
+    };
+}
+
+pub(crate) async fn cutoff(
+    conn: &mut AsyncPgConnection,
+) -> Result<Option<DateTime<Utc>>, RustexError> {
+    Ok(diesel::select(rustex_archive_cutoff())
+        .get_result(conn)
+        .await?)
+}
+
+/// Whether rows created from `from` on (or at any time if unbounded) may be archived
+pub(crate) fn reaches(cutoff: Option<DateTime<Utc>>, from: Option<DateTime<Utc>>) -> bool {
+    cutoff.is_some_and(|cutoff| from.is_none_or(|from| from < cutoff))
+}
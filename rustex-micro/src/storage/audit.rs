@@ -0,0 +1,37 @@
+//! Audit log appends shared by the Postgres storage operations.
+//! These functions are expected to run inside a DB transaction
+
+use chrono::Utc;
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use rustex_core::{db, prelude::*};
+use rustex_errors::RustexError;
+
+use super::rules;
+
+/// Chains the events after the last entry of the log
+pub(crate) async fn append(
+    conn: &mut AsyncPgConnection,
+    events: Vec<AuditEvent>,
+) -> Result<(), RustexError> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    use db::schema::audit_log::dsl::*;
+    // Appends are serialized until the transaction ends, so that no entry is linked twice
+    diesel::sql_query("LOCK TABLE audit_log IN EXCLUSIVE MODE")
+        .execute(conn)
+        .await?;
+    let last: Option<AuditEntry> = audit_log
+        .order_by(seq.desc())
+        .select(AuditEntry::as_select())
+        .first(conn)
+        .await
+        .optional()?;
+    let entries = rules::chain_events(last, events, Utc::now());
+    diesel::insert_into(audit_log)
+        .values(&entries)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
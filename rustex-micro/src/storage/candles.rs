@@ -0,0 +1,101 @@
+//! Candle aggregation shared by the Postgres storage operations.
+//! These functions are expected to run inside a DB transaction
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    upsert::excluded, BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use rustex_core::{db, prelude::*};
+use rustex_errors::RustexError;
+
+use super::{archive, rules};
+
+/// Loads the stored candles of the same bars, locking their rows
+pub(crate) async fn lock(
+    conn: &mut AsyncPgConnection,
+    bars: &[Candle],
+) -> Result<Vec<Candle>, RustexError> {
+    use db::schema::candles::dsl::*;
+    let mut stored = Vec::with_capacity(bars.len());
+    for bar in bars {
+        let candle: Option<Candle> = candles
+            .find((bar.exchange, bar.interval, bar.open_time))
+            .for_update()
+            .first(conn)
+            .await
+            .optional()?;
+        stored.extend(candle);
+    }
+    Ok(stored)
+}
+
+/// Inserts the candles or replaces the stored ones
+pub(crate) async fn store(
+    conn: &mut AsyncPgConnection,
+    merged: &[Candle],
+) -> Result<(), RustexError> {
+    use db::schema::candles::dsl::*;
+    for candle in merged {
+        diesel::insert_into(candles)
+            .values(candle)
+            .on_conflict((exchange, candle_interval, open_time))
+            .do_update()
+            .set((
+                high.eq(excluded(high)),
+                low.eq(excluded(low)),
+                close.eq(excluded(close)),
+                volume.eq(excluded(volume)),
+                quote_volume.eq(excluded(quote_volume)),
+                trade_count.eq(excluded(trade_count)),
+            ))
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Rebuilds from the trades table every candle overlapping the time range.
+/// Returns the number of candles stored
+pub(crate) async fn rebuild(
+    conn: &mut AsyncPgConnection,
+    market: ExchangeMarket,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<usize, RustexError> {
+    let (from, to) = rules::candle_range(from, to);
+
+    let archived = archive::reaches(archive::cutoff(conn).await?, Some(from));
+    let range_trades: Vec<Trade> = live_or_archived!(archived, [trades::dsl::*], {
+        trades
+            .filter(
+                exchange
+                    .eq(market)
+                    .and(created_at.ge(from))
+                    .and(created_at.lt(to)),
+            )
+            .order_by(trade_id)
+            .load(conn)
+            .await?
+    });
+
+    use db::schema::candles::dsl::*;
+    diesel::delete(
+        candles.filter(
+            exchange
+                .eq(market)
+                .and(open_time.ge(from))
+                .and(open_time.lt(to)),
+        ),
+    )
+    .execute(conn)
+    .await?;
+    let rebuilt = Candle::from_trades(market, &range_trades);
+    for chunk in rebuilt.chunks(1000) {
+        diesel::insert_into(candles)
+            .values(chunk)
+            .execute(conn)
+            .await?;
+    }
+    Ok(rebuilt.len())
+}
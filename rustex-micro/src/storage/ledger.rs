@@ -0,0 +1,79 @@
+//! Ledger bookkeeping shared by the Postgres storage operations.
+//! These functions are expected to run inside a DB transaction
+
+use diesel::{
+    upsert::excluded, BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use rustex_core::{db, prelude::*};
+use rustex_errors::RustexError;
+
+use super::rules;
+
+/// Appends the entries to the ledger and updates the balances accordingly
+pub(crate) async fn record_entries(
+    conn: &mut AsyncPgConnection,
+    entries: &[NewLedgerEntry],
+) -> Result<(), RustexError> {
+    let entries = entries
+        .iter()
+        .filter(|entry| entry.amount != 0.0)
+        .cloned()
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Ok(());
+    }
+    diesel::insert_into(db::schema::ledger_entries::table)
+        .values(&entries)
+        .execute(conn)
+        .await?;
+
+    use db::schema::balances::dsl::*;
+    for delta in rules::balance_deltas(&entries).into_values() {
+        diesel::insert_into(balances)
+            .values(&delta)
+            .on_conflict((user_id, currency))
+            .do_update()
+            .set((
+                available.eq(available + excluded(available)),
+                reserved.eq(reserved + excluded(reserved)),
+            ))
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Returns the available (unreserved) funds locking the balance row
+pub(crate) async fn available_funds(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    asset: Currencies,
+) -> Result<f64, RustexError> {
+    use db::schema::balances::dsl::*;
+    let funds: Option<f64> = balances
+        .filter(user_id.eq(user).and(currency.eq(asset)))
+        .select(available)
+        .for_update()
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(funds.unwrap_or(0.0))
+}
+
+/// Loads the orders locking their rows, such that a cancellation and
+/// the settlement of the trades of the same order are serialized
+pub(crate) async fn lock_orders(
+    conn: &mut AsyncPgConnection,
+    market: ExchangeMarket,
+    order_ids: &[OrderId],
+) -> Result<Vec<Order>, RustexError> {
+    use db::schema::orders::dsl::*;
+    let rows = orders
+        .filter(exchange.eq(market).and(order_id.eq_any(order_ids)))
+        .order_by(order_id) // Consistent locking order
+        .for_update()
+        .load(conn)
+        .await?;
+    Ok(rows)
+}
@@ -0,0 +1,1231 @@
+//! Storage kept in process memory, meant for tests
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
+use rustex_core::{lock, prelude::*};
+use rustex_errors::RustexError;
+
+use super::{
+    risk_limits_deletion_event, risk_limits_event, rolled_back, rules, transfer_event, Storage,
+};
+
+/// Next order and trade ids of a market
+#[derive(Debug, Clone, Copy)]
+struct IdSequences {
+    exchange: ExchangeMarket,
+    next_order_id: i64,
+    next_trade_id: i64,
+}
+
+/// Row of one of the in-memory tables
+trait Record {
+    type Key: Ord;
+    fn key(&self) -> Self::Key;
+}
+
+macro_rules! impl_record {
+    ($($record:ty, $key:ty, |$row:ident| $key_expr:expr;)*) => {
+        $(
+            impl Record for $record {
+                type Key = $key;
+                fn key(&self) -> Self::Key {
+                    let $row = self;
+                    $key_expr
+                }
+            }
+        )*
+    };
+}
+
+impl_record! {
+    Order, (ExchangeMarket, OrderId), |row| (row.exchange, row.order_id);
+    PendingOrder, (ExchangeMarket, OrderId), |row| (row.exchange, row.order_id);
+    Trade, (ExchangeMarket, TradeId), |row| (row.exchange, row.trade_id);
+    CancelledOrder, (ExchangeMarket, OrderId), |row| (row.exchange, row.order_id);
+    OrderGroup, (ExchangeMarket, GroupId), |row| (row.exchange, row.group_id);
+    Balance, (UserId, Currencies), |row| (row.user_id, row.currency);
+    LedgerEntry, i64, |row| row.entry_id;
+    Transfer, i64, |row| row.transfer_id;
+    Candle, (ExchangeMarket, CandleInterval, DateTime<Utc>), |row| (row.exchange, row.interval, row.open_time);
+    RiskLimits, UserId, |row| row.user_id;
+    IdSequences, ExchangeMarket, |row| row.exchange;
+    AuditEntry, i64, |row| row.seq;
+    User, UserId, |row| row.user_id;
+}
+
+struct Table<R: Record> {
+    rows: BTreeMap<R::Key, R>,
+}
+
+impl<R: Record> Default for Table<R> {
+    fn default() -> Self {
+        Self {
+            rows: BTreeMap::new(),
+        }
+    }
+}
+
+impl<R: Record> Table<R> {
+    fn get(&self, key: &R::Key) -> Option<&R> {
+        self.rows.get(key)
+    }
+
+    fn contains(&self, key: &R::Key) -> bool {
+        self.rows.contains_key(key)
+    }
+
+    fn values(&self) -> impl DoubleEndedIterator<Item = &R> {
+        self.rows.values()
+    }
+
+    fn last_key(&self) -> Option<&R::Key> {
+        self.rows.last_key_value().map(|(key, _)| key)
+    }
+
+    fn put(&mut self, row: R) {
+        self.rows.insert(row.key(), row);
+    }
+
+    fn remove(&mut self, key: &R::Key) -> Option<R> {
+        self.rows.remove(key)
+    }
+}
+
+/// Tables of the exchange. Every operation applies its rule before
+/// writing anything, such that a failed operation leaves the state untouched
+#[derive(Default)]
+struct MemoryState {
+    orders: Table<Order>,
+    pending_orders: Table<PendingOrder>,
+    trades: Table<Trade>,
+    cancelled_orders: Table<CancelledOrder>,
+    order_groups: Table<OrderGroup>,
+    balances: Table<Balance>,
+    ledger_entries: Table<LedgerEntry>,
+    transfers: Table<Transfer>,
+    candles: Table<Candle>,
+    risk_limits: Table<RiskLimits>,
+    id_sequences: Table<IdSequences>,
+    audit_log: Table<AuditEntry>,
+    users: Table<User>,
+}
+
+/// Whether the optional timestamp falls in the range. Unset bounds always match
+fn within(
+    ts: Option<DateTime<Utc>>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> bool {
+    from.is_none_or(|from| ts.is_some_and(|ts| ts >= from))
+        && to.is_none_or(|to| ts.is_some_and(|ts| ts < to))
+}
+
+impl MemoryState {
+    /// Sequences of the market. Seeded from the recorded ids the first time
+    fn id_sequences(&self, market: ExchangeMarket) -> IdSequences {
+        if let Some(sequences) = self.id_sequences.get(&market) {
+            return *sequences;
+        }
+        let last_order = self
+            .orders
+            .values()
+            .filter(|order| order.exchange == market)
+            .map(|order| i64::from(order.order_id))
+            .max();
+        let last_trade = self
+            .trades
+            .values()
+            .filter(|trade| trade.exchange == market)
+            .map(|trade| i64::from(trade.trade_id))
+            .max();
+        IdSequences {
+            exchange: market,
+            next_order_id: last_order.map_or(0, |id| id + 1),
+            next_trade_id: last_trade.map_or(0, |id| id + 1),
+        }
+    }
+
+    fn reserve_ids(
+        &mut self,
+        market: ExchangeMarket,
+        kind: IdKind,
+        from: i64,
+        count: i64,
+    ) -> Result<IdBlock, RustexError> {
+        let mut sequences = self.id_sequences(market);
+        let next = match kind {
+            IdKind::Order => &mut sequences.next_order_id,
+            IdKind::Trade => &mut sequences.next_trade_id,
+        };
+        let block = rules::reserve_ids(*next, from, count);
+        *next = block.end;
+        self.id_sequences.put(sequences);
+        Ok(block)
+    }
+
+    fn get_pending_orders_ids(&self, market: ExchangeMarket) -> Result<Vec<OrderId>, RustexError> {
+        Ok(self
+            .pending_orders
+            .values()
+            .filter(|pending| pending.exchange == market)
+            .map(|pending| pending.order_id)
+            .collect())
+    }
+
+    fn get_order_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> Result<Page<OrderDetails>, RustexError> {
+        let page_size = query.page_size()?;
+        let cursor = query
+            .cursor()?
+            .map(|cursor| (cursor.created_at, cursor.exchange, cursor.order_id));
+        let position = |order: &Order| {
+            (
+                order.created_at.unwrap_or_default(),
+                order.exchange,
+                order.order_id,
+            )
+        };
+
+        let mut rows = self
+            .orders
+            .values()
+            .filter(|order| order.user_id == user)
+            .filter(|order| query.market.is_none_or(|market| order.exchange == market))
+            .filter(|order| query.side.is_none_or(|side| order.order_type == side))
+            .filter(|order| query.status.is_none_or(|status| order.status == status))
+            .filter(|order| within(order.created_at, query.from, query.to))
+            .filter(|order| cursor.is_none_or(|cursor| position(order) < cursor))
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|order| Reverse(position(order)));
+
+        let details = rows
+            .into_iter()
+            .take(page_size + 1)
+            .cloned()
+            .map(OrderDetails::from)
+            .collect();
+        Ok(Page::new(details, page_size, OrderDetails::cursor))
+    }
+
+    fn get_trade_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> Result<Page<UserTrade>, RustexError> {
+        let page_size = query.page_size()?;
+        let cursor = query.cursor()?.map(|cursor| {
+            (
+                cursor.created_at,
+                cursor.exchange,
+                cursor.id,
+                cursor.order_id,
+            )
+        });
+        let position = |trade: &UserTrade| {
+            (
+                trade.created_at.unwrap_or_default(),
+                trade.exchange,
+                i64::from(trade.trade_id),
+                trade.order_id,
+            )
+        };
+
+        // A self-trade yields a record for each of the two orders
+        let mut rows = self
+            .trades
+            .values()
+            .filter(|trade| query.market.is_none_or(|market| trade.exchange == market))
+            .filter(|trade| within(trade.created_at, query.from, query.to))
+            .flat_map(|trade| {
+                [trade.buy_order, trade.sell_order]
+                    .into_iter()
+                    .filter_map(|order_id| self.orders.get(&(trade.exchange, order_id)))
+                    .filter(|order| order.user_id == user)
+                    .map(|order| UserTrade {
+                        trade_id: trade.trade_id,
+                        exchange: trade.exchange,
+                        order_id: order.order_id,
+                        side: order.order_type,
+                        price: trade.price,
+                        quantity: trade.quantity,
+                        created_at: trade.created_at,
+                    })
+            })
+            .filter(|trade| query.side.is_none_or(|side| trade.side == side))
+            .filter(|trade| cursor.is_none_or(|cursor| position(trade) < cursor))
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|trade| Reverse(position(trade)));
+        rows.truncate(page_size + 1);
+
+        Ok(Page::new(rows, page_size, UserTrade::cursor))
+    }
+
+    fn get_order_user(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> Result<Option<UserId>, RustexError> {
+        Ok(self.orders.get(&(market, order)).map(|order| order.user_id))
+    }
+
+    fn get_orders(
+        &self,
+        orders: Vec<OrderId>,
+        market: ExchangeMarket,
+    ) -> Result<Vec<Order>, RustexError> {
+        Ok(orders
+            .into_iter()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|order| self.orders.get(&(market, order)).cloned())
+            .collect())
+    }
+
+    fn order_trades(&self, order: OrderId, market: ExchangeMarket) -> impl Iterator<Item = &Trade> {
+        self.trades.values().filter(move |trade| {
+            trade.exchange == market && (trade.buy_order == order || trade.sell_order == order)
+        })
+    }
+
+    fn get_order_trades(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> Result<Vec<Trade>, RustexError> {
+        Ok(self.order_trades(order, market).cloned().collect())
+    }
+
+    fn insert_orders(&mut self, orders: Vec<(Order, AuditOrigin)>) -> Vec<Result<(), RustexError>> {
+        let recorded: HashSet<_> = orders
+            .iter()
+            .map(|(order, _)| order.key())
+            .filter(|key| self.orders.contains(key))
+            .collect();
+        let funds: HashMap<_, _> = orders
+            .iter()
+            .flat_map(|(order, _)| self.get_user_balances_of(order.user_id))
+            .map(|balance| ((balance.user_id, balance.currency), balance.available))
+            .collect();
+        let writes = rules::place_orders(orders, recorded, funds, Utc::now());
+
+        for user_id in writes.users() {
+            if !self.users.contains(&user_id) {
+                self.users.put(User {
+                    user_id,
+                    created_at: Utc::now(),
+                });
+            }
+        }
+        for order in writes.orders {
+            self.orders.put(order);
+        }
+        for pending in writes.pending {
+            self.pending_orders.put(pending);
+        }
+        self.record_entries(&writes.entries);
+        self.append_audit(writes.events);
+        writes.results
+    }
+
+    fn insert_trades(
+        &mut self,
+        market: ExchangeMarket,
+        trades: Vec<Trade>,
+        completed_orders: Vec<OrderId>,
+    ) -> Result<(), RustexError> {
+        // Retried batches are already recorded
+        let recorded = trades
+            .iter()
+            .filter(|trade| self.trades.contains(&trade.key()))
+            .count();
+        if rules::trades_recorded(&trades, recorded)? {
+            return Ok(());
+        }
+        let settled = rules::settled_orders(&trades, &completed_orders);
+        let rows = rules::SettlementRows {
+            orders: self.get_orders(settled.clone(), market)?,
+            pending: settled
+                .iter()
+                .copied()
+                .filter(|order| self.pending_orders.contains(&(market, *order)))
+                .collect(),
+            earlier_trades: completed_orders
+                .iter()
+                .flat_map(|order| self.order_trades(*order, market))
+                .cloned()
+                .collect(),
+            candles: Candle::from_trades(market, &trades)
+                .iter()
+                .filter_map(|candle| self.candles.get(&candle.key()).copied())
+                .collect(),
+        };
+        let writes = rules::settle_trades(market, &trades, &completed_orders, rows)?;
+
+        for order in &completed_orders {
+            self.pending_orders.remove(&(market, *order));
+        }
+        for trade in trades {
+            self.trades.put(trade);
+        }
+        for order in writes.orders {
+            self.orders.put(order);
+        }
+        for candle in writes.candles {
+            self.candles.put(candle);
+        }
+        self.record_entries(&writes.entries);
+        self.append_audit(writes.events);
+        Ok(())
+    }
+
+    fn insert_cancellation(
+        &mut self,
+        market: ExchangeMarket,
+        order: OrderId,
+        status: OrderStatus,
+        unmatched: Option<f64>,
+        origin: AuditOrigin,
+    ) -> Result<(), RustexError> {
+        let trades = self.get_order_trades(order, market)?;
+        let cancellation = rules::cancel_order(
+            self.orders.get(&(market, order)).copied(),
+            self.cancelled_orders.contains(&(market, order)),
+            unmatched,
+            &trades,
+            status,
+            &origin,
+            Utc::now(),
+        )?;
+        let Some(writes) = cancellation else {
+            return Ok(()); // Already recorded by an earlier attempt
+        };
+        self.cancelled_orders.put(writes.cancellation);
+        self.pending_orders.remove(&(market, order));
+        self.orders.put(writes.order);
+        self.record_entries(&writes.entries);
+        self.append_audit(vec![writes.event]);
+        Ok(())
+    }
+
+    fn get_last_group_id(&self, market: ExchangeMarket) -> Result<Option<GroupId>, RustexError> {
+        Ok(self
+            .order_groups
+            .values()
+            .filter(|group| group.exchange == market)
+            .map(|group| group.group_id)
+            .max())
+    }
+
+    fn get_open_order_groups(
+        &self,
+        market: ExchangeMarket,
+    ) -> Result<Vec<OrderGroup>, RustexError> {
+        Ok(self
+            .order_groups
+            .values()
+            .filter(|group| {
+                group.exchange == market
+                    && matches!(
+                        group.status,
+                        OrderGroupStatus::Pending | OrderGroupStatus::Active
+                    )
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn get_order_group(
+        &self,
+        group: GroupId,
+        market: ExchangeMarket,
+    ) -> Result<Option<OrderGroup>, RustexError> {
+        Ok(self.order_groups.get(&(market, group)).cloned())
+    }
+
+    fn upsert_order_groups(&mut self, groups: Vec<OrderGroup>) -> Result<(), RustexError> {
+        for group in groups {
+            let group = match self.order_groups.get(&group.key()) {
+                Some(stored) => OrderGroup {
+                    status: group.status,
+                    quantity: group.quantity,
+                    entry_order: group.entry_order,
+                    take_profit_order: group.take_profit_order,
+                    stop_loss_order: group.stop_loss_order,
+                    ..stored.clone()
+                },
+                None => OrderGroup {
+                    created_at: group.created_at.or(Some(Utc::now())),
+                    ..group
+                },
+            };
+            self.order_groups.put(group);
+        }
+        Ok(())
+    }
+
+    fn get_user_balances_of(&self, user: UserId) -> impl Iterator<Item = &Balance> {
+        self.balances
+            .values()
+            .filter(move |balance| balance.user_id == user)
+    }
+
+    fn get_user_balances(&self, user: UserId) -> Result<Vec<Balance>, RustexError> {
+        Ok(self.get_user_balances_of(user).cloned().collect())
+    }
+
+    fn insert_transfer(
+        &mut self,
+        transfer: NewTransfer,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> Result<Transfer, RustexError> {
+        let available = self
+            .balances
+            .get(&(transfer.user_id, transfer.currency))
+            .map_or(0.0, |balance| balance.available);
+        rules::check_transfer(&transfer, available)?;
+        let now = Utc::now();
+        let transfer = Transfer {
+            transfer_id: self.transfers.last_key().map_or(1, |id| id + 1),
+            user_id: transfer.user_id,
+            kind: transfer.kind,
+            currency: transfer.currency,
+            amount: transfer.amount,
+            status: TransferStatus::Requested,
+            external_reference: None,
+            created_at: Some(now),
+            updated_at: Some(now),
+        };
+        self.record_entries(&transfer.request_entries());
+        self.transfers.put(transfer.clone());
+        let event = transfer_event(&transfer, None, market, &origin);
+        self.append_audit(event.into_iter().collect());
+        Ok(transfer)
+    }
+
+    fn get_user_transfers(&self, user: UserId) -> Result<Vec<Transfer>, RustexError> {
+        Ok(self
+            .transfers
+            .values()
+            .filter(|transfer| transfer.user_id == user)
+            .cloned()
+            .collect())
+    }
+
+    fn transition_transfer(
+        &mut self,
+        transfer: i64,
+        from: TransferStatus,
+        to: TransferStatus,
+        reference: Option<String>,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> Result<Transfer, RustexError> {
+        let current = self.transfers.get(&transfer).cloned();
+        let updated =
+            match rules::transition_transfer(current, transfer, from, to, reference, Utc::now())? {
+                rules::Transition::Moved(updated) => updated,
+                rules::Transition::Unchanged(transfer) => return Ok(transfer),
+            };
+        self.record_entries(&updated.transition_entries());
+        self.transfers.put(updated.clone());
+        let event = transfer_event(&updated, Some(from), market, &origin);
+        self.append_audit(event.into_iter().collect());
+        Ok(updated)
+    }
+
+    fn get_unfinished_transfers(&self) -> Result<Vec<Transfer>, RustexError> {
+        Ok(self
+            .transfers
+            .values()
+            .filter(|transfer| transfer.is_unfinished())
+            .cloned()
+            .collect())
+    }
+
+    fn get_user_fills(
+        &self,
+        user: UserId,
+        market: ExchangeMarket,
+    ) -> Result<Vec<Fill>, RustexError> {
+        let user_orders = self
+            .orders
+            .values()
+            .filter(|order| order.user_id == user && order.exchange == market)
+            .map(|order| (order.order_id, order.order_type))
+            .collect::<Vec<_>>();
+        let user_trades = self
+            .trades
+            .values()
+            .filter(|trade| {
+                trade.exchange == market
+                    && user_orders
+                        .iter()
+                        .any(|(id, _)| *id == trade.buy_order || *id == trade.sell_order)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(Fill::from_trades(&user_trades, &user_orders))
+    }
+
+    fn get_trade_tape(
+        &self,
+        market: ExchangeMarket,
+        query: TapeQuery,
+    ) -> Result<Page<TapeTrade>, RustexError> {
+        let page_size = query.page_size()?;
+        let rows = self
+            .trades
+            .values()
+            .rev()
+            .filter(|trade| trade.exchange == market)
+            .filter(|trade| {
+                query
+                    .cursor
+                    .is_none_or(|cursor| trade.trade_id < cursor.into())
+            })
+            .take(page_size + 1)
+            .map(TapeTrade::from)
+            .collect();
+        Ok(Page::new(rows, page_size, |trade| {
+            i64::from(trade.trade_id)
+        }))
+    }
+
+    fn get_candles(
+        &self,
+        market: ExchangeMarket,
+        query: CandleQuery,
+    ) -> Result<Vec<Candle>, RustexError> {
+        let page_size = query.page_size()?;
+        let mut rows = self
+            .candles
+            .values()
+            .filter(|candle| candle.exchange == market && candle.interval == query.interval)
+            .filter(|candle| within(Some(candle.open_time), query.from, query.to))
+            .copied()
+            .collect::<Vec<_>>();
+        Ok(rows.split_off(rows.len().saturating_sub(page_size)))
+    }
+
+    fn rebuild_candles(
+        &mut self,
+        market: ExchangeMarket,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<usize, RustexError> {
+        let (from, to) = rules::candle_range(from, to);
+        let range_trades = self
+            .trades
+            .values()
+            .filter(|trade| {
+                trade.exchange == market && within(trade.created_at, Some(from), Some(to))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let stale = self
+            .candles
+            .values()
+            .filter(|candle| {
+                candle.exchange == market && within(Some(candle.open_time), Some(from), Some(to))
+            })
+            .map(Record::key)
+            .collect::<Vec<_>>();
+        for key in stale {
+            self.candles.remove(&key);
+        }
+        let rebuilt = Candle::from_trades(market, &range_trades);
+        let count = rebuilt.len();
+        for candle in rebuilt {
+            self.candles.put(candle);
+        }
+        Ok(count)
+    }
+
+    fn get_last_trade_price(&self, market: ExchangeMarket) -> Result<Option<i64>, RustexError> {
+        Ok(self
+            .trades
+            .values()
+            .rev()
+            .find(|trade| trade.exchange == market)
+            .map(|trade| trade.price))
+    }
+
+    fn get_book_at(&self, market: ExchangeMarket, at: DateTime<Utc>) -> BookSnapshot {
+        let (orders, trades) = (self.orders.values(), self.trades.values());
+        BookSnapshot::replay(market, at, orders, trades, self.cancelled_orders.values())
+    }
+
+    fn get_all_risk_limits(&self) -> Result<Vec<RiskLimits>, RustexError> {
+        Ok(self.risk_limits.values().copied().collect())
+    }
+
+    fn get_risk_limits(&self, user: UserId) -> Result<Option<RiskLimits>, RustexError> {
+        Ok(self.risk_limits.get(&user).copied())
+    }
+
+    fn upsert_risk_limits(
+        &mut self,
+        limits: RiskLimits,
+        origin: AuditOrigin,
+    ) -> Result<(), RustexError> {
+        self.risk_limits.put(limits);
+        self.append_audit(vec![risk_limits_event(&limits, &origin)]);
+        Ok(())
+    }
+
+    fn delete_risk_limits(
+        &mut self,
+        user: UserId,
+        origin: AuditOrigin,
+    ) -> Result<bool, RustexError> {
+        let deleted = self.risk_limits.remove(&user).is_some();
+        if deleted {
+            self.append_audit(vec![risk_limits_deletion_event(user, &origin)]);
+        }
+        Ok(deleted)
+    }
+
+    fn append_audit(&mut self, events: Vec<AuditEvent>) {
+        let last = self.audit_log.values().next_back().cloned();
+        for entry in rules::chain_events(last, events, Utc::now()) {
+            self.audit_log.put(entry);
+        }
+    }
+
+    fn get_audit_log(
+        &self,
+        after: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<AuditEntry>, RustexError> {
+        Ok(self
+            .audit_log
+            .values()
+            .filter(|entry| after.is_none_or(|after| entry.seq > after))
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    /// Appends the entries to the ledger and updates the balances accordingly
+    fn record_entries(&mut self, entries: &[NewLedgerEntry]) {
+        let now = Utc::now();
+        for entry in entries.iter().filter(|entry| entry.amount != 0.0) {
+            let ledger_entry = LedgerEntry {
+                entry_id: self.ledger_entries.last_key().map_or(1, |id| id + 1),
+                reference: entry.reference.clone(),
+                user_id: entry.user_id,
+                currency: entry.currency,
+                account: entry.account,
+                amount: entry.amount,
+                created_at: Some(now),
+            };
+            self.ledger_entries.put(ledger_entry);
+        }
+        for (key, delta) in rules::balance_deltas(entries) {
+            let mut balance = self.balances.get(&key).cloned().unwrap_or(Balance {
+                available: 0.0,
+                reserved: 0.0,
+                ..delta
+            });
+            balance.available += delta.available;
+            balance.reserved += delta.reserved;
+            self.balances.put(balance);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&MemoryState) -> Result<T, RustexError>,
+    ) -> BoxFuture<'_, Result<T, RustexError>> {
+        future::ready(op(&lock!(self.state))).boxed()
+    }
+
+    fn write<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&mut MemoryState) -> Result<T, RustexError>,
+    ) -> BoxFuture<'_, Result<T, RustexError>> {
+        future::ready(op(&mut lock!(self.state))).boxed()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn check(&self) -> BoxFuture<'_, Result<(), RustexError>> {
+        self.read(move |_| Ok(()))
+    }
+
+    fn reserve_ids(
+        &self,
+        market: ExchangeMarket,
+        kind: IdKind,
+        from: i64,
+        count: i64,
+    ) -> BoxFuture<'_, Result<IdBlock, RustexError>> {
+        self.write(move |state| state.reserve_ids(market, kind, from, count))
+    }
+
+    fn get_pending_orders_ids(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<OrderId>, RustexError>> {
+        self.read(move |state| state.get_pending_orders_ids(market))
+    }
+
+    fn get_order_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> BoxFuture<'_, Result<Page<OrderDetails>, RustexError>> {
+        self.read(move |state| state.get_order_history(user, query))
+    }
+
+    fn get_trade_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> BoxFuture<'_, Result<Page<UserTrade>, RustexError>> {
+        self.read(move |state| state.get_trade_history(user, query))
+    }
+
+    fn get_order_user(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<UserId>, RustexError>> {
+        self.read(move |state| state.get_order_user(order, market))
+    }
+
+    fn get_orders(
+        &self,
+        orders: Vec<OrderId>,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Order>, RustexError>> {
+        self.read(move |state| state.get_orders(orders, market))
+    }
+
+    fn get_order_trades(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Trade>, RustexError>> {
+        self.read(move |state| state.get_order_trades(order, market))
+    }
+
+    fn insert_order(
+        &self,
+        order: Order,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let mut results = self.insert_orders(vec![(order, origin)]).await?;
+            results.pop().expect("One result per order")
+        }
+        .boxed()
+    }
+
+    fn insert_orders(
+        &self,
+        orders: Vec<(Order, AuditOrigin)>,
+    ) -> BoxFuture<'_, Result<Vec<Result<(), RustexError>>, RustexError>> {
+        self.write(move |state| Ok(state.insert_orders(orders)))
+    }
+
+    fn insert_trades(
+        &self,
+        market: ExchangeMarket,
+        trades: Vec<Trade>,
+        completed_orders: Vec<OrderId>,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        let writes = format!("Insertion of {} trades in {:?}", trades.len(), market);
+        self.write(move |state| state.insert_trades(market, trades, completed_orders))
+            .map_err(rolled_back(writes))
+            .boxed()
+    }
+
+    fn insert_cancellation(
+        &self,
+        market: ExchangeMarket,
+        order: OrderId,
+        status: OrderStatus,
+        unmatched: Option<f64>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        let writes = format!("Cancellation of order {:?} in {:?}", order, market);
+        self.write(move |state| state.insert_cancellation(market, order, status, unmatched, origin))
+            .map_err(rolled_back(writes))
+            .boxed()
+    }
+
+    fn get_last_group_id(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<GroupId>, RustexError>> {
+        self.read(move |state| state.get_last_group_id(market))
+    }
+
+    fn get_open_order_groups(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<OrderGroup>, RustexError>> {
+        self.read(move |state| state.get_open_order_groups(market))
+    }
+
+    fn get_order_group(
+        &self,
+        group: GroupId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<OrderGroup>, RustexError>> {
+        self.read(move |state| state.get_order_group(group, market))
+    }
+
+    fn upsert_order_groups(
+        &self,
+        groups: Vec<OrderGroup>,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        self.write(move |state| state.upsert_order_groups(groups))
+    }
+
+    fn get_user_balances(&self, user: UserId) -> BoxFuture<'_, Result<Vec<Balance>, RustexError>> {
+        self.read(move |state| state.get_user_balances(user))
+    }
+
+    fn insert_transfer(
+        &self,
+        transfer: NewTransfer,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<Transfer, RustexError>> {
+        self.write(move |state| state.insert_transfer(transfer, market, origin))
+    }
+
+    fn get_user_transfers(
+        &self,
+        user: UserId,
+    ) -> BoxFuture<'_, Result<Vec<Transfer>, RustexError>> {
+        self.read(move |state| state.get_user_transfers(user))
+    }
+
+    fn transition_transfer(
+        &self,
+        transfer: i64,
+        from: TransferStatus,
+        to: TransferStatus,
+        reference: Option<String>,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<Transfer, RustexError>> {
+        self.write(move |state| {
+            state.transition_transfer(transfer, from, to, reference, market, origin)
+        })
+    }
+
+    fn get_unfinished_transfers(&self) -> BoxFuture<'_, Result<Vec<Transfer>, RustexError>> {
+        self.read(|state| state.get_unfinished_transfers())
+    }
+
+    fn get_user_fills(
+        &self,
+        user: UserId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Fill>, RustexError>> {
+        self.read(move |state| state.get_user_fills(user, market))
+    }
+
+    fn get_trade_tape(
+        &self,
+        market: ExchangeMarket,
+        query: TapeQuery,
+    ) -> BoxFuture<'_, Result<Page<TapeTrade>, RustexError>> {
+        self.read(move |state| state.get_trade_tape(market, query))
+    }
+
+    fn get_candles(
+        &self,
+        market: ExchangeMarket,
+        query: CandleQuery,
+    ) -> BoxFuture<'_, Result<Vec<Candle>, RustexError>> {
+        self.read(move |state| state.get_candles(market, query))
+    }
+
+    fn rebuild_candles(
+        &self,
+        market: ExchangeMarket,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<usize, RustexError>> {
+        self.write(move |state| state.rebuild_candles(market, from, to))
+            .map_err(rolled_back(format!("Candle backfill of {:?}", market)))
+            .boxed()
+    }
+
+    fn get_last_trade_price(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<i64>, RustexError>> {
+        self.read(move |state| state.get_last_trade_price(market))
+    }
+
+    fn get_book_at(
+        &self,
+        market: ExchangeMarket,
+        at: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<BookSnapshot, RustexError>> {
+        self.read(move |state| Ok(state.get_book_at(market, at)))
+    }
+
+    fn get_all_risk_limits(&self) -> BoxFuture<'_, Result<Vec<RiskLimits>, RustexError>> {
+        self.read(move |state| state.get_all_risk_limits())
+    }
+
+    fn get_risk_limits(
+        &self,
+        user: UserId,
+    ) -> BoxFuture<'_, Result<Option<RiskLimits>, RustexError>> {
+        self.read(move |state| state.get_risk_limits(user))
+    }
+
+    fn upsert_risk_limits(
+        &self,
+        limits: RiskLimits,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        self.write(move |state| state.upsert_risk_limits(limits, origin))
+    }
+
+    fn delete_risk_limits(
+        &self,
+        user: UserId,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<bool, RustexError>> {
+        self.write(move |state| state.delete_risk_limits(user, origin))
+    }
+
+    /// Every row is kept live
+    fn create_partitions(&self) -> BoxFuture<'_, Result<usize, RustexError>> {
+        future::ready(Ok(0)).boxed()
+    }
+
+    fn archive(&self, _before: DateTime<Utc>) -> BoxFuture<'_, Result<usize, RustexError>> {
+        future::ready(Ok(0)).boxed()
+    }
+
+    fn append_audit(&self, events: Vec<AuditEvent>) -> BoxFuture<'_, Result<(), RustexError>> {
+        let writes = format!("Audit of {} events", events.len());
+        self.write(move |state| {
+            state.append_audit(events);
+            Ok(())
+        })
+        .map_err(rolled_back(writes))
+        .boxed()
+    }
+
+    fn get_audit_log(
+        &self,
+        after: Option<i64>,
+        limit: i64,
+    ) -> BoxFuture<'_, Result<Vec<AuditEntry>, RustexError>> {
+        self.read(move |state| state.get_audit_log(after, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{fund, order};
+
+    #[tokio::test]
+    async fn test_cancelled_orders_are_no_longer_pending() {
+        let storage = MemoryStorage::new();
+        let market = ExchangeMarket::BTC_USD;
+        fund(&storage, 1.into(), Currencies::USD, 100.0).await;
+        storage
+            .insert_order(order(0).build(), AuditOrigin::default())
+            .await
+            .unwrap();
+        storage
+            .insert_cancellation(
+                market,
+                0.into(),
+                OrderStatus::Cancelled,
+                None,
+                AuditOrigin::default(),
+            )
+            .await
+            .unwrap();
+
+        let pending = storage.get_pending_orders_ids(market).await.unwrap();
+        assert!(pending.is_empty());
+        let balances = storage.get_user_balances(1.into()).await.unwrap();
+        assert_eq!((balances[0].available, balances[0].reserved), (100.0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_cancellations_keep_the_funds_of_unrecorded_trades() {
+        let storage = MemoryStorage::new();
+        let market = ExchangeMarket::BTC_USD;
+        fund(&storage, 1.into(), Currencies::USD, 100.0).await;
+        fund(&storage, 2.into(), Currencies::BTC, 1.0).await;
+        let sell = order(1).user(2).sell().quantity(0.75).build();
+        for order in [order(0).build(), sell] {
+            storage
+                .insert_order(order, AuditOrigin::default())
+                .await
+                .unwrap();
+        }
+
+        // Cancelled after trading 0.75 in the book, before the trade is recorded
+        storage
+            .insert_cancellation(
+                market,
+                0.into(),
+                OrderStatus::Cancelled,
+                Some(0.25),
+                AuditOrigin::default(),
+            )
+            .await
+            .unwrap();
+        let balances = storage.get_user_balances(1.into()).await.unwrap();
+        assert_eq!((balances[0].available, balances[0].reserved), (25.0, 75.0));
+
+        let trade = Trade {
+            trade_id: 0.into(),
+            exchange: market,
+            buy_order: 0.into(),
+            sell_order: 1.into(),
+            price: 100,
+            quantity: 0.75,
+            created_at: Some(Utc::now()),
+            aggressor: OrderType::Sell,
+        };
+        storage
+            .insert_trades(market, vec![trade], vec![1.into()])
+            .await
+            .unwrap();
+        let balances = storage.get_user_balances(1.into()).await.unwrap();
+        let funds: Vec<_> = balances
+            .iter()
+            .map(|balance| (balance.currency, balance.available, balance.reserved))
+            .collect();
+        assert_eq!(
+            funds,
+            [(Currencies::BTC, 0.75, 0.0), (Currencies::USD, 25.0, 0.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_orders_and_cancellations_are_audited_with_their_origin() {
+        let storage = MemoryStorage::new();
+        let market = ExchangeMarket::BTC_USD;
+        let origin = AuditOrigin::user(1.into(), Some("10.0.0.1".into()));
+        fund(&storage, 1.into(), Currencies::USD, 100.0).await;
+        let funded = storage.get_audit_log(None, 10).await.unwrap();
+        let funded = funded.last().map(|entry| entry.seq);
+        storage
+            .insert_order(order(0).build(), origin.clone())
+            .await
+            .unwrap();
+        let r = storage.insert_order(order(1).build(), origin.clone()).await;
+        assert!(matches!(r, Err(RustexError::UserFacingError(_))));
+        storage
+            .insert_cancellation(market, 0.into(), OrderStatus::Cancelled, None, origin)
+            .await
+            .unwrap();
+
+        let log = storage.get_audit_log(funded, 10).await.unwrap();
+        let actions: Vec<_> = log.iter().map(|entry| entry.action).collect();
+        assert_eq!(
+            actions,
+            [
+                AuditAction::OrderAccepted,
+                AuditAction::OrderRejected,
+                AuditAction::OrderCancelled
+            ]
+        );
+        assert!(log
+            .iter()
+            .all(|entry| entry.actor == Some(1.into())
+                && entry.source_ip.as_deref() == Some("10.0.0.1")));
+    }
+
+    #[tokio::test]
+    async fn test_transfers_and_risk_limits_are_audited_with_their_origin() {
+        let storage = MemoryStorage::new();
+        let admin = AuditOrigin::user(0.into(), Some("10.0.0.2".into()));
+        let limits = RiskLimits {
+            user_id: 1.into(),
+            max_order_quantity: Some(1.0),
+            max_notional: None,
+            max_open_orders: None,
+            max_orders_per_second: None,
+            max_price_deviation: None,
+        };
+        storage
+            .upsert_risk_limits(limits, admin.clone())
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            storage
+                .delete_risk_limits(1.into(), admin.clone())
+                .await
+                .unwrap();
+        }
+        let deposit = NewTransfer {
+            user_id: 1.into(),
+            kind: TransferKind::Deposit,
+            currency: Currencies::USD,
+            amount: 10.0,
+        };
+        let user = AuditOrigin::user(1.into(), None);
+        let market = Some(ExchangeMarket::BTC_USD);
+        let transfer = storage
+            .insert_transfer(deposit, market, user.clone())
+            .await
+            .unwrap();
+        storage
+            .transition_transfer(
+                transfer.transfer_id,
+                TransferStatus::Requested,
+                TransferStatus::Failed,
+                None,
+                market,
+                admin.clone(),
+            )
+            .await
+            .unwrap();
+
+        let log = storage.get_audit_log(None, 10).await.unwrap();
+        let audited: Vec<_> = log
+            .iter()
+            .map(|entry| (entry.action, entry.actor, entry.exchange))
+            .collect();
+        assert_eq!(
+            audited,
+            [
+                (AuditAction::RiskLimitsUpdated, admin.actor, None),
+                (AuditAction::RiskLimitsDeleted, admin.actor, None),
+                (AuditAction::TransferRequested, user.actor, market),
+                (AuditAction::TransferRejected, admin.actor, market),
+            ]
+        );
+    }
+}
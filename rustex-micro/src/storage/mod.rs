@@ -0,0 +1,547 @@
+//! Storage backends of the db-service. Every operation is atomic:
+//! either all of its writes are persisted or none of them is
+
+#[macro_use]
+mod archive;
+mod audit;
+mod candles;
+mod ledger;
+pub mod memory;
+pub mod postgres;
+mod rules;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use anyhow::Context;
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use rustex_core::prelude::*;
+use rustex_errors::RustexError;
+use serde_json::json;
+
+pub use memory::MemoryStorage;
+pub use postgres::PgStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+pub trait Storage: Send + Sync {
+    /// Fails if the backend cannot be reached
+    fn check(&self) -> BoxFuture<'_, Result<(), RustexError>>;
+
+    /// Reserves the next `count` ids of the kind in the market, starting at `from` or later
+    fn reserve_ids(
+        &self,
+        market: ExchangeMarket,
+        kind: IdKind,
+        from: i64,
+        count: i64,
+    ) -> BoxFuture<'_, Result<IdBlock, RustexError>>;
+
+    fn get_pending_orders_ids(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<OrderId>, RustexError>>;
+
+    fn get_order_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> BoxFuture<'_, Result<Page<OrderDetails>, RustexError>>;
+
+    /// The query never filters by order status
+    fn get_trade_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> BoxFuture<'_, Result<Page<UserTrade>, RustexError>>;
+
+    fn get_order_user(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<UserId>, RustexError>>;
+
+    fn get_orders(
+        &self,
+        orders: Vec<OrderId>,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Order>, RustexError>>;
+
+    fn get_order_trades(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Trade>, RustexError>>;
+
+    /// Records the order as pending locking its funds. If they are not
+    /// available the order is recorded as rejected and a user facing error returned.
+    /// Either way the order is audited along with it
+    fn insert_order(
+        &self,
+        order: Order,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>>;
+
+    /// Records the orders in a single write. Each order gets the result `insert_order`
+    /// would have returned. Fails as a whole if the write cannot be persisted
+    fn insert_orders(
+        &self,
+        orders: Vec<(Order, AuditOrigin)>,
+    ) -> BoxFuture<'_, Result<Vec<Result<(), RustexError>>, RustexError>>;
+
+    /// Records the timestamped trades settling their funds, updates the fills of
+    /// their orders and folds them into the candles. Batches already recorded are skipped
+    fn insert_trades(
+        &self,
+        market: ExchangeMarket,
+        trades: Vec<Trade>,
+        completed_orders: Vec<OrderId>,
+    ) -> BoxFuture<'_, Result<(), RustexError>>;
+
+    /// The status is either cancelled or expired. The cancellation is audited along with it.
+    /// Only the funds of the `unmatched` quantity are released if it is known, as those of
+    /// the trades not recorded yet stay reserved until then. Recording the same cancellation
+    /// again is a no-op
+    fn insert_cancellation(
+        &self,
+        market: ExchangeMarket,
+        order: OrderId,
+        status: OrderStatus,
+        unmatched: Option<f64>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>>;
+
+    fn get_last_group_id(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<GroupId>, RustexError>>;
+
+    fn get_open_order_groups(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<OrderGroup>, RustexError>>;
+
+    fn get_order_group(
+        &self,
+        group: GroupId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<OrderGroup>, RustexError>>;
+
+    /// Existing groups only get their status and orders updated
+    fn upsert_order_groups(
+        &self,
+        groups: Vec<OrderGroup>,
+    ) -> BoxFuture<'_, Result<(), RustexError>>;
+
+    fn get_user_balances(&self, user: UserId) -> BoxFuture<'_, Result<Vec<Balance>, RustexError>>;
+
+    /// Records a requested transfer. Withdrawals lock their funds.
+    /// The request is audited as made by `origin` in the same write
+    fn insert_transfer(
+        &self,
+        transfer: NewTransfer,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<Transfer, RustexError>>;
+
+    fn get_user_transfers(&self, user: UserId)
+        -> BoxFuture<'_, Result<Vec<Transfer>, RustexError>>;
+
+    /// Moves the transfer from one status to the next booking its ledger entries.
+    /// Approvals and rejections are audited as made by `origin` in the same write
+    fn transition_transfer(
+        &self,
+        transfer: i64,
+        from: TransferStatus,
+        to: TransferStatus,
+        reference: Option<String>,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<Transfer, RustexError>>;
+
+    /// Transfers handed over to the custodian whose outcome was not recorded yet
+    fn get_unfinished_transfers(&self) -> BoxFuture<'_, Result<Vec<Transfer>, RustexError>>;
+
+    fn get_user_fills(
+        &self,
+        user: UserId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Fill>, RustexError>>;
+
+    fn get_trade_tape(
+        &self,
+        market: ExchangeMarket,
+        query: TapeQuery,
+    ) -> BoxFuture<'_, Result<Page<TapeTrade>, RustexError>>;
+
+    fn get_candles(
+        &self,
+        market: ExchangeMarket,
+        query: CandleQuery,
+    ) -> BoxFuture<'_, Result<Vec<Candle>, RustexError>>;
+
+    /// Rebuilds from the trades every candle of the whole days overlapping
+    /// the time range. Returns the number of candles stored
+    fn rebuild_candles(
+        &self,
+        market: ExchangeMarket,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<usize, RustexError>>;
+
+    fn get_last_trade_price(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<i64>, RustexError>>;
+
+    /// Rebuilds the book of the market as it stood at the given time
+    fn get_book_at(
+        &self,
+        market: ExchangeMarket,
+        at: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<BookSnapshot, RustexError>>;
+
+    fn get_all_risk_limits(&self) -> BoxFuture<'_, Result<Vec<RiskLimits>, RustexError>>;
+
+    fn get_risk_limits(
+        &self,
+        user: UserId,
+    ) -> BoxFuture<'_, Result<Option<RiskLimits>, RustexError>>;
+
+    /// The change is audited as made by `origin` in the same write
+    fn upsert_risk_limits(
+        &self,
+        limits: RiskLimits,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>>;
+
+    /// Returns false if the user had no limits. Otherwise the removal is audited
+    /// as made by `origin` in the same write
+    fn delete_risk_limits(
+        &self,
+        user: UserId,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<bool, RustexError>>;
+
+    /// Creates the partitions of the coming months, so new rows never land in the default
+    /// partition. Returns the number of partitions created
+    fn create_partitions(&self) -> BoxFuture<'_, Result<usize, RustexError>>;
+
+    /// Moves the orders, trades and cancellations of the months ended before `before` out
+    /// of the live tables. Only the queries reaching before the archive cut-off read them.
+    /// Returns the number of partitions moved
+    fn archive(&self, before: DateTime<Utc>) -> BoxFuture<'_, Result<usize, RustexError>>;
+
+    /// Appends the events to the audit log, in order
+    fn append_audit(&self, events: Vec<AuditEvent>) -> BoxFuture<'_, Result<(), RustexError>>;
+
+    /// Returns up to `limit` entries of the audit log following `after`, in order
+    fn get_audit_log(
+        &self,
+        after: Option<i64>,
+        limit: i64,
+    ) -> BoxFuture<'_, Result<Vec<AuditEntry>, RustexError>>;
+}
+
+/// Reads the variable of the market, e.g. `BTC_GBP_POSTGRES_ADDRESS`,
+/// falling back to the one shared by every market
+fn market_var(market: Option<ExchangeMarket>, name: &str) -> Option<String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    market
+        .and_then(|market| var(&format!("{market:?}_{name}")))
+        .or_else(|| var(name))
+}
+
+/// Backend of a database, selected with `DB_STORAGE`: `postgres` (default), `sqlite` or `memory`
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum StorageConfig {
+    Postgres {
+        address: String,
+        username: String,
+        password: String,
+    },
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        path: String,
+    },
+    Memory,
+}
+
+impl StorageConfig {
+    /// Configuration of the market, or the default one if `None`. Every
+    /// variable can be prefixed with the market to override it
+    pub fn from_env(market: Option<ExchangeMarket>) -> anyhow::Result<Self> {
+        let var = |name| market_var(market, name);
+        let required =
+            |name| var(name).with_context(|| format!("{name} not defined as environment variable"));
+        match var("DB_STORAGE").as_deref().unwrap_or("postgres") {
+            "postgres" => Ok(Self::Postgres {
+                address: required("POSTGRES_ADDRESS")?,
+                username: required("PG_USERNAME")?,
+                password: required("PG_PASSWORD")?,
+            }),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(Self::Sqlite {
+                path: var("SQLITE_PATH").unwrap_or_else(|| "rustex.sqlite".into()),
+            }),
+            "memory" => Ok(Self::Memory),
+            backend => anyhow::bail!("Unsupported storage backend {backend}"),
+        }
+    }
+
+    pub fn open(&self) -> anyhow::Result<Arc<dyn Storage>> {
+        Ok(match self {
+            Self::Postgres {
+                address,
+                username,
+                password,
+            } => Arc::new(PgStorage::new(&format!(
+                "postgres://{username}:{password}@{address}"
+            ))?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite { path } => Arc::new(SqliteStorage::open(path)?),
+            Self::Memory => Arc::new(MemoryStorage::new()),
+        })
+    }
+}
+
+/// Never shows the password
+impl fmt::Display for StorageConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Postgres {
+                address, username, ..
+            } => write!(f, "postgres {username}@{address}"),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite { path } => write!(f, "sqlite {path}"),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// Storage of every market. Funds live in the storage of their market, and
+/// risk limits in the default one. Markets configured alike share a storage
+#[derive(Clone)]
+pub struct StorageRouter {
+    default: Arc<dyn Storage>, // Clone only increases reference counting
+    markets: HashMap<ExchangeMarket, Arc<dyn Storage>>,
+}
+
+impl StorageRouter {
+    /// Every market lives in the same storage
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            default: storage,
+            markets: HashMap::new(),
+        }
+    }
+
+    /// Moves the market to its own storage
+    pub fn with_market(mut self, market: ExchangeMarket, storage: Arc<dyn Storage>) -> Self {
+        self.markets.insert(market, storage);
+        self
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let default = StorageConfig::from_env(None)?;
+        log::info!("Default storage: {default}");
+        let mut opened = HashMap::from([(default.clone(), default.open()?)]);
+        let mut router = Self::new(opened[&default].clone());
+        for market in ExchangeMarket::ALL {
+            let config = StorageConfig::from_env(Some(market))?;
+            if config == default {
+                continue;
+            }
+            log::info!("{market:?} storage: {config}");
+            let storage = match opened.get(&config) {
+                Some(storage) => storage.clone(),
+                None => {
+                    let storage = config.open()?;
+                    opened.insert(config, storage.clone());
+                    storage
+                }
+            };
+            router = router.with_market(market, storage);
+        }
+        Ok(router)
+    }
+
+    /// Storage holding the orders, trades and candles of the market
+    pub fn market(&self, market: ExchangeMarket) -> &Arc<dyn Storage> {
+        self.markets.get(&market).unwrap_or(&self.default)
+    }
+
+    /// Storage holding the funds booked for the market. `None` is only
+    /// allowed while every market lives in the default storage, as the
+    /// funds of a sharded setup are spread over several storages
+    pub fn funds(&self, market: Option<ExchangeMarket>) -> Result<&Arc<dyn Storage>, RustexError> {
+        match market {
+            Some(market) => Ok(self.market(market)),
+            None if self.is_sharded() => Err(RustexError::UserFacingError(
+                "A market is required, as funds are booked in the database of their market".into(),
+            )),
+            None => Ok(&self.default),
+        }
+    }
+
+    /// Whether any market lives outside the default storage
+    pub fn is_sharded(&self) -> bool {
+        self.markets
+            .values()
+            .any(|storage| !Arc::ptr_eq(storage, &self.default))
+    }
+
+    /// Storage holding the risk limits
+    pub fn default(&self) -> &Arc<dyn Storage> {
+        &self.default
+    }
+
+    /// Distinct storages holding the market, or every market if `None`
+    pub fn shards(&self, market: Option<ExchangeMarket>) -> Vec<&Arc<dyn Storage>> {
+        let markets = market.map_or(ExchangeMarket::ALL.to_vec(), |market| vec![market]);
+        let mut shards: Vec<&Arc<dyn Storage>> = vec![];
+        for storage in markets.into_iter().map(|market| self.market(market)) {
+            if !shards.iter().any(|shard| Arc::ptr_eq(shard, storage)) {
+                shards.push(storage);
+            }
+        }
+        shards
+    }
+
+    /// Every distinct storage, the default one first
+    pub fn all(&self) -> Vec<&Arc<dyn Storage>> {
+        let mut all = vec![&self.default];
+        all.extend(
+            self.shards(None)
+                .into_iter()
+                .filter(|shard| !Arc::ptr_eq(shard, &self.default)),
+        );
+        all
+    }
+}
+
+/// Audit events of the trades, recorded along with them
+pub(crate) fn trade_events(market: ExchangeMarket, trades: &[Trade]) -> Vec<AuditEvent> {
+    trades
+        .iter()
+        .map(|trade| {
+            let details = serde_json::to_string(trade).expect("Trades are always serializable");
+            AuditEvent::system(AuditAction::Trade, market, details)
+        })
+        .collect()
+}
+
+/// Audit event of an order recorded as accepted, or as rejected with the error
+pub(crate) fn order_event(
+    order: &Order,
+    origin: &AuditOrigin,
+    rejection: Option<&RustexError>,
+) -> AuditEvent {
+    let (action, details) = match rejection {
+        None => (AuditAction::OrderAccepted, json!({ "order": order })),
+        Some(e) => (
+            AuditAction::OrderRejected,
+            json!({ "order": order, "error": e.to_string() }),
+        ),
+    };
+    origin.event(action, Some(order.exchange), details.to_string())
+}
+
+/// Audit event of a cancellation, recorded along with it
+pub(crate) fn cancellation_event(
+    market: ExchangeMarket,
+    order: OrderId,
+    status: OrderStatus,
+    origin: &AuditOrigin,
+) -> AuditEvent {
+    let details = json!({ "orderId": order, "status": status });
+    origin.event(
+        AuditAction::OrderCancelled,
+        Some(market),
+        details.to_string(),
+    )
+}
+
+/// Audit event of a transfer, recorded along with its request (`from` unset) or transition.
+/// Only requests, approvals and rejections are audited: the exchange makes the rest on its own
+pub(crate) fn transfer_event(
+    transfer: &Transfer,
+    from: Option<TransferStatus>,
+    market: Option<ExchangeMarket>,
+    origin: &AuditOrigin,
+) -> Option<AuditEvent> {
+    let action = match (from, transfer.status) {
+        (None, _) => AuditAction::TransferRequested,
+        (Some(TransferStatus::Requested), TransferStatus::Approved) => {
+            AuditAction::TransferApproved
+        }
+        (Some(TransferStatus::Requested), TransferStatus::Failed) => AuditAction::TransferRejected,
+        _ => return None,
+    };
+    let details = serde_json::to_string(transfer).expect("Transfers are always serializable");
+    Some(origin.event(action, market, details))
+}
+
+/// Audit event of the risk limits set, recorded along with them
+pub(crate) fn risk_limits_event(limits: &RiskLimits, origin: &AuditOrigin) -> AuditEvent {
+    let details = serde_json::to_string(limits).expect("Risk limits are always serializable");
+    origin.event(AuditAction::RiskLimitsUpdated, None, details)
+}
+
+/// Audit event of the removal of the risk limits of the user, recorded along with it
+pub(crate) fn risk_limits_deletion_event(user: UserId, origin: &AuditOrigin) -> AuditEvent {
+    let details = json!({ "userId": user });
+    origin.event(AuditAction::RiskLimitsDeleted, None, details.to_string())
+}
+
+/// Reports which batch of writes was rolled back and why.
+/// Errors meant for the user are passed through
+pub(crate) fn rolled_back(writes: String) -> impl FnOnce(RustexError) -> RustexError {
+    move |e| match e {
+        RustexError::UserFacingError(_) => e,
+        e => RustexError::DbServiceError(format!("{writes} was rolled back: {e:?}").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markets_configured_alike_share_a_storage() {
+        let default: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let gbp: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let router = StorageRouter::new(default.clone())
+            .with_market(ExchangeMarket::BTC_GBP, gbp.clone())
+            .with_market(ExchangeMarket::BTC_EUR, gbp.clone());
+
+        assert!(Arc::ptr_eq(
+            router.market(ExchangeMarket::BTC_USD),
+            &default
+        ));
+        assert!(matches!(
+            router.funds(None),
+            Err(RustexError::UserFacingError(_))
+        ));
+        assert!(Arc::ptr_eq(
+            router.funds(Some(ExchangeMarket::BTC_EUR)).unwrap(),
+            &gbp
+        ));
+        assert_eq!(router.shards(None).len(), 2);
+        assert_eq!(router.shards(Some(ExchangeMarket::BTC_GBP)).len(), 1);
+        assert!(Arc::ptr_eq(router.all()[0], &default));
+    }
+
+    #[test]
+    fn test_funds_need_no_market_in_a_single_storage() {
+        let default: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let router = StorageRouter::new(default.clone())
+            .with_market(ExchangeMarket::BTC_GBP, default.clone());
+
+        assert!(!router.is_sharded());
+        assert!(Arc::ptr_eq(router.funds(None).unwrap(), &default));
+    }
+}
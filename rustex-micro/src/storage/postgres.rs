@@ -0,0 +1,1274 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    sql_types::{Integer, Timestamptz},
+    upsert::excluded,
+    BoolExpressionMethods, ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl,
+    SelectableHelper,
+};
+use diesel_async::{
+    pooled_connection::{deadpool::Pool, AsyncDieselConnectionManager},
+    scoped_futures::ScopedFutureExt,
+    AsyncConnection, AsyncPgConnection, RunQueryDsl,
+};
+use futures::future::{BoxFuture, FutureExt};
+use rustex_core::{db, prelude::*};
+use rustex_errors::RustexError;
+
+use super::{
+    archive, audit, candles, ledger, risk_limits_deletion_event, risk_limits_event, rolled_back,
+    rules, transfer_event, Storage,
+};
+
+/// Months of partitions created beforehand
+const PARTITIONS_AHEAD: i32 = 2;
+
+diesel::define_sql_function! {
+    /// Creates the monthly partitions from the month of `since` on. Returns how many were created
+    fn rustex_create_partitions(since: Timestamptz, months_ahead: Integer) -> Integer;
+}
+
+diesel::define_sql_function! {
+    /// Detaches the partitions of the completed months and attaches them to the archive tables
+    fn rustex_archive_partitions(before: Timestamptz) -> Integer;
+}
+
+#[derive(Clone)]
+pub struct PgStorage {
+    pool: Pool<AsyncPgConnection>, // Clone only increases reference counting
+}
+
+impl PgStorage {
+    pub fn new(url: &str) -> Result<Self, RustexError> {
+        let config = AsyncDieselConnectionManager::<AsyncPgConnection>::new(url);
+        let pool = Pool::builder(config).build()?;
+        Ok(Self { pool })
+    }
+}
+
+impl Storage for PgStorage {
+    fn check(&self) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            diesel::sql_query("SELECT 1").execute(conn).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn reserve_ids(
+        &self,
+        market: ExchangeMarket,
+        kind: IdKind,
+        from: i64,
+        count: i64,
+    ) -> BoxFuture<'_, Result<IdBlock, RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move {
+                    use db::schema::id_sequences::dsl::*;
+                    // Markets added after the migration start from scratch
+                    diesel::insert_into(id_sequences)
+                        .values(exchange.eq(market))
+                        .on_conflict_do_nothing()
+                        .execute(conn)
+                        .await?;
+                    let (next_order, next_trade): (i64, i64) = id_sequences
+                        .find(market)
+                        .select((next_order_id, next_trade_id))
+                        .for_update()
+                        .first(conn)
+                        .await?;
+                    let next = match kind {
+                        IdKind::Order => next_order,
+                        IdKind::Trade => next_trade,
+                    };
+                    let block = rules::reserve_ids(next, from, count);
+                    let sequence = id_sequences.find(market);
+                    match kind {
+                        IdKind::Order => {
+                            diesel::update(sequence)
+                                .set(next_order_id.eq(block.end))
+                                .execute(conn)
+                                .await?
+                        }
+                        IdKind::Trade => {
+                            diesel::update(sequence)
+                                .set(next_trade_id.eq(block.end))
+                                .execute(conn)
+                                .await?
+                        }
+                    };
+                    Ok(block)
+                }
+                .scope_boxed()
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn get_pending_orders_ids(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<OrderId>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::pending_orders::dsl::*;
+            let query =
+                diesel::QueryDsl::select(pending_orders.filter(exchange.eq(market)), order_id);
+            let order_ids: Vec<i64> = query.load(conn).await?;
+            Ok(order_ids.into_iter().map(|e| e.into()).collect())
+        }
+        .boxed()
+    }
+
+    fn get_order_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> BoxFuture<'_, Result<Page<OrderDetails>, RustexError>> {
+        async move {
+            let page_size = query.page_size()?;
+            let cursor = query.cursor()?;
+            let conn = &mut *self.pool.get().await?;
+
+            let archived = archive::reaches(archive::cutoff(conn).await?, query.from);
+            let rows: Vec<Order> = live_or_archived!(archived, [orders::dsl::*], {
+                let mut rows = orders.filter(user_id.eq(user)).into_boxed();
+                if let Some(market) = query.market {
+                    rows = rows.filter(exchange.eq(market));
+                }
+                if let Some(side) = query.side {
+                    rows = rows.filter(order_type.eq(side));
+                }
+                if let Some(order_status) = query.status {
+                    rows = rows.filter(status.eq(order_status));
+                }
+                if let Some(from) = query.from {
+                    rows = rows.filter(created_at.ge(from));
+                }
+                if let Some(to) = query.to {
+                    rows = rows.filter(created_at.lt(to));
+                }
+                if let Some(cursor) = cursor {
+                    rows = rows.filter(
+                        created_at
+                            .lt(cursor.created_at)
+                            .or(created_at.eq(cursor.created_at).and(
+                                exchange.lt(cursor.exchange).or(exchange
+                                    .eq(cursor.exchange)
+                                    .and(order_id.lt(cursor.order_id))),
+                            )),
+                    );
+                }
+                rows.order_by((created_at.desc(), exchange.desc(), order_id.desc()))
+                    .limit(page_size as i64 + 1)
+                    .load(conn)
+                    .await?
+            });
+
+            let details = rows.into_iter().map(OrderDetails::from).collect();
+            Ok(Page::new(details, page_size, OrderDetails::cursor))
+        }
+        .boxed()
+    }
+
+    fn get_trade_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> BoxFuture<'_, Result<Page<UserTrade>, RustexError>> {
+        async move {
+            let page_size = query.page_size()?;
+            let cursor = query.cursor()?;
+            let conn = &mut *self.pool.get().await?;
+
+            // Trades from the cut-off on only belong to live orders
+            let archived = archive::reaches(archive::cutoff(conn).await?, query.from);
+            let rows: Vec<UserTrade> = live_or_archived!(archived, [orders, trades], {
+                // A self-trade yields a record for each of the two orders
+                let mut rows = trades::table
+                    .inner_join(
+                        orders::table.on(orders::exchange.eq(trades::exchange).and(
+                            orders::order_id
+                                .eq(trades::buy_order)
+                                .or(orders::order_id.eq(trades::sell_order)),
+                        )),
+                    )
+                    .filter(orders::user_id.eq(user))
+                    .select((
+                        trades::trade_id,
+                        trades::exchange,
+                        orders::order_id,
+                        orders::order_type,
+                        trades::price,
+                        trades::quantity,
+                        trades::created_at,
+                    ))
+                    .into_boxed();
+                if let Some(market) = query.market {
+                    rows = rows.filter(trades::exchange.eq(market));
+                }
+                if let Some(side) = query.side {
+                    rows = rows.filter(orders::order_type.eq(side));
+                }
+                if let Some(from) = query.from {
+                    rows = rows.filter(trades::created_at.ge(from));
+                }
+                if let Some(to) = query.to {
+                    rows = rows.filter(trades::created_at.lt(to));
+                }
+                if let Some(cursor) = cursor {
+                    let same_trade = trades::trade_id
+                        .eq(cursor.id)
+                        .and(orders::order_id.lt(cursor.order_id));
+                    let same_market = trades::trade_id.lt(cursor.id).or(same_trade);
+                    let same_time = trades::exchange
+                        .lt(cursor.exchange)
+                        .or(trades::exchange.eq(cursor.exchange).and(same_market));
+                    rows = rows.filter(
+                        trades::created_at
+                            .lt(cursor.created_at)
+                            .or(trades::created_at.eq(cursor.created_at).and(same_time)),
+                    );
+                }
+                rows.order_by((
+                    trades::created_at.desc(),
+                    trades::exchange.desc(),
+                    trades::trade_id.desc(),
+                    orders::order_id.desc(),
+                ))
+                .limit(page_size as i64 + 1)
+                .load(conn)
+                .await?
+            });
+
+            Ok(Page::new(rows, page_size, UserTrade::cursor))
+        }
+        .boxed()
+    }
+
+    fn get_order_user(
+        &self,
+        order_identifier: OrderId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<UserId>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+
+            use db::schema::orders::dsl::*;
+            let query = orders
+                .filter(order_id.eq(order_identifier).and(exchange.eq(market)))
+                .select(user_id);
+            let user_ids = query.load(conn).await?;
+
+            match user_ids.len() {
+                0 => Ok(None),
+                1 => Ok(Some(user_ids[0])),
+                _ => Err(RustexError::DbServiceError(
+                    "There are multiple users for a single order id".into(),
+                )),
+            }
+        }
+        .boxed()
+    }
+
+    fn get_orders(
+        &self,
+        order_ids: Vec<OrderId>,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Order>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            let order_ids = order_ids.into_iter().map(i64::from).collect::<Vec<_>>();
+
+            // Only the orders missing from the live tables can have been archived
+            let mut archived = false;
+            loop {
+                let rows: Vec<Order> = live_or_archived!(archived, [orders::dsl::*], {
+                    orders
+                        .filter(exchange.eq(market).and(order_id.eq_any(&order_ids)))
+                        .load(conn)
+                        .await?
+                });
+                if archived
+                    || rows.len() == order_ids.len()
+                    || archive::cutoff(conn).await?.is_none()
+                {
+                    return Ok(rows);
+                }
+                archived = true;
+            }
+        }
+        .boxed()
+    }
+
+    fn get_order_trades(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Trade>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+
+            let order: i64 = order.into();
+
+            use db::schema::trades::dsl::*;
+            let query = trades.filter(
+                exchange
+                    .eq(market)
+                    .and(buy_order.eq(order).or(sell_order.eq(order))),
+            );
+            let rows: Vec<Trade> = query.load(conn).await?;
+            Ok(rows)
+        }
+        .boxed()
+    }
+
+    fn insert_order(
+        &self,
+        new_order: Order,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let mut results = self.insert_orders(vec![(new_order, origin)]).await?;
+            results.pop().expect("One result per order")
+        }
+        .boxed()
+    }
+
+    fn insert_orders(
+        &self,
+        new_orders: Vec<(Order, AuditOrigin)>,
+    ) -> BoxFuture<'_, Result<Vec<Result<(), RustexError>>, RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            let batch_size = new_orders.len();
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move {
+                    // Orders already recorded, or repeated in the batch, are not inserted again
+                    let recorded: HashSet<(ExchangeMarket, OrderId)> = {
+                        use db::schema::orders::dsl::*;
+                        let markets = new_orders.iter().map(|(o, _)| o.exchange);
+                        let ids = new_orders.iter().map(|(o, _)| o.order_id);
+                        let rows: Vec<(ExchangeMarket, i64)> = orders
+                            .filter(exchange.eq_any(markets).and(order_id.eq_any(ids)))
+                            .select((exchange, order_id))
+                            .load(conn)
+                            .await?;
+                        rows.into_iter().map(|(m, id)| (m, id.into())).collect()
+                    };
+                    // Balances are locked once for the whole batch and drawn down in order
+                    let funds: HashMap<(UserId, Currencies), f64> = {
+                        use db::schema::balances::dsl::*;
+                        let users = new_orders.iter().map(|(o, _)| o.user_id);
+                        let rows: Vec<Balance> = balances
+                            .filter(user_id.eq_any(users))
+                            .order_by((user_id, currency))
+                            .for_update()
+                            .load(conn)
+                            .await?;
+                        rows.into_iter()
+                            .map(|b| ((b.user_id, b.currency), b.available))
+                            .collect()
+                    };
+
+                    let writes = rules::place_orders(new_orders, recorded, funds, Utc::now());
+                    if !writes.orders.is_empty() {
+                        register_users(conn, writes.users()).await?;
+                        diesel::insert_into(db::schema::orders::table)
+                            .values(&writes.orders)
+                            .execute(conn)
+                            .await?;
+                    }
+                    if !writes.pending.is_empty() {
+                        diesel::insert_into(db::schema::pending_orders::table)
+                            .values(&writes.pending)
+                            .execute(conn)
+                            .await?;
+                    }
+                    ledger::record_entries(conn, &writes.entries).await?;
+                    audit::append(conn, writes.events).await?;
+                    Ok(writes.results)
+                }
+                .scope_boxed()
+            })
+            .await
+            .map_err(rolled_back(format!("Batch of {batch_size} orders")))
+        }
+        .boxed()
+    }
+
+    fn insert_trades(
+        &self,
+        market: ExchangeMarket,
+        trades: Vec<Trade>,
+        completed_orders: Vec<OrderId>,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            let trades_count = trades.len();
+
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move {
+                    // Retried batches are already recorded. Every trade registers its key in
+                    // trade_keys, so a retry racing the first attempt fails on the key instead
+                    // and is skipped on the next one
+                    let ids = trades.iter().map(|t| t.trade_id).collect::<Vec<_>>();
+                    let recorded: i64 = {
+                        use db::schema::trade_keys::dsl::*;
+                        trade_keys
+                            .filter(exchange.eq(market).and(trade_id.eq_any(ids)))
+                            .count()
+                            .get_result(conn)
+                            .await?
+                    };
+                    if rules::trades_recorded(&trades, recorded as usize)? {
+                        return Ok(());
+                    }
+                    let settled = rules::settled_orders(&trades, &completed_orders);
+                    let rows = rules::SettlementRows {
+                        orders: ledger::lock_orders(conn, market, &settled).await?,
+                        pending: {
+                            use db::schema::pending_orders::dsl::*;
+                            pending_orders
+                                .filter(exchange.eq(market).and(order_id.eq_any(&settled)))
+                                .select(order_id)
+                                .load(conn)
+                                .await?
+                        },
+                        earlier_trades: {
+                            use db::schema::trades::dsl::*;
+                            trades
+                                .filter(
+                                    exchange.eq(market).and(
+                                        buy_order
+                                            .eq_any(&completed_orders)
+                                            .or(sell_order.eq_any(&completed_orders)),
+                                    ),
+                                )
+                                .load(conn)
+                                .await?
+                        },
+                        candles: candles::lock(conn, &Candle::from_trades(market, &trades)).await?,
+                    };
+                    let writes = rules::settle_trades(market, &trades, &completed_orders, rows)?;
+
+                    diesel::insert_into(db::schema::trades::table)
+                        .values(&trades)
+                        .execute(conn)
+                        .await?;
+                    {
+                        use db::schema::pending_orders::dsl::*;
+                        diesel::delete(
+                            pending_orders.filter(
+                                order_id.eq_any(&completed_orders).and(exchange.eq(market)),
+                            ),
+                        )
+                        .execute(conn)
+                        .await?;
+                    }
+                    update_orders(conn, &writes.orders).await?;
+                    candles::store(conn, &writes.candles).await?;
+                    ledger::record_entries(conn, &writes.entries).await?;
+                    audit::append(conn, writes.events).await
+                }
+                .scope_boxed()
+            })
+            .await
+            .map_err(rolled_back(format!(
+                "Insertion of {} trades in {:?}",
+                trades_count, market
+            )))
+        }
+        .boxed()
+    }
+
+    fn insert_cancellation(
+        &self,
+        market: ExchangeMarket,
+        order: OrderId,
+        order_status: OrderStatus,
+        unmatched: Option<f64>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move {
+                    let cancelled: i64 = {
+                        use db::schema::cancelled_orders::dsl::*;
+                        cancelled_orders
+                            .filter(order_id.eq(order).and(exchange.eq(market)))
+                            .count()
+                            .get_result(conn)
+                            .await?
+                    };
+                    let locked = ledger::lock_orders(conn, market, &[order]).await?;
+                    let trades: Vec<Trade> = {
+                        use db::schema::trades::dsl::*;
+                        trades
+                            .filter(
+                                exchange
+                                    .eq(market)
+                                    .and(buy_order.eq(order).or(sell_order.eq(order))),
+                            )
+                            .load(conn)
+                            .await?
+                    };
+                    let cancellation = rules::cancel_order(
+                        locked.into_iter().next(),
+                        cancelled != 0,
+                        unmatched,
+                        &trades,
+                        order_status,
+                        &origin,
+                        Utc::now(),
+                    )?;
+                    let Some(writes) = cancellation else {
+                        return Ok(()); // Already recorded by an earlier attempt
+                    };
+                    diesel::insert_into(db::schema::cancelled_orders::table)
+                        .values(&writes.cancellation)
+                        .execute(conn)
+                        .await?;
+                    update_orders(conn, &[writes.order]).await?;
+                    {
+                        use db::schema::pending_orders::dsl::*;
+                        diesel::delete(pending_orders.find((order, market)))
+                            .execute(conn)
+                            .await?;
+                    }
+                    ledger::record_entries(conn, &writes.entries).await?;
+                    audit::append(conn, vec![writes.event]).await
+                }
+                .scope_boxed()
+            })
+            .await
+            .map_err(rolled_back(format!(
+                "Cancellation of order {:?} in {:?}",
+                order, market
+            )))
+        }
+        .boxed()
+    }
+
+    fn get_last_group_id(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<GroupId>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::order_groups::dsl::*;
+            let max_group_id: Option<i64> = order_groups
+                .filter(exchange.eq(market))
+                .select(diesel::dsl::max(group_id))
+                .first(conn)
+                .await?;
+            Ok(max_group_id.map(|e| e.into()))
+        }
+        .boxed()
+    }
+
+    fn get_open_order_groups(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<OrderGroup>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::order_groups::dsl::*;
+            let rows: Vec<OrderGroup> = order_groups
+                .filter(
+                    exchange
+                        .eq(market)
+                        .and(status.eq_any([OrderGroupStatus::Pending, OrderGroupStatus::Active])),
+                )
+                .load(conn)
+                .await?;
+            Ok(rows)
+        }
+        .boxed()
+    }
+
+    fn get_order_group(
+        &self,
+        group: GroupId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<OrderGroup>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::order_groups::dsl::*;
+            let row: Option<OrderGroup> = order_groups
+                .filter(group_id.eq(group).and(exchange.eq(market)))
+                .first(conn)
+                .await
+                .optional()?;
+            Ok(row)
+        }
+        .boxed()
+    }
+
+    fn upsert_order_groups(
+        &self,
+        groups: Vec<OrderGroup>,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::order_groups::dsl::*;
+            let upserted = diesel::insert_into(order_groups)
+                .values(&groups)
+                .on_conflict((group_id, exchange))
+                .do_update()
+                .set((
+                    status.eq(excluded(status)),
+                    quantity.eq(excluded(quantity)),
+                    entry_order.eq(excluded(entry_order)),
+                    take_profit_order.eq(excluded(take_profit_order)),
+                    stop_loss_order.eq(excluded(stop_loss_order)),
+                ))
+                .execute(conn)
+                .await?;
+            if upserted != groups.len() {
+                return Err(RustexError::DbServiceError(
+                    "Failed to record all the order groups".into(),
+                ));
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get_user_balances(&self, user: UserId) -> BoxFuture<'_, Result<Vec<Balance>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::balances::dsl::*;
+            let rows: Vec<Balance> = balances
+                .filter(user_id.eq(user))
+                .order_by(currency)
+                .load(conn)
+                .await?;
+            Ok(rows)
+        }
+        .boxed()
+    }
+
+    fn insert_transfer(
+        &self,
+        new_transfer: NewTransfer,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<Transfer, RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move {
+                    let available =
+                        ledger::available_funds(conn, new_transfer.user_id, new_transfer.currency)
+                            .await?;
+                    rules::check_transfer(&new_transfer, available)?;
+                    let transfer: Transfer = diesel::insert_into(db::schema::transfers::table)
+                        .values(&new_transfer)
+                        .returning(Transfer::as_returning())
+                        .get_result(conn)
+                        .await?;
+                    ledger::record_entries(conn, &transfer.request_entries()).await?;
+                    let event = transfer_event(&transfer, None, market, &origin);
+                    audit::append(conn, event.into_iter().collect()).await?;
+                    Ok(transfer)
+                }
+                .scope_boxed()
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn get_user_transfers(
+        &self,
+        user: UserId,
+    ) -> BoxFuture<'_, Result<Vec<Transfer>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::transfers::dsl::*;
+            let rows: Vec<Transfer> = transfers
+                .filter(user_id.eq(user))
+                .order_by(transfer_id)
+                .load(conn)
+                .await?;
+            Ok(rows)
+        }
+        .boxed()
+    }
+
+    fn transition_transfer(
+        &self,
+        transfer: i64,
+        from: TransferStatus,
+        to: TransferStatus,
+        reference: Option<String>,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<Transfer, RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move {
+                    use db::schema::transfers::dsl::*;
+                    let current: Option<Transfer> = transfers
+                        .find(transfer)
+                        .for_update()
+                        .first(conn)
+                        .await
+                        .optional()?;
+                    let updated = match rules::transition_transfer(
+                        current,
+                        transfer,
+                        from,
+                        to,
+                        reference,
+                        Utc::now(),
+                    )? {
+                        rules::Transition::Moved(updated) => updated,
+                        rules::Transition::Unchanged(transfer) => return Ok(transfer),
+                    };
+                    diesel::update(transfers.find(transfer))
+                        .set((
+                            status.eq(updated.status),
+                            external_reference.eq(&updated.external_reference),
+                            updated_at.eq(updated.updated_at),
+                        ))
+                        .execute(conn)
+                        .await?;
+                    ledger::record_entries(conn, &updated.transition_entries()).await?;
+                    let event = transfer_event(&updated, Some(from), market, &origin);
+                    audit::append(conn, event.into_iter().collect()).await?;
+                    Ok(updated)
+                }
+                .scope_boxed()
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn get_unfinished_transfers(&self) -> BoxFuture<'_, Result<Vec<Transfer>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::transfers::dsl::*;
+            let rows: Vec<Transfer> = transfers
+                .filter(status.eq_any([TransferStatus::Confirming, TransferStatus::Approved]))
+                .order_by(transfer_id)
+                .load(conn)
+                .await?;
+            Ok(rows)
+        }
+        .boxed()
+    }
+
+    fn get_user_fills(
+        &self,
+        user: UserId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Fill>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            // The fills of every time
+            let archived = archive::reaches(archive::cutoff(conn).await?, None);
+            let user_orders: Vec<(OrderId, OrderType)> =
+                live_or_archived!(archived, [orders::dsl::*], {
+                    orders
+                        .filter(user_id.eq(user).and(exchange.eq(market)))
+                        .select((order_id, order_type))
+                        .load(conn)
+                        .await?
+                });
+            let order_ids = user_orders.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+            let user_trades: Vec<Trade> = live_or_archived!(archived, [trades::dsl::*], {
+                trades
+                    .filter(
+                        exchange.eq(market).and(
+                            buy_order
+                                .eq_any(&order_ids)
+                                .or(sell_order.eq_any(&order_ids)),
+                        ),
+                    )
+                    .order_by(trade_id)
+                    .load(conn)
+                    .await?
+            });
+            Ok(Fill::from_trades(&user_trades, &user_orders))
+        }
+        .boxed()
+    }
+
+    fn get_trade_tape(
+        &self,
+        market: ExchangeMarket,
+        query: TapeQuery,
+    ) -> BoxFuture<'_, Result<Page<TapeTrade>, RustexError>> {
+        async move {
+            let page_size = query.page_size()?;
+            let conn = &mut *self.pool.get().await?;
+
+            // The archived trades precede the live ones, so the page only reaches them
+            // once it runs past the live trades
+            let mut archived = false;
+            loop {
+                let rows: Vec<TapeTrade> = live_or_archived!(archived, [trades::dsl::*], {
+                    let mut rows = trades.filter(exchange.eq(market)).into_boxed();
+                    if let Some(cursor) = query.cursor {
+                        rows = rows.filter(trade_id.lt(cursor));
+                    }
+                    rows.select((trade_id, price, quantity, aggressor, created_at))
+                        .order_by(trade_id.desc())
+                        .limit(page_size as i64 + 1)
+                        .load(conn)
+                        .await?
+                });
+                if archived || rows.len() > page_size || archive::cutoff(conn).await?.is_none() {
+                    return Ok(Page::new(rows, page_size, |trade| {
+                        i64::from(trade.trade_id)
+                    }));
+                }
+                archived = true;
+            }
+        }
+        .boxed()
+    }
+
+    fn get_candles(
+        &self,
+        market: ExchangeMarket,
+        query: CandleQuery,
+    ) -> BoxFuture<'_, Result<Vec<Candle>, RustexError>> {
+        async move {
+            let page_size = query.page_size()?;
+            let conn = &mut *self.pool.get().await?;
+
+            use db::schema::candles::dsl::*;
+            let mut rows = candles
+                .filter(exchange.eq(market).and(candle_interval.eq(query.interval)))
+                .into_boxed();
+            if let Some(from) = query.from {
+                rows = rows.filter(open_time.ge(from));
+            }
+            if let Some(to) = query.to {
+                rows = rows.filter(open_time.lt(to));
+            }
+            let mut rows: Vec<Candle> = rows
+                .order_by(open_time.desc())
+                .limit(page_size as i64)
+                .load(conn)
+                .await?;
+            rows.reverse();
+            Ok(rows)
+        }
+        .boxed()
+    }
+
+    fn rebuild_candles(
+        &self,
+        market: ExchangeMarket,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<usize, RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move { candles::rebuild(conn, market, from, to).await }.scope_boxed()
+            })
+            .await
+            .map_err(rolled_back(format!("Candle backfill of {:?}", market)))
+        }
+        .boxed()
+    }
+
+    fn get_last_trade_price(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<i64>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            let mut archived = false;
+            loop {
+                let last_price: Option<i64> = live_or_archived!(archived, [trades::dsl::*], {
+                    trades
+                        .filter(exchange.eq(market))
+                        .order_by(trade_id.desc())
+                        .select(price)
+                        .first(conn)
+                        .await
+                        .optional()?
+                });
+                if archived || last_price.is_some() || archive::cutoff(conn).await?.is_none() {
+                    return Ok(last_price);
+                }
+                archived = true;
+            }
+        }
+        .boxed()
+    }
+
+    fn get_book_at(
+        &self,
+        market: ExchangeMarket,
+        at: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<BookSnapshot, RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            // Orders and trades are read from the same snapshot of the database
+            conn.build_transaction()
+                .read_only()
+                .repeatable_read()
+                .run::<_, RustexError, _>(|conn| {
+                    async move {
+                        // The archived orders were completed before the cut-off
+                        let archived = archive::cutoff(conn).await?.is_some_and(|c| at < c);
+                        let placed: Vec<Order> =
+                            live_or_archived!(archived, [orders::dsl::*], {
+                                orders
+                                    .filter(exchange.eq(market).and(created_at.le(at)))
+                                    .load(conn)
+                                    .await?
+                            });
+                        let executed: Vec<Trade> =
+                            live_or_archived!(archived, [trades::dsl::*], {
+                                trades
+                                    .filter(exchange.eq(market).and(created_at.le(at)))
+                                    .load(conn)
+                                    .await?
+                            });
+                        let cancelled: Vec<CancelledOrder> =
+                            live_or_archived!(archived, [cancelled_orders::dsl::*], {
+                                cancelled_orders
+                                    .filter(exchange.eq(market).and(created_at.le(at)))
+                                    .load(conn)
+                                    .await?
+                            });
+                        Ok(BookSnapshot::replay(
+                            market, at, &placed, &executed, &cancelled,
+                        ))
+                    }
+                    .scope_boxed()
+                })
+                .await
+        }
+        .boxed()
+    }
+
+    fn get_all_risk_limits(&self) -> BoxFuture<'_, Result<Vec<RiskLimits>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::risk_limits::dsl::*;
+            let rows: Vec<RiskLimits> = risk_limits.load(conn).await?;
+            Ok(rows)
+        }
+        .boxed()
+    }
+
+    fn get_risk_limits(
+        &self,
+        user: UserId,
+    ) -> BoxFuture<'_, Result<Option<RiskLimits>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::risk_limits::dsl::*;
+            let row: Option<RiskLimits> = risk_limits.find(user).first(conn).await.optional()?;
+            Ok(row)
+        }
+        .boxed()
+    }
+
+    fn upsert_risk_limits(
+        &self,
+        limits: RiskLimits,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move {
+                    use db::schema::risk_limits::dsl::*;
+                    diesel::insert_into(risk_limits)
+                        .values(&limits)
+                        .on_conflict(user_id)
+                        .do_update()
+                        .set((
+                            max_order_quantity.eq(excluded(max_order_quantity)),
+                            max_notional.eq(excluded(max_notional)),
+                            max_open_orders.eq(excluded(max_open_orders)),
+                            max_orders_per_second.eq(excluded(max_orders_per_second)),
+                            max_price_deviation.eq(excluded(max_price_deviation)),
+                        ))
+                        .execute(conn)
+                        .await?;
+                    audit::append(conn, vec![risk_limits_event(&limits, &origin)]).await
+                }
+                .scope_boxed()
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn delete_risk_limits(
+        &self,
+        user: UserId,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<bool, RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move {
+                    use db::schema::risk_limits::dsl::*;
+                    let deleted = diesel::delete(risk_limits.find(user)).execute(conn).await? == 1;
+                    if deleted {
+                        let event = risk_limits_deletion_event(user, &origin);
+                        audit::append(conn, vec![event]).await?;
+                    }
+                    Ok(deleted)
+                }
+                .scope_boxed()
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn create_partitions(&self) -> BoxFuture<'_, Result<usize, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            let created: i32 =
+                diesel::select(rustex_create_partitions(Utc::now(), PARTITIONS_AHEAD))
+                    .get_result(conn)
+                    .await?;
+            Ok(created as usize)
+        }
+        .boxed()
+    }
+
+    fn archive(&self, before: DateTime<Utc>) -> BoxFuture<'_, Result<usize, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            let archived: i32 = diesel::select(rustex_archive_partitions(before))
+                .get_result(conn)
+                .await?;
+            Ok(archived as usize)
+        }
+        .boxed()
+    }
+
+    fn append_audit(&self, events: Vec<AuditEvent>) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let mut conn = self.pool.get().await?;
+            let writes = format!("Audit of {} events", events.len());
+            conn.transaction::<_, RustexError, _>(|conn| {
+                async move { audit::append(conn, events).await }.scope_boxed()
+            })
+            .await
+            .map_err(rolled_back(writes))
+        }
+        .boxed()
+    }
+
+    fn get_audit_log(
+        &self,
+        after: Option<i64>,
+        limit: i64,
+    ) -> BoxFuture<'_, Result<Vec<AuditEntry>, RustexError>> {
+        async move {
+            let conn = &mut *self.pool.get().await?;
+            use db::schema::audit_log::dsl::*;
+            let entries = audit_log
+                .filter(seq.gt(after.unwrap_or(0)))
+                .order_by(seq.asc())
+                .limit(limit)
+                .select(AuditEntry::as_select())
+                .load(conn)
+                .await?;
+            Ok(entries)
+        }
+        .boxed()
+    }
+}
+
+/// Registers the users placing orders, as orders reference them
+async fn register_users(
+    conn: &mut AsyncPgConnection,
+    user_ids: impl IntoIterator<Item = UserId>,
+) -> Result<(), RustexError> {
+    use db::schema::users::dsl::*;
+    let rows = user_ids
+        .into_iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|id| user_id.eq(id))
+        .collect::<Vec<_>>();
+    diesel::insert_into(users)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Stores the filled quantity, average price and status of the orders
+async fn update_orders(conn: &mut AsyncPgConnection, updated: &[Order]) -> Result<(), RustexError> {
+    use db::schema::orders::dsl::*;
+    for order in updated {
+        diesel::update(orders.filter(order_id.eq(order.order_id).and(exchange.eq(order.exchange))))
+            .set((
+                status.eq(order.status),
+                filled_quantity.eq(order.filled_quantity),
+                average_price.eq(order.average_price),
+            ))
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Run against a migrated database with `TEST_DATABASE_URL=postgres://... cargo test -- --ignored`.
+/// Every test reserves its own ids and uses a user of its own, so runs do not collide
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{fund, order};
+
+    const MARKET: ExchangeMarket = ExchangeMarket::BTC_USD;
+
+    fn storage() -> PgStorage {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is set");
+        PgStorage::new(&url).unwrap()
+    }
+
+    /// Fresh user, and the first of `count` order ids nobody else holds
+    async fn reserve(storage: &PgStorage, count: i64) -> (i64, i64) {
+        let block = storage
+            .reserve_ids(MARKET, IdKind::Order, 0, count)
+            .await
+            .unwrap();
+        let user = Utc::now().timestamp_micros();
+        (user, block.start)
+    }
+
+    async fn balances(storage: &PgStorage, user: i64) -> Vec<(Currencies, f64, f64)> {
+        let balances = storage.get_user_balances(user.into()).await.unwrap();
+        balances
+            .iter()
+            .map(|b| (b.currency, b.available, b.reserved))
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_orders_are_placed_while_funds_last() {
+        let storage = storage();
+        let (user, id) = reserve(&storage, 2).await;
+        fund(&storage, user.into(), Currencies::USD, 100.0).await;
+
+        let results = storage
+            .insert_orders(vec![
+                (order(id).user(user).build(), AuditOrigin::default()),
+                (order(id + 1).user(user).build(), AuditOrigin::default()),
+            ])
+            .await
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(RustexError::UserFacingError(_))));
+
+        assert_eq!(
+            balances(&storage, user).await,
+            [(Currencies::USD, 0.0, 100.0)]
+        );
+        let pending = storage.get_pending_orders_ids(MARKET).await.unwrap();
+        assert!(pending.contains(&id.into()) && !pending.contains(&(id + 1).into()));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_trades_are_settled_once() {
+        let storage = storage();
+        let (user, id) = reserve(&storage, 2).await;
+        fund(&storage, user.into(), Currencies::USD, 100.0).await;
+        fund(&storage, user.into(), Currencies::BTC, 1.0).await;
+        for order in [order(id).user(user).sell(), order(id + 1).user(user)] {
+            storage
+                .insert_order(order.build(), AuditOrigin::default())
+                .await
+                .unwrap();
+        }
+        let trade_id = storage
+            .reserve_ids(MARKET, IdKind::Trade, 0, 1)
+            .await
+            .unwrap()
+            .start;
+        let trade = Trade {
+            trade_id: trade_id.into(),
+            exchange: MARKET,
+            buy_order: (id + 1).into(),
+            sell_order: id.into(),
+            price: 100,
+            quantity: 1.0,
+            created_at: Some(Utc::now()),
+            aggressor: OrderType::Buy,
+        };
+
+        // The retry of the batch is skipped
+        for _ in 0..2 {
+            storage
+                .insert_trades(
+                    MARKET,
+                    vec![trade.clone()],
+                    vec![id.into(), (id + 1).into()],
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            balances(&storage, user).await,
+            [(Currencies::BTC, 1.0, 0.0), (Currencies::USD, 100.0, 0.0)]
+        );
+        let orders = storage
+            .get_orders(vec![id.into(), (id + 1).into()], MARKET)
+            .await
+            .unwrap();
+        assert!(orders.iter().all(|o| o.status == OrderStatus::Filled));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_cancellations_release_the_funds_once() {
+        let storage = storage();
+        let (user, id) = reserve(&storage, 1).await;
+        fund(&storage, user.into(), Currencies::USD, 100.0).await;
+        storage
+            .insert_order(order(id).user(user).build(), AuditOrigin::default())
+            .await
+            .unwrap();
+
+        // The retry of the cancellation is skipped
+        for _ in 0..2 {
+            storage
+                .insert_cancellation(
+                    MARKET,
+                    id.into(),
+                    OrderStatus::Cancelled,
+                    None,
+                    AuditOrigin::default(),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            balances(&storage, user).await,
+            [(Currencies::USD, 100.0, 0.0)]
+        );
+        let pending = storage.get_pending_orders_ids(MARKET).await.unwrap();
+        assert!(!pending.contains(&id.into()));
+    }
+}
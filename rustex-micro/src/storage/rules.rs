@@ -0,0 +1,370 @@
+//! Business rules shared by the storage backends. They never touch the
+//! database: each backend reads the rows a rule needs, applies it and
+//! writes its outcome within the same transaction
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use rustex_core::prelude::*;
+use rustex_errors::RustexError;
+
+use super::{cancellation_event, order_event, rolled_back, trade_events};
+
+/// Rows written by a batch of new orders
+#[derive(Debug, Default)]
+pub(crate) struct OrderWrites {
+    pub orders: Vec<Order>, // Accepted and rejected orders
+    pub pending: Vec<PendingOrder>,
+    pub entries: Vec<NewLedgerEntry>,
+    pub events: Vec<AuditEvent>,
+    pub results: Vec<Result<(), RustexError>>, // One per order of the batch
+}
+
+impl OrderWrites {
+    /// Users placing the orders, as orders reference them
+    pub fn users(&self) -> BTreeSet<UserId> {
+        self.orders.iter().map(|order| order.user_id).collect()
+    }
+}
+
+/// Accepts the orders whose funds are available, drawing them down in order, and
+/// records the rest as rejected. Orders already `recorded`, or repeated in the
+/// batch, are not inserted again. `funds` holds the available funds of the users
+pub(crate) fn place_orders(
+    orders: Vec<(Order, AuditOrigin)>,
+    mut recorded: HashSet<(ExchangeMarket, OrderId)>,
+    mut funds: HashMap<(UserId, Currencies), f64>,
+    now: DateTime<Utc>,
+) -> OrderWrites {
+    let mut writes = OrderWrites::default();
+    for (order, origin) in orders {
+        if !recorded.insert((order.exchange, order.order_id)) {
+            let writes_name = format!(
+                "Insertion of order {:?} in {:?}",
+                order.order_id, order.exchange
+            );
+            writes
+                .results
+                .push(Err(rolled_back(writes_name)(RustexError::DbServiceError(
+                    "Failed to insert the order in the database".into(),
+                ))));
+            continue;
+        }
+        let order = Order {
+            created_at: order.created_at.or(Some(now)),
+            ..order
+        };
+        let (asset, amount) = order.required_funds();
+        let available = funds.entry((order.user_id, asset)).or_default();
+        if *available < amount {
+            // The order never reaches the book but its owner can still look it up
+            let rejected = Order {
+                status: OrderStatus::Rejected,
+                ..order
+            };
+            let e = RustexError::UserFacingError(format!(
+                "Insufficient {asset:?} funds to place the order"
+            ));
+            writes
+                .events
+                .push(order_event(&rejected, &origin, Some(&e)));
+            writes.orders.push(rejected);
+            writes.results.push(Err(e));
+            continue;
+        }
+        *available -= amount;
+        writes
+            .entries
+            .extend(NewLedgerEntry::order_reservation(&order));
+        writes.events.push(order_event(&order, &origin, None));
+        writes.pending.push(PendingOrder {
+            order_id: order.order_id,
+            exchange: order.exchange,
+        });
+        writes.orders.push(order);
+        writes.results.push(Ok(()));
+    }
+    writes
+}
+
+/// Whether a retried batch of trades was already recorded, given how many of its
+/// trades are. A batch recorded only in part cannot be settled again
+pub(crate) fn trades_recorded(trades: &[Trade], recorded: usize) -> Result<bool, RustexError> {
+    match recorded {
+        0 => Ok(false),
+        recorded if recorded == trades.len() => {
+            log::warn!("Skipping {} trades already recorded", trades.len());
+            Ok(true)
+        }
+        _ => Err(RustexError::DbServiceError(
+            "Failed to insert all orders in the database".into(),
+        )),
+    }
+}
+
+/// Ids of the orders traded in the batch or completed by it
+pub(crate) fn settled_orders(trades: &[Trade], completed_orders: &[OrderId]) -> Vec<OrderId> {
+    trades
+        .iter()
+        .flat_map(|trade| [trade.buy_order, trade.sell_order])
+        .chain(completed_orders.iter().copied())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Rows read by the backend to settle a batch of trades
+#[derive(Debug, Default)]
+pub(crate) struct SettlementRows {
+    pub orders: Vec<Order>,         // Traded and completed orders
+    pub pending: Vec<OrderId>,      // Completed orders still pending
+    pub earlier_trades: Vec<Trade>, // Recorded trades of the completed orders
+    pub candles: Vec<Candle>,       // Stored candles of the bars the trades fall in
+}
+
+/// Rows written by a batch of trades
+#[derive(Debug, Default)]
+pub(crate) struct TradeWrites {
+    pub orders: Vec<Order>, // With their fills and status updated
+    pub entries: Vec<NewLedgerEntry>,
+    pub candles: Vec<Candle>, // Merged with the stored ones
+    pub events: Vec<AuditEvent>,
+}
+
+/// Updates the fills of the traded orders, settles the funds of the trades, folds
+/// them into the candles and releases whatever the completed orders still hold
+pub(crate) fn settle_trades(
+    market: ExchangeMarket,
+    trades: &[Trade],
+    completed_orders: &[OrderId],
+    rows: SettlementRows,
+) -> Result<TradeWrites, RustexError> {
+    if !completed_orders
+        .iter()
+        .all(|order| rows.pending.contains(order))
+    {
+        return Err(RustexError::DbServiceError(
+            "Failed to delete completed orders from the pending orders table".into(),
+        ));
+    }
+    let mut orders = rows
+        .orders
+        .into_iter()
+        .map(|order| (order.order_id, order))
+        .collect::<BTreeMap<_, _>>();
+    let mut entries = Vec::with_capacity(4 * trades.len());
+    for trade in trades {
+        match (orders.get(&trade.buy_order), orders.get(&trade.sell_order)) {
+            (Some(buy_order), Some(sell_order)) => entries.extend(
+                NewLedgerEntry::trade_settlement(trade, buy_order, sell_order),
+            ),
+            _ => {
+                return Err(RustexError::DbServiceError(
+                    "Cannot settle a trade of an unknown order".into(),
+                ))
+            }
+        }
+    }
+
+    for order in orders.values_mut() {
+        order.record_fills(trades, completed_orders);
+    }
+    let order_trades = rows.earlier_trades.iter().chain(trades);
+    entries.extend(release_funds(
+        completed_orders
+            .iter()
+            .filter_map(|order| orders.get(order)),
+        order_trades,
+    ));
+
+    Ok(TradeWrites {
+        orders: orders.into_values().collect(),
+        entries,
+        candles: merge_candles(Candle::from_trades(market, trades), &rows.candles),
+        events: trade_events(market, trades),
+    })
+}
+
+/// Unlocks whatever the orders still hold reserved given their trades
+pub(crate) fn release_funds<'a>(
+    orders: impl IntoIterator<Item = &'a Order>,
+    trades: impl IntoIterator<Item = &'a Trade> + Clone,
+) -> Vec<NewLedgerEntry> {
+    orders
+        .into_iter()
+        .flat_map(|order| {
+            let order_trades = trades.clone().into_iter().filter(|trade| {
+                trade.exchange == order.exchange
+                    && (trade.buy_order == order.order_id || trade.sell_order == order.order_id)
+            });
+            NewLedgerEntry::order_release(order, order.remaining_quantity(order_trades))
+        })
+        .collect()
+}
+
+/// Rows written by a cancellation
+#[derive(Debug)]
+pub(crate) struct CancellationWrites {
+    pub cancellation: CancelledOrder,
+    pub order: Order, // With its final status
+    pub entries: Vec<NewLedgerEntry>,
+    pub event: AuditEvent,
+}
+
+/// Cancels the order releasing the funds of its `unmatched` quantity, or else whatever
+/// its recorded trades left. `None` if an earlier attempt already recorded it, as it
+/// is not recorded twice
+pub(crate) fn cancel_order(
+    order: Option<Order>,
+    already_cancelled: bool,
+    unmatched: Option<f64>,
+    trades: &[Trade],
+    status: OrderStatus,
+    origin: &AuditOrigin,
+    now: DateTime<Utc>,
+) -> Result<Option<CancellationWrites>, RustexError> {
+    if already_cancelled {
+        return Ok(None);
+    }
+    let order = order
+        .ok_or_else(|| RustexError::DbServiceError("Failed to record cancelled order".into()))?;
+    let order = Order { status, ..order };
+    let unmatched = unmatched.unwrap_or_else(|| order.remaining_quantity(trades));
+    Ok(Some(CancellationWrites {
+        cancellation: CancelledOrder {
+            order_id: order.order_id,
+            exchange: order.exchange,
+            created_at: Some(now),
+        },
+        entries: NewLedgerEntry::order_release(&order, unmatched).into(),
+        event: cancellation_event(order.exchange, order.order_id, status, origin),
+        order,
+    }))
+}
+
+/// Withdrawals are only requested if the funds are available
+pub(crate) fn check_transfer(transfer: &NewTransfer, available: f64) -> Result<(), RustexError> {
+    if transfer.kind == TransferKind::Withdrawal && available < transfer.amount {
+        return Err(RustexError::UserFacingError(format!(
+            "Insufficient {:?} funds to withdraw",
+            transfer.currency
+        )));
+    }
+    Ok(())
+}
+
+/// Transfer after a transition
+pub(crate) enum Transition {
+    Moved(Transfer),     // Reached the new status. Its entries are to be booked
+    Unchanged(Transfer), // Already moved by an earlier attempt, so nothing is booked twice
+}
+
+/// Moves the transfer from one status to the next. Its ledger entries
+/// are those booked when the transfer reaches the new status
+pub(crate) fn transition_transfer(
+    current: Option<Transfer>,
+    transfer: i64,
+    from: TransferStatus,
+    to: TransferStatus,
+    reference: Option<String>,
+    now: DateTime<Utc>,
+) -> Result<Transition, RustexError> {
+    let current = current.ok_or_else(|| {
+        RustexError::UserFacingError(format!("Transfer {transfer} does not exist"))
+    })?;
+    if current.status == to && current.status != from {
+        return Ok(Transition::Unchanged(current));
+    }
+    if current.status != from {
+        return Err(RustexError::UserFacingError(format!(
+            "Transfer {transfer} is {:?}",
+            current.status
+        )));
+    }
+    Ok(Transition::Moved(Transfer {
+        status: to,
+        external_reference: reference.or(current.external_reference),
+        updated_at: Some(now),
+        ..current
+    }))
+}
+
+/// Net change of every balance the entries book. Sorted to always lock the balance rows in the same order
+pub(crate) fn balance_deltas(
+    entries: &[NewLedgerEntry],
+) -> BTreeMap<(UserId, Currencies), Balance> {
+    let mut deltas: BTreeMap<(UserId, Currencies), Balance> = BTreeMap::new();
+    for entry in entries.iter().filter(|entry| entry.amount != 0.0) {
+        let delta = deltas
+            .entry((entry.user_id, entry.currency))
+            .or_insert(Balance {
+                user_id: entry.user_id,
+                currency: entry.currency,
+                available: 0.0,
+                reserved: 0.0,
+            });
+        match entry.account {
+            LedgerAccount::Available => delta.available += entry.amount,
+            LedgerAccount::Reserved => delta.reserved += entry.amount,
+            LedgerAccount::External => (),
+        }
+    }
+    deltas
+}
+
+/// Folds the candles of new trades into the stored candles of the same bars
+pub(crate) fn merge_candles(candles: Vec<Candle>, stored: &[Candle]) -> Vec<Candle> {
+    candles
+        .into_iter()
+        .map(|candle| {
+            let bar = stored.iter().find(|stored| {
+                (stored.exchange, stored.interval, stored.open_time)
+                    == (candle.exchange, candle.interval, candle.open_time)
+            });
+            match bar {
+                Some(stored) => {
+                    let mut stored = *stored;
+                    stored.merge(&candle);
+                    stored
+                }
+                None => candle,
+            }
+        })
+        .collect()
+}
+
+/// Time range of the candles rebuilt for the given one. Widened to
+/// whole days so that every bar of the range is complete
+pub(crate) fn candle_range(
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    let from = CandleInterval::OneDay.open_time(from);
+    let to = CandleInterval::OneDay.open_time(to) + CandleInterval::OneDay.duration();
+    (from, to)
+}
+
+/// Next block of ids of a sequence, starting at `from` or later
+pub(crate) fn reserve_ids(next: i64, from: i64, count: i64) -> IdBlock {
+    let start = next.max(from);
+    IdBlock {
+        start,
+        end: start + count,
+    }
+}
+
+/// Entries of the events chained after the last entry of the log
+pub(crate) fn chain_events(
+    mut last: Option<AuditEntry>,
+    events: Vec<AuditEvent>,
+    now: DateTime<Utc>,
+) -> Vec<AuditEntry> {
+    events
+        .into_iter()
+        .map(|event| {
+            let entry = AuditEntry::chain(last.as_ref(), event, now);
+            last = Some(entry.clone());
+            entry
+        })
+        .collect()
+}
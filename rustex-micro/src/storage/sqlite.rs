@@ -0,0 +1,1519 @@
+//! Storage for single-node setups, in a SQLite file whose tables mirror the
+//! Postgres migrations. SQLite blocks, so the database is only accessed from
+//! blocking tasks
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
+use rusqlite::{
+    params, params_from_iter,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, Row, ToSql, TransactionBehavior,
+};
+use rustex_core::{lock, prelude::*};
+use rustex_errors::RustexError;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use super::{
+    risk_limits_deletion_event, risk_limits_event, rolled_back, rules, transfer_event, Storage,
+};
+
+const SCHEMA: &str = include_str!("sqlite.sql");
+
+const ORDER_COLUMNS: &str = "order_id, user_id, price, quantity, created_at, order_type, \
+                             exchange, notional, status, filled_quantity, average_price";
+const TRADE_COLUMNS: &str =
+    "trade_id, exchange, buy_order, sell_order, price, quantity, created_at, aggressor";
+const GROUP_COLUMNS: &str = "group_id, exchange, user_id, kind, status, exit_type, quantity, \
+                             take_profit_price, stop_trigger_price, stop_limit_price, \
+                             entry_order, take_profit_order, stop_loss_order, created_at";
+const TRANSFER_COLUMNS: &str = "transfer_id, user_id, kind, currency, amount, status, \
+                                external_reference, created_at, updated_at";
+const CANDLE_COLUMNS: &str = "exchange, candle_interval, open_time, open, high, low, close, \
+                              volume, quote_volume, trade_count";
+const RISK_LIMITS_COLUMNS: &str = "user_id, max_order_quantity, max_notional, max_open_orders, \
+                                   max_orders_per_second, max_price_deviation";
+const AUDIT_COLUMNS: &str =
+    "seq, created_at, actor, source_ip, action, exchange, details, prev_hash, hash";
+
+/// Enum stored by its serialized name
+struct Name<T>(T);
+
+impl<T: Serialize> ToSql for Name<T> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match serde_json::to_value(&self.0) {
+            Ok(Value::String(name)) => Ok(ToSqlOutput::from(name)),
+            _ => Err(rusqlite::Error::ToSqlConversionFailure(
+                "Only enums are stored by name".into(),
+            )),
+        }
+    }
+}
+
+impl<T: DeserializeOwned> FromSql for Name<T> {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let name = String::column_result(value)?;
+        serde_json::from_value(Value::String(name))
+            .map(Name)
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+/// Timestamp stored as RFC 3339 text. Always in UTC with microseconds, so that it sorts chronologically
+struct Timestamp(DateTime<Utc>);
+
+impl ToSql for Timestamp {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let text = self.0.to_rfc3339_opts(SecondsFormat::Micros, true);
+        Ok(ToSqlOutput::from(text))
+    }
+}
+
+impl FromSql for Timestamp {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let text = String::column_result(value)?;
+        DateTime::parse_from_rfc3339(&text)
+            .map(|ts| Timestamp(ts.with_timezone(&Utc)))
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+fn name<T: DeserializeOwned>(row: &Row, column: &str) -> rusqlite::Result<T> {
+    Ok(row.get::<_, Name<T>>(column)?.0)
+}
+
+fn timestamp(row: &Row, column: &str) -> rusqlite::Result<Option<DateTime<Utc>>> {
+    Ok(row.get::<_, Option<Timestamp>>(column)?.map(|ts| ts.0))
+}
+
+/// Rank of the market in the sort order of the history, which follows
+/// the declaration of the markets as the Postgres enum does
+fn market_rank(column: &str) -> String {
+    let ranks = ExchangeMarket::ALL
+        .iter()
+        .enumerate()
+        .map(|(rank, market)| {
+            let name = serde_json::to_value(market).expect("Markets are named");
+            format!("WHEN '{}' THEN {rank}", name.as_str().unwrap_or_default())
+        })
+        .collect::<Vec<_>>();
+    format!("(CASE {column} {} END)", ranks.join(" "))
+}
+
+fn rank_of(market: ExchangeMarket) -> i64 {
+    ExchangeMarket::ALL
+        .iter()
+        .position(|m| *m == market)
+        .expect("Every market is listed") as i64
+}
+
+/// Conditions of a query along with the arguments they bind, in order
+#[derive(Default)]
+struct Filters {
+    conditions: Vec<String>,
+    args: Vec<Box<dyn ToSql>>,
+}
+
+impl Filters {
+    fn add(&mut self, condition: impl Into<String>, args: Vec<Box<dyn ToSql>>) {
+        self.conditions.push(condition.into());
+        self.args.extend(args);
+    }
+
+    fn sql(&self) -> String {
+        self.conditions.join(" AND ")
+    }
+}
+
+fn order_row(row: &Row) -> rusqlite::Result<Order> {
+    Ok(Order {
+        order_id: row.get::<_, i64>("order_id")?.into(),
+        user_id: row.get::<_, i64>("user_id")?.into(),
+        price: row.get("price")?,
+        quantity: row.get("quantity")?,
+        created_at: timestamp(row, "created_at")?,
+        order_type: name(row, "order_type")?,
+        exchange: name(row, "exchange")?,
+        notional: row.get("notional")?,
+        status: name(row, "status")?,
+        filled_quantity: row.get("filled_quantity")?,
+        average_price: row.get("average_price")?,
+    })
+}
+
+fn trade_row(row: &Row) -> rusqlite::Result<Trade> {
+    Ok(Trade {
+        trade_id: row.get::<_, i64>("trade_id")?.into(),
+        exchange: name(row, "exchange")?,
+        buy_order: row.get::<_, i64>("buy_order")?.into(),
+        sell_order: row.get::<_, i64>("sell_order")?.into(),
+        price: row.get("price")?,
+        quantity: row.get("quantity")?,
+        created_at: timestamp(row, "created_at")?,
+        aggressor: name(row, "aggressor")?,
+    })
+}
+
+fn cancellation_row(row: &Row) -> rusqlite::Result<CancelledOrder> {
+    Ok(CancelledOrder {
+        order_id: row.get::<_, i64>("order_id")?.into(),
+        exchange: name(row, "exchange")?,
+        created_at: timestamp(row, "created_at")?,
+    })
+}
+
+fn group_row(row: &Row) -> rusqlite::Result<OrderGroup> {
+    let order = |column| -> rusqlite::Result<_> {
+        Ok(row.get::<_, Option<i64>>(column)?.map(OrderId::from))
+    };
+    Ok(OrderGroup {
+        group_id: row.get::<_, i64>("group_id")?.into(),
+        exchange: name(row, "exchange")?,
+        user_id: row.get::<_, i64>("user_id")?.into(),
+        kind: name(row, "kind")?,
+        status: name(row, "status")?,
+        exit_type: name(row, "exit_type")?,
+        quantity: row.get("quantity")?,
+        take_profit_price: row.get("take_profit_price")?,
+        stop_trigger_price: row.get("stop_trigger_price")?,
+        stop_limit_price: row.get("stop_limit_price")?,
+        entry_order: order("entry_order")?,
+        take_profit_order: order("take_profit_order")?,
+        stop_loss_order: order("stop_loss_order")?,
+        created_at: timestamp(row, "created_at")?,
+    })
+}
+
+fn balance_row(row: &Row) -> rusqlite::Result<Balance> {
+    Ok(Balance {
+        user_id: row.get::<_, i64>("user_id")?.into(),
+        currency: name(row, "currency")?,
+        available: row.get("available")?,
+        reserved: row.get("reserved")?,
+    })
+}
+
+fn transfer_row(row: &Row) -> rusqlite::Result<Transfer> {
+    Ok(Transfer {
+        transfer_id: row.get("transfer_id")?,
+        user_id: row.get::<_, i64>("user_id")?.into(),
+        kind: name(row, "kind")?,
+        currency: name(row, "currency")?,
+        amount: row.get("amount")?,
+        status: name(row, "status")?,
+        external_reference: row.get("external_reference")?,
+        created_at: timestamp(row, "created_at")?,
+        updated_at: timestamp(row, "updated_at")?,
+    })
+}
+
+fn candle_row(row: &Row) -> rusqlite::Result<Candle> {
+    Ok(Candle {
+        exchange: name(row, "exchange")?,
+        interval: name(row, "candle_interval")?,
+        open_time: timestamp(row, "open_time")?.unwrap_or_default(),
+        open: row.get("open")?,
+        high: row.get("high")?,
+        low: row.get("low")?,
+        close: row.get("close")?,
+        volume: row.get("volume")?,
+        quote_volume: row.get("quote_volume")?,
+        trade_count: row.get("trade_count")?,
+    })
+}
+
+fn risk_limits_row(row: &Row) -> rusqlite::Result<RiskLimits> {
+    Ok(RiskLimits {
+        user_id: row.get::<_, i64>("user_id")?.into(),
+        max_order_quantity: row.get("max_order_quantity")?,
+        max_notional: row.get("max_notional")?,
+        max_open_orders: row.get("max_open_orders")?,
+        max_orders_per_second: row.get("max_orders_per_second")?,
+        max_price_deviation: row.get("max_price_deviation")?,
+    })
+}
+
+fn audit_row(row: &Row) -> rusqlite::Result<AuditEntry> {
+    Ok(AuditEntry {
+        seq: row.get("seq")?,
+        created_at: timestamp(row, "created_at")?.unwrap_or_default(),
+        actor: row.get::<_, Option<i64>>("actor")?.map(UserId::from),
+        source_ip: row.get("source_ip")?,
+        action: name(row, "action")?,
+        exchange: row.get::<_, Option<Name<_>>>("exchange")?.map(|m| m.0),
+        details: row.get("details")?,
+        prev_hash: row.get("prev_hash")?,
+        hash: row.get("hash")?,
+    })
+}
+
+fn load_orders(
+    conn: &Connection,
+    market: ExchangeMarket,
+    order_ids: &[OrderId],
+) -> Result<Vec<Order>, RustexError> {
+    let mut statement = conn.prepare_cached(&format!(
+        "SELECT {ORDER_COLUMNS} FROM orders WHERE exchange = ?1 AND order_id = ?2"
+    ))?;
+    let mut rows = vec![];
+    for order_id in order_ids {
+        let order = statement
+            .query_row(params![Name(market), i64::from(*order_id)], order_row)
+            .optional()?;
+        rows.extend(order);
+    }
+    Ok(rows)
+}
+
+/// Trades of any of the orders, each one once
+fn load_order_trades(
+    conn: &Connection,
+    market: ExchangeMarket,
+    order_ids: &[OrderId],
+) -> Result<Vec<Trade>, RustexError> {
+    let mut statement = conn.prepare_cached(&format!(
+        "SELECT {TRADE_COLUMNS} FROM trades \
+         WHERE exchange = ?1 AND (buy_order = ?2 OR sell_order = ?2)"
+    ))?;
+    let mut trades = BTreeMap::new();
+    for order_id in order_ids {
+        let rows = statement.query_map(params![Name(market), i64::from(*order_id)], trade_row)?;
+        for trade in rows {
+            let trade = trade?;
+            trades.insert(trade.trade_id, trade);
+        }
+    }
+    Ok(trades.into_values().collect())
+}
+
+/// Whether the row of the table exists
+fn exists(
+    conn: &Connection,
+    table: &str,
+    id_column: &str,
+    market: ExchangeMarket,
+    id: i64,
+) -> Result<bool, RustexError> {
+    let found = conn
+        .prepare_cached(&format!(
+            "SELECT 1 FROM {table} WHERE exchange = ?1 AND {id_column} = ?2"
+        ))?
+        .query_row(params![Name(market), id], |_| Ok(()))
+        .optional()?;
+    Ok(found.is_some())
+}
+
+/// Stores the filled quantity, average price and status of the orders
+fn update_orders(conn: &Connection, orders: &[Order]) -> Result<(), RustexError> {
+    let mut statement = conn.prepare_cached(
+        "UPDATE orders SET status = ?1, filled_quantity = ?2, average_price = ?3 \
+         WHERE exchange = ?4 AND order_id = ?5",
+    )?;
+    for order in orders {
+        statement.execute(params![
+            Name(order.status),
+            order.filled_quantity,
+            order.average_price,
+            Name(order.exchange),
+            i64::from(order.order_id)
+        ])?;
+    }
+    Ok(())
+}
+
+fn available_funds(
+    conn: &Connection,
+    user: UserId,
+    currency: Currencies,
+) -> Result<f64, RustexError> {
+    let funds = conn
+        .prepare_cached("SELECT available FROM balances WHERE user_id = ?1 AND currency = ?2")?
+        .query_row(params![i64::from(user), Name(currency)], |row| row.get(0))
+        .optional()?;
+    Ok(funds.unwrap_or(0.0))
+}
+
+/// Appends the entries to the ledger and updates the balances accordingly
+fn record_entries(conn: &Connection, entries: &[NewLedgerEntry]) -> Result<(), RustexError> {
+    let now = Timestamp(Utc::now());
+    let mut insert = conn.prepare_cached(
+        "INSERT INTO ledger_entries (reference, user_id, currency, account, amount, created_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for entry in entries.iter().filter(|entry| entry.amount != 0.0) {
+        insert.execute(params![
+            entry.reference,
+            i64::from(entry.user_id),
+            Name(entry.currency),
+            Name(entry.account),
+            entry.amount,
+            now
+        ])?;
+    }
+    let mut upsert = conn.prepare_cached(
+        "INSERT INTO balances (user_id, currency, available, reserved) VALUES (?1, ?2, ?3, ?4) \
+         ON CONFLICT (user_id, currency) DO UPDATE SET \
+         available = available + excluded.available, reserved = reserved + excluded.reserved",
+    )?;
+    for delta in rules::balance_deltas(entries).into_values() {
+        upsert.execute(params![
+            i64::from(delta.user_id),
+            Name(delta.currency),
+            delta.available,
+            delta.reserved
+        ])?;
+    }
+    Ok(())
+}
+
+/// Chains the events after the last entry of the log
+fn append_audit(conn: &Connection, events: Vec<AuditEvent>) -> Result<(), RustexError> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let last = conn
+        .prepare_cached(&format!(
+            "SELECT {AUDIT_COLUMNS} FROM audit_log ORDER BY seq DESC LIMIT 1"
+        ))?
+        .query_row([], audit_row)
+        .optional()?;
+    let mut insert = conn.prepare_cached(&format!(
+        "INSERT INTO audit_log ({AUDIT_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
+    ))?;
+    for entry in rules::chain_events(last, events, Utc::now()) {
+        insert.execute(params![
+            entry.seq,
+            Timestamp(entry.created_at),
+            entry.actor.map(i64::from),
+            entry.source_ip,
+            Name(entry.action),
+            entry.exchange.map(Name),
+            entry.details,
+            entry.prev_hash,
+            entry.hash
+        ])?;
+    }
+    Ok(())
+}
+
+fn insert_candles(conn: &Connection, candles: &[Candle]) -> Result<(), RustexError> {
+    let mut statement = conn.prepare_cached(&format!(
+        "INSERT OR REPLACE INTO candles ({CANDLE_COLUMNS}) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"
+    ))?;
+    for candle in candles {
+        statement.execute(params![
+            Name(candle.exchange),
+            Name(candle.interval),
+            Timestamp(candle.open_time),
+            candle.open,
+            candle.high,
+            candle.low,
+            candle.close,
+            candle.volume,
+            candle.quote_volume,
+            candle.trade_count
+        ])?;
+    }
+    Ok(())
+}
+
+/// Rows of the market recorded up to `at`, included
+fn rows_until<T>(
+    conn: &Connection,
+    columns: &str,
+    table: &str,
+    market: ExchangeMarket,
+    at: DateTime<Utc>,
+    row: impl FnMut(&Row) -> rusqlite::Result<T>,
+) -> Result<Vec<T>, RustexError> {
+    let rows = conn
+        .prepare_cached(&format!(
+            "SELECT {columns} FROM {table} WHERE exchange = ?1 AND created_at <= ?2"
+        ))?
+        .query_map(params![Name(market), Timestamp(at)], row)?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Opens the database creating its tables if they do not exist
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs the operation on a blocking task, as it waits for the
+    /// connection lock and possibly for the SQLite file
+    fn blocking<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&mut Connection) -> Result<T, RustexError> + Send + 'static,
+    ) -> BoxFuture<'_, Result<T, RustexError>> {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || op(&mut lock!(conn)))
+            .map(|r| r?)
+            .boxed()
+    }
+
+    fn read<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&Connection) -> Result<T, RustexError> + Send + 'static,
+    ) -> BoxFuture<'_, Result<T, RustexError>> {
+        self.blocking(move |conn| op(conn))
+    }
+
+    /// Runs the operation in a transaction, committed only if it succeeds
+    fn write<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&Connection) -> Result<T, RustexError> + Send + 'static,
+    ) -> BoxFuture<'_, Result<T, RustexError>> {
+        self.blocking(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let r = op(&tx)?;
+            tx.commit()?;
+            Ok(r)
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn check(&self) -> BoxFuture<'_, Result<(), RustexError>> {
+        self.read(|conn| {
+            conn.query_row("SELECT 1", [], |_| Ok(()))?;
+            Ok(())
+        })
+    }
+
+    fn reserve_ids(
+        &self,
+        market: ExchangeMarket,
+        kind: IdKind,
+        from: i64,
+        count: i64,
+    ) -> BoxFuture<'_, Result<IdBlock, RustexError>> {
+        self.write(move |conn| {
+            let stored = conn
+                .prepare_cached(
+                    "SELECT next_order_id, next_trade_id FROM id_sequences WHERE exchange = ?1",
+                )?
+                .query_row(params![Name(market)], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()?;
+            // Seeded from the recorded ids the first time
+            let (mut next_order, mut next_trade): (i64, i64) = match stored {
+                Some(next) => next,
+                None => conn.query_row(
+                    "SELECT COALESCE((SELECT MAX(order_id) + 1 FROM orders WHERE exchange = ?1), 0), \
+                            COALESCE((SELECT MAX(trade_id) + 1 FROM trades WHERE exchange = ?1), 0)",
+                    params![Name(market)],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?,
+            };
+            let next = match kind {
+                IdKind::Order => &mut next_order,
+                IdKind::Trade => &mut next_trade,
+            };
+            let block = rules::reserve_ids(*next, from, count);
+            *next = block.end;
+            conn.prepare_cached(
+                "INSERT OR REPLACE INTO id_sequences (exchange, next_order_id, next_trade_id) \
+                 VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![Name(market), next_order, next_trade])?;
+            Ok(block)
+        })
+    }
+
+    fn get_pending_orders_ids(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<OrderId>, RustexError>> {
+        self.read(move |conn| {
+            let ids = conn
+                .prepare_cached("SELECT order_id FROM pending_orders WHERE exchange = ?1")?
+                .query_map(params![Name(market)], |row| {
+                    Ok(OrderId::from(row.get::<_, i64>(0)?))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(ids)
+        })
+    }
+
+    fn get_order_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> BoxFuture<'_, Result<Page<OrderDetails>, RustexError>> {
+        self.read(move |conn| {
+            let page_size = query.page_size()?;
+            let cursor = query.cursor()?;
+            let rank = market_rank("exchange");
+
+            let mut filters = Filters::default();
+            filters.add("user_id = ?", vec![Box::new(i64::from(user))]);
+            if let Some(market) = query.market {
+                filters.add("exchange = ?", vec![Box::new(Name(market))]);
+            }
+            if let Some(side) = query.side {
+                filters.add("order_type = ?", vec![Box::new(Name(side))]);
+            }
+            if let Some(status) = query.status {
+                filters.add("status = ?", vec![Box::new(Name(status))]);
+            }
+            if let Some(from) = query.from {
+                filters.add("created_at >= ?", vec![Box::new(Timestamp(from))]);
+            }
+            if let Some(to) = query.to {
+                filters.add("created_at < ?", vec![Box::new(Timestamp(to))]);
+            }
+            if let Some(cursor) = cursor {
+                filters.add(
+                    format!(
+                        "(created_at < ? OR (created_at = ? AND \
+                         ({rank} < ? OR ({rank} = ? AND order_id < ?))))"
+                    ),
+                    vec![
+                        Box::new(Timestamp(cursor.created_at)),
+                        Box::new(Timestamp(cursor.created_at)),
+                        Box::new(rank_of(cursor.exchange)),
+                        Box::new(rank_of(cursor.exchange)),
+                        Box::new(i64::from(cursor.order_id)),
+                    ],
+                );
+            }
+            let rows: Vec<Order> = conn
+                .prepare(&format!(
+                    "SELECT {ORDER_COLUMNS} FROM orders WHERE {} \
+                     ORDER BY created_at DESC, {rank} DESC, order_id DESC LIMIT {}",
+                    filters.sql(),
+                    page_size + 1
+                ))?
+                .query_map(params_from_iter(filters.args), order_row)?
+                .collect::<rusqlite::Result<_>>()?;
+
+            let details = rows.into_iter().map(OrderDetails::from).collect();
+            Ok(Page::new(details, page_size, OrderDetails::cursor))
+        })
+    }
+
+    fn get_trade_history(
+        &self,
+        user: UserId,
+        query: HistoryQuery,
+    ) -> BoxFuture<'_, Result<Page<UserTrade>, RustexError>> {
+        self.read(move |conn| {
+            let page_size = query.page_size()?;
+            let cursor = query.cursor()?;
+            let rank = market_rank("t.exchange");
+
+            let mut filters = Filters::default();
+            filters.add("o.user_id = ?", vec![Box::new(i64::from(user))]);
+            if let Some(market) = query.market {
+                filters.add("t.exchange = ?", vec![Box::new(Name(market))]);
+            }
+            if let Some(side) = query.side {
+                filters.add("o.order_type = ?", vec![Box::new(Name(side))]);
+            }
+            if let Some(from) = query.from {
+                filters.add("t.created_at >= ?", vec![Box::new(Timestamp(from))]);
+            }
+            if let Some(to) = query.to {
+                filters.add("t.created_at < ?", vec![Box::new(Timestamp(to))]);
+            }
+            if let Some(cursor) = cursor {
+                filters.add(
+                    format!(
+                        "(t.created_at < ? OR (t.created_at = ? AND ({rank} < ? OR ({rank} = ? \
+                         AND (t.trade_id < ? OR (t.trade_id = ? AND o.order_id < ?))))))"
+                    ),
+                    vec![
+                        Box::new(Timestamp(cursor.created_at)),
+                        Box::new(Timestamp(cursor.created_at)),
+                        Box::new(rank_of(cursor.exchange)),
+                        Box::new(rank_of(cursor.exchange)),
+                        Box::new(cursor.id),
+                        Box::new(cursor.id),
+                        Box::new(i64::from(cursor.order_id)),
+                    ],
+                );
+            }
+            // A self-trade yields a record for each of the two orders
+            let rows: Vec<UserTrade> = conn
+                .prepare(&format!(
+                    "SELECT t.trade_id, t.exchange, o.order_id, o.order_type, t.price, \
+                     t.quantity, t.created_at FROM trades t JOIN orders o \
+                     ON o.exchange = t.exchange \
+                     AND (o.order_id = t.buy_order OR o.order_id = t.sell_order) WHERE {} \
+                     ORDER BY t.created_at DESC, {rank} DESC, t.trade_id DESC, \
+                     o.order_id DESC LIMIT {}",
+                    filters.sql(),
+                    page_size + 1
+                ))?
+                .query_map(params_from_iter(filters.args), |row| {
+                    Ok(UserTrade {
+                        trade_id: row.get::<_, i64>("trade_id")?.into(),
+                        exchange: name(row, "exchange")?,
+                        order_id: row.get::<_, i64>("order_id")?.into(),
+                        side: name(row, "order_type")?,
+                        price: row.get("price")?,
+                        quantity: row.get("quantity")?,
+                        created_at: timestamp(row, "created_at")?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+
+            Ok(Page::new(rows, page_size, UserTrade::cursor))
+        })
+    }
+
+    fn get_order_user(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<UserId>, RustexError>> {
+        self.read(move |conn| {
+            let user = conn
+                .prepare_cached("SELECT user_id FROM orders WHERE exchange = ?1 AND order_id = ?2")?
+                .query_row(params![Name(market), i64::from(order)], |row| {
+                    row.get::<_, i64>(0)
+                })
+                .optional()?;
+            Ok(user.map(UserId::from))
+        })
+    }
+
+    fn get_orders(
+        &self,
+        orders: Vec<OrderId>,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Order>, RustexError>> {
+        self.read(move |conn| {
+            let order_ids = orders.into_iter().collect::<BTreeSet<_>>();
+            load_orders(conn, market, &order_ids.into_iter().collect::<Vec<_>>())
+        })
+    }
+
+    fn get_order_trades(
+        &self,
+        order: OrderId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Trade>, RustexError>> {
+        self.read(move |conn| load_order_trades(conn, market, &[order]))
+    }
+
+    fn insert_order(
+        &self,
+        order: Order,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        async move {
+            let mut results = self.insert_orders(vec![(order, origin)]).await?;
+            results.pop().expect("One result per order")
+        }
+        .boxed()
+    }
+
+    fn insert_orders(
+        &self,
+        orders: Vec<(Order, AuditOrigin)>,
+    ) -> BoxFuture<'_, Result<Vec<Result<(), RustexError>>, RustexError>> {
+        let writes = format!("Batch of {} orders", orders.len());
+        self.write(move |conn| {
+            let mut recorded = HashSet::new();
+            for (order, _) in &orders {
+                let key = (order.exchange, order.order_id);
+                if exists(conn, "orders", "order_id", key.0, key.1.into())? {
+                    recorded.insert(key);
+                }
+            }
+            let mut funds = HashMap::new();
+            for (order, _) in &orders {
+                let (asset, _) = order.required_funds();
+                let available = available_funds(conn, order.user_id, asset)?;
+                funds.insert((order.user_id, asset), available);
+            }
+            let writes = rules::place_orders(orders, recorded, funds, Utc::now());
+
+            let now = Timestamp(Utc::now());
+            let mut register = conn.prepare_cached(
+                "INSERT INTO users (user_id, created_at) VALUES (?1, ?2) \
+                 ON CONFLICT (user_id) DO NOTHING",
+            )?;
+            for user_id in writes.users() {
+                register.execute(params![i64::from(user_id), now])?;
+            }
+            let mut insert = conn.prepare_cached(&format!(
+                "INSERT INTO orders ({ORDER_COLUMNS}) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"
+            ))?;
+            for order in &writes.orders {
+                insert.execute(params![
+                    i64::from(order.order_id),
+                    i64::from(order.user_id),
+                    order.price,
+                    order.quantity,
+                    order.created_at.map(Timestamp),
+                    Name(order.order_type),
+                    Name(order.exchange),
+                    order.notional,
+                    Name(order.status),
+                    order.filled_quantity,
+                    order.average_price
+                ])?;
+            }
+            let mut pending = conn.prepare_cached(
+                "INSERT INTO pending_orders (order_id, exchange) VALUES (?1, ?2)",
+            )?;
+            for order in &writes.pending {
+                pending.execute(params![i64::from(order.order_id), Name(order.exchange)])?;
+            }
+            record_entries(conn, &writes.entries)?;
+            append_audit(conn, writes.events)?;
+            Ok(writes.results)
+        })
+        .map_err(rolled_back(writes))
+        .boxed()
+    }
+
+    fn insert_trades(
+        &self,
+        market: ExchangeMarket,
+        trades: Vec<Trade>,
+        completed_orders: Vec<OrderId>,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        let writes = format!("Insertion of {} trades in {:?}", trades.len(), market);
+        self.write(move |conn| {
+            // Retried batches are already recorded
+            let mut recorded = 0;
+            for trade in &trades {
+                if exists(conn, "trades", "trade_id", market, trade.trade_id.into())? {
+                    recorded += 1;
+                }
+            }
+            if rules::trades_recorded(&trades, recorded)? {
+                return Ok(());
+            }
+            let settled = rules::settled_orders(&trades, &completed_orders);
+            let mut pending = vec![];
+            for order in &settled {
+                if exists(conn, "pending_orders", "order_id", market, (*order).into())? {
+                    pending.push(*order);
+                }
+            }
+            let mut stored_candles = vec![];
+            let mut candle = conn.prepare_cached(&format!(
+                "SELECT {CANDLE_COLUMNS} FROM candles \
+                 WHERE exchange = ?1 AND candle_interval = ?2 AND open_time = ?3"
+            ))?;
+            for bar in Candle::from_trades(market, &trades) {
+                let bar = params![
+                    Name(bar.exchange),
+                    Name(bar.interval),
+                    Timestamp(bar.open_time)
+                ];
+                stored_candles.extend(candle.query_row(bar, candle_row).optional()?);
+            }
+            let rows = rules::SettlementRows {
+                orders: load_orders(conn, market, &settled)?,
+                pending,
+                earlier_trades: load_order_trades(conn, market, &completed_orders)?,
+                candles: stored_candles,
+            };
+            let writes = rules::settle_trades(market, &trades, &completed_orders, rows)?;
+
+            let mut insert = conn.prepare_cached(&format!(
+                "INSERT INTO trades ({TRADE_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+            ))?;
+            for trade in &trades {
+                insert.execute(params![
+                    i64::from(trade.trade_id),
+                    Name(trade.exchange),
+                    i64::from(trade.buy_order),
+                    i64::from(trade.sell_order),
+                    trade.price,
+                    trade.quantity,
+                    trade.created_at.map(Timestamp),
+                    Name(trade.aggressor)
+                ])?;
+            }
+            let mut completed = conn.prepare_cached(
+                "DELETE FROM pending_orders WHERE exchange = ?1 AND order_id = ?2",
+            )?;
+            for order in &completed_orders {
+                completed.execute(params![Name(market), i64::from(*order)])?;
+            }
+            update_orders(conn, &writes.orders)?;
+            insert_candles(conn, &writes.candles)?;
+            record_entries(conn, &writes.entries)?;
+            append_audit(conn, writes.events)
+        })
+        .map_err(rolled_back(writes))
+        .boxed()
+    }
+
+    fn insert_cancellation(
+        &self,
+        market: ExchangeMarket,
+        order: OrderId,
+        status: OrderStatus,
+        unmatched: Option<f64>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        let writes = format!("Cancellation of order {:?} in {:?}", order, market);
+        self.write(move |conn| {
+            let trades = load_order_trades(conn, market, &[order])?;
+            let cancellation = rules::cancel_order(
+                load_orders(conn, market, &[order])?.pop(),
+                exists(conn, "cancelled_orders", "order_id", market, order.into())?,
+                unmatched,
+                &trades,
+                status,
+                &origin,
+                Utc::now(),
+            )?;
+            let Some(writes) = cancellation else {
+                return Ok(()); // Already recorded by an earlier attempt
+            };
+            conn.prepare_cached(
+                "INSERT INTO cancelled_orders (order_id, exchange, created_at) \
+                 VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![
+                i64::from(writes.cancellation.order_id),
+                Name(writes.cancellation.exchange),
+                writes.cancellation.created_at.map(Timestamp)
+            ])?;
+            update_orders(conn, &[writes.order])?;
+            conn.prepare_cached(
+                "DELETE FROM pending_orders WHERE exchange = ?1 AND order_id = ?2",
+            )?
+            .execute(params![Name(market), i64::from(order)])?;
+            record_entries(conn, &writes.entries)?;
+            append_audit(conn, vec![writes.event])
+        })
+        .map_err(rolled_back(writes))
+        .boxed()
+    }
+
+    fn get_last_group_id(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<GroupId>, RustexError>> {
+        self.read(move |conn| {
+            let last: Option<i64> = conn
+                .prepare_cached("SELECT MAX(group_id) FROM order_groups WHERE exchange = ?1")?
+                .query_row(params![Name(market)], |row| row.get(0))?;
+            Ok(last.map(GroupId::from))
+        })
+    }
+
+    fn get_open_order_groups(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<OrderGroup>, RustexError>> {
+        self.read(move |conn| {
+            let rows = conn
+                .prepare_cached(&format!(
+                    "SELECT {GROUP_COLUMNS} FROM order_groups \
+                     WHERE exchange = ?1 AND status IN (?2, ?3) ORDER BY group_id"
+                ))?
+                .query_map(
+                    params![
+                        Name(market),
+                        Name(OrderGroupStatus::Pending),
+                        Name(OrderGroupStatus::Active)
+                    ],
+                    group_row,
+                )?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(rows)
+        })
+    }
+
+    fn get_order_group(
+        &self,
+        group: GroupId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<OrderGroup>, RustexError>> {
+        self.read(move |conn| {
+            let row = conn
+                .prepare_cached(&format!(
+                    "SELECT {GROUP_COLUMNS} FROM order_groups WHERE exchange = ?1 AND group_id = ?2"
+                ))?
+                .query_row(params![Name(market), i64::from(group)], group_row)
+                .optional()?;
+            Ok(row)
+        })
+    }
+
+    fn upsert_order_groups(
+        &self,
+        groups: Vec<OrderGroup>,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        self.write(move |conn| {
+            let now = Utc::now();
+            let mut upsert = conn.prepare_cached(&format!(
+                "INSERT INTO order_groups ({GROUP_COLUMNS}) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14) \
+                 ON CONFLICT (group_id, exchange) DO UPDATE SET \
+                 status = excluded.status, quantity = excluded.quantity, \
+                 entry_order = excluded.entry_order, \
+                 take_profit_order = excluded.take_profit_order, \
+                 stop_loss_order = excluded.stop_loss_order"
+            ))?;
+            for group in &groups {
+                upsert.execute(params![
+                    i64::from(group.group_id),
+                    Name(group.exchange),
+                    i64::from(group.user_id),
+                    Name(group.kind),
+                    Name(group.status),
+                    Name(group.exit_type),
+                    group.quantity,
+                    group.take_profit_price,
+                    group.stop_trigger_price,
+                    group.stop_limit_price,
+                    group.entry_order.map(i64::from),
+                    group.take_profit_order.map(i64::from),
+                    group.stop_loss_order.map(i64::from),
+                    Timestamp(group.created_at.unwrap_or(now))
+                ])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_user_balances(&self, user: UserId) -> BoxFuture<'_, Result<Vec<Balance>, RustexError>> {
+        self.read(move |conn| {
+            let mut rows: Vec<Balance> = conn
+                .prepare_cached(
+                    "SELECT user_id, currency, available, reserved FROM balances \
+                     WHERE user_id = ?1",
+                )?
+                .query_map(params![i64::from(user)], balance_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            // In the declaration order of the currencies, as in Postgres
+            rows.sort_by_key(|balance| balance.currency);
+            Ok(rows)
+        })
+    }
+
+    fn insert_transfer(
+        &self,
+        transfer: NewTransfer,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<Transfer, RustexError>> {
+        self.write(move |conn| {
+            let available = available_funds(conn, transfer.user_id, transfer.currency)?;
+            rules::check_transfer(&transfer, available)?;
+            let now = Timestamp(Utc::now());
+            let transfer = conn
+                .prepare_cached(&format!(
+                    "INSERT INTO transfers \
+                     (user_id, kind, currency, amount, status, created_at, updated_at) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6) RETURNING {TRANSFER_COLUMNS}"
+                ))?
+                .query_row(
+                    params![
+                        i64::from(transfer.user_id),
+                        Name(transfer.kind),
+                        Name(transfer.currency),
+                        transfer.amount,
+                        Name(TransferStatus::Requested),
+                        now
+                    ],
+                    transfer_row,
+                )?;
+            record_entries(conn, &transfer.request_entries())?;
+            let event = transfer_event(&transfer, None, market, &origin);
+            append_audit(conn, event.into_iter().collect())?;
+            Ok(transfer)
+        })
+    }
+
+    fn get_user_transfers(
+        &self,
+        user: UserId,
+    ) -> BoxFuture<'_, Result<Vec<Transfer>, RustexError>> {
+        self.read(move |conn| {
+            let rows = conn
+                .prepare_cached(&format!(
+                    "SELECT {TRANSFER_COLUMNS} FROM transfers WHERE user_id = ?1 \
+                     ORDER BY transfer_id"
+                ))?
+                .query_map(params![i64::from(user)], transfer_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(rows)
+        })
+    }
+
+    fn transition_transfer(
+        &self,
+        transfer: i64,
+        from: TransferStatus,
+        to: TransferStatus,
+        reference: Option<String>,
+        market: Option<ExchangeMarket>,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<Transfer, RustexError>> {
+        self.write(move |conn| {
+            let current = conn
+                .prepare_cached(&format!(
+                    "SELECT {TRANSFER_COLUMNS} FROM transfers WHERE transfer_id = ?1"
+                ))?
+                .query_row(params![transfer], transfer_row)
+                .optional()?;
+            let updated = match rules::transition_transfer(
+                current,
+                transfer,
+                from,
+                to,
+                reference,
+                Utc::now(),
+            )? {
+                rules::Transition::Moved(updated) => updated,
+                rules::Transition::Unchanged(transfer) => return Ok(transfer),
+            };
+            conn.prepare_cached(
+                "UPDATE transfers SET status = ?1, external_reference = ?2, updated_at = ?3 \
+                 WHERE transfer_id = ?4",
+            )?
+            .execute(params![
+                Name(updated.status),
+                updated.external_reference,
+                updated.updated_at.map(Timestamp),
+                transfer
+            ])?;
+            record_entries(conn, &updated.transition_entries())?;
+            let event = transfer_event(&updated, Some(from), market, &origin);
+            append_audit(conn, event.into_iter().collect())?;
+            Ok(updated)
+        })
+    }
+
+    fn get_unfinished_transfers(&self) -> BoxFuture<'_, Result<Vec<Transfer>, RustexError>> {
+        self.read(move |conn| {
+            let rows = conn
+                .prepare_cached(&format!(
+                    "SELECT {TRANSFER_COLUMNS} FROM transfers WHERE status IN (?1, ?2) \
+                     ORDER BY transfer_id"
+                ))?
+                .query_map(
+                    params![
+                        Name(TransferStatus::Confirming),
+                        Name(TransferStatus::Approved)
+                    ],
+                    transfer_row,
+                )?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(rows)
+        })
+    }
+
+    fn get_user_fills(
+        &self,
+        user: UserId,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Vec<Fill>, RustexError>> {
+        self.read(move |conn| {
+            let user_orders: Vec<(OrderId, OrderType)> = conn
+                .prepare_cached(
+                    "SELECT order_id, order_type FROM orders WHERE user_id = ?1 AND exchange = ?2",
+                )?
+                .query_map(params![i64::from(user), Name(market)], |row| {
+                    Ok((row.get::<_, i64>(0)?.into(), name(row, "order_type")?))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            let order_ids = user_orders.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+            let user_trades = load_order_trades(conn, market, &order_ids)?;
+            Ok(Fill::from_trades(&user_trades, &user_orders))
+        })
+    }
+
+    fn get_trade_tape(
+        &self,
+        market: ExchangeMarket,
+        query: TapeQuery,
+    ) -> BoxFuture<'_, Result<Page<TapeTrade>, RustexError>> {
+        self.read(move |conn| {
+            let page_size = query.page_size()?;
+            let rows: Vec<Trade> = conn
+                .prepare_cached(&format!(
+                    "SELECT {TRADE_COLUMNS} FROM trades WHERE exchange = ?1 AND trade_id < ?2 \
+                     ORDER BY trade_id DESC LIMIT ?3"
+                ))?
+                .query_map(
+                    params![
+                        Name(market),
+                        query.cursor.unwrap_or(i64::MAX),
+                        page_size as i64 + 1
+                    ],
+                    trade_row,
+                )?
+                .collect::<rusqlite::Result<_>>()?;
+            let rows = rows.iter().map(TapeTrade::from).collect();
+            Ok(Page::new(rows, page_size, |trade| {
+                i64::from(trade.trade_id)
+            }))
+        })
+    }
+
+    fn get_candles(
+        &self,
+        market: ExchangeMarket,
+        query: CandleQuery,
+    ) -> BoxFuture<'_, Result<Vec<Candle>, RustexError>> {
+        self.read(move |conn| {
+            let page_size = query.page_size()?;
+            let mut filters = Filters::default();
+            filters.add("exchange = ?", vec![Box::new(Name(market))]);
+            filters.add("candle_interval = ?", vec![Box::new(Name(query.interval))]);
+            if let Some(from) = query.from {
+                filters.add("open_time >= ?", vec![Box::new(Timestamp(from))]);
+            }
+            if let Some(to) = query.to {
+                filters.add("open_time < ?", vec![Box::new(Timestamp(to))]);
+            }
+            let mut rows: Vec<Candle> = conn
+                .prepare(&format!(
+                    "SELECT {CANDLE_COLUMNS} FROM candles WHERE {} \
+                     ORDER BY open_time DESC LIMIT {page_size}",
+                    filters.sql()
+                ))?
+                .query_map(params_from_iter(filters.args), candle_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            rows.reverse();
+            Ok(rows)
+        })
+    }
+
+    fn rebuild_candles(
+        &self,
+        market: ExchangeMarket,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<usize, RustexError>> {
+        self.write(move |conn| {
+            let (from, to) = rules::candle_range(from, to);
+            let range_trades: Vec<Trade> = conn
+                .prepare_cached(&format!(
+                    "SELECT {TRADE_COLUMNS} FROM trades \
+                     WHERE exchange = ?1 AND created_at >= ?2 AND created_at < ?3 \
+                     ORDER BY trade_id"
+                ))?
+                .query_map(
+                    params![Name(market), Timestamp(from), Timestamp(to)],
+                    trade_row,
+                )?
+                .collect::<rusqlite::Result<_>>()?;
+            conn.prepare_cached(
+                "DELETE FROM candles WHERE exchange = ?1 AND open_time >= ?2 AND open_time < ?3",
+            )?
+            .execute(params![Name(market), Timestamp(from), Timestamp(to)])?;
+            let rebuilt = Candle::from_trades(market, &range_trades);
+            insert_candles(conn, &rebuilt)?;
+            Ok(rebuilt.len())
+        })
+        .map_err(rolled_back(format!("Candle backfill of {:?}", market)))
+        .boxed()
+    }
+
+    fn get_last_trade_price(
+        &self,
+        market: ExchangeMarket,
+    ) -> BoxFuture<'_, Result<Option<i64>, RustexError>> {
+        self.read(move |conn| {
+            let price = conn
+                .prepare_cached(
+                    "SELECT price FROM trades WHERE exchange = ?1 ORDER BY trade_id DESC LIMIT 1",
+                )?
+                .query_row(params![Name(market)], |row| row.get(0))
+                .optional()?;
+            Ok(price)
+        })
+    }
+
+    fn get_book_at(
+        &self,
+        market: ExchangeMarket,
+        at: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<BookSnapshot, RustexError>> {
+        // A single connection, so every table is read from the same state
+        self.read(move |conn| {
+            let placed = rows_until(conn, ORDER_COLUMNS, "orders", market, at, order_row)?;
+            let executed = rows_until(conn, TRADE_COLUMNS, "trades", market, at, trade_row)?;
+            let cancelled = rows_until(
+                conn,
+                "order_id, exchange, created_at",
+                "cancelled_orders",
+                market,
+                at,
+                cancellation_row,
+            )?;
+            Ok(BookSnapshot::replay(
+                market, at, &placed, &executed, &cancelled,
+            ))
+        })
+    }
+
+    fn get_all_risk_limits(&self) -> BoxFuture<'_, Result<Vec<RiskLimits>, RustexError>> {
+        self.read(move |conn| {
+            let rows = conn
+                .prepare_cached(&format!(
+                    "SELECT {RISK_LIMITS_COLUMNS} FROM risk_limits ORDER BY user_id"
+                ))?
+                .query_map([], risk_limits_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(rows)
+        })
+    }
+
+    fn get_risk_limits(
+        &self,
+        user: UserId,
+    ) -> BoxFuture<'_, Result<Option<RiskLimits>, RustexError>> {
+        self.read(move |conn| {
+            let row = conn
+                .prepare_cached(&format!(
+                    "SELECT {RISK_LIMITS_COLUMNS} FROM risk_limits WHERE user_id = ?1"
+                ))?
+                .query_row(params![i64::from(user)], risk_limits_row)
+                .optional()?;
+            Ok(row)
+        })
+    }
+
+    fn upsert_risk_limits(
+        &self,
+        limits: RiskLimits,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<(), RustexError>> {
+        self.write(move |conn| {
+            conn.prepare_cached(&format!(
+                "INSERT OR REPLACE INTO risk_limits ({RISK_LIMITS_COLUMNS}) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+            ))?
+            .execute(params![
+                i64::from(limits.user_id),
+                limits.max_order_quantity,
+                limits.max_notional,
+                limits.max_open_orders,
+                limits.max_orders_per_second,
+                limits.max_price_deviation
+            ])?;
+            append_audit(conn, vec![risk_limits_event(&limits, &origin)])
+        })
+    }
+
+    fn delete_risk_limits(
+        &self,
+        user: UserId,
+        origin: AuditOrigin,
+    ) -> BoxFuture<'_, Result<bool, RustexError>> {
+        self.write(move |conn| {
+            let deleted = conn
+                .prepare_cached("DELETE FROM risk_limits WHERE user_id = ?1")?
+                .execute(params![i64::from(user)])?
+                == 1;
+            if deleted {
+                append_audit(conn, vec![risk_limits_deletion_event(user, &origin)])?;
+            }
+            Ok(deleted)
+        })
+    }
+
+    /// Tables are not partitioned
+    fn create_partitions(&self) -> BoxFuture<'_, Result<usize, RustexError>> {
+        future::ready(Ok(0)).boxed()
+    }
+
+    fn archive(&self, _before: DateTime<Utc>) -> BoxFuture<'_, Result<usize, RustexError>> {
+        future::ready(Ok(0)).boxed()
+    }
+
+    fn append_audit(&self, events: Vec<AuditEvent>) -> BoxFuture<'_, Result<(), RustexError>> {
+        let writes = format!("Audit of {} events", events.len());
+        self.write(move |conn| append_audit(conn, events))
+            .map_err(rolled_back(writes))
+            .boxed()
+    }
+
+    fn get_audit_log(
+        &self,
+        after: Option<i64>,
+        limit: i64,
+    ) -> BoxFuture<'_, Result<Vec<AuditEntry>, RustexError>> {
+        self.read(move |conn| {
+            let entries = conn
+                .prepare_cached(&format!(
+                    "SELECT {AUDIT_COLUMNS} FROM audit_log WHERE seq > ?1 ORDER BY seq LIMIT ?2"
+                ))?
+                .query_map(params![after.unwrap_or(0), limit], audit_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(entries)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustex_core::prelude::*;
+
+    use super::*;
+    use crate::{
+        fixtures::{fund, order},
+        storage::Storage,
+    };
+
+    /// Fresh database file named after the test
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rustex-{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Order 0 sells to order 1. Order 2 is rejected for lack of funds
+    async fn trade_with_itself(storage: &SqliteStorage) {
+        fund(storage, 1.into(), Currencies::USD, 100.0).await;
+        fund(storage, 1.into(), Currencies::BTC, 1.0).await;
+        storage
+            .insert_order(order(0).sell().build(), AuditOrigin::default())
+            .await
+            .unwrap();
+        storage
+            .insert_order(order(1).build(), AuditOrigin::default())
+            .await
+            .unwrap();
+        let r = storage
+            .insert_order(order(2).build(), AuditOrigin::default())
+            .await;
+        assert!(matches!(r, Err(RustexError::UserFacingError(_))));
+        let trade = Trade {
+            trade_id: 0.into(),
+            exchange: ExchangeMarket::BTC_USD,
+            buy_order: 1.into(),
+            sell_order: 0.into(),
+            price: 100,
+            quantity: 1.0,
+            created_at: Some(chrono::Utc::now()),
+            aggressor: OrderType::Buy,
+        };
+        storage
+            .insert_trades(
+                ExchangeMarket::BTC_USD,
+                vec![trade],
+                vec![0.into(), 1.into()],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_settled_balances_survive_reopening() {
+        let path = temp_path("balances");
+        trade_with_itself(&SqliteStorage::open(&path).unwrap()).await;
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        let balances = storage.get_user_balances(1.into()).await.unwrap();
+        assert_eq!(
+            balances
+                .iter()
+                .map(|b| (b.currency, b.available, b.reserved))
+                .collect::<Vec<_>>(),
+            vec![(Currencies::BTC, 1.0, 0.0), (Currencies::USD, 100.0, 0.0)]
+        );
+        let last_price = storage.get_last_trade_price(ExchangeMarket::BTC_USD).await;
+        assert_eq!(last_price.unwrap(), Some(100));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_order_statuses_survive_reopening() {
+        let path = temp_path("statuses");
+        trade_with_itself(&SqliteStorage::open(&path).unwrap()).await;
+
+        // The rejected order is recorded as well
+        let storage = SqliteStorage::open(&path).unwrap();
+        let orders = storage
+            .get_orders(vec![0.into(), 1.into(), 2.into()], ExchangeMarket::BTC_USD)
+            .await
+            .unwrap();
+        assert_eq!(
+            orders.iter().map(|o| o.status).collect::<Vec<_>>(),
+            vec![
+                OrderStatus::Filled,
+                OrderStatus::Filled,
+                OrderStatus::Rejected
+            ]
+        );
+        let pending = storage
+            .get_pending_orders_ids(ExchangeMarket::BTC_USD)
+            .await
+            .unwrap();
+        assert!(pending.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_orders_and_trades_are_audited_along_with_them() {
+        let path = temp_path("audit");
+        let storage = SqliteStorage::open(&path).unwrap();
+        trade_with_itself(&storage).await;
+
+        let audit_log = storage.get_audit_log(None, 10).await.unwrap();
+        let actions: Vec<_> = audit_log.iter().map(|entry| entry.action).collect();
+        // The funding deposits are audited as well
+        assert_eq!(
+            actions,
+            [
+                AuditAction::TransferRequested,
+                AuditAction::TransferRequested,
+                AuditAction::OrderAccepted,
+                AuditAction::OrderAccepted,
+                AuditAction::OrderRejected,
+                AuditAction::Trade
+            ]
+        );
+        let mut verifier = AuditVerifier::default();
+        assert!(audit_log.iter().all(|entry| verifier.verify(entry).is_ok()));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_ids_are_never_handed_out_twice() {
+        let path = temp_path("ids");
+        let storage = SqliteStorage::open(&path).unwrap();
+        trade_with_itself(&storage).await;
+
+        // The sequence is seeded from the recorded orders
+        let block = storage
+            .reserve_ids(ExchangeMarket::BTC_USD, IdKind::Order, 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(block, IdBlock { start: 3, end: 13 });
+        drop(storage);
+        let storage = SqliteStorage::open(&path).unwrap();
+        let block = storage
+            .reserve_ids(ExchangeMarket::BTC_USD, IdKind::Order, 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(block, IdBlock { start: 13, end: 23 });
+        let _ = std::fs::remove_file(&path);
+    }
+}
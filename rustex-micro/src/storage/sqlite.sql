@@ -0,0 +1,187 @@
+-- Tables of the SQLite storage, mirroring the Postgres migrations. Orders, trades and
+-- cancellations are not partitioned. Enums are stored by their serialized name and
+-- timestamps as RFC 3339 UTC text with microseconds, so that they sort chronologically
+
+CREATE TABLE IF NOT EXISTS users
+(
+    user_id INTEGER NOT NULL PRIMARY KEY,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS orders
+(
+    order_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    price INTEGER NOT NULL CHECK (price > 0),
+    quantity REAL NOT NULL CHECK (quantity > 0),
+    created_at TEXT NOT NULL,
+    order_type TEXT NOT NULL,
+    exchange TEXT NOT NULL,
+    notional REAL,       -- Quote amount to spend
+    status TEXT NOT NULL,
+    filled_quantity REAL NOT NULL DEFAULT 0,
+    average_price REAL,  -- Volume-weighted price of the fills
+
+    PRIMARY KEY (order_id, exchange)
+);
+
+CREATE INDEX IF NOT EXISTS orders_user_id_created_at ON orders (user_id, created_at);
+
+CREATE TABLE IF NOT EXISTS pending_orders
+(
+    order_id INTEGER NOT NULL,
+    exchange TEXT NOT NULL,
+
+    PRIMARY KEY (order_id, exchange),
+    FOREIGN KEY (order_id, exchange) REFERENCES orders (order_id, exchange)
+);
+
+CREATE TABLE IF NOT EXISTS trades
+(
+    trade_id INTEGER NOT NULL,
+    exchange TEXT NOT NULL,
+    buy_order INTEGER NOT NULL,
+    sell_order INTEGER NOT NULL,
+    price INTEGER NOT NULL CHECK (price > 0),
+    quantity REAL NOT NULL CHECK (quantity > 0),
+    created_at TEXT NOT NULL,
+    aggressor TEXT NOT NULL,
+
+    PRIMARY KEY (trade_id, exchange),
+    FOREIGN KEY (buy_order, exchange) REFERENCES orders (order_id, exchange),
+    FOREIGN KEY (sell_order, exchange) REFERENCES orders (order_id, exchange)
+);
+
+CREATE INDEX IF NOT EXISTS trades_exchange_created_at ON trades (exchange, created_at);
+
+CREATE TABLE IF NOT EXISTS cancelled_orders
+(
+    order_id INTEGER NOT NULL,
+    exchange TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+
+    PRIMARY KEY (order_id, exchange),
+    FOREIGN KEY (order_id, exchange) REFERENCES orders (order_id, exchange)
+);
+
+CREATE TABLE IF NOT EXISTS order_groups
+(
+    group_id INTEGER NOT NULL,
+    exchange TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    status TEXT NOT NULL,
+    exit_type TEXT NOT NULL,
+    quantity REAL NOT NULL CHECK (quantity > 0),
+    take_profit_price INTEGER NOT NULL CHECK (take_profit_price > 0),
+    stop_trigger_price INTEGER NOT NULL CHECK (stop_trigger_price > 0),
+    stop_limit_price INTEGER NOT NULL CHECK (stop_limit_price > 0),
+    entry_order INTEGER,
+    take_profit_order INTEGER,
+    stop_loss_order INTEGER,
+    created_at TEXT,
+
+    PRIMARY KEY (group_id, exchange)
+);
+
+-- Double-entry ledger. Entries sharing a reference add up to zero per currency
+CREATE TABLE IF NOT EXISTS ledger_entries
+(
+    entry_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    reference TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    account TEXT NOT NULL,
+    amount REAL NOT NULL,
+    created_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS ledger_entries_reference ON ledger_entries (reference);
+
+-- Running totals of the available and reserved ledger accounts
+CREATE TABLE IF NOT EXISTS balances
+(
+    user_id INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    available REAL NOT NULL DEFAULT 0,
+    reserved REAL NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (user_id, currency)
+);
+
+-- Funds entering (deposits) or leaving (withdrawals) the exchange through the custodian
+CREATE TABLE IF NOT EXISTS transfers
+(
+    transfer_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    amount REAL NOT NULL CHECK (amount > 0),
+    status TEXT NOT NULL,
+    external_reference TEXT, -- Custodian reference once the funds moved
+    created_at TEXT,
+    updated_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS transfers_user_id ON transfers (user_id);
+
+CREATE TABLE IF NOT EXISTS risk_limits
+(
+    user_id INTEGER NOT NULL PRIMARY KEY,
+    max_order_quantity REAL,
+    max_notional REAL,
+    max_open_orders INTEGER,
+    max_orders_per_second INTEGER,
+    max_price_deviation REAL
+);
+
+-- OHLCV bars built from the trades as they are recorded
+CREATE TABLE IF NOT EXISTS candles
+(
+    exchange TEXT NOT NULL,
+    candle_interval TEXT NOT NULL,
+    open_time TEXT NOT NULL,
+    open INTEGER NOT NULL,
+    high INTEGER NOT NULL,
+    low INTEGER NOT NULL,
+    close INTEGER NOT NULL,
+    volume REAL NOT NULL,       -- Base quantity
+    quote_volume REAL NOT NULL, -- Quote amount
+    trade_count INTEGER NOT NULL,
+
+    PRIMARY KEY (exchange, candle_interval, open_time)
+);
+
+-- Next order and trade ids of every market. Seeded from the recorded ids the first time
+CREATE TABLE IF NOT EXISTS id_sequences
+(
+    exchange TEXT NOT NULL PRIMARY KEY,
+    next_order_id INTEGER NOT NULL DEFAULT 0,
+    next_trade_id INTEGER NOT NULL DEFAULT 0
+);
+
+-- Every row is hashed along with the hash of the previous one, so editing any row breaks the chain
+CREATE TABLE IF NOT EXISTS audit_log
+(
+    seq INTEGER NOT NULL PRIMARY KEY,
+    created_at TEXT NOT NULL,
+    actor INTEGER,     -- Unset for the changes made by the exchange itself
+    source_ip TEXT,
+    action TEXT NOT NULL,
+    exchange TEXT,
+    details TEXT NOT NULL, -- JSON
+    prev_hash TEXT NOT NULL,
+    hash TEXT NOT NULL
+);
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_update
+    BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'The audit log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_delete
+    BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'The audit log is append-only');
+END;
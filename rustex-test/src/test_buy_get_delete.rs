@@ -62,6 +62,24 @@ pub async fn test_buy_get_delete() {
     let mut rng = rand::rng();
     let quantity: f64 = rng.random_range(0.1..1_000_000.0);
 
+    // Fund the account. The order reserves price * quantity USD
+    let deposit_response = client
+        .post(format!("{}/v1/deposits", api_base_url))
+        .json(&json!({
+            "currency": "USD",
+            "amount": quantity
+        }))
+        .header("Authorization", bearer_token.clone())
+        .header("Content-Type", "application/json".to_string())
+        .send()
+        .await
+        .expect("Failed to execute deposit");
+    assert_eq!(
+        deposit_response.status(),
+        StatusCode::OK,
+        "Failed to deposit funds"
+    );
+
     // Step 2: Create a new order
     let orders_url = format!("{}/v1/orders", api_base_url);
     let order_response = client
@@ -83,10 +101,11 @@ pub async fn test_buy_get_delete() {
         StatusCode::OK,
         "Failed to create order"
     );
-    let order_id = order_response
-        .text()
+    let execution: serde_json::Value = order_response
+        .json()
         .await
-        .expect("Failed to read order ID");
+        .expect("Failed to parse order execution");
+    let order_id = execution["orderId"].to_string();
 
     // Step 3: Get all user orders
     let all_orders_response = client
@@ -124,11 +143,11 @@ pub async fn test_buy_get_delete() {
         order_status_response.status().is_success(),
         "Failed to check order state"
     );
-    let (is_pending, _remaining): (bool, f64) = order_status_response
+    let details: serde_json::Value = order_status_response
         .json()
         .await
         .expect("Failed to parse order state");
-    assert!(is_pending, "Order is not pending");
+    assert_eq!(details["status"], "new", "Order is not pending");
 
     // Step 5: Delete order
     let delete_response = client
@@ -161,11 +180,14 @@ pub async fn test_buy_get_delete() {
         final_status_response.status().is_success(),
         "Failed to get final order state"
     );
-    let (is_pending, _remaining): (bool, f64) = final_status_response
+    let details: serde_json::Value = final_status_response
         .json()
         .await
         .expect("Failed to parse final order state");
-    assert!(!is_pending, "Order is still pending after deletion");
+    assert_eq!(
+        details["status"], "cancelled",
+        "Order is still pending after deletion"
+    );
 
     println!("✅ All tests passed!");
 }